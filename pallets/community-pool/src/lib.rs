@@ -0,0 +1,594 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+pub use pallet::*;
+
+#[cfg(test)]
+mod mock;
+#[cfg(test)]
+mod tests;
+
+#[frame_support::pallet]
+pub mod pallet {
+    use frame_support::{
+        pallet_prelude::*,
+        traits::{Currency, ExistenceRequirement},
+        BoundedVec,
+    };
+    use frame_system::pallet_prelude::*;
+    use pallet_energy_trade::{self as energy_trade, DeliverySlot, OrderKind, OrderType, TokenBalanceOf};
+    use pallet_user_registry as user_registry;
+    use scale_info::TypeInfo;
+    use sp_runtime::traits::{One, Saturating, Zero};
+    use sp_std::prelude::*;
+
+    pub type PoolId = u32;
+
+    #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    #[scale_info(skip_type_params(T))]
+    pub struct CommunityPool<T: Config> {
+        pub admin: T::AccountId,
+        pub grid_location: BoundedVec<u8, ConstU32<32>>,
+        pub feed_in_tariff: TokenBalanceOf<T>,    // Paid by the grid for exported energy
+        pub retail_tariff: TokenBalanceOf<T>,     // Charged by the retailer for imported energy
+        pub members: BoundedVec<T::AccountId, T::MaxMembers>,
+        pub created_at: BlockNumberFor<T>,
+    }
+
+    #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    #[scale_info(skip_type_params(T))]
+    pub struct MemberPosition<T: Config> {
+        pub who: T::AccountId,
+        pub generation: TokenBalanceOf<T>,
+        pub consumption: TokenBalanceOf<T>,
+    }
+
+    #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    #[scale_info(skip_type_params(T))]
+    pub struct MemberAllocation<T: Config> {
+        pub who: T::AccountId,
+        pub supplied: TokenBalanceOf<T>,  // Net generation sold into the pool
+        pub received: TokenBalanceOf<T>,  // Net consumption covered by the pool
+        pub external_order: Option<T::Hash>,
+    }
+
+    #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    #[scale_info(skip_type_params(T))]
+    pub struct IntervalAllocation<T: Config> {
+        pub pool_price: TokenBalanceOf<T>,
+        pub pooled: TokenBalanceOf<T>,
+        pub surplus: TokenBalanceOf<T>,   // Offered on the market at the feed-in tariff
+        pub deficit: TokenBalanceOf<T>,   // Bid on the market at the retail tariff
+        pub members: BoundedVec<MemberAllocation<T>, T::MaxMembers>,
+        pub allocated_at: BlockNumberFor<T>,
+    }
+
+    #[pallet::config]
+    pub trait Config: frame_system::Config + energy_trade::Config + user_registry::Config {
+        type RuntimeEvent: From<Event<Self>> + IsType<<Self as frame_system::Config>::RuntimeEvent>;
+        #[pallet::constant]
+        type MaxMembers: Get<u32>;
+    }
+
+    #[pallet::pallet]
+    pub struct Pallet<T>(_);
+
+    #[pallet::storage]
+    #[pallet::getter(fn next_pool_id)]
+    pub type NextPoolId<T: Config> = StorageValue<_, PoolId, ValueQuery>;
+
+    #[pallet::storage]
+    #[pallet::getter(fn pools)]
+    pub type Pools<T: Config> = StorageMap<_, Twox64Concat, PoolId, CommunityPool<T>, OptionQuery>;
+
+    #[pallet::storage]
+    #[pallet::getter(fn member_of)]
+    pub type MemberOf<T: Config> = StorageMap<_, Blake2_128Concat, T::AccountId, PoolId, OptionQuery>;
+
+    #[pallet::storage]
+    #[pallet::getter(fn positions)]
+    pub type Positions<T: Config> = StorageDoubleMap<
+        _,
+        Twox64Concat,
+        PoolId,
+        Twox64Concat,
+        DeliverySlot,
+        BoundedVec<MemberPosition<T>, T::MaxMembers>,
+        ValueQuery,
+    >;
+
+    #[pallet::storage]
+    #[pallet::getter(fn allocations)]
+    pub type Allocations<T: Config> = StorageDoubleMap<
+        _,
+        Twox64Concat,
+        PoolId,
+        Twox64Concat,
+        DeliverySlot,
+        IntervalAllocation<T>,
+        OptionQuery,
+    >;
+
+    #[pallet::event]
+    #[pallet::generate_deposit(pub(super) fn deposit_event)]
+    pub enum Event<T: Config> {
+        PoolCreated {
+            pool_id: PoolId,
+            admin: T::AccountId,
+            location: Vec<u8>,
+        },
+        MemberAdded {
+            pool_id: PoolId,
+            who: T::AccountId,
+        },
+        MemberRemoved {
+            pool_id: PoolId,
+            who: T::AccountId,
+        },
+        TariffsUpdated {
+            pool_id: PoolId,
+            feed_in_tariff: TokenBalanceOf<T>,
+            retail_tariff: TokenBalanceOf<T>,
+        },
+        PositionSubmitted {
+            pool_id: PoolId,
+            delivery_slot: DeliverySlot,
+            who: T::AccountId,
+            generation: TokenBalanceOf<T>,
+            consumption: TokenBalanceOf<T>,
+        },
+        PositionExcluded {
+            pool_id: PoolId,
+            delivery_slot: DeliverySlot,
+            who: T::AccountId,
+        },
+        IntervalAllocated {
+            pool_id: PoolId,
+            delivery_slot: DeliverySlot,
+            pool_price: TokenBalanceOf<T>,
+            pooled: TokenBalanceOf<T>,
+            surplus: TokenBalanceOf<T>,
+            deficit: TokenBalanceOf<T>,
+        },
+        ExternalOrderFailed {
+            pool_id: PoolId,
+            delivery_slot: DeliverySlot,
+            who: T::AccountId,
+        },
+    }
+
+    #[pallet::error]
+    pub enum Error<T> {
+        PoolNotFound,
+        NotPoolAdmin,
+        NotRegistered,
+        AlreadyMember,
+        NotMember,
+        TooManyMembers,
+        InvalidTariffs,
+        InvalidLocation,
+        DeliverySlotPassed,
+        AlreadyAllocated,
+        NotAllowedToSell,
+    }
+
+    #[pallet::call]
+    impl<T: Config> Pallet<T> {
+        #[pallet::call_index(0)]
+        #[pallet::weight(10_000)]
+        pub fn create_pool(
+            origin: OriginFor<T>,
+            grid_location: Vec<u8>,
+            feed_in_tariff: TokenBalanceOf<T>,
+            retail_tariff: TokenBalanceOf<T>,
+        ) -> DispatchResult {
+            let admin = ensure_signed(origin)?;
+            ensure!(user_registry::Pallet::<T>::is_active_user(&admin), Error::<T>::NotRegistered);
+            ensure!(
+                !feed_in_tariff.is_zero() && feed_in_tariff <= retail_tariff,
+                Error::<T>::InvalidTariffs
+            );
+
+            let bounded_location: BoundedVec<u8, ConstU32<32>> = grid_location.clone().try_into()
+                .map_err(|_| Error::<T>::InvalidLocation)?;
+
+            let pool_id = Self::next_pool_id();
+            Pools::<T>::insert(pool_id, CommunityPool {
+                admin: admin.clone(),
+                grid_location: bounded_location,
+                feed_in_tariff,
+                retail_tariff,
+                members: BoundedVec::new(),
+                created_at: <frame_system::Pallet<T>>::block_number(),
+            });
+            NextPoolId::<T>::put(pool_id.saturating_add(1));
+
+            Self::deposit_event(Event::PoolCreated {
+                pool_id,
+                admin,
+                location: grid_location,
+            });
+
+            Ok(())
+        }
+
+        #[pallet::call_index(1)]
+        #[pallet::weight(10_000)]
+        pub fn add_member(
+            origin: OriginFor<T>,
+            pool_id: PoolId,
+            who: T::AccountId,
+        ) -> DispatchResult {
+            let caller = ensure_signed(origin)?;
+            ensure!(user_registry::Pallet::<T>::is_active_user(&who), Error::<T>::NotRegistered);
+            ensure!(!MemberOf::<T>::contains_key(&who), Error::<T>::AlreadyMember);
+
+            Pools::<T>::try_mutate(pool_id, |pool| -> DispatchResult {
+                let pool = pool.as_mut().ok_or(Error::<T>::PoolNotFound)?;
+                ensure!(pool.admin == caller, Error::<T>::NotPoolAdmin);
+                pool.members.try_push(who.clone()).map_err(|_| Error::<T>::TooManyMembers)?;
+                Ok(())
+            })?;
+            MemberOf::<T>::insert(&who, pool_id);
+
+            Self::deposit_event(Event::MemberAdded { pool_id, who });
+
+            Ok(())
+        }
+
+        // Called by the pool admin, or by a member to leave the pool
+        #[pallet::call_index(2)]
+        #[pallet::weight(10_000)]
+        pub fn remove_member(
+            origin: OriginFor<T>,
+            pool_id: PoolId,
+            who: T::AccountId,
+        ) -> DispatchResult {
+            let caller = ensure_signed(origin)?;
+            ensure!(Self::member_of(&who) == Some(pool_id), Error::<T>::NotMember);
+
+            Pools::<T>::try_mutate(pool_id, |pool| -> DispatchResult {
+                let pool = pool.as_mut().ok_or(Error::<T>::PoolNotFound)?;
+                ensure!(pool.admin == caller || who == caller, Error::<T>::NotPoolAdmin);
+                pool.members.retain(|member| *member != who);
+                Ok(())
+            })?;
+            MemberOf::<T>::remove(&who);
+
+            Self::deposit_event(Event::MemberRemoved { pool_id, who });
+
+            Ok(())
+        }
+
+        #[pallet::call_index(3)]
+        #[pallet::weight(10_000)]
+        pub fn set_tariffs(
+            origin: OriginFor<T>,
+            pool_id: PoolId,
+            feed_in_tariff: TokenBalanceOf<T>,
+            retail_tariff: TokenBalanceOf<T>,
+        ) -> DispatchResult {
+            let caller = ensure_signed(origin)?;
+            ensure!(
+                !feed_in_tariff.is_zero() && feed_in_tariff <= retail_tariff,
+                Error::<T>::InvalidTariffs
+            );
+
+            Pools::<T>::try_mutate(pool_id, |pool| -> DispatchResult {
+                let pool = pool.as_mut().ok_or(Error::<T>::PoolNotFound)?;
+                ensure!(pool.admin == caller, Error::<T>::NotPoolAdmin);
+                pool.feed_in_tariff = feed_in_tariff;
+                pool.retail_tariff = retail_tariff;
+                Ok(())
+            })?;
+
+            Self::deposit_event(Event::TariffsUpdated { pool_id, feed_in_tariff, retail_tariff });
+
+            Ok(())
+        }
+
+        // Expected generation and consumption of the caller in a delivery slot; a new
+        // submission for the same slot replaces the previous one
+        #[pallet::call_index(4)]
+        #[pallet::weight(10_000)]
+        pub fn submit_position(
+            origin: OriginFor<T>,
+            pool_id: PoolId,
+            delivery_slot: DeliverySlot,
+            generation: TokenBalanceOf<T>,
+            consumption: TokenBalanceOf<T>,
+        ) -> DispatchResult {
+            let who = ensure_signed(origin)?;
+            ensure!(Self::member_of(&who) == Some(pool_id), Error::<T>::NotMember);
+            ensure!(
+                delivery_slot >= energy_trade::Pallet::<T>::current_slot(),
+                Error::<T>::DeliverySlotPassed
+            );
+            ensure!(!Allocations::<T>::contains_key(pool_id, delivery_slot), Error::<T>::AlreadyAllocated);
+            if !generation.is_zero() {
+                ensure!(user_registry::Pallet::<T>::can_sell(&who), Error::<T>::NotAllowedToSell);
+            }
+
+            Positions::<T>::try_mutate(pool_id, delivery_slot, |positions| -> DispatchResult {
+                positions.retain(|position| position.who != who);
+                positions.try_push(MemberPosition { who: who.clone(), generation, consumption })
+                    .map_err(|_| Error::<T>::TooManyMembers)?;
+                Ok(())
+            })?;
+
+            Self::deposit_event(Event::PositionSubmitted {
+                pool_id,
+                delivery_slot,
+                who,
+                generation,
+                consumption,
+            });
+
+            Ok(())
+        }
+
+        // Net each member's position, trade pooled generation against pooled consumption at
+        // the pool price and take the remainder to the market for the same slot
+        #[pallet::call_index(5)]
+        #[pallet::weight(10_000)]
+        pub fn allocate_interval(
+            origin: OriginFor<T>,
+            pool_id: PoolId,
+            delivery_slot: DeliverySlot,
+        ) -> DispatchResult {
+            let caller = ensure_signed(origin)?;
+            let pool = Self::pools(pool_id).ok_or(Error::<T>::PoolNotFound)?;
+            ensure!(pool.admin == caller, Error::<T>::NotPoolAdmin);
+            ensure!(
+                delivery_slot >= energy_trade::Pallet::<T>::current_slot(),
+                Error::<T>::DeliverySlotPassed
+            );
+            ensure!(!Allocations::<T>::contains_key(pool_id, delivery_slot), Error::<T>::AlreadyAllocated);
+
+            let pool_price = Self::pool_price(&pool);
+            let (mut sellers, mut buyers) = Self::net_positions(pool_id, delivery_slot, pool_price);
+
+            // A member whose pooled transfer fails is excluded and the pool is shared out again
+            // among the others; every retry drops a member, so this ends
+            let (total_supply, total_demand, pooled, supplied, received) = loop {
+                let supply: Vec<TokenBalanceOf<T>> = sellers.iter().map(|(_, amount)| *amount).collect();
+                let demand: Vec<TokenBalanceOf<T>> = buyers.iter().map(|(_, amount)| *amount).collect();
+                let total_supply = supply.iter().fold(TokenBalanceOf::<T>::zero(), |acc, a| acc.saturating_add(*a));
+                let total_demand = demand.iter().fold(TokenBalanceOf::<T>::zero(), |acc, a| acc.saturating_add(*a));
+                let pooled = total_supply.min(total_demand);
+
+                let supplied = Self::share_out(&supply, total_supply, pooled);
+                let received = Self::share_out(&demand, total_demand, pooled);
+
+                let mut failed = None;
+                let transferred = frame_support::storage::with_storage_layer(|| {
+                    Self::pool_transfers(&sellers, &supplied, &buyers, &received, pool_price)
+                        .map_err(|(who, error)| {
+                            failed = Some(who);
+                            error
+                        })
+                });
+                match (transferred, failed) {
+                    (Ok(()), _) => break (total_supply, total_demand, pooled, supplied, received),
+                    (Err(_), Some(who)) => {
+                        sellers.retain(|(seller, _)| *seller != who);
+                        buyers.retain(|(buyer, _)| *buyer != who);
+                        Self::deposit_event(Event::PositionExcluded { pool_id, delivery_slot, who });
+                    },
+                    (Err(error), None) => return Err(error),
+                }
+            };
+
+            // Whatever the pool cannot absorb is offered at the feed-in tariff or bid at the
+            // retail tariff on the open market
+            let mut members = Vec::new();
+            for ((who, net), pooled_amount) in sellers.iter().zip(supplied.iter()) {
+                let external_order = Self::place_external(
+                    pool_id, &pool, delivery_slot, who, OrderType::Ask, net.saturating_sub(*pooled_amount),
+                );
+                members.push(MemberAllocation {
+                    who: who.clone(),
+                    supplied: *pooled_amount,
+                    received: Zero::zero(),
+                    external_order,
+                });
+            }
+            for ((who, net), pooled_amount) in buyers.iter().zip(received.iter()) {
+                let external_order = Self::place_external(
+                    pool_id, &pool, delivery_slot, who, OrderType::Bid, net.saturating_sub(*pooled_amount),
+                );
+                members.push(MemberAllocation {
+                    who: who.clone(),
+                    supplied: Zero::zero(),
+                    received: *pooled_amount,
+                    external_order,
+                });
+            }
+
+            let surplus = total_supply.saturating_sub(pooled);
+            let deficit = total_demand.saturating_sub(pooled);
+            Allocations::<T>::insert(pool_id, delivery_slot, IntervalAllocation {
+                pool_price,
+                pooled,
+                surplus,
+                deficit,
+                members: BoundedVec::truncate_from(members),
+                allocated_at: <frame_system::Pallet<T>>::block_number(),
+            });
+
+            Self::deposit_event(Event::IntervalAllocated {
+                pool_id,
+                delivery_slot,
+                pool_price,
+                pooled,
+                surplus,
+                deficit,
+            });
+
+            Ok(())
+        }
+    }
+
+    impl<T: Config> Pallet<T> {
+        // Members trade with each other at the midpoint of the feed-in and retail tariffs
+        pub fn pool_price(pool: &CommunityPool<T>) -> TokenBalanceOf<T> {
+            let two = TokenBalanceOf::<T>::one().saturating_add(One::one());
+            pool.feed_in_tariff.saturating_add(pool.retail_tariff) / two
+        }
+
+        // Split submitted positions into net sellers and net buyers. Members who left the
+        // pool or the registry are dropped, as are sides not backed by energy tokens or funds.
+        fn net_positions(
+            pool_id: PoolId,
+            delivery_slot: DeliverySlot,
+            pool_price: TokenBalanceOf<T>,
+        ) -> (Vec<(T::AccountId, TokenBalanceOf<T>)>, Vec<(T::AccountId, TokenBalanceOf<T>)>) {
+            let mut sellers = Vec::new();
+            let mut buyers = Vec::new();
+
+            for position in Self::positions(pool_id, delivery_slot) {
+                let who = position.who;
+                let net_supply = position.generation.saturating_sub(position.consumption);
+                let net_demand = position.consumption.saturating_sub(position.generation);
+
+                let backed = Self::member_of(&who) == Some(pool_id) &&
+                    user_registry::Pallet::<T>::is_active_user(&who) &&
+                    if !net_supply.is_zero() {
+                        pallet_energy_token::Pallet::<T>::token_balance(&who) >=
+                            energy_trade::Pallet::<T>::to_energy(net_supply)
+                    } else {
+                        energy_trade::Pallet::<T>::from_currency(
+                            <T as energy_trade::Config>::Currency::free_balance(&who)
+                        ) >= net_demand.saturating_mul(pool_price)
+                    };
+                if !backed {
+                    Self::deposit_event(Event::PositionExcluded { pool_id, delivery_slot, who });
+                    continue;
+                }
+
+                if !net_supply.is_zero() {
+                    sellers.push((who, net_supply));
+                } else if !net_demand.is_zero() {
+                    buyers.push((who, net_demand));
+                }
+            }
+
+            (sellers, buyers)
+        }
+
+        // Divide `volume` over `amounts` (summing to `total`) pro rata, handing out rounding
+        // dust one unit at a time in submission order
+        fn share_out(
+            amounts: &[TokenBalanceOf<T>],
+            total: TokenBalanceOf<T>,
+            volume: TokenBalanceOf<T>,
+        ) -> Vec<TokenBalanceOf<T>> {
+            let mut shares: Vec<TokenBalanceOf<T>> = amounts.iter()
+                .map(|amount| energy_trade::Pallet::<T>::pro_rata(*amount, volume, total))
+                .collect();
+
+            let allocated = shares.iter().fold(TokenBalanceOf::<T>::zero(), |acc, s| acc.saturating_add(*s));
+            let mut dust = volume.saturating_sub(allocated);
+            for (share, amount) in shares.iter_mut().zip(amounts.iter()) {
+                if dust.is_zero() {
+                    break;
+                }
+                if *share < *amount {
+                    *share = share.saturating_add(One::one());
+                    dust = dust.saturating_sub(One::one());
+                }
+            }
+            shares
+        }
+
+        // Pair sellers with buyers in order so each unit moves once, sellers' energy to buyers
+        // and buyers' payment to sellers. A failed transfer returns the member at fault.
+        fn pool_transfers(
+            sellers: &[(T::AccountId, TokenBalanceOf<T>)],
+            supplied: &[TokenBalanceOf<T>],
+            buyers: &[(T::AccountId, TokenBalanceOf<T>)],
+            received: &[TokenBalanceOf<T>],
+            price: TokenBalanceOf<T>,
+        ) -> Result<(), (T::AccountId, DispatchError)> {
+            let (mut i, mut j) = (0, 0);
+            let mut seller_left = supplied.first().copied().unwrap_or_default();
+            let mut buyer_left = received.first().copied().unwrap_or_default();
+            while i < sellers.len() && j < buyers.len() {
+                let amount = seller_left.min(buyer_left);
+                if !amount.is_zero() {
+                    Self::transfer_pooled(&sellers[i].0, &buyers[j].0, amount, price)?;
+                }
+                seller_left = seller_left.saturating_sub(amount);
+                buyer_left = buyer_left.saturating_sub(amount);
+                if seller_left.is_zero() {
+                    i += 1;
+                    seller_left = supplied.get(i).copied().unwrap_or_default();
+                }
+                if buyer_left.is_zero() {
+                    j += 1;
+                    buyer_left = received.get(j).copied().unwrap_or_default();
+                }
+            }
+            Ok(())
+        }
+
+        fn transfer_pooled(
+            seller: &T::AccountId,
+            buyer: &T::AccountId,
+            amount: TokenBalanceOf<T>,
+            price: TokenBalanceOf<T>,
+        ) -> Result<(), (T::AccountId, DispatchError)> {
+            let energy = energy_trade::Pallet::<T>::to_energy(amount);
+            pallet_energy_token::Pallet::<T>::reserve(seller, energy)
+                .and_then(|_| pallet_energy_token::Pallet::<T>::repatriate_reserved(seller, buyer, energy))
+                .map_err(|error| (seller.clone(), error))?;
+
+            <T as energy_trade::Config>::Currency::transfer(
+                buyer,
+                seller,
+                energy_trade::Pallet::<T>::to_currency(amount.saturating_mul(price)),
+                ExistenceRequirement::AllowDeath,
+            )
+            .map_err(|error| (buyer.clone(), error))
+        }
+
+        fn place_external(
+            pool_id: PoolId,
+            pool: &CommunityPool<T>,
+            delivery_slot: DeliverySlot,
+            who: &T::AccountId,
+            order_type: OrderType,
+            amount: TokenBalanceOf<T>,
+        ) -> Option<T::Hash> {
+            if amount.is_zero() {
+                return None;
+            }
+
+            let price = match order_type {
+                OrderType::Ask => pool.feed_in_tariff,
+                OrderType::Bid => pool.retail_tariff,
+            };
+            let placed = frame_support::storage::with_storage_layer(|| {
+                energy_trade::Pallet::<T>::do_place_order(
+                    who.clone(),
+                    order_type,
+                    OrderKind::Limit,
+                    amount,
+                    price,
+                    pool.grid_location.to_vec(),
+                    delivery_slot,
+                )
+            });
+            match placed {
+                Ok(order_id) => Some(order_id),
+                Err(_) => {
+                    Self::deposit_event(Event::ExternalOrderFailed {
+                        pool_id,
+                        delivery_slot,
+                        who: who.clone(),
+                    });
+                    None
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use crate::{mock::*, Error, Event};
+use frame_support::{
+    assert_noop, assert_ok,
+    traits::{LockableCurrency, WithdrawReasons},
+};
+use pallet_user_registry::UserRole;
+
+const ADMIN: u64 = 4;
+
+// Pool 0 at zone-a with a feed-in tariff of 4 and a retail tariff of 8; account 1 is a
+// prosumer, the other members consumers
+fn setup_pool(members: &[u64]) {
+    assert_ok!(UserRegistry::register_user(RuntimeOrigin::signed(ADMIN), UserRole::Consumer));
+    assert_ok!(CommunityPool::create_pool(RuntimeOrigin::signed(ADMIN), b"zone-a".to_vec(), 4, 8));
+    for &who in members {
+        let role = if who == 1 { UserRole::Prosumer } else { UserRole::Consumer };
+        assert_ok!(UserRegistry::register_user(RuntimeOrigin::signed(who), role));
+        assert_ok!(CommunityPool::add_member(RuntimeOrigin::signed(ADMIN), 0, who));
+    }
+}
+
+#[test]
+fn membership_is_managed_by_the_pool_admin() {
+    new_test_ext().execute_with(|| {
+        setup_pool(&[1]);
+        assert_noop!(
+            CommunityPool::add_member(RuntimeOrigin::signed(ADMIN), 0, 2),
+            Error::<Test>::NotRegistered
+        );
+        assert_ok!(UserRegistry::register_user(RuntimeOrigin::signed(2), UserRole::Consumer));
+        assert_noop!(
+            CommunityPool::add_member(RuntimeOrigin::signed(1), 0, 2),
+            Error::<Test>::NotPoolAdmin
+        );
+        assert_noop!(
+            CommunityPool::add_member(RuntimeOrigin::signed(ADMIN), 0, 1),
+            Error::<Test>::AlreadyMember
+        );
+        assert_noop!(
+            CommunityPool::create_pool(RuntimeOrigin::signed(ADMIN), b"zone-a".to_vec(), 9, 8),
+            Error::<Test>::InvalidTariffs
+        );
+
+        // Members may leave on their own
+        assert_ok!(CommunityPool::remove_member(RuntimeOrigin::signed(1), 0, 1));
+        assert_eq!(CommunityPool::member_of(1), None);
+        assert!(CommunityPool::pools(0).unwrap().members.is_empty());
+    });
+}
+
+#[test]
+fn consumers_cannot_submit_generation() {
+    new_test_ext().execute_with(|| {
+        setup_pool(&[1, 2]);
+        assert_noop!(
+            CommunityPool::submit_position(RuntimeOrigin::signed(2), 0, 0, 10, 0),
+            Error::<Test>::NotAllowedToSell
+        );
+        assert_noop!(
+            CommunityPool::submit_position(RuntimeOrigin::signed(3), 0, 0, 0, 10),
+            Error::<Test>::NotMember
+        );
+        assert_ok!(CommunityPool::submit_position(RuntimeOrigin::signed(1), 0, 0, 10, 0));
+    });
+}
+
+#[test]
+fn allocation_trades_pooled_energy_at_the_midpoint_and_bids_the_deficit() {
+    new_test_ext().execute_with(|| {
+        setup_pool(&[1, 2, 3]);
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(1), 30));
+        assert_ok!(CommunityPool::submit_position(RuntimeOrigin::signed(1), 0, 0, 40, 10));
+        assert_ok!(CommunityPool::submit_position(RuntimeOrigin::signed(2), 0, 0, 0, 20));
+        assert_ok!(CommunityPool::submit_position(RuntimeOrigin::signed(3), 0, 0, 0, 20));
+
+        assert_noop!(
+            CommunityPool::allocate_interval(RuntimeOrigin::signed(1), 0, 0),
+            Error::<Test>::NotPoolAdmin
+        );
+        assert_ok!(CommunityPool::allocate_interval(RuntimeOrigin::signed(ADMIN), 0, 0));
+
+        // The 30 pooled units are split evenly at a price of 6
+        assert_eq!((EnergyToken::token_balance(2), EnergyToken::token_balance(3)), (15, 15));
+        assert_eq!(Balances::free_balance(1), 10_000 + 180);
+        // The remaining 5 units each are bid at the retail tariff
+        assert_eq!(Balances::reserved_balance(2), 40);
+        assert_eq!(Balances::free_balance(2), 10_000 - 90 - 40);
+
+        let allocation = CommunityPool::allocations(0, 0).unwrap();
+        assert_eq!((allocation.pool_price, allocation.pooled, allocation.surplus, allocation.deficit), (6, 30, 0, 10));
+        assert_eq!(allocation.members.iter().filter(|member| member.external_order.is_some()).count(), 2);
+        assert_noop!(
+            CommunityPool::allocate_interval(RuntimeOrigin::signed(ADMIN), 0, 0),
+            Error::<Test>::AlreadyAllocated
+        );
+    });
+}
+
+#[test]
+fn positions_without_energy_behind_them_are_excluded() {
+    new_test_ext().execute_with(|| {
+        setup_pool(&[1, 2]);
+        assert_ok!(CommunityPool::submit_position(RuntimeOrigin::signed(1), 0, 0, 30, 0));
+        assert_ok!(CommunityPool::submit_position(RuntimeOrigin::signed(2), 0, 0, 0, 20));
+        assert_ok!(CommunityPool::allocate_interval(RuntimeOrigin::signed(ADMIN), 0, 0));
+
+        System::assert_has_event(Event::PositionExcluded { pool_id: 0, delivery_slot: 0, who: 1 }.into());
+        let allocation = CommunityPool::allocations(0, 0).unwrap();
+        assert_eq!((allocation.pooled, allocation.deficit), (0, 20));
+        assert_eq!(Balances::reserved_balance(2), 160);
+    });
+}
+
+#[test]
+fn members_whose_pooled_transfer_fails_are_excluded_and_the_pool_shared_again() {
+    new_test_ext().execute_with(|| {
+        setup_pool(&[1, 2, 3]);
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(1), 30));
+        assert_ok!(CommunityPool::submit_position(RuntimeOrigin::signed(1), 0, 0, 40, 10));
+        assert_ok!(CommunityPool::submit_position(RuntimeOrigin::signed(2), 0, 0, 0, 20));
+        assert_ok!(CommunityPool::submit_position(RuntimeOrigin::signed(3), 0, 0, 0, 20));
+        // Account 2's funds are locked, so paying for its pooled energy fails
+        Balances::set_lock(*b"testlock", &2, 10_000, WithdrawReasons::all());
+
+        assert_ok!(CommunityPool::allocate_interval(RuntimeOrigin::signed(ADMIN), 0, 0));
+
+        System::assert_has_event(Event::PositionExcluded { pool_id: 0, delivery_slot: 0, who: 2 }.into());
+        assert_eq!((EnergyToken::token_balance(2), EnergyToken::token_balance(3)), (0, 20));
+        assert_eq!(Balances::free_balance(1), 10_000 + 120);
+        assert_eq!(Balances::free_balance(2), 10_000);
+
+        let allocation = CommunityPool::allocations(0, 0).unwrap();
+        assert_eq!((allocation.pooled, allocation.surplus, allocation.deficit), (20, 10, 0));
+        assert!(allocation.members.iter().all(|member| member.who != 2));
+    });
+}
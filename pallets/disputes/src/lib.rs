@@ -0,0 +1,450 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+pub use pallet::*;
+
+#[cfg(test)]
+mod mock;
+#[cfg(test)]
+mod tests;
+
+#[frame_support::pallet]
+pub mod pallet {
+    use frame_support::{
+        pallet_prelude::*,
+        traits::{BalanceStatus, ReservableCurrency},
+        BoundedVec,
+    };
+    use frame_system::pallet_prelude::*;
+    use pallet_energy_trade::{self as energy_trade, OrderStatus, OrderType, TokenBalanceOf, TradeOrder};
+    use pallet_payment_settlement::{self as payment_settlement};
+    use pallet_user_registry::{self as user_registry};
+    use scale_info::TypeInfo;
+    use sp_runtime::traits::Saturating;
+    use sp_std::prelude::*;
+
+    #[derive(Clone, Encode, Decode, DecodeWithMemTracking, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    pub enum DisputeStatus {
+        Open,       // Collecting evidence and arbiter votes
+        Upheld,     // Claim accepted, settlement follows the claimed amount
+        Rejected,   // Claim dismissed, bond paid to the respondent
+        Lapsed,     // No arbiter voted, bond returned
+    }
+
+    #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    #[scale_info(skip_type_params(T))]
+    pub struct Dispute<T: Config> {
+        pub claimant: T::AccountId,
+        pub respondent: T::AccountId,
+        pub claimed_amount: TokenBalanceOf<T>,    // Delivered amount according to the claimant
+        pub bond: TokenBalanceOf<T>,
+        pub evidence: BoundedVec<T::Hash, T::MaxEvidence>,
+        pub opened_at: BlockNumberFor<T>,
+        pub votes_for: u32,
+        pub votes_against: u32,
+        pub status: DisputeStatus,
+    }
+
+    #[pallet::config]
+    pub trait Config:
+        frame_system::Config + energy_trade::Config + payment_settlement::Config + user_registry::Config
+    {
+        type RuntimeEvent: From<Event<Self>> + IsType<<Self as frame_system::Config>::RuntimeEvent>;
+        // Posted by the claimant and forfeited to the respondent if the claim is rejected
+        #[pallet::constant]
+        type DisputeBond: Get<TokenBalanceOf<Self>>;
+        // Blocks after matching (or completion) during which a trade can be disputed
+        #[pallet::constant]
+        type DisputeWindow: Get<BlockNumberFor<Self>>;
+        #[pallet::constant]
+        type VotingPeriod: Get<BlockNumberFor<Self>>;
+        #[pallet::constant]
+        type MaxEvidence: Get<u32>;
+        // Reputation taken from the losing party
+        #[pallet::constant]
+        type ReputationPenalty: Get<u32>;
+        // Appoints and removes the arbiters who vote on disputes
+        type ArbiterOrigin: EnsureOrigin<Self::RuntimeOrigin>;
+    }
+
+    #[pallet::pallet]
+    pub struct Pallet<T>(_);
+
+    #[pallet::storage]
+    #[pallet::getter(fn disputes)]
+    pub type Disputes<T: Config> = StorageMap<
+        _,
+        Blake2_128Concat,
+        T::Hash,  // ask order id of the disputed trade
+        Dispute<T>,
+        OptionQuery,
+    >;
+
+    #[pallet::storage]
+    #[pallet::getter(fn arbiters)]
+    pub type Arbiters<T: Config> = StorageMap<_, Blake2_128Concat, T::AccountId, (), OptionQuery>;
+
+    #[pallet::storage]
+    #[pallet::getter(fn votes)]
+    pub type Votes<T: Config> = StorageDoubleMap<
+        _,
+        Blake2_128Concat,
+        T::Hash,
+        Blake2_128Concat,
+        T::AccountId,  // arbiter
+        bool,          // uphold
+        OptionQuery,
+    >;
+
+    #[pallet::event]
+    #[pallet::generate_deposit(pub(super) fn deposit_event)]
+    pub enum Event<T: Config> {
+        DisputeOpened {
+            order_id: T::Hash,
+            claimant: T::AccountId,
+            claimed_amount: TokenBalanceOf<T>,
+        },
+        EvidenceSubmitted {
+            order_id: T::Hash,
+            account: T::AccountId,
+            evidence: T::Hash,
+        },
+        VoteCast {
+            order_id: T::Hash,
+            arbiter: T::AccountId,
+            uphold: bool,
+        },
+        DisputeResolved {
+            order_id: T::Hash,
+            status: DisputeStatus,
+            delivered_amount: Option<TokenBalanceOf<T>>,
+        },
+        ArbiterAdded {
+            arbiter: T::AccountId,
+        },
+        ArbiterRemoved {
+            arbiter: T::AccountId,
+        },
+    }
+
+    #[pallet::error]
+    pub enum Error<T> {
+        OrderNotFound,
+        InvalidOrderStatus,
+        InvalidAmount,
+        NotTradeParty,
+        DisputeWindowClosed,
+        DisputeAlreadyOpened,
+        DisputeNotFound,
+        DisputeNotOpen,
+        TooMuchEvidence,
+        InsufficientBond,
+        NotArbiter,
+        AlreadyArbiter,
+        PartyCannotVote,
+        AlreadyVoted,
+        VotingClosed,
+        VotingPeriodActive,
+    }
+
+    #[pallet::call]
+    impl<T: Config> Pallet<T> {
+        #[pallet::call_index(0)]
+        #[pallet::weight(10_000)]
+        pub fn open_dispute(
+            origin: OriginFor<T>,
+            order_id: T::Hash,
+            claimed_amount: TokenBalanceOf<T>,
+            evidence: Vec<T::Hash>,
+        ) -> DispatchResult {
+            let claimant = ensure_signed(origin)?;
+
+            let (trade_id, trade) = Self::settlement_order(order_id)?;
+            ensure!(!Disputes::<T>::contains_key(trade_id), Error::<T>::DisputeAlreadyOpened);
+            ensure!(claimed_amount <= trade.energy_amount, Error::<T>::InvalidAmount);
+
+            let seller = trade.creator.clone();
+            let buyer = trade.counterparty.clone().ok_or(Error::<T>::InvalidOrderStatus)?;
+            let respondent = if claimant == seller {
+                buyer
+            } else if claimant == buyer {
+                seller
+            } else {
+                return Err(Error::<T>::NotTradeParty.into());
+            };
+
+            // The window runs from matching while energy flows and from completion afterwards
+            let window_start = match trade.status {
+                OrderStatus::InTransfer => trade.matched_at,
+                OrderStatus::Completed => trade.completed_at,
+                _ => None,
+            }.ok_or(Error::<T>::InvalidOrderStatus)?;
+            let current_block = <frame_system::Pallet<T>>::block_number();
+            ensure!(
+                current_block <= window_start.saturating_add(T::DisputeWindow::get()),
+                Error::<T>::DisputeWindowClosed
+            );
+
+            let evidence: BoundedVec<T::Hash, T::MaxEvidence> = evidence.try_into()
+                .map_err(|_| Error::<T>::TooMuchEvidence)?;
+
+            let bond = T::DisputeBond::get();
+            <T as energy_trade::Config>::Currency::reserve(
+                &claimant,
+                energy_trade::Pallet::<T>::to_currency(bond),
+            ).map_err(|_| Error::<T>::InsufficientBond)?;
+
+            // Trades that have not settled yet wait for the ruling
+            if trade.status == OrderStatus::InTransfer {
+                energy_trade::Pallet::<T>::suspend_for_dispute(trade_id)?;
+            }
+
+            Disputes::<T>::insert(trade_id, Dispute {
+                claimant: claimant.clone(),
+                respondent,
+                claimed_amount,
+                bond,
+                evidence,
+                opened_at: current_block,
+                votes_for: 0,
+                votes_against: 0,
+                status: DisputeStatus::Open,
+            });
+
+            Self::deposit_event(Event::DisputeOpened {
+                order_id: trade_id,
+                claimant,
+                claimed_amount,
+            });
+
+            Ok(())
+        }
+
+        #[pallet::call_index(1)]
+        #[pallet::weight(10_000)]
+        pub fn submit_evidence(
+            origin: OriginFor<T>,
+            order_id: T::Hash,
+            evidence: T::Hash,
+        ) -> DispatchResult {
+            let who = ensure_signed(origin)?;
+
+            Disputes::<T>::try_mutate(order_id, |dispute| -> DispatchResult {
+                let dispute = dispute.as_mut().ok_or(Error::<T>::DisputeNotFound)?;
+                ensure!(dispute.status == DisputeStatus::Open, Error::<T>::DisputeNotOpen);
+                ensure!(
+                    who == dispute.claimant || who == dispute.respondent,
+                    Error::<T>::NotTradeParty
+                );
+
+                dispute.evidence.try_push(evidence).map_err(|_| Error::<T>::TooMuchEvidence)?;
+
+                Self::deposit_event(Event::EvidenceSubmitted {
+                    order_id,
+                    account: who,
+                    evidence,
+                });
+
+                Ok(())
+            })
+        }
+
+        #[pallet::call_index(2)]
+        #[pallet::weight(10_000)]
+        pub fn vote(
+            origin: OriginFor<T>,
+            order_id: T::Hash,
+            uphold: bool,
+        ) -> DispatchResult {
+            let arbiter = ensure_signed(origin)?;
+            ensure!(Arbiters::<T>::contains_key(&arbiter), Error::<T>::NotArbiter);
+
+            Disputes::<T>::try_mutate(order_id, |dispute| -> DispatchResult {
+                let dispute = dispute.as_mut().ok_or(Error::<T>::DisputeNotFound)?;
+                ensure!(dispute.status == DisputeStatus::Open, Error::<T>::DisputeNotOpen);
+                ensure!(
+                    arbiter != dispute.claimant && arbiter != dispute.respondent,
+                    Error::<T>::PartyCannotVote
+                );
+                ensure!(
+                    <frame_system::Pallet<T>>::block_number() <=
+                        dispute.opened_at.saturating_add(T::VotingPeriod::get()),
+                    Error::<T>::VotingClosed
+                );
+                ensure!(!Votes::<T>::contains_key(order_id, &arbiter), Error::<T>::AlreadyVoted);
+
+                Votes::<T>::insert(order_id, &arbiter, uphold);
+                if uphold {
+                    dispute.votes_for = dispute.votes_for.saturating_add(1);
+                } else {
+                    dispute.votes_against = dispute.votes_against.saturating_add(1);
+                }
+
+                Self::deposit_event(Event::VoteCast {
+                    order_id,
+                    arbiter,
+                    uphold,
+                });
+
+                Ok(())
+            })
+        }
+
+        #[pallet::call_index(3)]
+        #[pallet::weight(10_000)]
+        pub fn resolve_dispute(
+            origin: OriginFor<T>,
+            order_id: T::Hash,
+        ) -> DispatchResult {
+            let _ = ensure_signed(origin)?;
+
+            Disputes::<T>::try_mutate(order_id, |dispute| -> DispatchResult {
+                let dispute = dispute.as_mut().ok_or(Error::<T>::DisputeNotFound)?;
+                ensure!(dispute.status == DisputeStatus::Open, Error::<T>::DisputeNotOpen);
+                ensure!(
+                    <frame_system::Pallet<T>>::block_number() >
+                        dispute.opened_at.saturating_add(T::VotingPeriod::get()),
+                    Error::<T>::VotingPeriodActive
+                );
+
+                let trade = energy_trade::Pallet::<T>::trade_orders(order_id)
+                    .ok_or(Error::<T>::OrderNotFound)?;
+                let bond = energy_trade::Pallet::<T>::to_currency(dispute.bond);
+
+                // A tie leaves the recorded delivery in place
+                let status = if dispute.votes_for == 0 && dispute.votes_against == 0 {
+                    DisputeStatus::Lapsed
+                } else if dispute.votes_for > dispute.votes_against {
+                    DisputeStatus::Upheld
+                } else {
+                    DisputeStatus::Rejected
+                };
+
+                let delivered_amount = match status {
+                    DisputeStatus::Upheld => {
+                        Self::apply_ruling(order_id, &trade, dispute.claimed_amount)?;
+                        <T as energy_trade::Config>::Currency::unreserve(&dispute.claimant, bond);
+                        Self::penalise(&dispute.respondent);
+                        Some(dispute.claimed_amount)
+                    },
+                    DisputeStatus::Rejected => {
+                        Self::resume_trade(order_id, &trade)?;
+                        let _ = <T as energy_trade::Config>::Currency::repatriate_reserved(
+                            &dispute.claimant,
+                            &dispute.respondent,
+                            bond,
+                            BalanceStatus::Free,
+                        );
+                        Self::penalise(&dispute.claimant);
+                        None
+                    },
+                    _ => {
+                        Self::resume_trade(order_id, &trade)?;
+                        <T as energy_trade::Config>::Currency::unreserve(&dispute.claimant, bond);
+                        None
+                    },
+                };
+                dispute.status = status.clone();
+
+                Self::deposit_event(Event::DisputeResolved {
+                    order_id,
+                    status,
+                    delivered_amount,
+                });
+
+                Ok(())
+            })
+        }
+
+        #[pallet::call_index(4)]
+        #[pallet::weight(10_000)]
+        pub fn add_arbiter(
+            origin: OriginFor<T>,
+            arbiter: T::AccountId,
+        ) -> DispatchResult {
+            T::ArbiterOrigin::ensure_origin(origin)?;
+            ensure!(!Arbiters::<T>::contains_key(&arbiter), Error::<T>::AlreadyArbiter);
+
+            Arbiters::<T>::insert(&arbiter, ());
+            Self::deposit_event(Event::ArbiterAdded { arbiter });
+
+            Ok(())
+        }
+
+        // Votes already cast still count
+        #[pallet::call_index(5)]
+        #[pallet::weight(10_000)]
+        pub fn remove_arbiter(
+            origin: OriginFor<T>,
+            arbiter: T::AccountId,
+        ) -> DispatchResult {
+            T::ArbiterOrigin::ensure_origin(origin)?;
+            ensure!(Arbiters::<T>::take(&arbiter).is_some(), Error::<T>::NotArbiter);
+
+            Self::deposit_event(Event::ArbiterRemoved { arbiter });
+
+            Ok(())
+        }
+    }
+
+    impl<T: Config> Pallet<T> {
+        // Disputes are recorded against the ask side, which carries settlement
+        fn settlement_order(order_id: T::Hash) -> Result<(T::Hash, TradeOrder<T>), DispatchError> {
+            let order = energy_trade::Pallet::<T>::trade_orders(order_id)
+                .ok_or(Error::<T>::OrderNotFound)?;
+            match order.order_type {
+                OrderType::Ask => Ok((order_id, order)),
+                OrderType::Bid => {
+                    let ask_id = order.matched_order.ok_or(Error::<T>::InvalidOrderStatus)?;
+                    let ask = energy_trade::Pallet::<T>::trade_orders(ask_id)
+                        .ok_or(Error::<T>::OrderNotFound)?;
+                    Ok((ask_id, ask))
+                },
+            }
+        }
+
+        // Settle on the claimed amount: unsettled trades are released with it, completed
+        // trades get the payment difference refunded through payment settlement
+        fn apply_ruling(
+            order_id: T::Hash,
+            trade: &TradeOrder<T>,
+            claimed_amount: TokenBalanceOf<T>,
+        ) -> DispatchResult {
+            match trade.status {
+                OrderStatus::Disputed => {
+                    energy_trade::Pallet::<T>::resume_after_dispute(order_id, Some(claimed_amount))
+                },
+                OrderStatus::Completed => {
+                    let seller = trade.creator.clone();
+                    let buyer = trade.counterparty.clone().ok_or(Error::<T>::InvalidOrderStatus)?;
+                    let recorded = trade.delivered_amount
+                        .unwrap_or(trade.energy_amount)
+                        .min(trade.energy_amount);
+                    let paid = energy_trade::Pallet::<T>::pro_rata(trade.total_price, recorded, trade.energy_amount);
+                    let due = energy_trade::Pallet::<T>::pro_rata(trade.total_price, claimed_amount, trade.energy_amount);
+
+                    if paid > due {
+                        payment_settlement::Pallet::<T>::refund(order_id, seller, buyer, paid.saturating_sub(due))
+                    } else if due > paid {
+                        payment_settlement::Pallet::<T>::refund(order_id, buyer, seller, due.saturating_sub(paid))
+                    } else {
+                        Ok(())
+                    }
+                },
+                _ => Err(Error::<T>::InvalidOrderStatus.into()),
+            }
+        }
+
+        fn resume_trade(order_id: T::Hash, trade: &TradeOrder<T>) -> DispatchResult {
+            if trade.status == OrderStatus::Disputed {
+                energy_trade::Pallet::<T>::resume_after_dispute(order_id, None)?;
+            }
+            Ok(())
+        }
+
+        fn penalise(account: &T::AccountId) {
+            let penalty = T::ReputationPenalty::get().min(i32::MAX as u32) as i32;
+            // Parties without a registry profile have no reputation to lose
+            let _ = user_registry::Pallet::<T>::adjust_reputation(account, -penalty);
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use crate as pallet_disputes;
+use frame_support::{
+    derive_impl, parameter_types,
+    traits::{ConstU128, ConstU32, ConstU64},
+};
+use frame_system::EnsureRoot;
+use sp_runtime::BuildStorage;
+
+type Block = frame_system::mocking::MockBlock<Test>;
+
+frame_support::construct_runtime!(
+    pub enum Test {
+        System: frame_system,
+        Timestamp: pallet_timestamp,
+        Balances: pallet_balances,
+        EnergyToken: pallet_energy_token,
+        UserRegistry: pallet_user_registry,
+        EnergyTrade: pallet_energy_trade,
+        PaymentSettlement: pallet_payment_settlement,
+        Disputes: pallet_disputes,
+    }
+);
+
+#[derive_impl(frame_system::config_preludes::TestDefaultConfig)]
+impl frame_system::Config for Test {
+    type Block = Block;
+    type AccountData = pallet_balances::AccountData<u128>;
+}
+
+#[derive_impl(pallet_balances::config_preludes::TestDefaultConfig)]
+impl pallet_balances::Config for Test {
+    type Balance = u128;
+    type ExistentialDeposit = ConstU128<1>;
+    type AccountStore = System;
+}
+
+impl pallet_timestamp::Config for Test {
+    type Moment = u64;
+    type OnTimestampSet = ();
+    type MinimumPeriod = ConstU64<1>;
+    type WeightInfo = ();
+}
+
+impl pallet_energy_token::Config for Test {
+    type RuntimeEvent = RuntimeEvent;
+    type TokenBalance = u128;
+}
+
+impl pallet_user_registry::Config for Test {
+    type RuntimeEvent = RuntimeEvent;
+}
+
+parameter_types! {
+    pub const AuctionInterval: u64 = 10;
+    pub const TreasuryAccount: u64 = 100;
+    pub const CommunityFundAccount: u64 = 101;
+    pub const ImbalanceAccount: u64 = 102;
+}
+
+impl pallet_energy_trade::Config for Test {
+    type RuntimeEvent = RuntimeEvent;
+    type TokenBalance = u128;
+    type Currency = Balances;
+    type OperatorOrigin = EnsureRoot<u64>;
+    type MarketAdminOrigin = EnsureRoot<u64>;
+    type Participants = ();
+    type Distances = ();
+    type Statistics = ();
+    type AuctionInterval = AuctionInterval;
+    type MaxAuctionOrders = ConstU32<16>;
+    type MaxAuctionOrdersPerAccount = ConstU32<4>;
+    type MaxStopOrders = ConstU32<8>;
+    type TimeProvider = Timestamp;
+    type SlotDuration = ConstU64<{ 15 * 60 * 1000 }>;
+    type RevealPeriod = ConstU64<3>;
+    type CommitDeposit = ConstU128<5>;
+    type TreasuryAccount = TreasuryAccount;
+    type CommunityFundAccount = CommunityFundAccount;
+    type ImbalancePrices = ();
+    type ImbalanceAccount = ImbalanceAccount;
+    type MaxOrdersPerLevel = ConstU32<8>;
+    type MaxPriceLevels = ConstU32<16>;
+    type MaxFillsPerOrder = ConstU32<8>;
+    type MaxLastResortPerBlock = ConstU32<8>;
+    type RateLimitWindow = ConstU64<10>;
+    type MaxOrdersPerWindow = ConstU32<5>;
+    type MaxOrderValue = ConstU128<1_000_000>;
+    type OrderHooks = ();
+    type WeightInfo = ();
+    #[cfg(feature = "runtime-benchmarks")]
+    type BenchmarkHelper = ();
+}
+
+impl pallet_payment_settlement::Config for Test {
+    type RuntimeEvent = RuntimeEvent;
+    type Currency = Balances;
+}
+
+impl pallet_disputes::Config for Test {
+    type RuntimeEvent = RuntimeEvent;
+    type DisputeBond = ConstU128<50>;
+    type DisputeWindow = ConstU64<20>;
+    type VotingPeriod = ConstU64<10>;
+    type MaxEvidence = ConstU32<4>;
+    type ReputationPenalty = ConstU32<10>;
+    type ArbiterOrigin = EnsureRoot<u64>;
+}
+
+// Accounts 1 to 5 start with funds; the fee and penalty accounts are endowed so small shares
+// stay above the existential deposit
+pub fn new_test_ext() -> sp_io::TestExternalities {
+    let mut t = frame_system::GenesisConfig::<Test>::default().build_storage().unwrap();
+    pallet_balances::GenesisConfig::<Test> {
+        balances: (1..=5)
+            .chain([TreasuryAccount::get(), CommunityFundAccount::get(), ImbalanceAccount::get()])
+            .map(|who| (who, 10_000))
+            .collect(),
+        ..Default::default()
+    }
+    .assimilate_storage(&mut t)
+    .unwrap();
+
+    let mut ext = sp_io::TestExternalities::new(t);
+    ext.execute_with(|| System::set_block_number(1));
+    ext
+}
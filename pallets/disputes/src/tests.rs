@@ -0,0 +1,131 @@
+use crate::{mock::*, DisputeStatus, Error, Event};
+use frame_support::{assert_noop, assert_ok};
+use pallet_energy_trade::OrderStatus;
+use pallet_user_registry::UserRole;
+
+const ARBITER: u64 = 4;
+
+// Account 1 sells 100 units at 10 to account 2 and the transfer is verified at 80 units
+fn trade_in_transfer() -> sp_core::H256 {
+    assert_ok!(UserRegistry::register_user(RuntimeOrigin::signed(1), UserRole::Prosumer));
+    assert_ok!(UserRegistry::register_user(RuntimeOrigin::signed(2), UserRole::Consumer));
+    assert_ok!(Disputes::add_arbiter(RuntimeOrigin::root(), ARBITER));
+    assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(1), 100));
+    assert_ok!(EnergyTrade::create_ask_order(RuntimeOrigin::signed(1), 100, 10, b"zone-a".to_vec(), 0));
+    assert_ok!(EnergyTrade::create_bid_order(RuntimeOrigin::signed(2), 100, 10, b"zone-a".to_vec(), 0));
+
+    let ask_id = EnergyTrade::account_orders(&1, 0, 1)[0];
+    let bid_id = EnergyTrade::account_orders(&2, 0, 1)[0];
+    assert_ok!(EnergyTrade::match_orders(RuntimeOrigin::signed(3), ask_id, bid_id));
+    assert_ok!(EnergyTrade::verify_transfer(RuntimeOrigin::root(), ask_id, vec![1], 80));
+    ask_id
+}
+
+#[test]
+fn only_trade_parties_open_disputes_and_the_trade_is_suspended() {
+    new_test_ext().execute_with(|| {
+        let ask_id = trade_in_transfer();
+        let bid_id = EnergyTrade::account_orders(&2, 0, 1)[0];
+
+        assert_noop!(
+            Disputes::open_dispute(RuntimeOrigin::signed(3), ask_id, 60, vec![]),
+            Error::<Test>::NotTradeParty
+        );
+        assert_noop!(
+            Disputes::open_dispute(RuntimeOrigin::signed(2), ask_id, 150, vec![]),
+            Error::<Test>::InvalidAmount
+        );
+        // The bid side is recorded against its ask
+        assert_ok!(Disputes::open_dispute(RuntimeOrigin::signed(2), bid_id, 60, vec![]));
+
+        let dispute = Disputes::disputes(ask_id).unwrap();
+        assert_eq!((dispute.claimant, dispute.respondent, dispute.status), (2, 1, DisputeStatus::Open));
+        assert_eq!(Balances::reserved_balance(2), 1000 + 50);
+        assert_eq!(EnergyTrade::trade_orders(ask_id).unwrap().status, OrderStatus::Disputed);
+        assert_noop!(
+            Disputes::open_dispute(RuntimeOrigin::signed(1), ask_id, 80, vec![]),
+            Error::<Test>::DisputeAlreadyOpened
+        );
+    });
+}
+
+#[test]
+fn upheld_claims_replace_the_delivered_amount() {
+    new_test_ext().execute_with(|| {
+        let ask_id = trade_in_transfer();
+        assert_ok!(Disputes::open_dispute(RuntimeOrigin::signed(2), ask_id, 60, vec![]));
+
+        assert_noop!(Disputes::vote(RuntimeOrigin::signed(3), ask_id, true), Error::<Test>::NotArbiter);
+        // Registering as a grid operator does not make an account an arbiter
+        assert_ok!(UserRegistry::register_user(RuntimeOrigin::signed(3), UserRole::GridOperator));
+        assert_noop!(Disputes::vote(RuntimeOrigin::signed(3), ask_id, true), Error::<Test>::NotArbiter);
+        assert_ok!(Disputes::vote(RuntimeOrigin::signed(ARBITER), ask_id, true));
+        assert_noop!(Disputes::vote(RuntimeOrigin::signed(ARBITER), ask_id, true), Error::<Test>::AlreadyVoted);
+        assert_noop!(
+            Disputes::resolve_dispute(RuntimeOrigin::signed(3), ask_id),
+            Error::<Test>::VotingPeriodActive
+        );
+
+        System::set_block_number(12);
+        assert_ok!(Disputes::resolve_dispute(RuntimeOrigin::signed(3), ask_id));
+
+        let trade = EnergyTrade::trade_orders(ask_id).unwrap();
+        assert_eq!((trade.status, trade.delivered_amount), (OrderStatus::InTransfer, Some(60)));
+        assert_eq!(Balances::reserved_balance(2), 1000);
+        assert_eq!(UserRegistry::user_profiles(1).unwrap().reputation_score, 90);
+        System::assert_last_event(
+            Event::DisputeResolved { order_id: ask_id, status: DisputeStatus::Upheld, delivered_amount: Some(60) }.into()
+        );
+    });
+}
+
+#[test]
+fn arbiters_are_appointed_by_the_arbiter_origin_and_never_vote_on_their_own_trades() {
+    new_test_ext().execute_with(|| {
+        let ask_id = trade_in_transfer();
+        assert_ok!(Disputes::open_dispute(RuntimeOrigin::signed(2), ask_id, 60, vec![]));
+
+        assert_noop!(Disputes::add_arbiter(RuntimeOrigin::signed(1), 1), sp_runtime::DispatchError::BadOrigin);
+        assert_noop!(Disputes::add_arbiter(RuntimeOrigin::root(), ARBITER), Error::<Test>::AlreadyArbiter);
+        assert_ok!(Disputes::add_arbiter(RuntimeOrigin::root(), 1));
+        assert_noop!(Disputes::vote(RuntimeOrigin::signed(1), ask_id, false), Error::<Test>::PartyCannotVote);
+
+        assert_ok!(Disputes::remove_arbiter(RuntimeOrigin::root(), ARBITER));
+        assert_noop!(Disputes::vote(RuntimeOrigin::signed(ARBITER), ask_id, true), Error::<Test>::NotArbiter);
+        assert_noop!(Disputes::remove_arbiter(RuntimeOrigin::root(), ARBITER), Error::<Test>::NotArbiter);
+    });
+}
+
+#[test]
+fn rejected_claims_forfeit_the_bond_to_the_respondent() {
+    new_test_ext().execute_with(|| {
+        let ask_id = trade_in_transfer();
+        assert_ok!(Disputes::open_dispute(RuntimeOrigin::signed(2), ask_id, 60, vec![]));
+        assert_ok!(Disputes::vote(RuntimeOrigin::signed(ARBITER), ask_id, false));
+
+        let seller_funds = Balances::free_balance(1);
+        System::set_block_number(12);
+        assert_ok!(Disputes::resolve_dispute(RuntimeOrigin::signed(3), ask_id));
+
+        assert_eq!(Disputes::disputes(ask_id).unwrap().status, DisputeStatus::Rejected);
+        assert_eq!(Balances::free_balance(1), seller_funds + 50);
+        assert_eq!(UserRegistry::user_profiles(2).unwrap().reputation_score, 90);
+        let trade = EnergyTrade::trade_orders(ask_id).unwrap();
+        assert_eq!((trade.status, trade.delivered_amount), (OrderStatus::InTransfer, Some(80)));
+    });
+}
+
+#[test]
+fn disputes_without_votes_lapse_and_return_the_bond() {
+    new_test_ext().execute_with(|| {
+        let ask_id = trade_in_transfer();
+        assert_ok!(Disputes::open_dispute(RuntimeOrigin::signed(2), ask_id, 60, vec![]));
+
+        System::set_block_number(12);
+        assert_noop!(Disputes::vote(RuntimeOrigin::signed(ARBITER), ask_id, true), Error::<Test>::VotingClosed);
+        assert_ok!(Disputes::resolve_dispute(RuntimeOrigin::signed(3), ask_id));
+
+        assert_eq!(Disputes::disputes(ask_id).unwrap().status, DisputeStatus::Lapsed);
+        assert_eq!(Balances::reserved_balance(2), 1000);
+    });
+}
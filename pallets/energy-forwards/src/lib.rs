@@ -0,0 +1,531 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+pub use pallet::*;
+
+#[cfg(test)]
+mod mock;
+#[cfg(test)]
+mod tests;
+
+#[frame_support::pallet]
+pub mod pallet {
+    use frame_support::{
+        pallet_prelude::*,
+        traits::{BalanceStatus, ReservableCurrency},
+        BoundedVec,
+    };
+    use frame_system::pallet_prelude::*;
+    use pallet_energy_trade::{self as energy_trade, TokenBalanceOf};
+    use pallet_price_discovery::{self as price_discovery};
+    use scale_info::TypeInfo;
+    use sp_runtime::{
+        traits::{CheckedMul, Hash, Saturating, Zero},
+        Percent,
+    };
+    use sp_std::prelude::*;
+
+    #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    pub enum ForwardStatus {
+        Offered,     // Waiting for a buyer
+        Active,      // Both margins posted, marked to market until delivery
+        Delivering,  // Physical trade created in the energy trade pallet
+        Defaulted,
+        Cancelled,
+        Voided,      // Could not be delivered within its window for reasons neither party controls
+    }
+
+    #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    #[scale_info(skip_type_params(T))]
+    pub struct ForwardContract<T: Config> {
+        pub seller: T::AccountId,
+        pub buyer: Option<T::AccountId>,
+        pub energy_amount: TokenBalanceOf<T>,
+        pub contract_price: TokenBalanceOf<T>,
+        pub mark_price: TokenBalanceOf<T>,
+        pub grid_location: BoundedVec<u8, ConstU32<32>>,
+        pub delivery_start: BlockNumberFor<T>,
+        pub delivery_end: BlockNumberFor<T>,
+        pub seller_margin: TokenBalanceOf<T>,
+        pub buyer_margin: TokenBalanceOf<T>,
+        pub status: ForwardStatus,
+        pub created_at: BlockNumberFor<T>,
+        pub trade_id: Option<T::Hash>,
+    }
+
+    #[pallet::config]
+    pub trait Config: frame_system::Config + price_discovery::Config {
+        type RuntimeEvent: From<Event<Self>> + IsType<<Self as frame_system::Config>::RuntimeEvent>;
+        // Share of the notional each party posts when entering a contract
+        #[pallet::constant]
+        type InitialMargin: Get<Percent>;
+        // Share of the marked notional below which a margin call is raised
+        #[pallet::constant]
+        type MaintenanceMargin: Get<Percent>;
+        #[pallet::constant]
+        type MarkToMarketPeriod: Get<BlockNumberFor<Self>>;
+        // Minimum number of blocks between contract creation and the delivery window
+        #[pallet::constant]
+        type MinLeadTime: Get<BlockNumberFor<Self>>;
+        #[pallet::constant]
+        type MaxOpenForwards: Get<u32>;
+    }
+
+    #[pallet::pallet]
+    pub struct Pallet<T>(_);
+
+    #[pallet::storage]
+    #[pallet::getter(fn forwards)]
+    pub type Forwards<T: Config> = StorageMap<_, Blake2_128Concat, T::Hash, ForwardContract<T>, OptionQuery>;
+
+    // Offered and active contracts still handled by the block hook
+    #[pallet::storage]
+    #[pallet::getter(fn open_forwards)]
+    pub type OpenForwards<T: Config> = StorageValue<_, BoundedVec<T::Hash, T::MaxOpenForwards>, ValueQuery>;
+
+    #[pallet::event]
+    #[pallet::generate_deposit(pub(super) fn deposit_event)]
+    pub enum Event<T: Config> {
+        ForwardOffered {
+            contract_id: T::Hash,
+            seller: T::AccountId,
+            amount: TokenBalanceOf<T>,
+            price: TokenBalanceOf<T>,
+            delivery_start: BlockNumberFor<T>,
+            delivery_end: BlockNumberFor<T>,
+        },
+        ForwardAccepted {
+            contract_id: T::Hash,
+            buyer: T::AccountId,
+        },
+        ForwardCancelled {
+            contract_id: T::Hash,
+        },
+        MarginToppedUp {
+            contract_id: T::Hash,
+            account: T::AccountId,
+            amount: TokenBalanceOf<T>,
+        },
+        MarkedToMarket {
+            contract_id: T::Hash,
+            mark_price: TokenBalanceOf<T>,
+            variation: TokenBalanceOf<T>,
+        },
+        MarginCall {
+            contract_id: T::Hash,
+            account: T::AccountId,
+            required: TokenBalanceOf<T>,
+        },
+        ForwardDelivering {
+            contract_id: T::Hash,
+            trade_id: T::Hash,
+            price: TokenBalanceOf<T>,
+        },
+        ForwardDefaulted {
+            contract_id: T::Hash,
+            defaulter: T::AccountId,
+        },
+        ForwardDeliveryFailed {
+            contract_id: T::Hash,
+            error: DispatchError,
+        },
+        ForwardVoided {
+            contract_id: T::Hash,
+        },
+    }
+
+    #[pallet::error]
+    pub enum Error<T> {
+        ContractNotFound,
+        InvalidContractStatus,
+        InvalidAmount,
+        InvalidPrice,
+        InvalidLocation,
+        InvalidDeliveryWindow,
+        InsufficientBalance,
+        Unauthorized,
+        DuplicateContract,
+        TooManyContracts,
+    }
+
+    #[pallet::hooks]
+    impl<T: Config> Hooks<BlockNumberFor<T>> for Pallet<T> {
+        fn on_initialize(n: BlockNumberFor<T>) -> Weight {
+            let period = T::MarkToMarketPeriod::get();
+            let mark_due = !period.is_zero() && (n % period).is_zero();
+
+            let open = Self::open_forwards();
+            let mut still_open: Vec<T::Hash> = Vec::new();
+            for contract_id in open.iter() {
+                let Some(mut contract) = Self::forwards(contract_id) else { continue };
+
+                match contract.status {
+                    ForwardStatus::Offered if n >= contract.delivery_start => {
+                        Self::cancel(*contract_id, &mut contract);
+                    },
+                    ForwardStatus::Active if n >= contract.delivery_start => {
+                        Self::mark_to_market(*contract_id, &mut contract);
+                        if contract.status == ForwardStatus::Active {
+                            Self::deliver(*contract_id, &mut contract, n);
+                        }
+                    },
+                    ForwardStatus::Active if mark_due => {
+                        Self::mark_to_market(*contract_id, &mut contract);
+                    },
+                    _ => {},
+                }
+
+                if matches!(contract.status, ForwardStatus::Offered | ForwardStatus::Active) {
+                    still_open.push(*contract_id);
+                }
+                Forwards::<T>::insert(contract_id, contract);
+            }
+
+            let len = open.len() as u64;
+            if still_open.len() as u64 != len {
+                OpenForwards::<T>::put(BoundedVec::truncate_from(still_open));
+            }
+
+            T::DbWeight::get().reads_writes(
+                len.saturating_mul(4).saturating_add(1),
+                len.saturating_mul(4).saturating_add(1),
+            )
+        }
+    }
+
+    #[pallet::call]
+    impl<T: Config> Pallet<T> {
+        #[pallet::call_index(0)]
+        #[pallet::weight(10_000)]
+        pub fn offer_forward(
+            origin: OriginFor<T>,
+            energy_amount: TokenBalanceOf<T>,
+            price_per_unit: TokenBalanceOf<T>,
+            grid_location: Vec<u8>,
+            delivery_start: BlockNumberFor<T>,
+            delivery_end: BlockNumberFor<T>,
+        ) -> DispatchResult {
+            let seller = ensure_signed(origin)?;
+            ensure!(!energy_amount.is_zero(), Error::<T>::InvalidAmount);
+            ensure!(!price_per_unit.is_zero(), Error::<T>::InvalidPrice);
+
+            let current_block = <frame_system::Pallet<T>>::block_number();
+            ensure!(
+                delivery_start >= current_block.saturating_add(T::MinLeadTime::get()) &&
+                    delivery_end > delivery_start,
+                Error::<T>::InvalidDeliveryWindow
+            );
+
+            let bounded_location: BoundedVec<u8, ConstU32<32>> = grid_location.try_into()
+                .map_err(|_| Error::<T>::InvalidLocation)?;
+
+            let notional = price_per_unit
+                .checked_mul(&energy_amount)
+                .ok_or(Error::<T>::InvalidPrice)?;
+            let margin = T::InitialMargin::get().mul_ceil(notional);
+            Self::reserve_margin(&seller, margin)?;
+
+            let contract = ForwardContract {
+                seller: seller.clone(),
+                buyer: None,
+                energy_amount,
+                contract_price: price_per_unit,
+                mark_price: price_per_unit,
+                grid_location: bounded_location,
+                delivery_start,
+                delivery_end,
+                seller_margin: margin,
+                buyer_margin: Zero::zero(),
+                status: ForwardStatus::Offered,
+                created_at: current_block,
+                trade_id: None,
+            };
+
+            let contract_id = T::Hashing::hash_of(&contract);
+            ensure!(!Forwards::<T>::contains_key(contract_id), Error::<T>::DuplicateContract);
+
+            OpenForwards::<T>::try_mutate(|open| {
+                open.try_push(contract_id).map_err(|_| Error::<T>::TooManyContracts)
+            })?;
+            Forwards::<T>::insert(contract_id, contract);
+
+            Self::deposit_event(Event::ForwardOffered {
+                contract_id,
+                seller,
+                amount: energy_amount,
+                price: price_per_unit,
+                delivery_start,
+                delivery_end,
+            });
+
+            Ok(())
+        }
+
+        #[pallet::call_index(1)]
+        #[pallet::weight(10_000)]
+        pub fn accept_forward(
+            origin: OriginFor<T>,
+            contract_id: T::Hash,
+        ) -> DispatchResult {
+            let buyer = ensure_signed(origin)?;
+
+            Forwards::<T>::try_mutate(contract_id, |contract| -> DispatchResult {
+                let contract = contract.as_mut().ok_or(Error::<T>::ContractNotFound)?;
+                ensure!(contract.status == ForwardStatus::Offered, Error::<T>::InvalidContractStatus);
+                ensure!(contract.seller != buyer, Error::<T>::Unauthorized);
+
+                let margin = T::InitialMargin::get().mul_ceil(Self::notional(contract, contract.contract_price));
+                Self::reserve_margin(&buyer, margin)?;
+
+                contract.buyer = Some(buyer.clone());
+                contract.buyer_margin = margin;
+                contract.status = ForwardStatus::Active;
+
+                Self::deposit_event(Event::ForwardAccepted { contract_id, buyer });
+
+                Ok(())
+            })
+        }
+
+        #[pallet::call_index(2)]
+        #[pallet::weight(10_000)]
+        pub fn cancel_forward(
+            origin: OriginFor<T>,
+            contract_id: T::Hash,
+        ) -> DispatchResult {
+            let who = ensure_signed(origin)?;
+
+            Forwards::<T>::try_mutate(contract_id, |contract| -> DispatchResult {
+                let contract = contract.as_mut().ok_or(Error::<T>::ContractNotFound)?;
+                ensure!(contract.seller == who, Error::<T>::Unauthorized);
+                ensure!(contract.status == ForwardStatus::Offered, Error::<T>::InvalidContractStatus);
+
+                Self::cancel(contract_id, contract);
+                Ok(())
+            })?;
+
+            OpenForwards::<T>::mutate(|open| open.retain(|id| *id != contract_id));
+
+            Ok(())
+        }
+
+        #[pallet::call_index(3)]
+        #[pallet::weight(10_000)]
+        pub fn top_up_margin(
+            origin: OriginFor<T>,
+            contract_id: T::Hash,
+            amount: TokenBalanceOf<T>,
+        ) -> DispatchResult {
+            let who = ensure_signed(origin)?;
+            ensure!(!amount.is_zero(), Error::<T>::InvalidAmount);
+
+            Forwards::<T>::try_mutate(contract_id, |contract| -> DispatchResult {
+                let contract = contract.as_mut().ok_or(Error::<T>::ContractNotFound)?;
+                ensure!(
+                    matches!(contract.status, ForwardStatus::Offered | ForwardStatus::Active),
+                    Error::<T>::InvalidContractStatus
+                );
+
+                if contract.seller == who {
+                    Self::reserve_margin(&who, amount)?;
+                    contract.seller_margin = contract.seller_margin.saturating_add(amount);
+                } else if contract.buyer.as_ref() == Some(&who) {
+                    Self::reserve_margin(&who, amount)?;
+                    contract.buyer_margin = contract.buyer_margin.saturating_add(amount);
+                } else {
+                    return Err(Error::<T>::Unauthorized.into());
+                }
+
+                Self::deposit_event(Event::MarginToppedUp {
+                    contract_id,
+                    account: who,
+                    amount,
+                });
+
+                Ok(())
+            })
+        }
+    }
+
+    impl<T: Config> Pallet<T> {
+        fn notional(contract: &ForwardContract<T>, price: TokenBalanceOf<T>) -> TokenBalanceOf<T> {
+            price.saturating_mul(contract.energy_amount)
+        }
+
+        fn reserve_margin(who: &T::AccountId, amount: TokenBalanceOf<T>) -> DispatchResult {
+            <T as energy_trade::Config>::Currency::reserve(
+                who,
+                energy_trade::Pallet::<T>::to_currency(amount),
+            ).map_err(|_| Error::<T>::InsufficientBalance.into())
+        }
+
+        fn release_margin(who: &T::AccountId, amount: TokenBalanceOf<T>) {
+            <T as energy_trade::Config>::Currency::unreserve(
+                who,
+                energy_trade::Pallet::<T>::to_currency(amount),
+            );
+        }
+
+        // Move margin between the parties; the receiver's share stays reserved
+        fn move_margin(from: &T::AccountId, to: &T::AccountId, amount: TokenBalanceOf<T>, status: BalanceStatus) {
+            let _ = <T as energy_trade::Config>::Currency::repatriate_reserved(
+                from,
+                to,
+                energy_trade::Pallet::<T>::to_currency(amount),
+                status,
+            );
+        }
+
+        fn reference_price(contract: &ForwardContract<T>) -> Option<TokenBalanceOf<T>> {
+            price_discovery::Pallet::<T>::market_data(contract.grid_location.to_vec())
+                .map(|data| data.current_price)
+                .filter(|price| !price.is_zero())
+        }
+
+        fn cancel(contract_id: T::Hash, contract: &mut ForwardContract<T>) {
+            Self::release_margin(&contract.seller, contract.seller_margin);
+            contract.seller_margin = Zero::zero();
+            contract.status = ForwardStatus::Cancelled;
+
+            Self::deposit_event(Event::ForwardCancelled { contract_id });
+        }
+
+        // Settle the price move since the last mark as variation margin between the parties
+        fn mark_to_market(contract_id: T::Hash, contract: &mut ForwardContract<T>) {
+            let Some(buyer) = contract.buyer.clone() else { return };
+            let Some(price) = Self::reference_price(contract) else { return };
+            if price == contract.mark_price {
+                return;
+            }
+
+            // A rising price is a gain for the buyer (long) and a loss for the seller (short)
+            let seller_loses = price > contract.mark_price;
+            let variation = Self::notional(contract, price.max(contract.mark_price) - price.min(contract.mark_price));
+            let (loser, winner) = if seller_loses {
+                (contract.seller.clone(), buyer)
+            } else {
+                (buyer, contract.seller.clone())
+            };
+            let loser_margin = if seller_loses { contract.seller_margin } else { contract.buyer_margin };
+
+            if variation > loser_margin {
+                Self::settle_default(contract_id, contract, loser);
+                return;
+            }
+
+            Self::move_margin(&loser, &winner, variation, BalanceStatus::Reserved);
+            if seller_loses {
+                contract.seller_margin -= variation;
+                contract.buyer_margin = contract.buyer_margin.saturating_add(variation);
+            } else {
+                contract.buyer_margin -= variation;
+                contract.seller_margin = contract.seller_margin.saturating_add(variation);
+            }
+            contract.mark_price = price;
+
+            Self::deposit_event(Event::MarkedToMarket {
+                contract_id,
+                mark_price: price,
+                variation,
+            });
+
+            let required = T::MaintenanceMargin::get().mul_ceil(Self::notional(contract, price));
+            if contract.seller_margin < required {
+                Self::deposit_event(Event::MarginCall {
+                    contract_id,
+                    account: contract.seller.clone(),
+                    required,
+                });
+            }
+            if let Some(buyer) = contract.buyer.clone() {
+                if contract.buyer_margin < required {
+                    Self::deposit_event(Event::MarginCall {
+                        contract_id,
+                        account: buyer,
+                        required,
+                    });
+                }
+            }
+        }
+
+        // Hand the contract over to the trade pallet at the last mark; the variation margin
+        // already paid makes up the difference to the contract price. Delivery is retried each
+        // block of the window until it goes through
+        fn deliver(contract_id: T::Hash, contract: &mut ForwardContract<T>, n: BlockNumberFor<T>) {
+            let Some(buyer) = contract.buyer.clone() else { return };
+
+            let created = frame_support::storage::with_storage_layer(|| {
+                energy_trade::Pallet::<T>::create_matched_trade(
+                    contract.seller.clone(),
+                    buyer.clone(),
+                    contract.energy_amount,
+                    contract.mark_price,
+                    contract.grid_location.to_vec(),
+                    energy_trade::Pallet::<T>::current_slot(),
+                )
+            });
+
+            match created {
+                Ok(trade_id) => {
+                    Self::release_margin(&contract.seller, contract.seller_margin);
+                    Self::release_margin(&buyer, contract.buyer_margin);
+                    contract.seller_margin = Zero::zero();
+                    contract.buyer_margin = Zero::zero();
+                    contract.trade_id = Some(trade_id);
+                    contract.status = ForwardStatus::Delivering;
+
+                    Self::deposit_event(Event::ForwardDelivering {
+                        contract_id,
+                        trade_id,
+                        price: contract.mark_price,
+                    });
+                },
+                // Whoever cannot fund their leg of the physical trade defaults
+                Err(error) if error == pallet_energy_token::Error::<T>::InsufficientBalance.into() => {
+                    Self::settle_default(contract_id, contract, contract.seller.clone());
+                },
+                Err(error) if error == energy_trade::Error::<T>::InsufficientBalance.into() => {
+                    Self::settle_default(contract_id, contract, buyer);
+                },
+                // Anything else, such as a halted market, is retried until the window closes
+                Err(error) => {
+                    Self::deposit_event(Event::ForwardDeliveryFailed { contract_id, error });
+                    if n.saturating_add(1u32.into()) >= contract.delivery_end {
+                        Self::void(contract_id, contract, buyer);
+                    }
+                },
+            }
+        }
+
+        // Neither party is at fault, so both get their margin back
+        fn void(contract_id: T::Hash, contract: &mut ForwardContract<T>, buyer: T::AccountId) {
+            Self::release_margin(&contract.seller, contract.seller_margin);
+            Self::release_margin(&buyer, contract.buyer_margin);
+            contract.seller_margin = Zero::zero();
+            contract.buyer_margin = Zero::zero();
+            contract.status = ForwardStatus::Voided;
+
+            Self::deposit_event(Event::ForwardVoided { contract_id });
+        }
+
+        // The defaulting party forfeits its margin to the counterparty
+        fn settle_default(contract_id: T::Hash, contract: &mut ForwardContract<T>, defaulter: T::AccountId) {
+            let Some(buyer) = contract.buyer.clone() else { return };
+
+            if defaulter == contract.seller {
+                Self::move_margin(&contract.seller, &buyer, contract.seller_margin, BalanceStatus::Free);
+                Self::release_margin(&buyer, contract.buyer_margin);
+            } else {
+                Self::move_margin(&buyer, &contract.seller, contract.buyer_margin, BalanceStatus::Free);
+                Self::release_margin(&contract.seller, contract.seller_margin);
+            }
+            contract.seller_margin = Zero::zero();
+            contract.buyer_margin = Zero::zero();
+            contract.status = ForwardStatus::Defaulted;
+
+            Self::deposit_event(Event::ForwardDefaulted {
+                contract_id,
+                defaulter,
+            });
+        }
+    }
+}
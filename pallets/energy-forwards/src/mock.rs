@@ -0,0 +1,160 @@
+use crate as pallet_energy_forwards;
+use frame_support::{
+    derive_impl, parameter_types,
+    traits::{ConstU128, ConstU32, ConstU64, Hooks},
+};
+use frame_system::EnsureRoot;
+use sp_runtime::{BuildStorage, Percent};
+
+type Block = frame_system::mocking::MockBlock<Test>;
+
+frame_support::construct_runtime!(
+    pub enum Test {
+        System: frame_system,
+        Timestamp: pallet_timestamp,
+        Balances: pallet_balances,
+        EnergyToken: pallet_energy_token,
+        EnergyTrade: pallet_energy_trade,
+        PriceDiscovery: pallet_price_discovery,
+        EnergyForwards: pallet_energy_forwards,
+    }
+);
+
+#[derive_impl(frame_system::config_preludes::TestDefaultConfig)]
+impl frame_system::Config for Test {
+    type Block = Block;
+    type AccountData = pallet_balances::AccountData<u128>;
+}
+
+#[derive_impl(pallet_balances::config_preludes::TestDefaultConfig)]
+impl pallet_balances::Config for Test {
+    type Balance = u128;
+    type ExistentialDeposit = ConstU128<1>;
+    type AccountStore = System;
+}
+
+impl pallet_timestamp::Config for Test {
+    type Moment = u64;
+    type OnTimestampSet = ();
+    type MinimumPeriod = ConstU64<1>;
+    type WeightInfo = ();
+}
+
+impl pallet_energy_token::Config for Test {
+    type RuntimeEvent = RuntimeEvent;
+    type TokenBalance = u128;
+}
+
+parameter_types! {
+    pub const AuctionInterval: u64 = 10;
+    pub const InitialMargin: Percent = Percent::from_percent(20);
+    pub const MaintenanceMargin: Percent = Percent::from_percent(10);
+    pub const TreasuryAccount: u64 = 100;
+    pub const CommunityFundAccount: u64 = 101;
+    pub const ImbalanceAccount: u64 = 102;
+    pub const ImbalancePremium: Percent = Percent::from_percent(20);
+    pub const SpillDiscount: Percent = Percent::from_percent(50);
+    pub const BreakerThreshold: Percent = Percent::from_percent(30);
+}
+
+impl pallet_energy_trade::Config for Test {
+    type RuntimeEvent = RuntimeEvent;
+    type TokenBalance = u128;
+    type Currency = Balances;
+    type OperatorOrigin = EnsureRoot<u64>;
+    type MarketAdminOrigin = EnsureRoot<u64>;
+    type Participants = ();
+    type Distances = ();
+    type Statistics = ();
+    type AuctionInterval = AuctionInterval;
+    type MaxAuctionOrders = ConstU32<16>;
+    type MaxAuctionOrdersPerAccount = ConstU32<4>;
+    type MaxStopOrders = ConstU32<8>;
+    type TimeProvider = Timestamp;
+    type SlotDuration = ConstU64<{ 15 * 60 * 1000 }>;
+    type RevealPeriod = ConstU64<3>;
+    type CommitDeposit = ConstU128<5>;
+    type TreasuryAccount = TreasuryAccount;
+    type CommunityFundAccount = CommunityFundAccount;
+    type ImbalancePrices = ();
+    type ImbalanceAccount = ImbalanceAccount;
+    type MaxOrdersPerLevel = ConstU32<8>;
+    type MaxPriceLevels = ConstU32<16>;
+    type MaxFillsPerOrder = ConstU32<8>;
+    type MaxLastResortPerBlock = ConstU32<8>;
+    type RateLimitWindow = ConstU64<10>;
+    type MaxOrdersPerWindow = ConstU32<5>;
+    type MaxOrderValue = ConstU128<1_000_000>;
+    type OrderHooks = ();
+    type WeightInfo = ();
+    #[cfg(feature = "runtime-benchmarks")]
+    type BenchmarkHelper = ();
+}
+
+pub type Extrinsic = sp_runtime::testing::TestXt<RuntimeCall, ()>;
+
+impl<C> frame_system::offchain::CreateTransactionBase<C> for Test
+where
+    RuntimeCall: From<C>,
+{
+    type RuntimeCall = RuntimeCall;
+    type Extrinsic = Extrinsic;
+}
+
+impl<C> frame_system::offchain::CreateInherent<C> for Test
+where
+    RuntimeCall: From<C>,
+{
+    fn create_inherent(call: RuntimeCall) -> Extrinsic {
+        Extrinsic::new_bare(call)
+    }
+}
+
+impl pallet_price_discovery::Config for Test {
+    type RuntimeEvent = RuntimeEvent;
+    type UnsignedPriority = ConstU64<100>;
+    type MaxMatchesPerBlock = ConstU32<4>;
+    type ImbalancePremium = ImbalancePremium;
+    type SpillDiscount = SpillDiscount;
+    type BreakerThreshold = BreakerThreshold;
+    type BreakerWindow = ConstU64<10>;
+    type BreakerCooldown = ConstU64<20>;
+    type PriceOracleOrigin = EnsureRoot<u64>;
+}
+
+impl pallet_energy_forwards::Config for Test {
+    type RuntimeEvent = RuntimeEvent;
+    type InitialMargin = InitialMargin;
+    type MaintenanceMargin = MaintenanceMargin;
+    type MarkToMarketPeriod = ConstU64<5>;
+    type MinLeadTime = ConstU64<10>;
+    type MaxOpenForwards = ConstU32<8>;
+}
+
+// Accounts 1 to 5 start with funds; the fee and penalty accounts are endowed so small shares
+// stay above the existential deposit
+pub fn new_test_ext() -> sp_io::TestExternalities {
+    let mut t = frame_system::GenesisConfig::<Test>::default().build_storage().unwrap();
+    pallet_balances::GenesisConfig::<Test> {
+        balances: (1..=5)
+            .chain([TreasuryAccount::get(), CommunityFundAccount::get(), ImbalanceAccount::get()])
+            .map(|who| (who, 10_000))
+            .collect(),
+        ..Default::default()
+    }
+    .assimilate_storage(&mut t)
+    .unwrap();
+
+    let mut ext = sp_io::TestExternalities::new(t);
+    ext.execute_with(|| System::set_block_number(1));
+    ext
+}
+
+pub fn run_to_block(n: u64) {
+    while System::block_number() < n {
+        let next = System::block_number() + 1;
+        System::set_block_number(next);
+        EnergyTrade::on_initialize(next);
+        EnergyForwards::on_initialize(next);
+    }
+}
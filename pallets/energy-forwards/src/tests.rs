@@ -0,0 +1,151 @@
+use crate::{mock::*, Error, Event, ForwardStatus};
+use frame_support::{assert_noop, assert_ok};
+use pallet_energy_trade::OrderStatus;
+
+// 100 units at 10 from account 1, delivered from block 20 to 30
+fn offer() -> sp_core::H256 {
+    assert_ok!(EnergyForwards::offer_forward(RuntimeOrigin::signed(1), 100, 10, b"zone-a".to_vec(), 20, 30));
+    *EnergyForwards::open_forwards().last().unwrap()
+}
+
+#[test]
+fn offer_and_accept_reserve_the_initial_margin() {
+    new_test_ext().execute_with(|| {
+        assert_noop!(
+            EnergyForwards::offer_forward(RuntimeOrigin::signed(1), 100, 10, b"zone-a".to_vec(), 5, 30),
+            Error::<Test>::InvalidDeliveryWindow
+        );
+        let contract_id = offer();
+        assert_eq!(Balances::reserved_balance(1), 200);
+
+        assert_noop!(
+            EnergyForwards::accept_forward(RuntimeOrigin::signed(1), contract_id),
+            Error::<Test>::Unauthorized
+        );
+        assert_ok!(EnergyForwards::accept_forward(RuntimeOrigin::signed(2), contract_id));
+        assert_eq!(Balances::reserved_balance(2), 200);
+
+        let contract = EnergyForwards::forwards(contract_id).unwrap();
+        assert_eq!((contract.status, contract.buyer), (ForwardStatus::Active, Some(2)));
+        assert_noop!(
+            EnergyForwards::cancel_forward(RuntimeOrigin::signed(1), contract_id),
+            Error::<Test>::InvalidContractStatus
+        );
+    });
+}
+
+#[test]
+fn unaccepted_offers_lapse_at_the_delivery_start() {
+    new_test_ext().execute_with(|| {
+        let contract_id = offer();
+        run_to_block(20);
+
+        assert_eq!(EnergyForwards::forwards(contract_id).unwrap().status, ForwardStatus::Cancelled);
+        assert_eq!(Balances::reserved_balance(1), 0);
+        assert!(EnergyForwards::open_forwards().is_empty());
+    });
+}
+
+#[test]
+fn active_contracts_are_delivered_through_a_matched_trade() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(1), 100));
+        let contract_id = offer();
+        assert_ok!(EnergyForwards::accept_forward(RuntimeOrigin::signed(2), contract_id));
+        run_to_block(20);
+
+        let contract = EnergyForwards::forwards(contract_id).unwrap();
+        assert_eq!(contract.status, ForwardStatus::Delivering);
+        let trade = EnergyTrade::trade_orders(contract.trade_id.unwrap()).unwrap();
+        assert_eq!((trade.status, trade.energy_amount, trade.price_per_unit), (OrderStatus::Matched, 100, 10));
+        // Margins are released and the trade holds the buyer's payment instead
+        assert_eq!(Balances::reserved_balance(1), 0);
+        assert_eq!(Balances::reserved_balance(2), 1000);
+    });
+}
+
+#[test]
+fn a_buyer_without_funds_defaults_and_forfeits_its_margin() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(1), 100));
+        let contract_id = offer();
+        assert_ok!(EnergyForwards::accept_forward(RuntimeOrigin::signed(2), contract_id));
+        // Leave the buyer its margin but not the trade value
+        assert_ok!(Balances::transfer_allow_death(RuntimeOrigin::signed(2), 3, 9_500));
+        run_to_block(20);
+
+        assert_eq!(EnergyForwards::forwards(contract_id).unwrap().status, ForwardStatus::Defaulted);
+        System::assert_has_event(Event::ForwardDefaulted { contract_id, defaulter: 2 }.into());
+        assert_eq!(Balances::free_balance(1), 10_000 + 200);
+    });
+}
+
+#[test]
+fn deliveries_blocked_by_the_market_are_retried_then_voided() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(1), 100));
+        let contract_id = offer();
+        assert_ok!(EnergyForwards::accept_forward(RuntimeOrigin::signed(2), contract_id));
+        assert_ok!(EnergyTrade::set_global_halt(RuntimeOrigin::root(), true));
+
+        run_to_block(25);
+        assert_eq!(EnergyForwards::forwards(contract_id).unwrap().status, ForwardStatus::Active);
+        assert!(System::events().iter().any(|r| matches!(
+            r.event,
+            RuntimeEvent::EnergyForwards(Event::ForwardDeliveryFailed { .. })
+        )));
+
+        run_to_block(29);
+        assert_eq!(EnergyForwards::forwards(contract_id).unwrap().status, ForwardStatus::Voided);
+        System::assert_has_event(Event::ForwardVoided { contract_id }.into());
+        assert_eq!(Balances::reserved_balance(1), 0);
+        assert_eq!(Balances::reserved_balance(2), 0);
+        assert!(EnergyForwards::open_forwards().is_empty());
+    });
+}
+
+#[test]
+fn deliveries_resume_within_the_window_once_the_market_reopens() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(1), 100));
+        let contract_id = offer();
+        assert_ok!(EnergyForwards::accept_forward(RuntimeOrigin::signed(2), contract_id));
+        assert_ok!(EnergyTrade::set_global_halt(RuntimeOrigin::root(), true));
+        run_to_block(22);
+
+        assert_ok!(EnergyTrade::set_global_halt(RuntimeOrigin::root(), false));
+        run_to_block(23);
+        assert_eq!(EnergyForwards::forwards(contract_id).unwrap().status, ForwardStatus::Delivering);
+    });
+}
+
+#[test]
+fn a_seller_without_energy_defaults_and_forfeits_its_margin() {
+    new_test_ext().execute_with(|| {
+        let contract_id = offer();
+        assert_ok!(EnergyForwards::accept_forward(RuntimeOrigin::signed(2), contract_id));
+        run_to_block(20);
+
+        assert_eq!(EnergyForwards::forwards(contract_id).unwrap().status, ForwardStatus::Defaulted);
+        System::assert_has_event(Event::ForwardDefaulted { contract_id, defaulter: 1 }.into());
+        assert_eq!(Balances::free_balance(1), 10_000 - 200);
+        assert_eq!(Balances::free_balance(2), 10_000 + 200);
+    });
+}
+
+#[test]
+fn only_the_price_oracle_moves_reference_prices() {
+    new_test_ext().execute_with(|| {
+        assert_noop!(
+            PriceDiscovery::update_market_data(RuntimeOrigin::signed(1), b"zone-a".to_vec(), 10, 100),
+            sp_runtime::DispatchError::BadOrigin
+        );
+        assert_ok!(PriceDiscovery::update_market_data(RuntimeOrigin::root(), b"zone-a".to_vec(), 10, 100));
+        assert_eq!(PriceDiscovery::market_data(b"zone-a".to_vec()).unwrap().current_price, 10);
+        assert!(!EnergyTrade::is_halted(b"zone-a"));
+
+        // A reported spike beyond the breaker threshold halts the location
+        assert_ok!(PriceDiscovery::update_market_data(RuntimeOrigin::root(), b"zone-a".to_vec(), 20, 100));
+        assert!(EnergyTrade::is_halted(b"zone-a"));
+    });
+}
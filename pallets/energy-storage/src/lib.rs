@@ -0,0 +1,305 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+pub use pallet::*;
+
+#[cfg(test)]
+mod mock;
+#[cfg(test)]
+mod tests;
+
+#[frame_support::pallet]
+pub mod pallet {
+    use frame_support::pallet_prelude::*;
+    use frame_system::pallet_prelude::*;
+    use pallet_energy_trade::{self as energy_trade, DeliverySlot, OrderHooks, OrderType, TokenBalanceOf};
+    use pallet_energy_transfer as energy_transfer;
+    use pallet_user_registry::{self as user_registry, DeviceType};
+    use scale_info::TypeInfo;
+    use sp_runtime::{
+        traits::{SaturatedConversion, Saturating, Zero},
+        Percent,
+    };
+
+    #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    #[scale_info(skip_type_params(T))]
+    pub struct Battery<T: Config> {
+        pub device_id: T::Hash,
+        pub capacity: TokenBalanceOf<T>,
+        pub state_of_charge: TokenBalanceOf<T>,
+        pub efficiency: Percent,                    // Round trip, applied when charging
+        pub committed_discharge: TokenBalanceOf<T>,   // Open and matched asks
+        pub committed_charge: TokenBalanceOf<T>,      // Open and matched bids, before losses
+        pub last_measured: Option<BlockNumberFor<T>>,
+        pub last_reading: Option<u64>,              // Timestamp of the meter reading last applied
+    }
+
+    impl<T: Config> Battery<T> {
+        // Charge not yet promised to an ask
+        pub fn available(&self) -> TokenBalanceOf<T> {
+            self.state_of_charge.saturating_sub(self.committed_discharge)
+        }
+
+        // Room left once every committed bid has been stored
+        pub fn headroom(&self) -> TokenBalanceOf<T> {
+            self.capacity
+                .saturating_sub(self.state_of_charge)
+                .saturating_sub(self.efficiency.mul_floor(self.committed_charge))
+        }
+    }
+
+    #[pallet::config]
+    pub trait Config:
+        frame_system::Config + energy_trade::Config + energy_transfer::Config + user_registry::Config
+    {
+        type RuntimeEvent: From<Event<Self>> + IsType<<Self as frame_system::Config>::RuntimeEvent>;
+    }
+
+    #[pallet::pallet]
+    pub struct Pallet<T>(_);
+
+    // One battery per storage operator account, so its orders can be attributed to it
+    #[pallet::storage]
+    #[pallet::getter(fn batteries)]
+    pub type Batteries<T: Config> = StorageMap<_, Blake2_128Concat, T::AccountId, Battery<T>, OptionQuery>;
+
+    #[pallet::storage]
+    #[pallet::getter(fn battery_operator)]
+    pub type BatteryOperators<T: Config> = StorageMap<_, Blake2_128Concat, T::Hash, T::AccountId, OptionQuery>;
+
+    #[pallet::event]
+    #[pallet::generate_deposit(pub(super) fn deposit_event)]
+    pub enum Event<T: Config> {
+        BatteryRegistered {
+            operator: T::AccountId,
+            device_id: T::Hash,
+            capacity: TokenBalanceOf<T>,
+            efficiency: Percent,
+        },
+        BatteryUpdated {
+            operator: T::AccountId,
+            capacity: TokenBalanceOf<T>,
+            efficiency: Percent,
+        },
+        BatteryDeregistered {
+            operator: T::AccountId,
+            device_id: T::Hash,
+        },
+        StateOfChargeMeasured {
+            operator: T::AccountId,
+            tracked: TokenBalanceOf<T>,
+            measured: TokenBalanceOf<T>,
+        },
+        StateOfChargeUpdated {
+            operator: T::AccountId,
+            state_of_charge: TokenBalanceOf<T>,
+        },
+    }
+
+    #[pallet::error]
+    pub enum Error<T> {
+        DeviceNotFound,
+        NotDeviceOwner,
+        DeviceInactive,
+        NotABattery,
+        AlreadyRegistered,
+        BatteryNotFound,
+        InvalidCapacity,
+        InvalidEfficiency,
+        StateOfChargeAboveCapacity,
+        CapacityBelowCommitments,
+        OutstandingCommitments,
+        InsufficientStateOfCharge,
+        InsufficientHeadroom,
+        NoReading,
+        StaleReading,
+    }
+
+    #[pallet::call]
+    impl<T: Config> Pallet<T> {
+        // The device must be an active registry battery owned by the caller
+        #[pallet::call_index(0)]
+        #[pallet::weight(10_000)]
+        pub fn register_battery(
+            origin: OriginFor<T>,
+            device_id: T::Hash,
+            capacity: TokenBalanceOf<T>,
+            efficiency: Percent,
+            state_of_charge: TokenBalanceOf<T>,
+        ) -> DispatchResult {
+            let operator = ensure_signed(origin)?;
+            ensure!(!Batteries::<T>::contains_key(&operator), Error::<T>::AlreadyRegistered);
+            ensure!(!BatteryOperators::<T>::contains_key(device_id), Error::<T>::AlreadyRegistered);
+
+            let device = user_registry::Pallet::<T>::devices(device_id).ok_or(Error::<T>::DeviceNotFound)?;
+            ensure!(device.owner == operator, Error::<T>::NotDeviceOwner);
+            ensure!(device.active, Error::<T>::DeviceInactive);
+            ensure!(device.device_type == DeviceType::Battery, Error::<T>::NotABattery);
+
+            ensure!(!capacity.is_zero(), Error::<T>::InvalidCapacity);
+            ensure!(!efficiency.is_zero(), Error::<T>::InvalidEfficiency);
+            ensure!(state_of_charge <= capacity, Error::<T>::StateOfChargeAboveCapacity);
+
+            Batteries::<T>::insert(&operator, Battery {
+                device_id,
+                capacity,
+                state_of_charge,
+                efficiency,
+                committed_discharge: Zero::zero(),
+                committed_charge: Zero::zero(),
+                last_measured: Some(<frame_system::Pallet<T>>::block_number()),
+                last_reading: None,
+            });
+            BatteryOperators::<T>::insert(device_id, &operator);
+
+            Self::deposit_event(Event::BatteryRegistered { operator, device_id, capacity, efficiency });
+
+            Ok(())
+        }
+
+        #[pallet::call_index(1)]
+        #[pallet::weight(10_000)]
+        pub fn update_battery(
+            origin: OriginFor<T>,
+            capacity: TokenBalanceOf<T>,
+            efficiency: Percent,
+        ) -> DispatchResult {
+            let operator = ensure_signed(origin)?;
+            ensure!(!capacity.is_zero(), Error::<T>::InvalidCapacity);
+            ensure!(!efficiency.is_zero(), Error::<T>::InvalidEfficiency);
+
+            Batteries::<T>::try_mutate(&operator, |battery| -> DispatchResult {
+                let battery = battery.as_mut().ok_or(Error::<T>::BatteryNotFound)?;
+                battery.capacity = capacity;
+                battery.efficiency = efficiency;
+                ensure!(
+                    battery.state_of_charge.saturating_add(efficiency.mul_floor(battery.committed_charge)) <= capacity,
+                    Error::<T>::CapacityBelowCommitments
+                );
+                Ok(())
+            })?;
+
+            Self::deposit_event(Event::BatteryUpdated { operator, capacity, efficiency });
+
+            Ok(())
+        }
+
+        #[pallet::call_index(2)]
+        #[pallet::weight(10_000)]
+        pub fn deregister_battery(origin: OriginFor<T>) -> DispatchResult {
+            let operator = ensure_signed(origin)?;
+            let battery = Self::batteries(&operator).ok_or(Error::<T>::BatteryNotFound)?;
+            ensure!(
+                battery.committed_discharge.is_zero() && battery.committed_charge.is_zero(),
+                Error::<T>::OutstandingCommitments
+            );
+
+            Batteries::<T>::remove(&operator);
+            BatteryOperators::<T>::remove(battery.device_id);
+
+            Self::deposit_event(Event::BatteryDeregistered { operator, device_id: battery.device_id });
+
+            Ok(())
+        }
+
+        // The battery's own metered state of charge replaces the tracked one, correcting
+        // for self-discharge and trades settled on estimates. It is the latest reading of the
+        // battery device in the energy transfer pallet, and each reading applies once.
+        #[pallet::call_index(3)]
+        #[pallet::weight(10_000)]
+        pub fn record_state_of_charge(origin: OriginFor<T>) -> DispatchResult {
+            let operator = ensure_signed(origin)?;
+
+            let (tracked, state_of_charge) = Batteries::<T>::try_mutate(
+                &operator,
+                |battery| -> Result<(TokenBalanceOf<T>, TokenBalanceOf<T>), DispatchError> {
+                    let battery = battery.as_mut().ok_or(Error::<T>::BatteryNotFound)?;
+                    let meter_id = energy_transfer::Pallet::<T>::meter_id(&battery.device_id);
+                    let (timestamp, energy) = energy_transfer::Pallet::<T>::latest_reading(meter_id)
+                        .ok_or(Error::<T>::NoReading)?;
+                    ensure!(battery.last_reading.map_or(true, |at| timestamp > at), Error::<T>::StaleReading);
+
+                    let state_of_charge = energy.saturated_into::<TokenBalanceOf<T>>();
+                    ensure!(state_of_charge <= battery.capacity, Error::<T>::StateOfChargeAboveCapacity);
+                    let tracked = battery.state_of_charge;
+                    battery.state_of_charge = state_of_charge;
+                    battery.last_measured = Some(<frame_system::Pallet<T>>::block_number());
+                    battery.last_reading = Some(timestamp);
+                    Ok((tracked, state_of_charge))
+                },
+            )?;
+
+            Self::deposit_event(Event::StateOfChargeMeasured { operator, tracked, measured: state_of_charge });
+
+            Ok(())
+        }
+    }
+
+    // Orders from storage operators are checked against the battery's charge, ignoring the
+    // delivery slot, so every commitment can be honoured whatever order they are delivered in
+    impl<T: Config> OrderHooks<T::AccountId, TokenBalanceOf<T>> for Pallet<T> {
+        fn on_order_placed(
+            who: &T::AccountId,
+            order_type: &OrderType,
+            amount: TokenBalanceOf<T>,
+            _slot: DeliverySlot,
+        ) -> DispatchResult {
+            Batteries::<T>::try_mutate(who, |battery| -> DispatchResult {
+                let Some(battery) = battery.as_mut() else { return Ok(()) };
+                match order_type {
+                    OrderType::Ask => {
+                        ensure!(amount <= battery.available(), Error::<T>::InsufficientStateOfCharge);
+                        battery.committed_discharge = battery.committed_discharge.saturating_add(amount);
+                    },
+                    OrderType::Bid => {
+                        ensure!(
+                            battery.efficiency.mul_floor(amount) <= battery.headroom(),
+                            Error::<T>::InsufficientHeadroom
+                        );
+                        battery.committed_charge = battery.committed_charge.saturating_add(amount);
+                    },
+                }
+                Ok(())
+            })
+        }
+
+        fn on_order_released(who: &T::AccountId, order_type: &OrderType, amount: TokenBalanceOf<T>) {
+            Batteries::<T>::mutate(who, |battery| {
+                if let Some(battery) = battery.as_mut() {
+                    match order_type {
+                        OrderType::Ask => battery.committed_discharge = battery.committed_discharge.saturating_sub(amount),
+                        OrderType::Bid => battery.committed_charge = battery.committed_charge.saturating_sub(amount),
+                    }
+                }
+            });
+        }
+
+        fn on_trade_settled(
+            seller: &T::AccountId,
+            buyer: &T::AccountId,
+            contracted: TokenBalanceOf<T>,
+            delivered: TokenBalanceOf<T>,
+        ) {
+            if let Some(state_of_charge) = Batteries::<T>::mutate(seller, |battery| {
+                battery.as_mut().map(|battery| {
+                    battery.committed_discharge = battery.committed_discharge.saturating_sub(contracted);
+                    battery.state_of_charge = battery.state_of_charge.saturating_sub(delivered);
+                    battery.state_of_charge
+                })
+            }) {
+                Self::deposit_event(Event::StateOfChargeUpdated { operator: seller.clone(), state_of_charge });
+            }
+
+            if let Some(state_of_charge) = Batteries::<T>::mutate(buyer, |battery| {
+                battery.as_mut().map(|battery| {
+                    battery.committed_charge = battery.committed_charge.saturating_sub(contracted);
+                    battery.state_of_charge = battery.state_of_charge
+                        .saturating_add(battery.efficiency.mul_floor(delivered))
+                        .min(battery.capacity);
+                    battery.state_of_charge
+                })
+            }) {
+                Self::deposit_event(Event::StateOfChargeUpdated { operator: buyer.clone(), state_of_charge });
+            }
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use crate as pallet_energy_storage;
+use frame_support::{
+    derive_impl, ord_parameter_types, parameter_types,
+    traits::{ConstU128, ConstU32, ConstU64},
+};
+use frame_system::{EnsureRoot, EnsureSignedBy};
+use sp_runtime::BuildStorage;
+
+type Block = frame_system::mocking::MockBlock<Test>;
+
+frame_support::construct_runtime!(
+    pub enum Test {
+        System: frame_system,
+        Timestamp: pallet_timestamp,
+        Balances: pallet_balances,
+        EnergyToken: pallet_energy_token,
+        UserRegistry: pallet_user_registry,
+        EnergyTrade: pallet_energy_trade,
+        EnergyTransfer: pallet_energy_transfer,
+        EnergyStorage: pallet_energy_storage,
+    }
+);
+
+#[derive_impl(frame_system::config_preludes::TestDefaultConfig)]
+impl frame_system::Config for Test {
+    type Block = Block;
+    type AccountData = pallet_balances::AccountData<u128>;
+}
+
+#[derive_impl(pallet_balances::config_preludes::TestDefaultConfig)]
+impl pallet_balances::Config for Test {
+    type Balance = u128;
+    type ExistentialDeposit = ConstU128<1>;
+    type AccountStore = System;
+}
+
+impl pallet_timestamp::Config for Test {
+    type Moment = u64;
+    type OnTimestampSet = ();
+    type MinimumPeriod = ConstU64<1>;
+    type WeightInfo = ();
+}
+
+impl pallet_energy_token::Config for Test {
+    type RuntimeEvent = RuntimeEvent;
+    type TokenBalance = u128;
+}
+
+impl pallet_user_registry::Config for Test {
+    type RuntimeEvent = RuntimeEvent;
+}
+
+parameter_types! {
+    pub const AuctionInterval: u64 = 10;
+    pub const TreasuryAccount: u64 = 100;
+    pub const CommunityFundAccount: u64 = 101;
+    pub const ImbalanceAccount: u64 = 102;
+}
+
+impl pallet_energy_trade::Config for Test {
+    type RuntimeEvent = RuntimeEvent;
+    type TokenBalance = u128;
+    type Currency = Balances;
+    type OperatorOrigin = EnsureRoot<u64>;
+    type MarketAdminOrigin = EnsureRoot<u64>;
+    type Participants = ();
+    type Distances = ();
+    type Statistics = ();
+    type AuctionInterval = AuctionInterval;
+    type MaxAuctionOrders = ConstU32<16>;
+    type MaxAuctionOrdersPerAccount = ConstU32<4>;
+    type MaxStopOrders = ConstU32<8>;
+    type TimeProvider = Timestamp;
+    type SlotDuration = ConstU64<{ 15 * 60 * 1000 }>;
+    type RevealPeriod = ConstU64<3>;
+    type CommitDeposit = ConstU128<5>;
+    type TreasuryAccount = TreasuryAccount;
+    type CommunityFundAccount = CommunityFundAccount;
+    type ImbalancePrices = ();
+    type ImbalanceAccount = ImbalanceAccount;
+    type MaxOrdersPerLevel = ConstU32<8>;
+    type MaxPriceLevels = ConstU32<16>;
+    type MaxFillsPerOrder = ConstU32<8>;
+    type MaxLastResortPerBlock = ConstU32<8>;
+    type RateLimitWindow = ConstU64<10>;
+    type MaxOrdersPerWindow = ConstU32<5>;
+    type MaxOrderValue = ConstU128<1_000_000>;
+    type OrderHooks = EnergyStorage;
+    type WeightInfo = ();
+    #[cfg(feature = "runtime-benchmarks")]
+    type BenchmarkHelper = ();
+}
+
+ord_parameter_types! {
+    pub const MeterOracle: u64 = 9;
+}
+
+impl pallet_energy_transfer::Config for Test {
+    type RuntimeEvent = RuntimeEvent;
+    type Moment = u64;
+    type MeterOracleOrigin = EnsureSignedBy<MeterOracle, u64>;
+}
+
+impl pallet_energy_storage::Config for Test {
+    type RuntimeEvent = RuntimeEvent;
+}
+
+// Accounts 1 to 5 start with funds; the fee and penalty accounts are endowed so small shares
+// stay above the existential deposit
+pub fn new_test_ext() -> sp_io::TestExternalities {
+    let mut t = frame_system::GenesisConfig::<Test>::default().build_storage().unwrap();
+    pallet_balances::GenesisConfig::<Test> {
+        balances: (1..=5)
+            .chain([TreasuryAccount::get(), CommunityFundAccount::get(), ImbalanceAccount::get()])
+            .map(|who| (who, 10_000))
+            .collect(),
+        ..Default::default()
+    }
+    .assimilate_storage(&mut t)
+    .unwrap();
+
+    let mut ext = sp_io::TestExternalities::new(t);
+    ext.execute_with(|| System::set_block_number(1));
+    ext
+}
+
+// Registers `who` as a prosumer if needed and returns the id of a new device of that type
+pub fn register_device(who: u64, device_type: pallet_user_registry::DeviceType, max_capacity: u32) -> sp_core::H256 {
+    if UserRegistry::user_profiles(who).is_none() {
+        frame_support::assert_ok!(UserRegistry::register_user(
+            RuntimeOrigin::signed(who),
+            pallet_user_registry::UserRole::Prosumer,
+        ));
+    }
+    frame_support::assert_ok!(UserRegistry::register_device(RuntimeOrigin::signed(who), device_type, max_capacity));
+    *UserRegistry::user_profiles(who).unwrap().devices.last().unwrap()
+}
@@ -0,0 +1,108 @@
+use crate::{mock::*, Error, Event};
+use frame_support::{assert_noop, assert_ok};
+use pallet_energy_transfer::IoTMeasurement;
+use pallet_user_registry::DeviceType;
+use sp_runtime::Percent;
+
+#[test]
+fn register_battery_requires_an_owned_registry_battery() {
+    new_test_ext().execute_with(|| {
+        let panel = register_device(1, DeviceType::SolarPanel, 100);
+        let battery = register_device(1, DeviceType::Battery, 100);
+
+        assert_noop!(
+            EnergyStorage::register_battery(RuntimeOrigin::signed(1), panel, 100, Percent::from_percent(90), 50),
+            Error::<Test>::NotABattery
+        );
+        assert_noop!(
+            EnergyStorage::register_battery(RuntimeOrigin::signed(2), battery, 100, Percent::from_percent(90), 50),
+            Error::<Test>::NotDeviceOwner
+        );
+        assert_noop!(
+            EnergyStorage::register_battery(RuntimeOrigin::signed(1), battery, 100, Percent::from_percent(90), 150),
+            Error::<Test>::StateOfChargeAboveCapacity
+        );
+
+        assert_ok!(EnergyStorage::register_battery(RuntimeOrigin::signed(1), battery, 100, Percent::from_percent(90), 50));
+        assert_eq!(EnergyStorage::battery_operator(battery), Some(1));
+        assert_eq!(EnergyStorage::batteries(1).unwrap().state_of_charge, 50);
+    });
+}
+
+#[test]
+fn orders_are_limited_by_charge_and_headroom() {
+    new_test_ext().execute_with(|| {
+        let battery = register_device(1, DeviceType::Battery, 100);
+        assert_ok!(EnergyStorage::register_battery(RuntimeOrigin::signed(1), battery, 100, Percent::from_percent(50), 40));
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(1), 100));
+
+        assert_noop!(
+            EnergyTrade::create_ask_order(RuntimeOrigin::signed(1), 50, 5, b"zone-a".to_vec(), 0),
+            Error::<Test>::InsufficientStateOfCharge
+        );
+        assert_ok!(EnergyTrade::create_ask_order(RuntimeOrigin::signed(1), 40, 5, b"zone-a".to_vec(), 0));
+
+        // 60 units of headroom take 120 bought units at 50% efficiency
+        assert_noop!(
+            EnergyTrade::create_bid_order(RuntimeOrigin::signed(1), 130, 1, b"zone-b".to_vec(), 0),
+            Error::<Test>::InsufficientHeadroom
+        );
+        assert_ok!(EnergyTrade::create_bid_order(RuntimeOrigin::signed(1), 120, 1, b"zone-b".to_vec(), 0));
+
+        let battery = EnergyStorage::batteries(1).unwrap();
+        assert_eq!((battery.committed_discharge, battery.committed_charge), (40, 120));
+        assert_noop!(
+            EnergyStorage::deregister_battery(RuntimeOrigin::signed(1)),
+            Error::<Test>::OutstandingCommitments
+        );
+    });
+}
+
+#[test]
+fn settled_trades_discharge_the_battery() {
+    new_test_ext().execute_with(|| {
+        let battery = register_device(1, DeviceType::Battery, 100);
+        assert_ok!(EnergyStorage::register_battery(RuntimeOrigin::signed(1), battery, 100, Percent::from_percent(90), 50));
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(1), 50));
+        assert_ok!(EnergyTrade::create_ask_order(RuntimeOrigin::signed(1), 30, 5, b"zone-a".to_vec(), 0));
+        assert_ok!(EnergyTrade::create_bid_order(RuntimeOrigin::signed(2), 30, 5, b"zone-a".to_vec(), 0));
+
+        let ask_id = EnergyTrade::account_orders(&1, 0, 1)[0];
+        let bid_id = EnergyTrade::account_orders(&2, 0, 1)[0];
+        assert_ok!(EnergyTrade::match_orders(RuntimeOrigin::signed(3), ask_id, bid_id));
+        assert_ok!(EnergyTrade::verify_transfer(RuntimeOrigin::root(), ask_id, vec![1], 30));
+        assert_ok!(EnergyTrade::complete_trade(RuntimeOrigin::signed(3), ask_id));
+
+        let battery = EnergyStorage::batteries(1).unwrap();
+        assert_eq!((battery.state_of_charge, battery.committed_discharge), (20, 0));
+    });
+}
+
+#[test]
+fn state_of_charge_comes_from_the_battery_meter() {
+    new_test_ext().execute_with(|| {
+        let battery = register_device(1, DeviceType::Battery, 100);
+        assert_ok!(EnergyStorage::register_battery(RuntimeOrigin::signed(1), battery, 100, Percent::from_percent(90), 50));
+        assert_noop!(EnergyStorage::record_state_of_charge(RuntimeOrigin::signed(1)), Error::<Test>::NoReading);
+
+        let reading = |timestamp, energy_amount| IoTMeasurement {
+            device_id: EnergyTransfer::meter_id(&battery).into_inner(),
+            timestamp,
+            energy_amount,
+            grid_frequency: 50,
+            voltage: 230,
+        };
+        assert_ok!(EnergyTransfer::submit_meter_reading(RuntimeOrigin::signed(1), reading(1, 35)));
+        assert_ok!(EnergyStorage::record_state_of_charge(RuntimeOrigin::signed(1)));
+        System::assert_last_event(Event::StateOfChargeMeasured { operator: 1, tracked: 50, measured: 35 }.into());
+        assert_eq!(EnergyStorage::batteries(1).unwrap().state_of_charge, 35);
+
+        // A reading is applied once, and never above the capacity
+        assert_noop!(EnergyStorage::record_state_of_charge(RuntimeOrigin::signed(1)), Error::<Test>::StaleReading);
+        assert_ok!(EnergyTransfer::submit_meter_reading(RuntimeOrigin::signed(1), reading(2, 150)));
+        assert_noop!(
+            EnergyStorage::record_state_of_charge(RuntimeOrigin::signed(1)),
+            Error::<Test>::StateOfChargeAboveCapacity
+        );
+    });
+}
@@ -1,12 +1,17 @@
 #![cfg_attr(not(feature = "std"), no_std)]
 
-pub use frame_system::pallet::*;
+pub use pallet::*;
+
+#[cfg(test)]
+mod mock;
+#[cfg(test)]
+mod tests;
 
 #[frame_support::pallet]
 pub mod pallet {
     use frame_support::pallet_prelude::*;
     use frame_system::pallet_prelude::*;
-    use sp_runtime::traits::{AtLeast32BitUnsigned, CheckedAdd, CheckedSub};
+    use sp_runtime::traits::{AtLeast32BitUnsigned, CheckedAdd, CheckedSub, Saturating, Zero};
 
     #[pallet::pallet]
     pub struct Pallet<T>(_);
@@ -27,6 +32,16 @@ pub mod pallet {
         ValueQuery,
     >;
 
+    #[pallet::storage]
+    #[pallet::getter(fn reserved_balance)]
+    pub type ReservedBalance<T: Config> = StorageMap<
+        _,
+        Blake2_128Concat,
+        T::AccountId,
+        T::TokenBalance,
+        ValueQuery,
+    >;
+
     #[pallet::event]
     #[pallet::generate_deposit(pub(super) fn deposit_event)]
     pub enum Event<T: Config> {
@@ -39,6 +54,14 @@ pub mod pallet {
             to: T::AccountId,
             amount: T::TokenBalance,
         },
+        TokensReserved {
+            account: T::AccountId,
+            amount: T::TokenBalance,
+        },
+        TokensUnreserved {
+            account: T::AccountId,
+            amount: T::TokenBalance,
+        },
     }
 
     #[pallet::error]
@@ -105,4 +128,74 @@ pub mod pallet {
             Ok(())
         }
     }
+
+    impl<T: Config> Pallet<T> {
+        // Move `amount` from the free balance into the reserved balance
+        pub fn reserve(who: &T::AccountId, amount: T::TokenBalance) -> DispatchResult {
+            TokenBalance::<T>::try_mutate(who, |free| -> DispatchResult {
+                *free = free.checked_sub(&amount)
+                    .ok_or(Error::<T>::InsufficientBalance)?;
+
+                ReservedBalance::<T>::try_mutate(who, |reserved| -> DispatchResult {
+                    *reserved = reserved.checked_add(&amount)
+                        .ok_or(Error::<T>::OverflowError)?;
+                    Ok(())
+                })
+            })?;
+
+            Self::deposit_event(Event::TokensReserved {
+                account: who.clone(),
+                amount,
+            });
+
+            Ok(())
+        }
+
+        // Move up to `amount` back to the free balance, returning what was actually released
+        pub fn unreserve(who: &T::AccountId, amount: T::TokenBalance) -> T::TokenBalance {
+            let released = ReservedBalance::<T>::mutate(who, |reserved| {
+                let released = amount.min(*reserved);
+                *reserved -= released;
+                released
+            });
+            if released.is_zero() {
+                return released;
+            }
+
+            TokenBalance::<T>::mutate(who, |free| *free = free.saturating_add(released));
+
+            Self::deposit_event(Event::TokensUnreserved {
+                account: who.clone(),
+                amount: released,
+            });
+
+            released
+        }
+
+        // Transfer `amount` out of `from`'s reserved balance into `to`'s free balance
+        pub fn repatriate_reserved(
+            from: &T::AccountId,
+            to: &T::AccountId,
+            amount: T::TokenBalance,
+        ) -> DispatchResult {
+            ReservedBalance::<T>::try_mutate(from, |reserved| -> DispatchResult {
+                *reserved = reserved.checked_sub(&amount)
+                    .ok_or(Error::<T>::InsufficientBalance)?;
+
+                TokenBalance::<T>::try_mutate(to, |to_balance| -> DispatchResult {
+                    *to_balance = to_balance.checked_add(&amount)
+                        .ok_or(Error::<T>::OverflowError)?;
+                    Ok(())
+                })
+            })?;
+
+            Self::deposit_event(Event::TokensTransferred {
+                from: from.clone(),
+                to: to.clone(),
+                amount,
+            });
+
+            Ok(())
+        }
+    }
 }
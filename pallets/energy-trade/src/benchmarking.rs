@@ -0,0 +1,131 @@
+// Benchmarks for the order book calls. Each one fills the affected book up to its bounds
+// first, so the measured weight is the worst case the indices allow.
+
+use super::*;
+
+use frame_benchmarking::v2::*;
+use frame_support::traits::Currency;
+use frame_system::RawOrigin;
+use sp_runtime::traits::Bounded;
+use sp_std::prelude::*;
+
+const LOCATION: &[u8] = b"bench-zone";
+
+fn participant<T: Config>(name: &'static str, index: u32) -> T::AccountId {
+    let who: T::AccountId = account(name, index, 0);
+    T::BenchmarkHelper::register_participant(&who);
+    T::Currency::make_free_balance_be(&who, BalanceOf::<T>::max_value() / 4u32.into());
+    pallet_energy_token::Pallet::<T>::mint_tokens(
+        RawOrigin::Signed(who.clone()).into(),
+        1_000_000u32.into(),
+    ).expect("minting to a fresh account succeeds");
+    who
+}
+
+// Rest a one-unit limit order from its own account
+fn rest_order<T: Config>(order_type: OrderType, index: u32, price: u32) -> T::Hash {
+    let who = participant::<T>("maker", index);
+    Pallet::<T>::do_create_order(
+        DEFAULT_MARKET,
+        who,
+        order_type,
+        OrderKind::Limit,
+        1u32.into(),
+        price.into(),
+        LOCATION.to_vec(),
+        Pallet::<T>::current_slot(),
+    ).expect("book has room")
+}
+
+// Fill every price level but the lowest one, so a new lowest price shifts all of them
+fn fill_levels<T: Config>(order_type: OrderType) {
+    for price in 2..=T::MaxPriceLevels::get() {
+        rest_order::<T>(order_type.clone(), price, price);
+    }
+}
+
+// Queue `MaxOrdersPerLevel` orders at one price from accounts numbered from `first_account`
+// and return the last one
+fn fill_queue<T: Config>(order_type: OrderType, price: u32, first_account: u32) -> T::Hash {
+    let mut last = None;
+    for index in 0..T::MaxOrdersPerLevel::get() {
+        last = Some(rest_order::<T>(order_type.clone(), first_account + index, price));
+    }
+    last.expect("MaxOrdersPerLevel is not zero")
+}
+
+#[benchmarks]
+mod benchmarks {
+    use super::*;
+
+    #[benchmark]
+    fn create_ask_order() {
+        fill_levels::<T>(OrderType::Ask);
+        let seller = participant::<T>("seller", 0);
+        let slot = Pallet::<T>::current_slot();
+
+        #[extrinsic_call]
+        _(RawOrigin::Signed(seller.clone()), 1u32.into(), 1u32.into(), LOCATION.to_vec(), slot);
+
+        assert_eq!(Pallet::<T>::account_order_count(&seller), 1);
+    }
+
+    #[benchmark]
+    fn create_bid_order() {
+        fill_levels::<T>(OrderType::Bid);
+        let buyer = participant::<T>("buyer", 0);
+        let slot = Pallet::<T>::current_slot();
+
+        #[extrinsic_call]
+        _(RawOrigin::Signed(buyer.clone()), 1u32.into(), 1u32.into(), LOCATION.to_vec(), slot);
+
+        assert_eq!(Pallet::<T>::account_order_count(&buyer), 1);
+    }
+
+    #[benchmark]
+    fn match_orders() {
+        let ask_id = fill_queue::<T>(OrderType::Ask, 5, 10_000);
+        let bid_id = fill_queue::<T>(OrderType::Bid, 5, 20_000);
+        let caller = participant::<T>("caller", 0);
+
+        #[extrinsic_call]
+        _(RawOrigin::Signed(caller), ask_id, bid_id);
+
+        assert_eq!(Pallet::<T>::trade_orders(ask_id).map(|o| o.status), Some(OrderStatus::Matched));
+    }
+
+    #[benchmark]
+    fn cancel_order() {
+        fill_levels::<T>(OrderType::Ask);
+        let order_id = fill_queue::<T>(OrderType::Ask, 1, 10_000);
+        let creator = Pallet::<T>::trade_orders(order_id).expect("order was created").creator;
+
+        #[extrinsic_call]
+        _(RawOrigin::Signed(creator), order_id);
+
+        assert_eq!(Pallet::<T>::trade_orders(order_id).map(|o| o.status), Some(OrderStatus::Cancelled));
+    }
+
+    // An immediate-or-cancel bid crossing `n` asks, one per price level
+    #[benchmark]
+    fn create_order(n: Linear<1, { T::MaxFillsPerOrder::get() }>) {
+        for price in 1..=n {
+            rest_order::<T>(OrderType::Ask, price, price);
+        }
+        let buyer = participant::<T>("buyer", 0);
+        let slot = Pallet::<T>::current_slot();
+
+        #[extrinsic_call]
+        _(
+            RawOrigin::Signed(buyer.clone()),
+            OrderType::Bid,
+            OrderKind::ImmediateOrCancel,
+            n.into(),
+            n.into(),
+            LOCATION.to_vec(),
+            slot,
+        );
+
+        assert_eq!(Pallet::<T>::account_order_count(&buyer), n);
+    }
+}
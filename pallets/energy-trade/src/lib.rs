@@ -2,16 +2,41 @@
 
 pub use pallet::*;
 
+#[cfg(feature = "runtime-benchmarks")]
+mod benchmarking;
+#[cfg(test)]
+mod mock;
+pub mod migrations;
+pub mod rate_limit;
+pub mod weights;
+#[cfg(test)]
+mod tests;
+pub use rate_limit::CheckOrderRate;
+pub use weights::WeightInfo;
+
 #[frame_support::pallet]
 pub mod pallet {
-    use frame_support::{pallet_prelude::*, traits::Currency, BoundedVec};
+    use frame_support::{
+        pallet_prelude::*,
+        traits::{BalanceStatus, Currency, ExistenceRequirement, ReservableCurrency, UnixTime},
+        BoundedVec,
+    };
     use frame_system::pallet_prelude::*;
     use scale_info::TypeInfo;
-    use sp_std::prelude::*;
-    use sp_runtime::traits::{AtLeast32BitUnsigned, Hash, CheckedAdd, CheckedSub};
+    use sp_std::{collections::btree_map::BTreeMap, prelude::*};
+    use sp_runtime::{
+        helpers_128bit::multiply_by_rational_with_rounding,
+        traits::{AtLeast32BitUnsigned, Bounded, Hash, CheckedAdd, CheckedSub, One, Saturating, Zero},
+        transaction_validity::{InvalidTransaction, TransactionValidityError},
+        Percent, Rounding, SaturatedConversion,
+    };
     use pallet_energy_token;
+    use pallet_user_registry::{DeviceType, UserRole};
+    use crate::weights::WeightInfo;
 
-    #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    const STORAGE_VERSION: StorageVersion = StorageVersion::new(3);
+
+    #[derive(Clone, Encode, Decode, DecodeWithMemTracking, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
     pub enum OrderType {
         Ask,    // Seller's offer
         Bid,    // Buyer's offer
@@ -19,70 +44,684 @@ pub mod pallet {
 
     #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
     pub enum OrderStatus {
+        Pending,    // Stop order waiting for its trigger price
         Open,
         Matched,
         InTransfer,
         Completed,
         Cancelled,
         Failed,
+        Disputed,   // Settlement on hold while a delivery dispute is open
+    }
+
+    #[derive(Clone, Encode, Decode, DecodeWithMemTracking, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    pub enum OrderKind<Balance> {
+        Limit,                              // Rests on the book until matched or cancelled
+        Market { max_slippage: Percent },   // Sweeps the book up to a bound around the best price
+        ImmediateOrCancel,                  // Sweeps the book at its limit, remainder is cancelled
+        FillOrKill,                         // Fills completely at its limit or is rejected
+        PostOnly,                           // Rejected if it would cross the book
+        Stop { trigger_price: Balance },    // Becomes a limit order once the reference price crosses
+    }
+
+    // What happens when an incoming order would trade with a resting order of its creator
+    #[derive(Clone, Copy, Encode, Decode, DecodeWithMemTracking, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen, Default)]
+    pub enum SelfTradePrevention {
+        #[default]
+        CancelNewest,   // The rest of the incoming order is cancelled
+        CancelOldest,   // The resting order is cancelled and the sweep goes on
+        DecrementBoth,  // The overlapping amount is cancelled on both orders
+    }
+
+    #[derive(Clone, Copy, Encode, Decode, DecodeWithMemTracking, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen, Default)]
+    pub enum MatchingMode {
+        #[default]
+        Continuous,         // Bilateral pay-as-bid matching via `match_orders`
+        PeriodicAuction,    // Uniform-price double auction per delivery interval
+        SealedBid,          // Periodic auction fed by committed orders revealed before gate closure
+    }
+
+    #[derive(Clone, Encode, Decode, DecodeWithMemTracking, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen, Default)]
+    pub struct FeeSchedule<Balance> {
+        pub maker_fee_bps: u16,     // Charged to the order that was placed first
+        pub taker_fee_bps: u16,
+        pub min_fee: Balance,       // Per side and trade
+    }
+
+    // Checked when an order is placed on a book; zero maximums do not limit, but every
+    // order's total price stays within `Config::MaxOrderValue`
+    #[derive(Clone, Encode, Decode, DecodeWithMemTracking, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen, Default)]
+    pub struct OrderLimits<Balance> {
+        pub tick_size: Balance,     // Prices must be a multiple of it; zero allows any
+        pub min_lot: Balance,
+        pub max_lot: Balance,
+        pub min_notional: Balance,  // Of the order's total price
+        pub max_notional: Balance,
+    }
+
+    #[derive(Clone, Encode, Decode, DecodeWithMemTracking, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen, Default)]
+    pub struct FeeDistribution {
+        pub treasury: Percent,
+        pub grid_operator: Percent,
+        pub community: Percent,
+    }
+
+    // Who may trade and how much they can sell; wired to the user registry by the runtime
+    pub trait ParticipantRegistry<AccountId> {
+        fn is_active(who: &AccountId) -> bool;
+        fn can_sell(who: &AccountId) -> bool;
+        // Energy the account can generate in one delivery slot
+        fn generation_capacity(who: &AccountId) -> u128;
+        // Whether the account has an active device of this type
+        fn has_device_type(who: &AccountId, device_type: &DeviceType) -> bool;
+        // Whether the accounts share devices or delegations, so trades between them are not
+        // at arm's length
+        fn are_related(a: &AccountId, b: &AccountId) -> bool;
+        // Reputation score, 100 for a new participant
+        fn reputation(who: &AccountId) -> u32;
+        // Whether the account is an active participant with this role
+        fn has_role(who: &AccountId, role: &UserRole) -> bool;
+    }
+
+    impl<AccountId> ParticipantRegistry<AccountId> for () {
+        fn is_active(_: &AccountId) -> bool {
+            true
+        }
+
+        fn can_sell(_: &AccountId) -> bool {
+            true
+        }
+
+        fn generation_capacity(_: &AccountId) -> u128 {
+            u128::MAX
+        }
+
+        fn has_device_type(_: &AccountId, _: &DeviceType) -> bool {
+            true
+        }
+
+        fn are_related(_: &AccountId, _: &AccountId) -> bool {
+            false
+        }
+
+        fn reputation(_: &AccountId) -> u32 {
+            100
+        }
+
+        fn has_role(_: &AccountId, _: &UserRole) -> bool {
+            true
+        }
+    }
+
+    // Receives the price of every arm's-length market trade, e.g. price discovery statistics
+    pub trait TradeStatistics<Balance> {
+        fn record_trade(market: MarketId, location: &[u8], price_per_unit: Balance, amount: Balance);
+    }
+
+    impl<Balance> TradeStatistics<Balance> for () {
+        fn record_trade(_: MarketId, _: &[u8], _: Balance, _: Balance) {}
+    }
+
+    // Distance between grid locations, e.g. the price discovery location priorities
+    pub trait LocationDistance {
+        // `None` if the locations are not known to be connected
+        fn distance(from: &[u8], to: &[u8]) -> Option<u8>;
+    }
+
+    impl LocationDistance for () {
+        fn distance(_: &[u8], _: &[u8]) -> Option<u8> {
+            None
+        }
+    }
+
+    // Reference prices for settling delivery imbalances, e.g. derived from price discovery
+    pub trait ImbalancePricing<Balance> {
+        // Per-unit price charged for energy a seller failed to deliver
+        fn imbalance_price(location: &[u8]) -> Option<Balance>;
+        // Per-unit price credited for energy delivered beyond the contract
+        fn spill_price(location: &[u8]) -> Option<Balance>;
+    }
+
+    impl<Balance> ImbalancePricing<Balance> for () {
+        fn imbalance_price(_: &[u8]) -> Option<Balance> {
+            None
+        }
+
+        fn spill_price(_: &[u8]) -> Option<Balance> {
+            None
+        }
+    }
+
+    // Lets asset pallets, e.g. battery storage, constrain and follow an account's orders
+    pub trait OrderHooks<AccountId, Balance> {
+        // Called before a new order's hold is taken; an error rejects the order
+        fn on_order_placed(who: &AccountId, order_type: &OrderType, amount: Balance, slot: DeliverySlot) -> DispatchResult;
+        // Called when an order's hold is released without the order trading
+        fn on_order_released(who: &AccountId, order_type: &OrderType, amount: Balance);
+        // Called when a trade settles; `delivered` is the metered part of `contracted`
+        fn on_trade_settled(seller: &AccountId, buyer: &AccountId, contracted: Balance, delivered: Balance);
+    }
+
+    impl<AccountId, Balance> OrderHooks<AccountId, Balance> for () {
+        fn on_order_placed(_: &AccountId, _: &OrderType, _: Balance, _: DeliverySlot) -> DispatchResult {
+            Ok(())
+        }
+
+        fn on_order_released(_: &AccountId, _: &OrderType, _: Balance) {}
+
+        fn on_trade_settled(_: &AccountId, _: &AccountId, _: Balance, _: Balance) {}
+    }
+
+    // Lets benchmarks set up accounts that pass the participant checks
+    #[cfg(feature = "runtime-benchmarks")]
+    pub trait BenchmarkHelper<AccountId> {
+        // Register `who` as a seller with enough capacity for any benchmarked order
+        fn register_participant(who: &AccountId);
+    }
+
+    #[cfg(feature = "runtime-benchmarks")]
+    impl<AccountId> BenchmarkHelper<AccountId> for () {
+        fn register_participant(_: &AccountId) {}
+    }
+
+    pub type IntervalIndex = u32;
+    pub type MarketId = u32;
+
+    // Market of the order calls without a market; it follows the pallet-wide matching mode and
+    // fee schedules
+    pub const DEFAULT_MARKET: MarketId = 0;
+    pub type DeliverySlot = u32;    // Settlement period index since the Unix epoch
+
+    pub type BalanceOf<T> =
+        <<T as Config>::Currency as Currency<<T as frame_system::Config>::AccountId>>::Balance;
+    pub type EnergyBalanceOf<T> = <T as pallet_energy_token::Config>::TokenBalance;
+
+    #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    #[scale_info(skip_type_params(T))]
+    pub struct SealedOrder<T: Config> {
+        pub who: T::AccountId,
+        pub commitment: T::Hash,
+        pub deposit: TokenBalanceOf<T>,
+    }
+
+    #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    #[scale_info(skip_type_params(T))]
+    pub struct AuctionResult<T: Config> {
+        pub clearing_price: TokenBalanceOf<T>,
+        pub cleared_volume: TokenBalanceOf<T>,
+        pub supply: TokenBalanceOf<T>,    // Offered volume at the clearing price
+        pub demand: TokenBalanceOf<T>,    // Requested volume at the clearing price
+        pub cleared_at: BlockNumberFor<T>,
+    }
+
+    // Restrictions a buyer puts on the asks their bid may be matched with; empty lists and
+    // `None` do not restrict
+    #[derive(CloneNoBound, Encode, Decode, DecodeWithMemTracking, PartialEqNoBound, RuntimeDebugNoBound, TypeInfo, MaxEncodedLen)]
+    #[scale_info(skip_type_params(T))]
+    pub struct BidConstraints<T: Config> {
+        pub device_types: BoundedVec<DeviceType, ConstU32<4>>,   // Seller needs one of them
+        pub locations: BoundedVec<BoundedVec<u8, ConstU32<32>>, ConstU32<8>>,
+        pub max_distance: Option<u8>,   // From the bid's location, per `Config::Distances`
+        pub allowed_sellers: BoundedVec<T::AccountId, ConstU32<16>>,
+        pub denied_sellers: BoundedVec<T::AccountId, ConstU32<16>>,
+    }
+
+    // Time-of-use tariff from `start_slot` (slot of the day) up to the next period's start
+    #[derive(Clone, Encode, Decode, DecodeWithMemTracking, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    pub struct TariffPeriod<Balance> {
+        pub start_slot: u32,
+        pub retail_price: Balance,      // Charged for unmatched demand
+        pub feed_in_price: Balance,     // Paid for unmatched surplus
+    }
+
+    #[derive(Clone, Encode, Decode, DecodeWithMemTracking, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    pub enum HaltReason {
+        Operator,
+        CircuitBreaker,
+    }
+
+    #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    pub struct MarketHalt<BlockNumber> {
+        pub reason: HaltReason,
+        pub halted_at: BlockNumber,
+        pub until: Option<BlockNumber>,    // Lifts by itself at this block; `None` until resumed
+    }
+
+    #[derive(CloneNoBound, Encode, Decode, DecodeWithMemTracking, PartialEqNoBound, RuntimeDebugNoBound, TypeInfo, MaxEncodedLen)]
+    #[scale_info(skip_type_params(T))]
+    pub struct MarketConfig<T: Config> {
+        pub matching_mode: MatchingMode,    // Continuous or PeriodicAuction
+        pub allowed_roles: BoundedVec<UserRole, ConstU32<4>>,   // Empty allows every role
+        pub members_only: bool,             // Only accounts in `MarketMembers` may place orders
+        pub fee_schedule: FeeSchedule<TokenBalanceOf<T>>,
+        pub limits: OrderLimits<TokenBalanceOf<T>>,
+    }
+
+    #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen, Default)]
+    pub struct MarketStatistics<Balance> {
+        pub last_price: Balance,
+        pub volume: Balance,
+        pub trades: u32,
+    }
+
+    #[derive(Clone, Encode, Decode, DecodeWithMemTracking, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    pub enum BlockingConstraint {
+        DeviceType,
+        Location,
+        Distance,
+        SellerNotAllowed,
+        SellerDenied,
+    }
+
+    #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    #[scale_info(skip_type_params(T))]
+    pub struct ImbalanceRecord<T: Config> {
+        pub shortfall: TokenBalanceOf<T>,
+        pub excess: TokenBalanceOf<T>,
+        pub imbalance_price: TokenBalanceOf<T>,
+        pub spill_price: TokenBalanceOf<T>,
+        pub penalty_owed: TokenBalanceOf<T>,
+        pub penalty_paid: TokenBalanceOf<T>,  // Limited by the seller's free balance
+        pub compensation: TokenBalanceOf<T>,  // Part of the penalty paid to the buyer
+        pub spill_credit: TokenBalanceOf<T>,
     }
 
     #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
     #[scale_info(skip_type_params(T))]
     pub struct TradeOrder<T: Config> {
         pub order_type: OrderType,
+        pub order_kind: OrderKind<TokenBalanceOf<T>>,
         pub creator: T::AccountId,
         pub counterparty: Option<T::AccountId>,
-        pub energy_amount: T::TokenBalance,
-        pub price_per_unit: T::TokenBalance,
-        pub total_price: T::TokenBalance,
+        pub energy_amount: TokenBalanceOf<T>,
+        pub price_per_unit: TokenBalanceOf<T>,
+        pub total_price: TokenBalanceOf<T>,
         pub status: OrderStatus,
+        pub market: MarketId,
         pub grid_location: BoundedVec<u8, ConstU32<32>>,
+        pub delivery_slot: DeliverySlot,
         pub created_at: BlockNumberFor<T>,
         pub matched_at: Option<BlockNumberFor<T>>,
         pub completed_at: Option<BlockNumberFor<T>>,
         pub transfer_verification: Option<T::Hash>,
+        pub matched_order: Option<T::Hash>,
+        pub delivered_amount: Option<TokenBalanceOf<T>>,
+        pub fee: TokenBalanceOf<T>,   // Fee for the full amount, fixed when matched; open bids hold the most they could pay
     }
 
+    // Trade amounts and prices, as opposed to the energy token's own balance
+    pub type TokenBalanceOf<T> = <T as Config>::TokenBalance;
+
     #[pallet::config]
     pub trait Config: frame_system::Config + pallet_energy_token::Config {
         type RuntimeEvent: From<Event<Self>> + IsType<<Self as frame_system::Config>::RuntimeEvent>;
         type TokenBalance: Member + Parameter + AtLeast32BitUnsigned + Default + Copy + MaxEncodedLen;
-        type Currency: Currency<Self::AccountId>;
+        type Currency: ReservableCurrency<Self::AccountId>;
+        type OperatorOrigin: EnsureOrigin<Self::RuntimeOrigin>;
+        // Creates markets and manages their configuration and members
+        type MarketAdminOrigin: EnsureOrigin<Self::RuntimeOrigin>;
+        type Participants: ParticipantRegistry<Self::AccountId>;
+        type Distances: LocationDistance;
+        type Statistics: TradeStatistics<<Self as Config>::TokenBalance>;
+        // Length of a delivery interval in blocks; auction gate closure happens at each boundary
+        #[pallet::constant]
+        type AuctionInterval: Get<BlockNumberFor<Self>>;
+        #[pallet::constant]
+        type MaxAuctionOrders: Get<u32>;
+        // Orders and commitments one account may have in a single interval's auction
+        #[pallet::constant]
+        type MaxAuctionOrdersPerAccount: Get<u32>;
+        #[pallet::constant]
+        type MaxStopOrders: Get<u32>;
+        type TimeProvider: UnixTime;
+        // Length of a delivery slot in milliseconds, e.g. the DSO's 15-minute settlement period
+        #[pallet::constant]
+        type SlotDuration: Get<u64>;
+        // Closing blocks of each interval in which sealed orders are revealed
+        #[pallet::constant]
+        type RevealPeriod: Get<BlockNumberFor<Self>>;
+        // Reserved per commitment and forfeited to the treasury if it is not revealed
+        #[pallet::constant]
+        type CommitDeposit: Get<<Self as Config>::TokenBalance>;
+        #[pallet::constant]
+        type TreasuryAccount: Get<Self::AccountId>;
+        #[pallet::constant]
+        type CommunityFundAccount: Get<Self::AccountId>;
+        type ImbalancePrices: ImbalancePricing<<Self as Config>::TokenBalance>;
+        // Balancing account that collects shortfall penalties and pays spill credits
+        #[pallet::constant]
+        type ImbalanceAccount: Get<Self::AccountId>;
+        // Orders queued at one price of a (location, slot, side) book
+        #[pallet::constant]
+        type MaxOrdersPerLevel: Get<u32>;
+        // Distinct prices on one (location, slot, side) book
+        #[pallet::constant]
+        type MaxPriceLevels: Get<u32>;
+        // Resting orders a single incoming order can trade against
+        #[pallet::constant]
+        type MaxFillsPerOrder: Get<u32>;
+        // Unmatched orders settled against the retailer in one block after their slot ends
+        #[pallet::constant]
+        type MaxLastResortPerBlock: Get<u32>;
+        // Blocks in each order rate limit window
+        #[pallet::constant]
+        type RateLimitWindow: Get<BlockNumberFor<Self>>;
+        // Orders an account with reputation 100 may submit per window
+        #[pallet::constant]
+        type MaxOrdersPerWindow: Get<u32>;
+        // Upper bound on the total price of any order, so amounts derived from it cannot overflow
+        #[pallet::constant]
+        type MaxOrderValue: Get<<Self as Config>::TokenBalance>;
+        type OrderHooks: OrderHooks<Self::AccountId, <Self as Config>::TokenBalance>;
+        type WeightInfo: WeightInfo;
+        #[cfg(feature = "runtime-benchmarks")]
+        type BenchmarkHelper: BenchmarkHelper<Self::AccountId>;
     }
 
     #[pallet::pallet]
+    #[pallet::storage_version(STORAGE_VERSION)]
     pub struct Pallet<T>(_);
 
     #[pallet::storage]
     #[pallet::getter(fn trade_orders)]
     pub type TradeOrders<T: Config> = StorageMap<_, Blake2_128Concat, T::Hash, TradeOrder<T>, OptionQuery>;
 
+    // Orders of each account in creation order, read a page at a time via `account_orders`
+    #[pallet::storage]
+    #[pallet::getter(fn account_order)]
+    pub type AccountOrders<T: Config> = StorageDoubleMap<
+        _,
+        Blake2_128Concat,
+        T::AccountId,
+        Twox64Concat,
+        u32,
+        T::Hash,
+        OptionQuery,
+    >;
+
+    #[pallet::storage]
+    #[pallet::getter(fn account_order_count)]
+    pub type AccountOrderCount<T: Config> = StorageMap<_, Blake2_128Concat, T::AccountId, u32, ValueQuery>;
+
+    #[pallet::storage]
+    pub type OrdersByStatus<T: Config> = StorageDoubleMap<
+        _,
+        Twox64Concat,
+        OrderStatus,
+        Blake2_128Concat,
+        T::Hash,
+        (),
+        OptionQuery,
+    >;
+
+    // Open orders queued in time priority per (market, location, slot, side, price)
+    #[pallet::storage]
+    #[pallet::getter(fn order_book)]
+    pub type OrderBook<T: Config> = StorageNMap<
+        _,
+        (
+            NMapKey<Twox64Concat, MarketId>,
+            NMapKey<Blake2_128Concat, BoundedVec<u8, ConstU32<32>>>,  // location
+            NMapKey<Twox64Concat, DeliverySlot>,
+            NMapKey<Twox64Concat, OrderType>,
+            NMapKey<Twox64Concat, TokenBalanceOf<T>>,
+        ),
+        BoundedVec<T::Hash, T::MaxOrdersPerLevel>,
+        ValueQuery,
+    >;
+
+    // Prices with open orders per (market, location, slot, side), ascending
+    #[pallet::storage]
+    #[pallet::getter(fn price_levels)]
+    pub type PriceLevels<T: Config> = StorageNMap<
+        _,
+        (
+            NMapKey<Twox64Concat, MarketId>,
+            NMapKey<Blake2_128Concat, BoundedVec<u8, ConstU32<32>>>,  // location
+            NMapKey<Twox64Concat, DeliverySlot>,
+            NMapKey<Twox64Concat, OrderType>,
+        ),
+        BoundedVec<TokenBalanceOf<T>, T::MaxPriceLevels>,
+        ValueQuery,
+    >;
+
+    // Ask volume each seller has open or sold per delivery slot
+    #[pallet::storage]
+    #[pallet::getter(fn slot_ask_volume)]
+    pub type SlotAskVolume<T: Config> = StorageDoubleMap<
+        _,
+        Blake2_128Concat,
+        T::AccountId,
+        Twox64Concat,
+        DeliverySlot,
+        TokenBalanceOf<T>,
+        ValueQuery,
+    >;
+
+    #[pallet::storage]
+    #[pallet::getter(fn stop_orders)]
+    pub type StopOrders<T: Config> = StorageMap<
+        _,
+        Blake2_128Concat,
+        BoundedVec<u8, ConstU32<32>>,  // location
+        BoundedVec<T::Hash, T::MaxStopOrders>,
+        ValueQuery,
+    >;
+
+    #[pallet::storage]
+    #[pallet::getter(fn matching_mode)]
+    pub type CurrentMatchingMode<T: Config> = StorageValue<_, MatchingMode, ValueQuery>;
+
+    #[pallet::storage]
+    #[pallet::getter(fn auction_book)]
+    pub type AuctionBook<T: Config> = StorageMap<
+        _,
+        Twox64Concat,
+        IntervalIndex,
+        BoundedVec<T::Hash, T::MaxAuctionOrders>,
+        ValueQuery,
+    >;
+
+    #[pallet::storage]
+    #[pallet::getter(fn auction_entries)]
+    pub type AuctionEntries<T: Config> = StorageDoubleMap<
+        _,
+        Twox64Concat,
+        IntervalIndex,
+        Blake2_128Concat,
+        T::AccountId,
+        u32,
+        ValueQuery,
+    >;
+
+    #[pallet::storage]
+    #[pallet::getter(fn commitments)]
+    pub type Commitments<T: Config> = StorageMap<
+        _,
+        Twox64Concat,
+        IntervalIndex,
+        BoundedVec<SealedOrder<T>, T::MaxAuctionOrders>,
+        ValueQuery,
+    >;
+
+    #[pallet::storage]
+    #[pallet::getter(fn auction_results)]
+    pub type AuctionResults<T: Config> = StorageNMap<
+        _,
+        (
+            NMapKey<Twox64Concat, MarketId>,
+            NMapKey<Twox64Concat, IntervalIndex>,
+            NMapKey<Twox64Concat, DeliverySlot>,
+        ),
+        AuctionResult<T>,
+        OptionQuery,
+    >;
+
+    #[pallet::storage]
+    #[pallet::getter(fn default_fee_schedule)]
+    pub type DefaultFeeSchedule<T: Config> = StorageValue<_, FeeSchedule<TokenBalanceOf<T>>, ValueQuery>;
+
+    #[pallet::storage]
+    #[pallet::getter(fn location_fee_schedules)]
+    pub type LocationFeeSchedules<T: Config> = StorageMap<
+        _,
+        Blake2_128Concat,
+        BoundedVec<u8, ConstU32<32>>,  // location
+        FeeSchedule<TokenBalanceOf<T>>,
+        OptionQuery,
+    >;
+
+    #[pallet::storage]
+    #[pallet::getter(fn default_order_limits)]
+    pub type DefaultOrderLimits<T: Config> = StorageValue<_, OrderLimits<TokenBalanceOf<T>>, ValueQuery>;
+
+    #[pallet::storage]
+    #[pallet::getter(fn location_order_limits)]
+    pub type LocationOrderLimits<T: Config> = StorageMap<
+        _,
+        Blake2_128Concat,
+        BoundedVec<u8, ConstU32<32>>,  // location
+        OrderLimits<TokenBalanceOf<T>>,
+        OptionQuery,
+    >;
+
+    #[pallet::storage]
+    #[pallet::getter(fn fee_distribution)]
+    pub type CurrentFeeDistribution<T: Config> = StorageValue<_, FeeDistribution, ValueQuery>;
+
+    #[pallet::storage]
+    #[pallet::getter(fn grid_operator_account)]
+    pub type GridOperatorAccounts<T: Config> = StorageMap<
+        _,
+        Blake2_128Concat,
+        BoundedVec<u8, ConstU32<32>>,  // location
+        T::AccountId,
+        OptionQuery,
+    >;
+
+    #[pallet::storage]
+    #[pallet::getter(fn imbalances)]
+    pub type Imbalances<T: Config> = StorageMap<_, Blake2_128Concat, T::Hash, ImbalanceRecord<T>, OptionQuery>;
+
+    // Supplier of last resort for orders still open when their delivery slot ends
+    #[pallet::storage]
+    #[pallet::getter(fn retailer)]
+    pub type RetailerAccount<T: Config> = StorageValue<_, T::AccountId, OptionQuery>;
+
+    // Sorted by start slot, the first period starting at slot 0 of the day
+    #[pallet::storage]
+    #[pallet::getter(fn tariffs)]
+    pub type TimeOfUseTariffs<T: Config> = StorageValue<
+        _,
+        BoundedVec<TariffPeriod<TokenBalanceOf<T>>, ConstU32<24>>,
+        ValueQuery,
+    >;
+
+    // Markets and locations with a book for the slot, until the slot has been closed
+    #[pallet::storage]
+    pub type BookLocations<T: Config> = StorageDoubleMap<
+        _,
+        Twox64Concat,
+        DeliverySlot,
+        Blake2_128Concat,
+        (MarketId, BoundedVec<u8, ConstU32<32>>),  // market, location
+        (),
+        OptionQuery,
+    >;
+
+    // First delivery slot whose unmatched orders have not been settled yet
+    #[pallet::storage]
+    #[pallet::getter(fn next_slot_to_close)]
+    pub type NextSlotToClose<T: Config> = StorageValue<_, DeliverySlot, OptionQuery>;
+
+    #[pallet::storage]
+    #[pallet::getter(fn self_trade_prevention)]
+    pub type SelfTradePreventionMode<T: Config> = StorageValue<_, SelfTradePrevention, ValueQuery>;
+
+    // Trades between related accounts, keyed by ask id; kept out of price statistics
     #[pallet::storage]
-    #[pallet::getter(fn user_orders)]
-    pub type UserOrders<T: Config> = StorageMap<
+    #[pallet::getter(fn wash_trades)]
+    pub type WashTrades<T: Config> = StorageMap<_, Blake2_128Concat, T::Hash, T::Hash, OptionQuery>;
+
+    #[pallet::storage]
+    #[pallet::getter(fn global_halt)]
+    pub type GlobalHalt<T: Config> = StorageValue<_, bool, ValueQuery>;
+
+    #[pallet::storage]
+    #[pallet::getter(fn location_halt)]
+    pub type LocationHalts<T: Config> = StorageMap<
+        _,
+        Blake2_128Concat,
+        BoundedVec<u8, ConstU32<32>>,  // location
+        MarketHalt<BlockNumberFor<T>>,
+        OptionQuery,
+    >;
+
+    // Start of the account's current rate limit window and the orders submitted in it
+    #[pallet::storage]
+    #[pallet::getter(fn order_rate)]
+    pub type OrderRates<T: Config> = StorageMap<
+        _,
+        Blake2_128Concat,
+        T::AccountId,
+        (BlockNumberFor<T>, u32),
+        OptionQuery,
+    >;
+
+    // Markets other than `DEFAULT_MARKET`
+    #[pallet::storage]
+    #[pallet::getter(fn markets)]
+    pub type Markets<T: Config> = StorageMap<_, Twox64Concat, MarketId, MarketConfig<T>, OptionQuery>;
+
+    #[pallet::storage]
+    #[pallet::getter(fn market_count)]
+    pub type MarketCount<T: Config> = StorageValue<_, MarketId, ValueQuery>;
+
+    #[pallet::storage]
+    #[pallet::getter(fn market_member)]
+    pub type MarketMembers<T: Config> = StorageDoubleMap<
         _,
+        Twox64Concat,
+        MarketId,
         Blake2_128Concat,
         T::AccountId,
-        BoundedVec<T::Hash, ConstU32<100>>,
+        (),
+        OptionQuery,
+    >;
+
+    // Arm's-length trades per market and location
+    #[pallet::storage]
+    #[pallet::getter(fn market_statistics)]
+    pub type MarketStats<T: Config> = StorageDoubleMap<
+        _,
+        Twox64Concat,
+        MarketId,
+        Blake2_128Concat,
+        BoundedVec<u8, ConstU32<32>>,  // location
+        MarketStatistics<TokenBalanceOf<T>>,
         ValueQuery,
     >;
 
+    // Bids without an entry accept any ask
+    #[pallet::storage]
+    #[pallet::getter(fn bid_constraints)]
+    pub type BidConstraintsOf<T: Config> = StorageMap<_, Blake2_128Concat, T::Hash, BidConstraints<T>, OptionQuery>;
+
     #[pallet::event]
     #[pallet::generate_deposit(pub(super) fn deposit_event)]
     pub enum Event<T: Config> {
         AskOrderCreated {
             order_id: T::Hash,
             seller: T::AccountId,
-            amount: T::TokenBalance,
-            price: T::TokenBalance,
+            amount: TokenBalanceOf<T>,
+            price: TokenBalanceOf<T>,
             location: Vec<u8>,
         },
         BidOrderCreated {
             order_id: T::Hash,
             buyer: T::AccountId,
-            amount: T::TokenBalance,
-            price: T::TokenBalance,
+            amount: TokenBalanceOf<T>,
+            price: TokenBalanceOf<T>,
             location: Vec<u8>,
         },
         OrdersMatched {
@@ -90,8 +729,8 @@ pub mod pallet {
             bid_id: T::Hash,
             seller: T::AccountId,
             buyer: T::AccountId,
-            amount: T::TokenBalance,
-            price: T::TokenBalance,
+            amount: TokenBalanceOf<T>,
+            price: TokenBalanceOf<T>,
         },
         TransferVerified {
             order_id: T::Hash,
@@ -101,206 +740,272 @@ pub mod pallet {
             order_id: T::Hash,
             seller: T::AccountId,
             buyer: T::AccountId,
-            amount: T::TokenBalance,
-            price: T::TokenBalance,
+            amount: TokenBalanceOf<T>,
+            price: TokenBalanceOf<T>,
+            seller_fee: TokenBalanceOf<T>,
+            buyer_fee: TokenBalanceOf<T>,
         },
         OrderFailed {
             order_id: T::Hash,
             reason: Vec<u8>,
         },
-    }
-
-    #[pallet::error]
-    pub enum Error<T> {
-        OrderNotFound,
-        InvalidOrderStatus,
-        InsufficientBalance,
-        UnauthorizedAccess,
-        InvalidAmount,
-        InvalidPrice,
-        OrderMismatch,
-        TransferVerificationFailed,
-        PaymentFailed,
-        InvalidLocation,
-        TooManyOrders,
-    }
-
-    #[pallet::call]
-    impl<T: Config> Pallet<T> {
-        #[pallet::call_index(0)]
-        #[pallet::weight(10_000)]
-        pub fn create_ask_order(
-            origin: OriginFor<T>,
-            energy_amount: T::TokenBalance,
-            price_per_unit: T::TokenBalance,
-            grid_location: Vec<u8>,
-        ) -> DispatchResult {
-            let seller = ensure_signed(origin)?;
-            ensure!(!energy_amount.is_zero(), Error::<T>::InvalidAmount);
-            ensure!(!price_per_unit.is_zero(), Error::<T>::InvalidPrice);
-
-            let total_price = price_per_unit
-                .checked_mul(&energy_amount)
-                .ok_or(Error::<T>::InvalidPrice)?;
-
-            let bounded_location: BoundedVec<u8, ConstU32<32>> = grid_location.try_into()
-                .map_err(|_| Error::<T>::InvalidLocation)?;
-
-            let order = TradeOrder {
-                order_type: OrderType::Ask,
-                creator: seller.clone(),
-                counterparty: None,
-                energy_amount,
-                price_per_unit,
-                total_price,
-                status: OrderStatus::Open,
-                grid_location: bounded_location,
-                created_at: <frame_system::Pallet<T>>::block_number(),
-                matched_at: None,
-                completed_at: None,
-                transfer_verification: None,
-            };
+        OrderCancelled {
+            order_id: T::Hash,
+        },
+        StopOrderTriggered {
+            order_id: T::Hash,
+            reference_price: TokenBalanceOf<T>,
+        },
+        MatchingModeChanged {
+            mode: MatchingMode,
+        },
+        OrderCommitted {
+            interval: IntervalIndex,
+            who: T::AccountId,
+            commitment: T::Hash,
+        },
+        OrderRevealed {
+            commitment: T::Hash,
+            order_id: T::Hash,
+        },
+        CommitmentForfeited {
+            interval: IntervalIndex,
+            who: T::AccountId,
+            commitment: T::Hash,
+            deposit: TokenBalanceOf<T>,
+        },
+        FeeScheduleUpdated {
+            location: Option<Vec<u8>>,
+        },
+        OrderLimitsUpdated {
+            location: Option<Vec<u8>>,
+        },
+        FeeDistributionUpdated {
+            distribution: FeeDistribution,
+        },
+        GridOperatorAccountSet {
+            location: Vec<u8>,
+            account: Option<T::AccountId>,
+        },
+        AuctionCleared {
+            market: MarketId,
+            interval: IntervalIndex,
+            delivery_slot: DeliverySlot,
+            clearing_price: TokenBalanceOf<T>,
+            volume: TokenBalanceOf<T>,
+        },
+        AuctionOrderExpired {
+            order_id: T::Hash,
+            interval: IntervalIndex,
+        },
+        ImbalanceSettled {
+            order_id: T::Hash,
+            shortfall: TokenBalanceOf<T>,
+            excess: TokenBalanceOf<T>,
+            penalty: TokenBalanceOf<T>,
+            compensation: TokenBalanceOf<T>,
+            spill_credit: TokenBalanceOf<T>,
+        },
+        MatchBlocked {
+            ask_id: T::Hash,
+            bid_id: T::Hash,
+            constraint: BlockingConstraint,
+        },
+        GlobalHaltSet {
+            halted: bool,
+        },
+        MarketHalted {
+            location: Vec<u8>,
+            reason: HaltReason,
+            until: Option<BlockNumberFor<T>>,
+        },
+        MarketResumed {
+            location: Vec<u8>,
+        },
+        CommitmentReleased {
+            interval: IntervalIndex,
+            who: T::AccountId,
+            commitment: T::Hash,
+        },
+        RetailerSet {
+            account: Option<T::AccountId>,
+        },
+        TariffsUpdated {
+            periods: u32,
+        },
+        LastResortSettled {
+            order_id: T::Hash,
+            retailer_order_id: T::Hash,
+            amount: TokenBalanceOf<T>,
+            price: TokenBalanceOf<T>,
+        },
+        SelfTradePreventionSet {
+            mode: SelfTradePrevention,
+        },
+        MarketCreated {
+            market: MarketId,
+        },
+        MarketUpdated {
+            market: MarketId,
+        },
+        MarketMemberAdded {
+            market: MarketId,
+            who: T::AccountId,
+        },
+        MarketMemberRemoved {
+            market: MarketId,
+            who: T::AccountId,
+        },
+        SelfTradePrevented {
+            ask_id: T::Hash,
+            bid_id: T::Hash,
+            mode: SelfTradePrevention,
+        },
+        WashTradeFlagged {
+            ask_id: T::Hash,
+            bid_id: T::Hash,
+            seller: T::AccountId,
+            buyer: T::AccountId,
+        },
+    }
 
-            let order_id = T::Hashing::hash_of(&order);
-            <TradeOrders<T>>::insert(order_id, order);
-            
-            UserOrders::<T>::try_mutate(&seller, |orders| -> DispatchResult {
-                orders.try_push(order_id).map_err(|_| Error::<T>::TooManyOrders)?;
-                Ok(())
-            })?;
+    #[pallet::error]
+    pub enum Error<T> {
+        OrderNotFound,
+        InvalidOrderStatus,
+        InsufficientBalance,
+        UnauthorizedAccess,
+        InvalidAmount,
+        InvalidPrice,
+        OrderMismatch,
+        TransferVerificationFailed,
+        PaymentFailed,
+        InvalidLocation,
+        TooManyOrders,
+        AuctionModeActive,
+        AuctionBookFull,
+        TooManyAuctionOrders,
+        UnsupportedOrderKind,
+        NoLiquidity,
+        WouldCrossBook,
+        FillOrKillNotFilled,
+        TooManyStopOrders,
+        DeliverySlotPassed,
+        SlotMismatch,
+        InvalidFeeSchedule,
+        InvalidFeeDistribution,
+        NotRegistered,
+        NotAllowedToSell,
+        ExceedsGenerationCapacity,
+        SealedBidModeActive,
+        NotSealedBidMode,
+        CommitPhaseClosed,
+        RevealPhaseNotOpen,
+        CommitmentNotFound,
+        DuplicateCommitment,
+        PriceLevelFull,
+        TooManyPriceLevels,
+        BidConstraintViolated,
+        MarketHalted,
+        MarketNotHalted,
+        InvalidTariffs,
+        NoRetailer,
+        SelfTrade,
+        MarketNotFound,
+        MarketMismatch,
+        NotMarketMember,
+        AlreadyMarketMember,
+        RoleNotAllowed,
+        InvalidTickSize,
+        BelowMinimumLot,
+        UnsupportedMatchingMode,
+        AboveMaximumLot,
+        BelowMinimumNotional,
+        AboveMaximumNotional,
+        InvalidOrderLimits,
+    }
 
-            Self::deposit_event(Event::AskOrderCreated {
-                order_id,
-                seller,
-                amount: energy_amount,
-                price: total_price,
-                location: grid_location,
-            });
+    #[pallet::hooks]
+    impl<T: Config> Hooks<BlockNumberFor<T>> for Pallet<T> {
+        fn on_initialize(n: BlockNumberFor<T>) -> Weight {
+            let interval = T::AuctionInterval::get();
+            if interval.is_zero() || !(n % interval).is_zero() {
+                return Self::close_slots(T::MaxLastResortPerBlock::get());
+            }
+
+            // Gate closure: clear the book collected during the interval that just ended
+            let auction_weight = match Self::interval_index(n).checked_sub(1) {
+                Some(closed) => Self::clear_auction(closed),
+                None => Weight::zero(),
+            };
+            auction_weight.saturating_add(Self::close_slots(T::MaxLastResortPerBlock::get()))
+        }
 
+        // Slots are closed from the first slot seen once the block's timestamp is set, so a
+        // chain whose clock starts late does not walk every slot since the epoch
+        fn on_finalize(_n: BlockNumberFor<T>) {
+            if Self::next_slot_to_close().is_none() {
+                NextSlotToClose::<T>::put(Self::current_slot());
+            }
+        }
+    }
+
+    #[pallet::call]
+    impl<T: Config> Pallet<T> {
+        #[pallet::call_index(0)]
+        #[pallet::weight(T::WeightInfo::create_ask_order())]
+        pub fn create_ask_order(
+            origin: OriginFor<T>,
+            energy_amount: TokenBalanceOf<T>,
+            price_per_unit: TokenBalanceOf<T>,
+            grid_location: Vec<u8>,
+            delivery_slot: DeliverySlot,
+        ) -> DispatchResult {
+            let seller = ensure_signed(origin)?;
+            ensure!(Self::matching_mode() != MatchingMode::SealedBid, Error::<T>::SealedBidModeActive);
+            Self::ensure_order_limits(DEFAULT_MARKET, &grid_location, energy_amount, price_per_unit)?;
+            Self::do_create_order(DEFAULT_MARKET, seller, OrderType::Ask, OrderKind::Limit, energy_amount, price_per_unit, grid_location, delivery_slot)?;
             Ok(())
         }
 
         #[pallet::call_index(1)]
-        #[pallet::weight(10_000)]
+        #[pallet::weight(T::WeightInfo::create_bid_order())]
         pub fn create_bid_order(
             origin: OriginFor<T>,
-            energy_amount: T::TokenBalance,
-            price_per_unit: T::TokenBalance,
+            energy_amount: TokenBalanceOf<T>,
+            price_per_unit: TokenBalanceOf<T>,
             grid_location: Vec<u8>,
+            delivery_slot: DeliverySlot,
         ) -> DispatchResult {
             let buyer = ensure_signed(origin)?;
-            ensure!(!energy_amount.is_zero(), Error::<T>::InvalidAmount);
-            ensure!(!price_per_unit.is_zero(), Error::<T>::InvalidPrice);
-
-            let total_price = price_per_unit
-                .checked_mul(&energy_amount)
-                .ok_or(Error::<T>::InvalidPrice)?;
-
-            let bounded_location: BoundedVec<u8, ConstU32<32>> = grid_location.try_into()
-                .map_err(|_| Error::<T>::InvalidLocation)?;
-
-            let order = TradeOrder {
-                order_type: OrderType::Bid,
-                creator: buyer.clone(),
-                counterparty: None,
-                energy_amount,
-                price_per_unit,
-                total_price,
-                status: OrderStatus::Open,
-                grid_location: bounded_location,
-                created_at: <frame_system::Pallet<T>>::block_number(),
-                matched_at: None,
-                completed_at: None,
-                transfer_verification: None,
-            };
-
-            let order_id = T::Hashing::hash_of(&order);
-            <TradeOrders<T>>::insert(order_id, order);
-            
-            UserOrders::<T>::try_mutate(&buyer, |orders| -> DispatchResult {
-                orders.try_push(order_id).map_err(|_| Error::<T>::TooManyOrders)?;
-                Ok(())
-            })?;
-
-            Self::deposit_event(Event::BidOrderCreated {
-                order_id,
-                buyer,
-                amount: energy_amount,
-                price: total_price,
-                location: grid_location,
-            });
-
+            ensure!(Self::matching_mode() != MatchingMode::SealedBid, Error::<T>::SealedBidModeActive);
+            Self::ensure_order_limits(DEFAULT_MARKET, &grid_location, energy_amount, price_per_unit)?;
+            Self::do_create_order(DEFAULT_MARKET, buyer, OrderType::Bid, OrderKind::Limit, energy_amount, price_per_unit, grid_location, delivery_slot)?;
             Ok(())
         }
 
         #[pallet::call_index(2)]
-        #[pallet::weight(10_000)]
+        #[pallet::weight(T::WeightInfo::match_orders())]
         pub fn match_orders(
             origin: OriginFor<T>,
             ask_id: T::Hash,
             bid_id: T::Hash,
         ) -> DispatchResult {
             let _ = ensure_signed(origin)?;
-
-            let mut ask_order = TradeOrders::<T>::get(ask_id).ok_or(Error::<T>::OrderNotFound)?;
-            let mut bid_order = TradeOrders::<T>::get(bid_id).ok_or(Error::<T>::OrderNotFound)?;
-
-            ensure!(ask_order.status == OrderStatus::Open, Error::<T>::InvalidOrderStatus);
-            ensure!(bid_order.status == OrderStatus::Open, Error::<T>::InvalidOrderStatus);
-            ensure!(ask_order.energy_amount == bid_order.energy_amount, Error::<T>::OrderMismatch);
-            ensure!(ask_order.price_per_unit <= bid_order.price_per_unit, Error::<T>::OrderMismatch);
-
-            let current_block = <frame_system::Pallet<T>>::block_number();
-            
-            // Update orders
-            ask_order.status = OrderStatus::Matched;
-            ask_order.counterparty = Some(bid_order.creator.clone());
-            ask_order.matched_at = Some(current_block);
-
-            bid_order.status = OrderStatus::Matched;
-            bid_order.counterparty = Some(ask_order.creator.clone());
-            bid_order.matched_at = Some(current_block);
-
-            <TradeOrders<T>>::insert(ask_id, ask_order.clone());
-            <TradeOrders<T>>::insert(bid_id, bid_order.clone());
-
-            Self::deposit_event(Event::OrdersMatched {
-                ask_id,
-                bid_id,
-                seller: ask_order.creator,
-                buyer: bid_order.creator,
-                amount: ask_order.energy_amount,
-                price: ask_order.total_price,
-            });
-
-            Ok(())
+            Self::do_match_orders(ask_id, bid_id)
         }
 
+        // The delivered amount decides the settlement, so only the operator may attest it
         #[pallet::call_index(3)]
         #[pallet::weight(10_000)]
         pub fn verify_transfer(
             origin: OriginFor<T>,
             order_id: T::Hash,
             verification_data: Vec<u8>,
+            delivered_amount: TokenBalanceOf<T>,
         ) -> DispatchResult {
-            let _ = ensure_signed(origin)?;
-
-            TradeOrders::<T>::try_mutate(order_id, |order| -> DispatchResult {
-                let order = order.as_mut().ok_or(Error::<T>::OrderNotFound)?;
-                ensure!(order.status == OrderStatus::Matched, Error::<T>::InvalidOrderStatus);
-
-                // Verify the transfer using IoT data
-                let verification_hash = T::Hashing::hash_of(&verification_data);
-                order.transfer_verification = Some(verification_hash);
-                order.status = OrderStatus::InTransfer;
-
-                Self::deposit_event(Event::TransferVerified {
-                    order_id,
-                    verification_hash,
-                });
-
-                Ok(())
-            })
+            T::OperatorOrigin::ensure_origin(origin)?;
+            Self::do_verify_transfer(order_id, verification_data, delivered_amount)
         }
 
         #[pallet::call_index(4)]
@@ -316,31 +1021,2173 @@ pub mod pallet {
                 ensure!(order.status == OrderStatus::InTransfer, Error::<T>::InvalidOrderStatus);
                 ensure!(order.transfer_verification.is_some(), Error::<T>::TransferVerificationFailed);
 
+                ensure!(order.order_type == OrderType::Ask, Error::<T>::OrderMismatch);
+
                 let seller = order.creator.clone();
                 let buyer = order.counterparty.clone().ok_or(Error::<T>::OrderMismatch)?;
 
-                // Transfer payment
-                T::Currency::transfer(
+                // Settle only what the meters verified; the rest of both holds goes back
+                let delivered = order.delivered_amount
+                    .ok_or(Error::<T>::TransferVerificationFailed)?
+                    .min(order.energy_amount);
+                let payment = Self::pro_rata(order.total_price, delivered, order.energy_amount);
+
+                // Fees were fixed at matching for the full amount and scale with delivery
+                let bid_fee = order.matched_order
+                    .and_then(|bid_id| Self::trade_orders(bid_id))
+                    .map(|bid| bid.fee)
+                    .unwrap_or_default();
+                let seller_fee = Self::pro_rata(order.fee, delivered, order.energy_amount).min(payment);
+                let buyer_fee = Self::pro_rata(bid_fee, delivered, order.energy_amount);
+
+                // Delivery versus payment: energy and funds move out of the holds together
+                pallet_energy_token::Pallet::<T>::repatriate_reserved(
+                    &seller,
+                    &buyer,
+                    Self::to_energy(delivered),
+                )?;
+                pallet_energy_token::Pallet::<T>::unreserve(
+                    &seller,
+                    Self::to_energy(order.energy_amount.saturating_sub(delivered)),
+                );
+
+                let unpaid = T::Currency::repatriate_reserved(
                     &buyer,
                     &seller,
-                    order.total_price,
-                    frame_support::traits::ExistenceRequirement::KeepAlive,
+                    Self::to_currency(payment.saturating_sub(seller_fee)),
+                    BalanceStatus::Free,
                 )?;
+                ensure!(unpaid.is_zero(), Error::<T>::PaymentFailed);
+                Self::distribute_fees(&buyer, &order.grid_location, seller_fee.saturating_add(buyer_fee))?;
+                T::Currency::unreserve(
+                    &buyer,
+                    Self::to_currency(
+                        order.total_price
+                            .saturating_sub(payment)
+                            .saturating_add(bid_fee.saturating_sub(buyer_fee)),
+                    ),
+                );
+                Self::settle_imbalance(order_id, order, &buyer)?;
+                T::OrderHooks::on_trade_settled(&seller, &buyer, order.energy_amount, delivered);
 
                 // Update order status
-                order.status = OrderStatus::Completed;
-                order.completed_at = Some(<frame_system::Pallet<T>>::block_number());
+                let completed_at = <frame_system::Pallet<T>>::block_number();
+                Self::set_status(&order_id, order, OrderStatus::Completed);
+                order.completed_at = Some(completed_at);
+                Self::close_matched_order(order.matched_order, OrderStatus::Completed, Some(completed_at));
 
                 Self::deposit_event(Event::OrderCompleted {
                     order_id,
                     seller,
                     buyer,
-                    amount: order.energy_amount,
-                    price: order.total_price,
+                    amount: delivered,
+                    price: payment,
+                    seller_fee,
+                    buyer_fee,
                 });
 
                 Ok(())
             })
         }
+
+        #[pallet::call_index(5)]
+        #[pallet::weight(10_000)]
+        pub fn set_matching_mode(
+            origin: OriginFor<T>,
+            mode: MatchingMode,
+        ) -> DispatchResult {
+            T::OperatorOrigin::ensure_origin(origin)?;
+
+            CurrentMatchingMode::<T>::put(mode);
+            Self::deposit_event(Event::MatchingModeChanged { mode });
+
+            Ok(())
+        }
+
+        #[pallet::call_index(6)]
+        #[pallet::weight(T::WeightInfo::cancel_order())]
+        pub fn cancel_order(
+            origin: OriginFor<T>,
+            order_id: T::Hash,
+        ) -> DispatchResult {
+            let who = ensure_signed(origin)?;
+
+            TradeOrders::<T>::try_mutate(order_id, |order| -> DispatchResult {
+                let order = order.as_mut().ok_or(Error::<T>::OrderNotFound)?;
+                ensure!(order.creator == who, Error::<T>::UnauthorizedAccess);
+                ensure!(
+                    matches!(order.status, OrderStatus::Open | OrderStatus::Pending),
+                    Error::<T>::InvalidOrderStatus
+                );
+
+                Self::release_hold(order);
+                Self::set_status(&order_id, order, OrderStatus::Cancelled);
+
+                Self::deposit_event(Event::OrderCancelled { order_id });
+
+                Ok(())
+            })
+        }
+
+        #[pallet::call_index(7)]
+        #[pallet::weight(T::WeightInfo::create_order(T::MaxFillsPerOrder::get()))]
+        pub fn create_order(
+            origin: OriginFor<T>,
+            order_type: OrderType,
+            order_kind: OrderKind<TokenBalanceOf<T>>,
+            energy_amount: TokenBalanceOf<T>,
+            price_per_unit: TokenBalanceOf<T>,
+            grid_location: Vec<u8>,
+            delivery_slot: DeliverySlot,
+        ) -> DispatchResult {
+            let who = ensure_signed(origin)?;
+            Self::do_place_order(who, order_type, order_kind, energy_amount, price_per_unit, grid_location, delivery_slot)?;
+            Ok(())
+        }
+
+        // Sets the default schedule, or a location override when `location` is given;
+        // `None` as schedule removes the override
+        #[pallet::call_index(8)]
+        #[pallet::weight(10_000)]
+        pub fn set_fee_schedule(
+            origin: OriginFor<T>,
+            location: Option<Vec<u8>>,
+            schedule: Option<FeeSchedule<TokenBalanceOf<T>>>,
+        ) -> DispatchResult {
+            T::OperatorOrigin::ensure_origin(origin)?;
+
+            if let Some(schedule) = &schedule {
+                ensure!(
+                    schedule.maker_fee_bps <= 10_000 && schedule.taker_fee_bps <= 10_000,
+                    Error::<T>::InvalidFeeSchedule
+                );
+            }
+
+            match &location {
+                Some(location) => {
+                    let bounded_location: BoundedVec<u8, ConstU32<32>> = location.clone().try_into()
+                        .map_err(|_| Error::<T>::InvalidLocation)?;
+                    LocationFeeSchedules::<T>::set(bounded_location, schedule);
+                },
+                None => DefaultFeeSchedule::<T>::put(schedule.unwrap_or_default()),
+            }
+
+            Self::deposit_event(Event::FeeScheduleUpdated { location });
+
+            Ok(())
+        }
+
+        // Sets the default limits of the default market, or a location override when
+        // `location` is given; `None` as limits removes the override
+        #[pallet::call_index(25)]
+        #[pallet::weight(10_000)]
+        pub fn set_order_limits(
+            origin: OriginFor<T>,
+            location: Option<Vec<u8>>,
+            limits: Option<OrderLimits<TokenBalanceOf<T>>>,
+        ) -> DispatchResult {
+            T::OperatorOrigin::ensure_origin(origin)?;
+
+            if let Some(limits) = &limits {
+                Self::ensure_valid_order_limits(limits)?;
+            }
+
+            match &location {
+                Some(location) => {
+                    let bounded_location: BoundedVec<u8, ConstU32<32>> = location.clone().try_into()
+                        .map_err(|_| Error::<T>::InvalidLocation)?;
+                    LocationOrderLimits::<T>::set(bounded_location, limits);
+                },
+                None => DefaultOrderLimits::<T>::put(limits.unwrap_or_default()),
+            }
+
+            Self::deposit_event(Event::OrderLimitsUpdated { location });
+
+            Ok(())
+        }
+
+        #[pallet::call_index(9)]
+        #[pallet::weight(10_000)]
+        pub fn set_fee_distribution(
+            origin: OriginFor<T>,
+            distribution: FeeDistribution,
+        ) -> DispatchResult {
+            T::OperatorOrigin::ensure_origin(origin)?;
+
+            let total = (distribution.treasury.deconstruct() as u32)
+                .saturating_add(distribution.grid_operator.deconstruct() as u32)
+                .saturating_add(distribution.community.deconstruct() as u32);
+            ensure!(total == 100, Error::<T>::InvalidFeeDistribution);
+
+            CurrentFeeDistribution::<T>::put(distribution.clone());
+            Self::deposit_event(Event::FeeDistributionUpdated { distribution });
+
+            Ok(())
+        }
+
+        #[pallet::call_index(10)]
+        #[pallet::weight(10_000)]
+        pub fn set_grid_operator_account(
+            origin: OriginFor<T>,
+            location: Vec<u8>,
+            account: Option<T::AccountId>,
+        ) -> DispatchResult {
+            T::OperatorOrigin::ensure_origin(origin)?;
+
+            let bounded_location: BoundedVec<u8, ConstU32<32>> = location.clone().try_into()
+                .map_err(|_| Error::<T>::InvalidLocation)?;
+            GridOperatorAccounts::<T>::set(bounded_location, account.clone());
+
+            Self::deposit_event(Event::GridOperatorAccountSet { location, account });
+
+            Ok(())
+        }
+
+        // `commitment` is `sealed_commitment` of the order to be revealed later
+        #[pallet::call_index(11)]
+        #[pallet::weight(10_000)]
+        pub fn commit_order(
+            origin: OriginFor<T>,
+            commitment: T::Hash,
+        ) -> DispatchResult {
+            let who = ensure_signed(origin)?;
+            ensure!(Self::matching_mode() == MatchingMode::SealedBid, Error::<T>::NotSealedBidMode);
+            ensure!(!Self::global_halt(), Error::<T>::MarketHalted);
+
+            let current_block = <frame_system::Pallet<T>>::block_number();
+            ensure!(!Self::in_reveal_phase(current_block), Error::<T>::CommitPhaseClosed);
+
+            let interval = Self::interval_index(current_block);
+            let deposit = T::CommitDeposit::get();
+            Commitments::<T>::try_mutate(interval, |sealed| -> DispatchResult {
+                ensure!(
+                    !sealed.iter().any(|s| s.commitment == commitment),
+                    Error::<T>::DuplicateCommitment
+                );
+                Self::note_auction_entry(interval, &who)?;
+                T::Currency::reserve(&who, Self::to_currency(deposit))
+                    .map_err(|_| Error::<T>::InsufficientBalance)?;
+                sealed.try_push(SealedOrder { who: who.clone(), commitment, deposit })
+                    .map_err(|_| Error::<T>::AuctionBookFull)?;
+                Ok(())
+            })?;
+
+            Self::deposit_event(Event::OrderCommitted { interval, who, commitment });
+
+            Ok(())
+        }
+
+        #[pallet::call_index(12)]
+        #[pallet::weight(10_000)]
+        pub fn reveal_order(
+            origin: OriginFor<T>,
+            order_type: OrderType,
+            energy_amount: TokenBalanceOf<T>,
+            price_per_unit: TokenBalanceOf<T>,
+            grid_location: Vec<u8>,
+            delivery_slot: DeliverySlot,
+            salt: T::Hash,
+        ) -> DispatchResult {
+            let who = ensure_signed(origin)?;
+            ensure!(Self::matching_mode() == MatchingMode::SealedBid, Error::<T>::NotSealedBidMode);
+
+            let current_block = <frame_system::Pallet<T>>::block_number();
+            ensure!(Self::in_reveal_phase(current_block), Error::<T>::RevealPhaseNotOpen);
+
+            let commitment = Self::sealed_commitment(
+                &who,
+                &order_type,
+                energy_amount,
+                price_per_unit,
+                &grid_location,
+                delivery_slot,
+                salt,
+            );
+            let interval = Self::interval_index(current_block);
+            let sealed = Commitments::<T>::try_mutate(interval, |sealed| {
+                let index = sealed.iter()
+                    .position(|s| s.commitment == commitment && s.who == who)
+                    .ok_or(Error::<T>::CommitmentNotFound)?;
+                Ok::<_, Error<T>>(sealed.remove(index))
+            })?;
+            // The revealed order takes over the commitment's place in the account's allowance
+            AuctionEntries::<T>::mutate(interval, &who, |count| *count = count.saturating_sub(1));
+
+            // A market halted, or limits tightened, after the commitment was made returns the
+            // deposit instead
+            if Self::is_halted(&grid_location)
+                || Self::ensure_order_limits(DEFAULT_MARKET, &grid_location, energy_amount, price_per_unit).is_err()
+            {
+                T::Currency::unreserve(&who, Self::to_currency(sealed.deposit));
+                Self::deposit_event(Event::CommitmentReleased { interval, who, commitment });
+                return Ok(());
+            }
+
+            // The revealed order joins the interval's book and takes over from the deposit
+            let order_id = Self::do_create_order(
+                DEFAULT_MARKET,
+                who.clone(),
+                order_type,
+                OrderKind::Limit,
+                energy_amount,
+                price_per_unit,
+                grid_location,
+                delivery_slot,
+            )?;
+            T::Currency::unreserve(&who, Self::to_currency(sealed.deposit));
+
+            Self::deposit_event(Event::OrderRevealed { commitment, order_id });
+
+            Ok(())
+        }
+
+        // A bid that only matches asks meeting `constraints`
+        #[pallet::call_index(13)]
+        #[pallet::weight(T::WeightInfo::create_order(T::MaxFillsPerOrder::get()))]
+        pub fn create_constrained_bid(
+            origin: OriginFor<T>,
+            order_kind: OrderKind<TokenBalanceOf<T>>,
+            energy_amount: TokenBalanceOf<T>,
+            price_per_unit: TokenBalanceOf<T>,
+            grid_location: Vec<u8>,
+            delivery_slot: DeliverySlot,
+            constraints: BidConstraints<T>,
+        ) -> DispatchResult {
+            let who = ensure_signed(origin)?;
+            Self::do_place_constrained_order(
+                who,
+                OrderType::Bid,
+                order_kind,
+                energy_amount,
+                price_per_unit,
+                grid_location,
+                delivery_slot,
+                Some(constraints),
+            )?;
+            Ok(())
+        }
+
+        // Stops new orders and matches in every location; cancellations and settlement of
+        // matched trades carry on
+        #[pallet::call_index(14)]
+        #[pallet::weight(10_000)]
+        pub fn set_global_halt(
+            origin: OriginFor<T>,
+            halted: bool,
+        ) -> DispatchResult {
+            T::OperatorOrigin::ensure_origin(origin)?;
+
+            GlobalHalt::<T>::put(halted);
+            Self::deposit_event(Event::GlobalHaltSet { halted });
+
+            Ok(())
+        }
+
+        #[pallet::call_index(15)]
+        #[pallet::weight(10_000)]
+        pub fn halt_location(
+            origin: OriginFor<T>,
+            location: Vec<u8>,
+        ) -> DispatchResult {
+            T::OperatorOrigin::ensure_origin(origin)?;
+
+            let bounded_location: BoundedVec<u8, ConstU32<32>> = location.clone().try_into()
+                .map_err(|_| Error::<T>::InvalidLocation)?;
+            LocationHalts::<T>::insert(bounded_location, MarketHalt {
+                reason: HaltReason::Operator,
+                halted_at: <frame_system::Pallet<T>>::block_number(),
+                until: None,
+            });
+
+            Self::deposit_event(Event::MarketHalted { location, reason: HaltReason::Operator, until: None });
+
+            Ok(())
+        }
+
+        // Lifts an operator halt or a tripped circuit breaker early
+        #[pallet::call_index(16)]
+        #[pallet::weight(10_000)]
+        pub fn resume_location(
+            origin: OriginFor<T>,
+            location: Vec<u8>,
+        ) -> DispatchResult {
+            T::OperatorOrigin::ensure_origin(origin)?;
+
+            let bounded_location: BoundedVec<u8, ConstU32<32>> = location.clone().try_into()
+                .map_err(|_| Error::<T>::InvalidLocation)?;
+            ensure!(LocationHalts::<T>::contains_key(&bounded_location), Error::<T>::MarketNotHalted);
+            LocationHalts::<T>::remove(bounded_location);
+
+            Self::deposit_event(Event::MarketResumed { location });
+
+            Ok(())
+        }
+
+        #[pallet::call_index(17)]
+        #[pallet::weight(10_000)]
+        pub fn set_retailer(
+            origin: OriginFor<T>,
+            account: Option<T::AccountId>,
+        ) -> DispatchResult {
+            T::OperatorOrigin::ensure_origin(origin)?;
+
+            RetailerAccount::<T>::set(account.clone());
+            Self::deposit_event(Event::RetailerSet { account });
+
+            Ok(())
+        }
+
+        // An empty table disables the fallback; unmatched orders are then cancelled
+        #[pallet::call_index(18)]
+        #[pallet::weight(10_000)]
+        pub fn set_tariffs(
+            origin: OriginFor<T>,
+            periods: Vec<TariffPeriod<TokenBalanceOf<T>>>,
+        ) -> DispatchResult {
+            T::OperatorOrigin::ensure_origin(origin)?;
+
+            let slots_per_day = Self::slots_per_day();
+            ensure!(
+                periods.first().map_or(true, |first| first.start_slot == 0) &&
+                    periods.windows(2).all(|pair| pair[0].start_slot < pair[1].start_slot) &&
+                    periods.iter().all(|period| {
+                        period.start_slot < slots_per_day &&
+                            !period.feed_in_price.is_zero() &&
+                            period.feed_in_price <= period.retail_price
+                    }),
+                Error::<T>::InvalidTariffs
+            );
+            let bounded: BoundedVec<_, ConstU32<24>> = periods.try_into()
+                .map_err(|_| Error::<T>::InvalidTariffs)?;
+
+            let count = bounded.len() as u32;
+            TimeOfUseTariffs::<T>::put(bounded);
+            Self::deposit_event(Event::TariffsUpdated { periods: count });
+
+            Ok(())
+        }
+
+        #[pallet::call_index(19)]
+        #[pallet::weight(10_000)]
+        pub fn set_self_trade_prevention(
+            origin: OriginFor<T>,
+            mode: SelfTradePrevention,
+        ) -> DispatchResult {
+            T::OperatorOrigin::ensure_origin(origin)?;
+
+            SelfTradePreventionMode::<T>::put(mode);
+            Self::deposit_event(Event::SelfTradePreventionSet { mode });
+
+            Ok(())
+        }
+
+        #[pallet::call_index(20)]
+        #[pallet::weight(10_000)]
+        pub fn create_market(origin: OriginFor<T>, config: MarketConfig<T>) -> DispatchResult {
+            T::MarketAdminOrigin::ensure_origin(origin)?;
+            Self::ensure_valid_market_config(&config)?;
+
+            let market = Self::market_count().checked_add(1).ok_or(Error::<T>::MarketNotFound)?;
+            Markets::<T>::insert(market, config);
+            MarketCount::<T>::put(market);
+
+            Self::deposit_event(Event::MarketCreated { market });
+
+            Ok(())
+        }
+
+        // Applies to orders placed from now on; resting orders keep their price and amount
+        #[pallet::call_index(21)]
+        #[pallet::weight(10_000)]
+        pub fn update_market(origin: OriginFor<T>, market: MarketId, config: MarketConfig<T>) -> DispatchResult {
+            T::MarketAdminOrigin::ensure_origin(origin)?;
+            ensure!(Markets::<T>::contains_key(market), Error::<T>::MarketNotFound);
+            Self::ensure_valid_market_config(&config)?;
+
+            Markets::<T>::insert(market, config);
+
+            Self::deposit_event(Event::MarketUpdated { market });
+
+            Ok(())
+        }
+
+        #[pallet::call_index(22)]
+        #[pallet::weight(10_000)]
+        pub fn add_market_member(origin: OriginFor<T>, market: MarketId, who: T::AccountId) -> DispatchResult {
+            T::MarketAdminOrigin::ensure_origin(origin)?;
+            ensure!(Markets::<T>::contains_key(market), Error::<T>::MarketNotFound);
+            ensure!(!MarketMembers::<T>::contains_key(market, &who), Error::<T>::AlreadyMarketMember);
+
+            MarketMembers::<T>::insert(market, &who, ());
+
+            Self::deposit_event(Event::MarketMemberAdded { market, who });
+
+            Ok(())
+        }
+
+        // Open orders of the member stay on the book
+        #[pallet::call_index(23)]
+        #[pallet::weight(10_000)]
+        pub fn remove_market_member(origin: OriginFor<T>, market: MarketId, who: T::AccountId) -> DispatchResult {
+            T::MarketAdminOrigin::ensure_origin(origin)?;
+            ensure!(MarketMembers::<T>::contains_key(market, &who), Error::<T>::NotMarketMember);
+
+            MarketMembers::<T>::remove(market, &who);
+
+            Self::deposit_event(Event::MarketMemberRemoved { market, who });
+
+            Ok(())
+        }
+
+        // `create_order` in a market other than `DEFAULT_MARKET`
+        #[pallet::call_index(24)]
+        #[pallet::weight(T::WeightInfo::create_order(T::MaxFillsPerOrder::get()))]
+        pub fn create_market_order(
+            origin: OriginFor<T>,
+            market: MarketId,
+            order_type: OrderType,
+            order_kind: OrderKind<TokenBalanceOf<T>>,
+            energy_amount: TokenBalanceOf<T>,
+            price_per_unit: TokenBalanceOf<T>,
+            grid_location: Vec<u8>,
+            delivery_slot: DeliverySlot,
+        ) -> DispatchResult {
+            let who = ensure_signed(origin)?;
+            Self::do_place_in_market(
+                market,
+                who,
+                order_type,
+                order_kind,
+                energy_amount,
+                price_per_unit,
+                grid_location,
+                delivery_slot,
+                None,
+            )?;
+            Ok(())
+        }
+    }
+
+    impl<T: Config> Pallet<T> {
+        pub fn interval_index(block: BlockNumberFor<T>) -> IntervalIndex {
+            let interval = T::AuctionInterval::get();
+            if interval.is_zero() {
+                return 0;
+            }
+            (block / interval).saturated_into()
+        }
+
+        pub fn in_reveal_phase(block: BlockNumberFor<T>) -> bool {
+            let interval = T::AuctionInterval::get();
+            if interval.is_zero() {
+                return false;
+            }
+            block % interval >= interval.saturating_sub(T::RevealPeriod::get())
+        }
+
+        pub fn sealed_commitment(
+            who: &T::AccountId,
+            order_type: &OrderType,
+            energy_amount: TokenBalanceOf<T>,
+            price_per_unit: TokenBalanceOf<T>,
+            grid_location: &[u8],
+            delivery_slot: DeliverySlot,
+            salt: T::Hash,
+        ) -> T::Hash {
+            T::Hashing::hash_of(&(who, order_type, energy_amount, price_per_unit, grid_location, delivery_slot, salt))
+        }
+
+        // Slot containing a Unix timestamp in milliseconds
+        pub fn slot_of(timestamp: u64) -> DeliverySlot {
+            timestamp.checked_div(T::SlotDuration::get()).unwrap_or_default().saturated_into()
+        }
+
+        pub fn current_slot() -> DeliverySlot {
+            Self::slot_of(T::TimeProvider::now().as_millis().saturated_into())
+        }
+
+        pub fn to_currency(amount: TokenBalanceOf<T>) -> BalanceOf<T> {
+            amount.saturated_into::<u128>().saturated_into()
+        }
+
+        pub fn to_energy(amount: TokenBalanceOf<T>) -> EnergyBalanceOf<T> {
+            amount.saturated_into::<u128>().saturated_into()
+        }
+
+        pub fn from_currency(amount: BalanceOf<T>) -> TokenBalanceOf<T> {
+            amount.saturated_into::<u128>().saturated_into()
+        }
+
+        // Give back whatever an order still holds: energy tokens for asks, currency for bids
+        pub(crate) fn release_hold(order: &TradeOrder<T>) {
+            match order.order_type {
+                OrderType::Ask => {
+                    pallet_energy_token::Pallet::<T>::unreserve(
+                        &order.creator,
+                        Self::to_energy(order.energy_amount),
+                    );
+                    SlotAskVolume::<T>::mutate(&order.creator, order.delivery_slot, |volume| {
+                        *volume = volume.saturating_sub(order.energy_amount);
+                    });
+                },
+                OrderType::Bid => {
+                    T::Currency::unreserve(
+                        &order.creator,
+                        Self::to_currency(order.total_price.saturating_add(order.fee)),
+                    );
+                },
+            }
+            T::OrderHooks::on_order_released(&order.creator, &order.order_type, order.energy_amount);
+        }
+
+        pub(crate) fn close_matched_order(
+            order_id: Option<T::Hash>,
+            status: OrderStatus,
+            completed_at: Option<BlockNumberFor<T>>,
+        ) {
+            if let Some(order_id) = order_id {
+                TradeOrders::<T>::mutate(order_id, |maybe_order| {
+                    if let Some(order) = maybe_order {
+                        Self::set_status(&order_id, order, status);
+                        order.completed_at = completed_at;
+                    }
+                });
+            }
+        }
+
+        // `value * part / whole`, rounded down
+        pub fn pro_rata(
+            value: TokenBalanceOf<T>,
+            part: TokenBalanceOf<T>,
+            whole: TokenBalanceOf<T>,
+        ) -> TokenBalanceOf<T> {
+            if whole.is_zero() {
+                return Zero::zero();
+            }
+            multiply_by_rational_with_rounding(
+                value.saturated_into::<u128>(),
+                part.saturated_into::<u128>(),
+                whole.saturated_into::<u128>(),
+                Rounding::Down,
+            ).unwrap_or_default().saturated_into()
+        }
+
+        pub fn do_verify_transfer(
+            order_id: T::Hash,
+            verification_data: Vec<u8>,
+            delivered_amount: TokenBalanceOf<T>,
+        ) -> DispatchResult {
+            TradeOrders::<T>::try_mutate(order_id, |order| -> DispatchResult {
+                let order = order.as_mut().ok_or(Error::<T>::OrderNotFound)?;
+                ensure!(order.status == OrderStatus::Matched, Error::<T>::InvalidOrderStatus);
+
+                // Verify the transfer using IoT data
+                let verification_hash = T::Hashing::hash_of(&verification_data);
+                order.transfer_verification = Some(verification_hash);
+                order.delivered_amount = Some(delivered_amount);
+                Self::set_status(&order_id, order, OrderStatus::InTransfer);
+
+                Self::deposit_event(Event::TransferVerified {
+                    order_id,
+                    verification_hash,
+                });
+
+                Ok(())
+            })
+        }
+
+        // Abort a matched trade and release both sides' holds
+        pub fn fail_trade(order_id: T::Hash, reason: Vec<u8>) -> DispatchResult {
+            TradeOrders::<T>::try_mutate(order_id, |order| -> DispatchResult {
+                let order = order.as_mut().ok_or(Error::<T>::OrderNotFound)?;
+                ensure!(
+                    matches!(order.status, OrderStatus::Matched | OrderStatus::InTransfer),
+                    Error::<T>::InvalidOrderStatus
+                );
+
+                Self::release_hold(order);
+                Self::set_status(&order_id, order, OrderStatus::Failed);
+
+                if let Some(matched_id) = order.matched_order {
+                    if let Some(matched) = Self::trade_orders(matched_id) {
+                        Self::release_hold(&matched);
+                    }
+                    Self::close_matched_order(Some(matched_id), OrderStatus::Failed, None);
+                }
+
+                Self::deposit_event(Event::OrderFailed { order_id, reason });
+
+                Ok(())
+            })
+        }
+
+        pub fn fee_schedule_for(
+            market: MarketId,
+            location: &BoundedVec<u8, ConstU32<32>>,
+        ) -> FeeSchedule<TokenBalanceOf<T>> {
+            match Self::markets(market) {
+                Some(config) => config.fee_schedule,
+                None => Self::location_fee_schedules(location).unwrap_or_else(Self::default_fee_schedule),
+            }
+        }
+
+        // Most a bid can pay in fees, whether it ends up the maker or the taker
+        pub fn max_fee(
+            market: MarketId,
+            location: &BoundedVec<u8, ConstU32<32>>,
+            total_price: TokenBalanceOf<T>,
+        ) -> TokenBalanceOf<T> {
+            let schedule = Self::fee_schedule_for(market, location);
+            Self::trade_fee(total_price, schedule.maker_fee_bps.max(schedule.taker_fee_bps), &schedule)
+        }
+
+        pub fn trade_fee(
+            total_price: TokenBalanceOf<T>,
+            fee_bps: u16,
+            schedule: &FeeSchedule<TokenBalanceOf<T>>,
+        ) -> TokenBalanceOf<T> {
+            Self::pro_rata(total_price, (fee_bps as u32).into(), 10_000u32.into()).max(schedule.min_fee)
+        }
+
+        // Pay settled fees out of the buyer's hold to the treasury, the location's grid
+        // operator (the treasury if none is set) and the community fund
+        fn distribute_fees(
+            payer: &T::AccountId,
+            location: &BoundedVec<u8, ConstU32<32>>,
+            fees: TokenBalanceOf<T>,
+        ) -> DispatchResult {
+            if fees.is_zero() {
+                return Ok(());
+            }
+
+            let distribution = Self::fee_distribution();
+            let grid_operator_share = distribution.grid_operator * fees;
+            let community_share = distribution.community * fees;
+            let treasury_share = fees.saturating_sub(grid_operator_share).saturating_sub(community_share);
+            let grid_operator = Self::grid_operator_account(location).unwrap_or_else(T::TreasuryAccount::get);
+
+            for (recipient, amount) in [
+                (T::TreasuryAccount::get(), treasury_share),
+                (grid_operator, grid_operator_share),
+                (T::CommunityFundAccount::get(), community_share),
+            ] {
+                if amount.is_zero() {
+                    continue;
+                }
+                let unpaid = T::Currency::repatriate_reserved(
+                    payer,
+                    &recipient,
+                    Self::to_currency(amount),
+                    BalanceStatus::Free,
+                )?;
+                ensure!(unpaid.is_zero(), Error::<T>::PaymentFailed);
+            }
+
+            Ok(())
+        }
+
+        // Charge energy the seller did not deliver at the imbalance price, paying the buyer's
+        // replacement cost above the contract price out of it, and credit energy delivered
+        // beyond the contract at the spill price. Without reference prices a shortfall is
+        // charged at the contract price and excess earns nothing.
+        fn settle_imbalance(
+            order_id: T::Hash,
+            order: &TradeOrder<T>,
+            buyer: &T::AccountId,
+        ) -> DispatchResult {
+            let metered = order.delivered_amount.unwrap_or_default();
+            let shortfall = order.energy_amount.saturating_sub(metered);
+            let excess = metered.saturating_sub(order.energy_amount);
+            if shortfall.is_zero() && excess.is_zero() {
+                return Ok(());
+            }
+
+            let seller = &order.creator;
+            let balancing = T::ImbalanceAccount::get();
+            let imbalance_price = T::ImbalancePrices::imbalance_price(&order.grid_location)
+                .unwrap_or(order.price_per_unit);
+            let spill_price = T::ImbalancePrices::spill_price(&order.grid_location).unwrap_or_default();
+
+            let penalty_owed = imbalance_price.saturating_mul(shortfall);
+            let penalty_paid = penalty_owed.min(Self::from_currency(T::Currency::free_balance(seller)));
+            let compensation = imbalance_price
+                .saturating_sub(order.price_per_unit)
+                .saturating_mul(shortfall)
+                .min(penalty_paid);
+            Self::transfer_funds(seller, buyer, compensation, ExistenceRequirement::AllowDeath)?;
+            Self::transfer_funds(
+                seller,
+                &balancing,
+                penalty_paid.saturating_sub(compensation),
+                ExistenceRequirement::AllowDeath,
+            )?;
+
+            // The balancing account only pays out what it holds above its existential deposit
+            let spill_credit = spill_price.saturating_mul(excess).min(Self::from_currency(
+                T::Currency::free_balance(&balancing).saturating_sub(T::Currency::minimum_balance()),
+            ));
+            Self::transfer_funds(&balancing, seller, spill_credit, ExistenceRequirement::KeepAlive)?;
+
+            Imbalances::<T>::insert(order_id, ImbalanceRecord {
+                shortfall,
+                excess,
+                imbalance_price,
+                spill_price,
+                penalty_owed,
+                penalty_paid,
+                compensation,
+                spill_credit,
+            });
+
+            Self::deposit_event(Event::ImbalanceSettled {
+                order_id,
+                shortfall,
+                excess,
+                penalty: penalty_paid,
+                compensation,
+                spill_credit,
+            });
+
+            Ok(())
+        }
+
+        fn transfer_funds(
+            from: &T::AccountId,
+            to: &T::AccountId,
+            amount: TokenBalanceOf<T>,
+            existence: ExistenceRequirement,
+        ) -> DispatchResult {
+            if amount.is_zero() {
+                return Ok(());
+            }
+            T::Currency::transfer(from, to, Self::to_currency(amount), existence)
+        }
+
+        // Put a verified trade on hold so it cannot settle while its delivery is contested
+        pub fn suspend_for_dispute(order_id: T::Hash) -> DispatchResult {
+            TradeOrders::<T>::try_mutate(order_id, |order| -> DispatchResult {
+                let order = order.as_mut().ok_or(Error::<T>::OrderNotFound)?;
+                ensure!(order.status == OrderStatus::InTransfer, Error::<T>::InvalidOrderStatus);
+                Self::set_status(&order_id, order, OrderStatus::Disputed);
+                Ok(())
+            })
+        }
+
+        // Release a disputed trade for settlement, optionally with the delivered amount
+        // established by the dispute
+        pub fn resume_after_dispute(
+            order_id: T::Hash,
+            delivered_amount: Option<TokenBalanceOf<T>>,
+        ) -> DispatchResult {
+            TradeOrders::<T>::try_mutate(order_id, |order| -> DispatchResult {
+                let order = order.as_mut().ok_or(Error::<T>::OrderNotFound)?;
+                ensure!(order.status == OrderStatus::Disputed, Error::<T>::InvalidOrderStatus);
+                if let Some(delivered) = delivered_amount {
+                    order.delivered_amount = Some(delivered);
+                }
+                Self::set_status(&order_id, order, OrderStatus::InTransfer);
+                Ok(())
+            })
+        }
+
+        pub(crate) fn do_create_order(
+            market: MarketId,
+            creator: T::AccountId,
+            order_type: OrderType,
+            order_kind: OrderKind<TokenBalanceOf<T>>,
+            energy_amount: TokenBalanceOf<T>,
+            price_per_unit: TokenBalanceOf<T>,
+            grid_location: Vec<u8>,
+            delivery_slot: DeliverySlot,
+        ) -> Result<T::Hash, DispatchError> {
+            ensure!(!energy_amount.is_zero(), Error::<T>::InvalidAmount);
+            ensure!(!price_per_unit.is_zero(), Error::<T>::InvalidPrice);
+            ensure!(delivery_slot >= Self::current_slot(), Error::<T>::DeliverySlotPassed);
+            Self::ensure_not_halted(&grid_location)?;
+            ensure!(T::Participants::is_active(&creator), Error::<T>::NotRegistered);
+            let matching_mode = Self::market_matching_mode(market).ok_or(Error::<T>::MarketNotFound)?;
+            Self::ensure_market_rules(market, &creator)?;
+
+            // Sellers cannot offer more in a slot than their devices can produce
+            if order_type == OrderType::Ask {
+                ensure!(T::Participants::can_sell(&creator), Error::<T>::NotAllowedToSell);
+                let committed = Self::slot_ask_volume(&creator, delivery_slot).saturating_add(energy_amount);
+                ensure!(
+                    committed.saturated_into::<u128>() <= T::Participants::generation_capacity(&creator),
+                    Error::<T>::ExceedsGenerationCapacity
+                );
+                SlotAskVolume::<T>::insert(&creator, delivery_slot, committed);
+            }
+            T::OrderHooks::on_order_placed(&creator, &order_type, energy_amount, delivery_slot)?;
+
+            let total_price = price_per_unit
+                .checked_mul(&energy_amount)
+                .filter(|total| *total <= T::MaxOrderValue::get())
+                .ok_or(Error::<T>::AboveMaximumNotional)?;
+
+            let bounded_location: BoundedVec<u8, ConstU32<32>> = grid_location.clone().try_into()
+                .map_err(|_| Error::<T>::InvalidLocation)?;
+
+            // Escrow the seller's energy or the buyer's funds and fee for the lifetime of the order
+            let fee = match order_type {
+                OrderType::Ask => {
+                    pallet_energy_token::Pallet::<T>::reserve(&creator, Self::to_energy(energy_amount))?;
+                    Zero::zero()
+                },
+                OrderType::Bid => {
+                    let fee = Self::max_fee(market, &bounded_location, total_price);
+                    T::Currency::reserve(&creator, Self::to_currency(total_price.saturating_add(fee)))
+                        .map_err(|_| Error::<T>::InsufficientBalance)?;
+                    fee
+                },
+            };
+
+            let current_block = <frame_system::Pallet<T>>::block_number();
+            let status = match order_kind {
+                OrderKind::Stop { .. } => OrderStatus::Pending,
+                _ => OrderStatus::Open,
+            };
+            let order = TradeOrder {
+                order_type: order_type.clone(),
+                order_kind,
+                creator: creator.clone(),
+                counterparty: None,
+                energy_amount,
+                price_per_unit,
+                total_price,
+                status,
+                market,
+                grid_location: bounded_location,
+                delivery_slot,
+                created_at: current_block,
+                matched_at: None,
+                completed_at: None,
+                transfer_verification: None,
+                matched_order: None,
+                delivered_amount: None,
+                fee,
+            };
+
+            let order_id = T::Hashing::hash_of(&order);
+            Self::index_order(&order_id, &order)?;
+            <TradeOrders<T>>::insert(order_id, order);
+            Self::push_account_order(&creator, order_id);
+
+            // In auction modes orders rest in the current interval's book until gate closure
+            if matches!(matching_mode, MatchingMode::PeriodicAuction | MatchingMode::SealedBid) {
+                let interval = Self::interval_index(current_block);
+                Self::note_auction_entry(interval, &creator)?;
+                AuctionBook::<T>::try_mutate(interval, |book| {
+                    book.try_push(order_id).map_err(|_| Error::<T>::AuctionBookFull)
+                })?;
+            }
+
+            match order_type {
+                OrderType::Ask => Self::deposit_event(Event::AskOrderCreated {
+                    order_id,
+                    seller: creator,
+                    amount: energy_amount,
+                    price: total_price,
+                    location: grid_location,
+                }),
+                OrderType::Bid => Self::deposit_event(Event::BidOrderCreated {
+                    order_id,
+                    buyer: creator,
+                    amount: energy_amount,
+                    price: total_price,
+                    location: grid_location,
+                }),
+            }
+
+            Ok(order_id)
+        }
+
+        // Checks that an ask and a bid can be matched bilaterally right now
+        pub fn ensure_matchable(
+            ask_id: T::Hash,
+            bid_id: T::Hash,
+        ) -> Result<(TradeOrder<T>, TradeOrder<T>), DispatchError> {
+            let ask_order = TradeOrders::<T>::get(ask_id).ok_or(Error::<T>::OrderNotFound)?;
+            let bid_order = TradeOrders::<T>::get(bid_id).ok_or(Error::<T>::OrderNotFound)?;
+            ensure!(bid_order.market == ask_order.market, Error::<T>::MarketMismatch);
+            ensure!(
+                Self::market_matching_mode(ask_order.market) == Some(MatchingMode::Continuous),
+                Error::<T>::AuctionModeActive
+            );
+
+            ensure!(ask_order.order_type == OrderType::Ask, Error::<T>::OrderMismatch);
+            ensure!(bid_order.order_type == OrderType::Bid, Error::<T>::OrderMismatch);
+            ensure!(ask_order.status == OrderStatus::Open, Error::<T>::InvalidOrderStatus);
+            ensure!(bid_order.status == OrderStatus::Open, Error::<T>::InvalidOrderStatus);
+            ensure!(ask_order.creator != bid_order.creator, Error::<T>::SelfTrade);
+            Self::ensure_not_halted(&ask_order.grid_location)?;
+            Self::ensure_not_halted(&bid_order.grid_location)?;
+            ensure!(ask_order.delivery_slot == bid_order.delivery_slot, Error::<T>::SlotMismatch);
+            ensure!(ask_order.energy_amount == bid_order.energy_amount, Error::<T>::OrderMismatch);
+            ensure!(ask_order.price_per_unit <= bid_order.price_per_unit, Error::<T>::OrderMismatch);
+            ensure!(
+                Self::violated_constraint(&bid_id, &bid_order, &ask_order).is_none(),
+                Error::<T>::BidConstraintViolated
+            );
+
+            Ok((ask_order, bid_order))
+        }
+
+        pub fn do_match_orders(ask_id: T::Hash, bid_id: T::Hash) -> DispatchResult {
+            let (ask_order, _) = Self::ensure_matchable(ask_id, bid_id)?;
+
+            // Pay-as-ask: the buyer's hold above the ask price is released by the fill
+            Self::execute_fill(ask_id, bid_id, ask_order.energy_amount, ask_order.price_per_unit)?;
+
+            Ok(())
+        }
+
+        // Validate an order against its kind, create it and run any immediate execution
+        pub fn do_place_order(
+            creator: T::AccountId,
+            order_type: OrderType,
+            order_kind: OrderKind<TokenBalanceOf<T>>,
+            energy_amount: TokenBalanceOf<T>,
+            price_per_unit: TokenBalanceOf<T>,
+            grid_location: Vec<u8>,
+            delivery_slot: DeliverySlot,
+        ) -> Result<T::Hash, DispatchError> {
+            Self::do_place_constrained_order(
+                creator,
+                order_type,
+                order_kind,
+                energy_amount,
+                price_per_unit,
+                grid_location,
+                delivery_slot,
+                None,
+            )
+        }
+
+        // As `do_place_order`; a bid's constraints are stored before it can trade, and
+        // market prices and fill-or-kill liquidity only count asks the bid accepts
+        pub fn do_place_constrained_order(
+            creator: T::AccountId,
+            order_type: OrderType,
+            order_kind: OrderKind<TokenBalanceOf<T>>,
+            energy_amount: TokenBalanceOf<T>,
+            price_per_unit: TokenBalanceOf<T>,
+            grid_location: Vec<u8>,
+            delivery_slot: DeliverySlot,
+            constraints: Option<BidConstraints<T>>,
+        ) -> Result<T::Hash, DispatchError> {
+            Self::do_place_in_market(
+                DEFAULT_MARKET,
+                creator,
+                order_type,
+                order_kind,
+                energy_amount,
+                price_per_unit,
+                grid_location,
+                delivery_slot,
+                constraints,
+            )
+        }
+
+        // Stop orders follow the price discovery reference price, so they are only
+        // accepted in `DEFAULT_MARKET`
+        pub fn do_place_in_market(
+            market: MarketId,
+            creator: T::AccountId,
+            order_type: OrderType,
+            order_kind: OrderKind<TokenBalanceOf<T>>,
+            energy_amount: TokenBalanceOf<T>,
+            price_per_unit: TokenBalanceOf<T>,
+            grid_location: Vec<u8>,
+            delivery_slot: DeliverySlot,
+            constraints: Option<BidConstraints<T>>,
+        ) -> Result<T::Hash, DispatchError> {
+            ensure!(
+                constraints.is_none() || order_type == OrderType::Bid,
+                Error::<T>::OrderMismatch
+            );
+            let matching_mode = Self::market_matching_mode(market).ok_or(Error::<T>::MarketNotFound)?;
+            ensure!(matching_mode != MatchingMode::SealedBid, Error::<T>::SealedBidModeActive);
+            if order_kind != OrderKind::Limit {
+                ensure!(matching_mode == MatchingMode::Continuous, Error::<T>::UnsupportedOrderKind);
+            }
+            ensure!(
+                market == DEFAULT_MARKET || !matches!(order_kind, OrderKind::Stop { .. }),
+                Error::<T>::UnsupportedOrderKind
+            );
+
+            let bounded_location: BoundedVec<u8, ConstU32<32>> = grid_location.clone().try_into()
+                .map_err(|_| Error::<T>::InvalidLocation)?;
+
+            // Market orders take their limit from the best opposite price and the slippage bound
+            let price_per_unit = match &order_kind {
+                OrderKind::Market { max_slippage } => {
+                    let best = Self::crossing_orders(market, &order_type, &bounded_location, delivery_slot, None)
+                        .iter()
+                        .find(|(_, ask)| Self::accepts(constraints.as_ref(), &bounded_location, ask))
+                        .map(|(_, best)| best.price_per_unit)
+                        .ok_or(Error::<T>::NoLiquidity)?;
+                    let limit = match order_type {
+                        OrderType::Bid => best.saturating_add(*max_slippage * best),
+                        OrderType::Ask => best.saturating_sub(*max_slippage * best),
+                    };
+                    // Round towards the best price so the limit lands on the tick grid
+                    let tick_size = Self::order_limits_for(market, &grid_location).tick_size;
+                    let remainder = if tick_size.is_zero() { tick_size } else { limit % tick_size };
+                    match order_type {
+                        _ if remainder.is_zero() => limit,
+                        OrderType::Bid => limit - remainder,
+                        OrderType::Ask => limit.saturating_add(tick_size - remainder),
+                    }
+                },
+                _ => price_per_unit,
+            };
+            Self::ensure_order_limits(market, &grid_location, energy_amount, price_per_unit)?;
+
+            match &order_kind {
+                OrderKind::PostOnly => ensure!(
+                    Self::crossing_orders(market, &order_type, &bounded_location, delivery_slot, Some(price_per_unit)).is_empty(),
+                    Error::<T>::WouldCrossBook
+                ),
+                OrderKind::FillOrKill => {
+                    let liquidity = Self::crossing_orders(market, &order_type, &bounded_location, delivery_slot, Some(price_per_unit))
+                        .iter()
+                        .filter(|(_, ask)| Self::accepts(constraints.as_ref(), &bounded_location, ask))
+                        .fold(TokenBalanceOf::<T>::zero(), |acc, (_, o)| acc.saturating_add(o.energy_amount));
+                    ensure!(liquidity >= energy_amount, Error::<T>::FillOrKillNotFilled);
+                },
+                _ => {},
+            }
+
+            let order_id = Self::do_create_order(
+                market,
+                creator,
+                order_type,
+                order_kind.clone(),
+                energy_amount,
+                price_per_unit,
+                grid_location,
+                delivery_slot,
+            )?;
+            if let Some(constraints) = constraints {
+                BidConstraintsOf::<T>::insert(order_id, constraints);
+            }
+
+            match order_kind {
+                OrderKind::Market { .. } | OrderKind::ImmediateOrCancel | OrderKind::FillOrKill => {
+                    Self::sweep(order_id)?;
+                    Self::cancel_remainder(order_id);
+                },
+                OrderKind::Stop { .. } => {
+                    StopOrders::<T>::try_mutate(&bounded_location, |stops| {
+                        stops.try_push(order_id).map_err(|_| Error::<T>::TooManyStopOrders)
+                    })?;
+                },
+                OrderKind::Limit | OrderKind::PostOnly => {},
+            }
+
+            Ok(order_id)
+        }
+
+        // Open orders on the other side of the book for the same location and slot that trade
+        // at `limit` (or at any price), best price first and oldest first within a price.
+        // Walks the price levels from the best one and stops after `MaxFillsPerOrder` orders.
+        pub fn crossing_orders(
+            market: MarketId,
+            order_type: &OrderType,
+            location: &BoundedVec<u8, ConstU32<32>>,
+            delivery_slot: DeliverySlot,
+            limit: Option<TokenBalanceOf<T>>,
+        ) -> Vec<(T::Hash, TradeOrder<T>)> {
+            let opposite = match order_type {
+                OrderType::Ask => OrderType::Bid,
+                OrderType::Bid => OrderType::Ask,
+            };
+
+            // Levels are ascending: a bid takes the lowest asks first, an ask the highest bids
+            let mut levels = Self::price_levels((market, location, delivery_slot, &opposite)).into_inner();
+            if *order_type == OrderType::Ask {
+                levels.reverse();
+            }
+
+            levels.into_iter()
+                .take_while(|price| match order_type {
+                    OrderType::Bid => *price <= limit.unwrap_or_else(Bounded::max_value),
+                    OrderType::Ask => *price >= limit.unwrap_or_else(Zero::zero),
+                })
+                .flat_map(|price| Self::order_book((market, location, delivery_slot, &opposite, price)).into_inner())
+                .take(T::MaxFillsPerOrder::get() as usize)
+                .filter_map(|order_id| Self::trade_orders(order_id).map(|order| (order_id, order)))
+                .collect()
+        }
+
+        // Orders `who` may submit per window, in proportion to their reputation but at least
+        // one and at most twice `MaxOrdersPerWindow`
+        pub fn order_allowance(who: &T::AccountId) -> u32 {
+            let base = T::MaxOrdersPerWindow::get();
+            let scaled = (base as u64).saturating_mul(T::Participants::reputation(who) as u64) / 100;
+            (scaled.min(base as u64 * 2) as u32).max(1)
+        }
+
+        // The current window and `who`'s order count in it once another order is submitted,
+        // or an error if the allowance is used up
+        pub fn check_order_rate(
+            who: &T::AccountId,
+        ) -> Result<(BlockNumberFor<T>, u32), TransactionValidityError> {
+            let now = <frame_system::Pallet<T>>::block_number();
+            let window = T::RateLimitWindow::get().max(One::one());
+            let window_start = now - now % window;
+
+            let submitted = match Self::order_rate(who) {
+                Some((started_at, submitted)) if started_at == window_start => submitted,
+                _ => 0,
+            };
+            ensure!(
+                submitted < Self::order_allowance(who),
+                InvalidTransaction::Custom(crate::rate_limit::ORDER_RATE_EXCEEDED)
+            );
+
+            Ok((window_start, submitted + 1))
+        }
+
+        // `None` for a market that does not exist
+        pub fn market_matching_mode(market: MarketId) -> Option<MatchingMode> {
+            if market == DEFAULT_MARKET {
+                return Some(Self::matching_mode());
+            }
+            Self::markets(market).map(|config| config.matching_mode)
+        }
+
+        fn ensure_valid_market_config(config: &MarketConfig<T>) -> DispatchResult {
+            ensure!(config.matching_mode != MatchingMode::SealedBid, Error::<T>::UnsupportedMatchingMode);
+            ensure!(
+                config.fee_schedule.maker_fee_bps <= 10_000 && config.fee_schedule.taker_fee_bps <= 10_000,
+                Error::<T>::InvalidFeeSchedule
+            );
+            Self::ensure_valid_order_limits(&config.limits)
+        }
+
+        // Membership and role rules of the order's market
+        pub fn ensure_market_rules(market: MarketId, who: &T::AccountId) -> DispatchResult {
+            if market == DEFAULT_MARKET {
+                return Ok(());
+            }
+            let config = Self::markets(market).ok_or(Error::<T>::MarketNotFound)?;
+            ensure!(
+                !config.members_only || MarketMembers::<T>::contains_key(market, who),
+                Error::<T>::NotMarketMember
+            );
+            ensure!(
+                config.allowed_roles.is_empty() ||
+                    config.allowed_roles.iter().any(|role| T::Participants::has_role(who, role)),
+                Error::<T>::RoleNotAllowed
+            );
+            Ok(())
+        }
+
+        // Limits of the market, or for the default market those of the location, falling
+        // back to the default limits
+        pub fn order_limits_for(market: MarketId, location: &[u8]) -> OrderLimits<TokenBalanceOf<T>> {
+            if let Some(config) = Self::markets(market) {
+                return config.limits;
+            }
+            BoundedVec::<u8, ConstU32<32>>::try_from(location.to_vec())
+                .ok()
+                .and_then(Self::location_order_limits)
+                .unwrap_or_else(Self::default_order_limits)
+        }
+
+        // Tick, lot and notional limits of an order placed on a book. The notional check
+        // runs before the order's total price is computed anywhere else.
+        pub fn ensure_order_limits(
+            market: MarketId,
+            location: &[u8],
+            energy_amount: TokenBalanceOf<T>,
+            price_per_unit: TokenBalanceOf<T>,
+        ) -> DispatchResult {
+            let limits = Self::order_limits_for(market, location);
+            ensure!(
+                limits.tick_size.is_zero() || (price_per_unit % limits.tick_size).is_zero(),
+                Error::<T>::InvalidTickSize
+            );
+            ensure!(energy_amount >= limits.min_lot, Error::<T>::BelowMinimumLot);
+            ensure!(
+                limits.max_lot.is_zero() || energy_amount <= limits.max_lot,
+                Error::<T>::AboveMaximumLot
+            );
+
+            let notional = price_per_unit
+                .checked_mul(&energy_amount)
+                .filter(|notional| *notional <= T::MaxOrderValue::get())
+                .ok_or(Error::<T>::AboveMaximumNotional)?;
+            ensure!(notional >= limits.min_notional, Error::<T>::BelowMinimumNotional);
+            ensure!(
+                limits.max_notional.is_zero() || notional <= limits.max_notional,
+                Error::<T>::AboveMaximumNotional
+            );
+            Ok(())
+        }
+
+        fn ensure_valid_order_limits(limits: &OrderLimits<TokenBalanceOf<T>>) -> DispatchResult {
+            ensure!(
+                limits.max_lot.is_zero() || limits.min_lot <= limits.max_lot,
+                Error::<T>::InvalidOrderLimits
+            );
+            ensure!(
+                limits.max_notional.is_zero() || limits.min_notional <= limits.max_notional,
+                Error::<T>::InvalidOrderLimits
+            );
+            ensure!(
+                limits.min_notional <= T::MaxOrderValue::get() && limits.max_notional <= T::MaxOrderValue::get(),
+                Error::<T>::InvalidOrderLimits
+            );
+            Ok(())
+        }
+
+        // Whether new orders and matches are stopped at `location`
+        pub fn is_halted(location: &[u8]) -> bool {
+            if Self::global_halt() {
+                return true;
+            }
+            let Ok(bounded_location) = BoundedVec::<u8, ConstU32<32>>::try_from(location.to_vec()) else {
+                return false;
+            };
+            Self::location_halt(bounded_location).map_or(false, |halt| {
+                halt.until.map_or(true, |until| <frame_system::Pallet<T>>::block_number() < until)
+            })
+        }
+
+        pub fn ensure_not_halted(location: &[u8]) -> DispatchResult {
+            ensure!(!Self::is_halted(location), Error::<T>::MarketHalted);
+            Ok(())
+        }
+
+        // Called by price discovery when prices at `location` move too far too fast. An
+        // operator halt is left in place.
+        pub fn trip_circuit_breaker(location: &[u8], until: BlockNumberFor<T>) {
+            let Ok(bounded_location) = BoundedVec::<u8, ConstU32<32>>::try_from(location.to_vec()) else {
+                return;
+            };
+            if Self::location_halt(&bounded_location).map_or(false, |halt| halt.reason == HaltReason::Operator) {
+                return;
+            }
+
+            LocationHalts::<T>::insert(bounded_location, MarketHalt {
+                reason: HaltReason::CircuitBreaker,
+                halted_at: <frame_system::Pallet<T>>::block_number(),
+                until: Some(until),
+            });
+            Self::deposit_event(Event::MarketHalted {
+                location: location.to_vec(),
+                reason: HaltReason::CircuitBreaker,
+                until: Some(until),
+            });
+        }
+
+        // The first of the bid's constraints the ask fails, if any
+        pub fn blocking_constraint(ask_id: T::Hash, bid_id: T::Hash) -> Option<BlockingConstraint> {
+            let ask = Self::trade_orders(ask_id)?;
+            let bid = Self::trade_orders(bid_id)?;
+            Self::violated_constraint(&bid_id, &bid, &ask)
+        }
+
+        pub(crate) fn violated_constraint(
+            bid_id: &T::Hash,
+            bid: &TradeOrder<T>,
+            ask: &TradeOrder<T>,
+        ) -> Option<BlockingConstraint> {
+            let constraints = Self::bid_constraints(bid_id)?;
+            Self::check_constraints(&constraints, &bid.grid_location, ask)
+        }
+
+        fn accepts(
+            constraints: Option<&BidConstraints<T>>,
+            bid_location: &BoundedVec<u8, ConstU32<32>>,
+            ask: &TradeOrder<T>,
+        ) -> bool {
+            constraints.map_or(true, |c| Self::check_constraints(c, bid_location, ask).is_none())
+        }
+
+        fn check_constraints(
+            constraints: &BidConstraints<T>,
+            bid_location: &BoundedVec<u8, ConstU32<32>>,
+            ask: &TradeOrder<T>,
+        ) -> Option<BlockingConstraint> {
+            let seller = &ask.creator;
+            if constraints.denied_sellers.contains(seller) {
+                return Some(BlockingConstraint::SellerDenied);
+            }
+            if !constraints.allowed_sellers.is_empty() && !constraints.allowed_sellers.contains(seller) {
+                return Some(BlockingConstraint::SellerNotAllowed);
+            }
+            if !constraints.device_types.is_empty() &&
+                !constraints.device_types.iter().any(|t| T::Participants::has_device_type(seller, t))
+            {
+                return Some(BlockingConstraint::DeviceType);
+            }
+            if !constraints.locations.is_empty() && !constraints.locations.contains(&ask.grid_location) {
+                return Some(BlockingConstraint::Location);
+            }
+            if let Some(max_distance) = constraints.max_distance {
+                let distance = if ask.grid_location == *bid_location {
+                    Some(0)
+                } else {
+                    T::Distances::distance(bid_location, &ask.grid_location)
+                };
+                if distance.map_or(true, |d| d > max_distance) {
+                    return Some(BlockingConstraint::Distance);
+                }
+            }
+            None
+        }
+
+        // Page of an account's orders in creation order, starting at the `start`th order
+        pub fn account_orders(who: &T::AccountId, start: u32, limit: u32) -> Vec<T::Hash> {
+            let end = start.saturating_add(limit).min(Self::account_order_count(who));
+            (start..end).filter_map(|index| Self::account_order(who, index)).collect()
+        }
+
+        // Page of the orders in `status`, continuing after `start_after` if given
+        pub fn orders_with_status(
+            status: OrderStatus,
+            start_after: Option<T::Hash>,
+            limit: u32,
+        ) -> Vec<T::Hash> {
+            let iter = match start_after {
+                Some(last) => OrdersByStatus::<T>::iter_key_prefix_from(
+                    &status,
+                    OrdersByStatus::<T>::hashed_key_for(&status, last),
+                ),
+                None => OrdersByStatus::<T>::iter_key_prefix(&status),
+            };
+            iter.take(limit as usize).collect()
+        }
+
+        pub(crate) fn push_account_order(who: &T::AccountId, order_id: T::Hash) {
+            let index = AccountOrderCount::<T>::mutate(who, |count| {
+                let index = *count;
+                *count = count.saturating_add(1);
+                index
+            });
+            AccountOrders::<T>::insert(who, index, order_id);
+        }
+
+        // Add a new order to the status index and, if it is open, to its book
+        pub(crate) fn index_order(order_id: &T::Hash, order: &TradeOrder<T>) -> DispatchResult {
+            if order.status == OrderStatus::Open {
+                Self::add_to_book(order_id, order)?;
+            }
+            OrdersByStatus::<T>::insert(&order.status, order_id, ());
+            Ok(())
+        }
+
+        // Move an order to another status index, taking it off the book once it is no longer
+        // open. Orders becoming open must be added with `add_to_book` first.
+        pub(crate) fn set_status(order_id: &T::Hash, order: &mut TradeOrder<T>, status: OrderStatus) {
+            if order.status == OrderStatus::Open {
+                Self::remove_from_book(order_id, order);
+            }
+            OrdersByStatus::<T>::remove(&order.status, order_id);
+            OrdersByStatus::<T>::insert(&status, order_id, ());
+            order.status = status;
+        }
+
+        pub(crate) fn add_to_book(order_id: &T::Hash, order: &TradeOrder<T>) -> DispatchResult {
+            let market = order.market;
+            let price = order.price_per_unit;
+            PriceLevels::<T>::try_mutate(
+                (market, &order.grid_location, order.delivery_slot, &order.order_type),
+                |levels| -> DispatchResult {
+                    if let Err(index) = levels.binary_search(&price) {
+                        levels.try_insert(index, price).map_err(|_| Error::<T>::TooManyPriceLevels)?;
+                    }
+                    OrderBook::<T>::try_mutate(
+                        (market, &order.grid_location, order.delivery_slot, &order.order_type, price),
+                        |queue| queue.try_push(*order_id).map_err(|_| Error::<T>::PriceLevelFull),
+                    )?;
+                    BookLocations::<T>::insert(order.delivery_slot, (market, &order.grid_location), ());
+                    Ok(())
+                },
+            )
+        }
+
+        fn remove_from_book(order_id: &T::Hash, order: &TradeOrder<T>) {
+            let market = order.market;
+            let price = order.price_per_unit;
+            let key = (market, &order.grid_location, order.delivery_slot, &order.order_type, price);
+            let mut queue = OrderBook::<T>::get(key);
+            queue.retain(|id| id != order_id);
+            if !queue.is_empty() {
+                OrderBook::<T>::insert(key, queue);
+                return;
+            }
+
+            OrderBook::<T>::remove(key);
+            PriceLevels::<T>::mutate((market, &order.grid_location, order.delivery_slot, &order.order_type), |levels| {
+                if let Ok(index) = levels.binary_search(&price) {
+                    levels.remove(index);
+                }
+            });
+        }
+
+        // Take liquidity for an incoming order at the resting orders' prices
+        fn sweep(order_id: T::Hash) -> DispatchResult {
+            let order = TradeOrders::<T>::get(order_id).ok_or(Error::<T>::OrderNotFound)?;
+
+            for (resting_id, resting) in Self::crossing_orders(
+                order.market,
+                &order.order_type,
+                &order.grid_location,
+                order.delivery_slot,
+                Some(order.price_per_unit),
+            ) {
+                let remaining = Self::trade_orders(order_id)
+                    .filter(|o| o.status == OrderStatus::Open)
+                    .map(|o| o.energy_amount)
+                    .unwrap_or_default();
+                if remaining.is_zero() {
+                    break;
+                }
+
+                let amount = remaining.min(resting.energy_amount);
+                let (ask_id, bid_id) = match order.order_type {
+                    OrderType::Ask => (order_id, resting_id),
+                    OrderType::Bid => (resting_id, order_id),
+                };
+                if resting.creator == order.creator {
+                    let mode = Self::self_trade_prevention();
+                    Self::deposit_event(Event::SelfTradePrevented { ask_id, bid_id, mode });
+                    match mode {
+                        SelfTradePrevention::CancelNewest => {
+                            Self::cancel_remainder(order_id);
+                            break;
+                        },
+                        SelfTradePrevention::CancelOldest => Self::cancel_remainder(resting_id),
+                        SelfTradePrevention::DecrementBoth => {
+                            Self::cancel_amount(order_id, amount)?;
+                            Self::cancel_amount(resting_id, amount)?;
+                        },
+                    }
+                    continue;
+                }
+                if let Some(constraint) = Self::blocking_constraint(ask_id, bid_id) {
+                    Self::deposit_event(Event::MatchBlocked { ask_id, bid_id, constraint });
+                    continue;
+                }
+                Self::execute_fill(ask_id, bid_id, amount, resting.price_per_unit)?;
+            }
+
+            Ok(())
+        }
+
+        // Cancel `amount` of an open order; the rest stays open under its id
+        fn cancel_amount(order_id: T::Hash, amount: TokenBalanceOf<T>) -> DispatchResult {
+            let cancelled_id = Self::split_order(order_id, amount)?;
+            Self::cancel_remainder(cancelled_id);
+            Ok(())
+        }
+
+        fn cancel_remainder(order_id: T::Hash) {
+            TradeOrders::<T>::mutate(order_id, |maybe_order| {
+                if let Some(order) = maybe_order {
+                    if order.status == OrderStatus::Open {
+                        Self::release_hold(order);
+                        Self::set_status(&order_id, order, OrderStatus::Cancelled);
+                        Self::deposit_event(Event::OrderCancelled { order_id });
+                    }
+                }
+            });
+        }
+
+        // Called by price discovery on every price update for `location`
+        pub fn trigger_stop_orders(location: &[u8], reference_price: TokenBalanceOf<T>) {
+            let Ok(bounded_location) = BoundedVec::<u8, ConstU32<32>>::try_from(location.to_vec()) else {
+                return;
+            };
+            // Stops wait for the market to reopen
+            if Self::is_halted(location) {
+                return;
+            }
+
+            StopOrders::<T>::mutate(&bounded_location, |stops| {
+                stops.retain(|order_id| {
+                    let Some(mut order) = Self::trade_orders(order_id) else { return false };
+                    let OrderKind::Stop { trigger_price } = order.order_kind else { return false };
+                    if order.status != OrderStatus::Pending {
+                        return false;
+                    }
+
+                    let triggered = match order.order_type {
+                        OrderType::Bid => reference_price >= trigger_price,
+                        OrderType::Ask => reference_price <= trigger_price,
+                    };
+                    if !triggered {
+                        return true;
+                    }
+
+                    // Stays pending until its price level has room
+                    if Self::add_to_book(order_id, &order).is_err() {
+                        return true;
+                    }
+                    Self::set_status(order_id, &mut order, OrderStatus::Open);
+                    TradeOrders::<T>::insert(order_id, order);
+                    Self::deposit_event(Event::StopOrderTriggered {
+                        order_id: *order_id,
+                        reference_price,
+                    });
+
+                    // Triggered stops take what crosses and then rest as limit orders
+                    if Self::matching_mode() == MatchingMode::Continuous {
+                        let _ = frame_support::storage::with_storage_layer(|| Self::sweep(*order_id));
+                    }
+                    false
+                })
+            });
+        }
+
+        // Open an escrowed ask/bid pair and match it straight away, e.g. for contracts
+        // agreed outside the order book. Returns the ask id used for settlement.
+        pub fn create_matched_trade(
+            seller: T::AccountId,
+            buyer: T::AccountId,
+            energy_amount: TokenBalanceOf<T>,
+            price_per_unit: TokenBalanceOf<T>,
+            grid_location: Vec<u8>,
+            delivery_slot: DeliverySlot,
+        ) -> Result<T::Hash, DispatchError> {
+            let ask_id = Self::do_create_order(DEFAULT_MARKET, seller, OrderType::Ask, OrderKind::Limit, energy_amount, price_per_unit, grid_location.clone(), delivery_slot)?;
+            let bid_id = Self::do_create_order(DEFAULT_MARKET, buyer, OrderType::Bid, OrderKind::Limit, energy_amount, price_per_unit, grid_location, delivery_slot)?;
+            let (ask_id, _) = Self::execute_fill(ask_id, bid_id, energy_amount, price_per_unit)?;
+            Ok(ask_id)
+        }
+
+        // Counts an order or commitment against the account's allowance for the interval, so
+        // one account cannot fill the book with minimum-lot orders
+        fn note_auction_entry(interval: IntervalIndex, who: &T::AccountId) -> DispatchResult {
+            AuctionEntries::<T>::try_mutate(interval, who, |count| {
+                ensure!(*count < T::MaxAuctionOrdersPerAccount::get(), Error::<T>::TooManyAuctionOrders);
+                *count += 1;
+                Ok(())
+            })
+        }
+
+        // Clear the book of a closed interval at one uniform price per delivery slot
+        pub(crate) fn clear_auction(interval: IntervalIndex) -> Weight {
+            let book = AuctionBook::<T>::take(interval);
+            let _ = AuctionEntries::<T>::clear_prefix(interval, T::MaxAuctionOrders::get().saturating_mul(2), None);
+            let book_len = book.len() as u64;
+
+            let mut slots: BTreeMap<(MarketId, DeliverySlot), (Vec<_>, Vec<_>)> = BTreeMap::new();
+            for order_id in book.iter() {
+                if let Some(order) = Self::trade_orders(order_id) {
+                    if order.status != OrderStatus::Open {
+                        continue;
+                    }
+                    let (asks, bids) = slots.entry((order.market, order.delivery_slot)).or_default();
+                    match order.order_type {
+                        OrderType::Ask => asks.push((*order_id, order.price_per_unit, order.energy_amount)),
+                        OrderType::Bid => bids.push((*order_id, order.price_per_unit, order.energy_amount)),
+                    }
+                }
+            }
+
+            for ((market, delivery_slot), (asks, bids)) in slots {
+                Self::clear_slot(market, interval, delivery_slot, asks, bids);
+            }
+
+            // Commitments nobody revealed lose their deposit
+            let unrevealed = Commitments::<T>::take(interval);
+            for sealed in unrevealed.iter() {
+                let _ = T::Currency::repatriate_reserved(
+                    &sealed.who,
+                    &T::TreasuryAccount::get(),
+                    Self::to_currency(sealed.deposit),
+                    BalanceStatus::Free,
+                );
+                Self::deposit_event(Event::CommitmentForfeited {
+                    interval,
+                    who: sealed.who.clone(),
+                    commitment: sealed.commitment,
+                    deposit: sealed.deposit,
+                });
+            }
+
+            // Whatever did not clear expires with the interval
+            for order_id in book.iter() {
+                TradeOrders::<T>::mutate(order_id, |maybe_order| {
+                    if let Some(order) = maybe_order {
+                        if order.status == OrderStatus::Open {
+                            Self::release_hold(order);
+                            Self::set_status(order_id, order, OrderStatus::Cancelled);
+                            Self::deposit_event(Event::AuctionOrderExpired {
+                                order_id: *order_id,
+                                interval,
+                            });
+                        }
+                    }
+                });
+            }
+
+            let unrevealed_len = unrevealed.len() as u64;
+            T::DbWeight::get().reads_writes(
+                book_len.saturating_mul(3).saturating_add(unrevealed_len).saturating_add(3),
+                book_len.saturating_mul(4).saturating_add(unrevealed_len.saturating_mul(2)).saturating_add(3),
+            )
+        }
+
+        fn clear_slot(
+            market: MarketId,
+            interval: IntervalIndex,
+            delivery_slot: DeliverySlot,
+            mut asks: Vec<(T::Hash, TokenBalanceOf<T>, TokenBalanceOf<T>)>,
+            mut bids: Vec<(T::Hash, TokenBalanceOf<T>, TokenBalanceOf<T>)>,
+        ) {
+            if let Some((clearing_price, volume, supply, demand)) = Self::find_clearing_point(&asks, &bids) {
+                // Price priority first; the sort is stable so submission order breaks ties
+                asks.retain(|(_, price, _)| *price <= clearing_price);
+                asks.sort_by(|a, b| a.1.cmp(&b.1));
+                bids.retain(|(_, price, _)| *price >= clearing_price);
+                bids.sort_by(|a, b| b.1.cmp(&a.1));
+
+                let ask_fills = Self::allocate_pro_rata(&asks, volume);
+                let bid_fills = Self::allocate_pro_rata(&bids, volume);
+
+                // Pair the allocations into bilateral fills at the clearing price
+                let (mut i, mut j) = (0, 0);
+                let mut ask_left = ask_fills.first().map(|f| f.1).unwrap_or_default();
+                let mut bid_left = bid_fills.first().map(|f| f.1).unwrap_or_default();
+                while i < ask_fills.len() && j < bid_fills.len() {
+                    let amount = ask_left.min(bid_left);
+                    if !amount.is_zero() {
+                        let (ask_id, bid_id) = (ask_fills[i].0, bid_fills[j].0);
+                        // A blocked pair or a self-trade leaves both sides' allocation unfilled
+                        let same_creator = Self::trade_orders(ask_id).map(|o| o.creator) ==
+                            Self::trade_orders(bid_id).map(|o| o.creator);
+                        match Self::blocking_constraint(ask_id, bid_id) {
+                            _ if same_creator => Self::deposit_event(Event::SelfTradePrevented {
+                                ask_id,
+                                bid_id,
+                                mode: Self::self_trade_prevention(),
+                            }),
+                            Some(constraint) => Self::deposit_event(Event::MatchBlocked { ask_id, bid_id, constraint }),
+                            None => {
+                                let _ = frame_support::storage::with_storage_layer(|| {
+                                    Self::execute_fill(ask_id, bid_id, amount, clearing_price)
+                                });
+                            },
+                        }
+                    }
+                    ask_left = ask_left.saturating_sub(amount);
+                    bid_left = bid_left.saturating_sub(amount);
+                    if ask_left.is_zero() {
+                        i += 1;
+                        ask_left = ask_fills.get(i).map(|f| f.1).unwrap_or_default();
+                    }
+                    if bid_left.is_zero() {
+                        j += 1;
+                        bid_left = bid_fills.get(j).map(|f| f.1).unwrap_or_default();
+                    }
+                }
+
+                let result = AuctionResult {
+                    clearing_price,
+                    cleared_volume: volume,
+                    supply,
+                    demand,
+                    cleared_at: <frame_system::Pallet<T>>::block_number(),
+                };
+                AuctionResults::<T>::insert((market, interval, delivery_slot), result);
+
+                Self::deposit_event(Event::AuctionCleared {
+                    market,
+                    interval,
+                    delivery_slot,
+                    clearing_price,
+                    volume,
+                });
+            }
+        }
+
+        // Returns (price, volume, supply, demand) maximising traded volume, preferring the
+        // smallest supply/demand imbalance and then the lowest price
+        fn find_clearing_point(
+            asks: &[(T::Hash, TokenBalanceOf<T>, TokenBalanceOf<T>)],
+            bids: &[(T::Hash, TokenBalanceOf<T>, TokenBalanceOf<T>)],
+        ) -> Option<(TokenBalanceOf<T>, TokenBalanceOf<T>, TokenBalanceOf<T>, TokenBalanceOf<T>)> {
+            let mut prices: Vec<TokenBalanceOf<T>> = asks.iter()
+                .chain(bids.iter())
+                .map(|(_, price, _)| *price)
+                .collect();
+            prices.sort();
+            prices.dedup();
+
+            let mut best: Option<(TokenBalanceOf<T>, TokenBalanceOf<T>, TokenBalanceOf<T>, TokenBalanceOf<T>)> = None;
+            for price in prices {
+                let supply = asks.iter()
+                    .filter(|(_, p, _)| *p <= price)
+                    .fold(TokenBalanceOf::<T>::zero(), |acc, (_, _, amount)| acc.saturating_add(*amount));
+                let demand = bids.iter()
+                    .filter(|(_, p, _)| *p >= price)
+                    .fold(TokenBalanceOf::<T>::zero(), |acc, (_, _, amount)| acc.saturating_add(*amount));
+                let volume = supply.min(demand);
+                if volume.is_zero() {
+                    continue;
+                }
+
+                let imbalance = supply.max(demand) - volume;
+                let better = match best {
+                    None => true,
+                    Some((_, best_volume, best_supply, best_demand)) => {
+                        let best_imbalance = best_supply.max(best_demand) - best_volume;
+                        volume > best_volume || (volume == best_volume && imbalance < best_imbalance)
+                    },
+                };
+                if better {
+                    best = Some((price, volume, supply, demand));
+                }
+            }
+            best
+        }
+
+        // Fill whole price levels in priority order and ration the marginal level pro rata
+        fn allocate_pro_rata(
+            orders: &[(T::Hash, TokenBalanceOf<T>, TokenBalanceOf<T>)],
+            volume: TokenBalanceOf<T>,
+        ) -> Vec<(T::Hash, TokenBalanceOf<T>)> {
+            let mut fills = Vec::new();
+            let mut remaining = volume;
+            let mut start = 0;
+
+            while start < orders.len() && !remaining.is_zero() {
+                let level_price = orders[start].1;
+                let end = orders[start..].iter()
+                    .position(|(_, price, _)| *price != level_price)
+                    .map(|offset| start + offset)
+                    .unwrap_or(orders.len());
+                let level = &orders[start..end];
+                let level_total = level.iter()
+                    .fold(TokenBalanceOf::<T>::zero(), |acc, (_, _, amount)| acc.saturating_add(*amount));
+
+                if level_total <= remaining {
+                    fills.extend(level.iter().map(|(id, _, amount)| (*id, *amount)));
+                    remaining = remaining.saturating_sub(level_total);
+                } else {
+                    let mut level_fills: Vec<(T::Hash, TokenBalanceOf<T>)> = level.iter()
+                        .map(|(id, _, amount)| (*id, Self::pro_rata(*amount, remaining, level_total)))
+                        .collect();
+
+                    // Hand out rounding dust one unit at a time in time priority
+                    let allocated = level_fills.iter()
+                        .fold(TokenBalanceOf::<T>::zero(), |acc, (_, fill)| acc.saturating_add(*fill));
+                    let mut dust = remaining.saturating_sub(allocated);
+                    for (fill, (_, _, amount)) in level_fills.iter_mut().zip(level.iter()) {
+                        if dust.is_zero() {
+                            break;
+                        }
+                        if fill.1 < *amount {
+                            fill.1 = fill.1.saturating_add(One::one());
+                            dust = dust.saturating_sub(One::one());
+                        }
+                    }
+
+                    fills.extend(level_fills.into_iter().filter(|(_, fill)| !fill.is_zero()));
+                    remaining = Zero::zero();
+                }
+                start = end;
+            }
+            fills
+        }
+
+        // Match `amount` of an ask against a bid at `price`, splitting off partially filled orders
+        pub(crate) fn execute_fill(
+            ask_id: T::Hash,
+            bid_id: T::Hash,
+            amount: TokenBalanceOf<T>,
+            price: TokenBalanceOf<T>,
+        ) -> Result<(T::Hash, T::Hash), DispatchError> {
+            ensure!(Self::blocking_constraint(ask_id, bid_id).is_none(), Error::<T>::BidConstraintViolated);
+            Self::fill(ask_id, bid_id, amount, price, true)
+        }
+
+        // `execute_fill` without the bid constraints; the supplier of last resort also skips
+        // halts and the bid's limit price (`market_rules` false)
+        fn fill(
+            ask_id: T::Hash,
+            bid_id: T::Hash,
+            amount: TokenBalanceOf<T>,
+            price: TokenBalanceOf<T>,
+            market_rules: bool,
+        ) -> Result<(T::Hash, T::Hash), DispatchError> {
+            let ask_id = Self::split_order(ask_id, amount)?;
+            let bid_id = Self::split_order(bid_id, amount)?;
+
+            let mut ask_order = TradeOrders::<T>::get(ask_id).ok_or(Error::<T>::OrderNotFound)?;
+            let mut bid_order = TradeOrders::<T>::get(bid_id).ok_or(Error::<T>::OrderNotFound)?;
+            let total_price = price.checked_mul(&amount).ok_or(Error::<T>::InvalidPrice)?;
+            let current_block = <frame_system::Pallet<T>>::block_number();
+
+            let market = ask_order.market;
+            if market_rules {
+                ensure!(bid_order.market == market, Error::<T>::MarketMismatch);
+                ensure!(ask_order.creator != bid_order.creator, Error::<T>::SelfTrade);
+                Self::ensure_not_halted(&ask_order.grid_location)?;
+                Self::ensure_not_halted(&bid_order.grid_location)?;
+                ensure!(price <= bid_order.price_per_unit, Error::<T>::OrderMismatch);
+            }
+
+            // The order placed first is the maker; on a tie the ask is
+            let schedule = Self::fee_schedule_for(market, &ask_order.grid_location);
+            let (ask_bps, bid_bps) = if ask_order.created_at <= bid_order.created_at {
+                (schedule.maker_fee_bps, schedule.taker_fee_bps)
+            } else {
+                (schedule.taker_fee_bps, schedule.maker_fee_bps)
+            };
+            let ask_fee = Self::trade_fee(total_price, ask_bps, &schedule);
+            let bid_fee = Self::trade_fee(total_price, bid_bps, &schedule);
+
+            // The buyer keeps the trade value and their fee on hold; the bid already holds its
+            // highest possible fee, so only a minimum fee on a partial fill can need more
+            let held = bid_order.total_price.saturating_add(bid_order.fee);
+            let needed = total_price.saturating_add(bid_fee);
+            if held >= needed {
+                T::Currency::unreserve(&bid_order.creator, Self::to_currency(held.saturating_sub(needed)));
+            } else {
+                T::Currency::reserve(&bid_order.creator, Self::to_currency(needed.saturating_sub(held)))
+                    .map_err(|_| Error::<T>::InsufficientBalance)?;
+            }
+
+            let seller = ask_order.creator.clone();
+            let buyer = bid_order.creator.clone();
+            for (order_id, order, counterparty, matched_id, fee) in [
+                (ask_id, &mut ask_order, buyer, bid_id, ask_fee),
+                (bid_id, &mut bid_order, seller, ask_id, bid_fee),
+            ] {
+                // Leaves the book at the price it was queued at
+                Self::set_status(&order_id, order, OrderStatus::Matched);
+                order.fee = fee;
+                order.counterparty = Some(counterparty);
+                order.matched_at = Some(current_block);
+                order.matched_order = Some(matched_id);
+                order.price_per_unit = price;
+                order.total_price = total_price;
+            }
+
+            <TradeOrders<T>>::insert(ask_id, ask_order.clone());
+            <TradeOrders<T>>::insert(bid_id, bid_order.clone());
+
+            // Retailer trades are at tariff prices and never count as market prices
+            if T::Participants::are_related(&ask_order.creator, &bid_order.creator) {
+                WashTrades::<T>::insert(ask_id, bid_id);
+                Self::deposit_event(Event::WashTradeFlagged {
+                    ask_id,
+                    bid_id,
+                    seller: ask_order.creator.clone(),
+                    buyer: bid_order.creator.clone(),
+                });
+            } else if market_rules {
+                MarketStats::<T>::mutate(market, &ask_order.grid_location, |stats| {
+                    stats.last_price = price;
+                    stats.volume = stats.volume.saturating_add(amount);
+                    stats.trades = stats.trades.saturating_add(1);
+                });
+                T::Statistics::record_trade(market, &ask_order.grid_location, price, amount);
+            }
+
+            Self::deposit_event(Event::OrdersMatched {
+                ask_id,
+                bid_id,
+                seller: ask_order.creator,
+                buyer: bid_order.creator,
+                amount,
+                price: total_price,
+            });
+
+            Ok((ask_id, bid_id))
+        }
+
+        pub fn slots_per_day() -> u32 {
+            (86_400_000 / T::SlotDuration::get().max(1)).max(1) as u32
+        }
+
+        // Time-of-use tariff in force during `slot`
+        pub fn tariff_for(slot: DeliverySlot) -> Option<TariffPeriod<TokenBalanceOf<T>>> {
+            let slot_of_day = slot % Self::slots_per_day();
+            Self::tariffs().into_iter().take_while(|period| period.start_slot <= slot_of_day).last()
+        }
+
+        // Settle orders left open on the books of ended slots against the retailer, oldest
+        // slot first and at most `budget` orders or empty slots per call
+        pub(crate) fn close_slots(budget: u32) -> Weight {
+            // Until `on_finalize` seeds the first slot there is nothing to close; the weight
+            // covers that seeding
+            let Some(mut slot) = Self::next_slot_to_close() else {
+                return T::DbWeight::get().reads_writes(1, 1);
+            };
+            let current = Self::current_slot();
+
+            let (mut reads, mut writes, mut budget) = (1u64, 0u64, budget);
+            while slot < current && budget > 0 {
+                reads += 1;
+                let Some((market, location)) = BookLocations::<T>::iter_key_prefix(slot).next() else {
+                    slot += 1;
+                    budget -= 1;
+                    continue;
+                };
+
+                let mut orders = Vec::new();
+                for order_type in [OrderType::Bid, OrderType::Ask] {
+                    for price in Self::price_levels((market, &location, slot, &order_type)) {
+                        orders.extend(Self::order_book((market, &location, slot, &order_type, price)).into_inner());
+                    }
+                }
+                if orders.is_empty() {
+                    budget -= 1;
+                }
+                let remaining = orders.len() > budget as usize;
+                for order_id in orders.into_iter().take(budget as usize) {
+                    Self::settle_with_retailer(order_id);
+                    budget -= 1;
+                    reads += 4;
+                    writes += 8;
+                }
+                if !remaining {
+                    BookLocations::<T>::remove(slot, (market, &location));
+                    writes += 1;
+                }
+                reads += 2 * T::MaxPriceLevels::get() as u64;
+            }
+
+            NextSlotToClose::<T>::put(slot);
+            T::DbWeight::get().reads_writes(reads, writes.saturating_add(1))
+        }
+
+        // Match an unmatched order with a new retailer order at the tariff in force. Without
+        // a retailer or tariff, or if either side cannot cover it, the order is cancelled.
+        fn settle_with_retailer(order_id: T::Hash) {
+            let settled = frame_support::storage::with_storage_layer(|| -> DispatchResult {
+                let retailer = Self::retailer().ok_or(Error::<T>::NoRetailer)?;
+                let order = Self::trade_orders(order_id).ok_or(Error::<T>::OrderNotFound)?;
+                let tariff = Self::tariff_for(order.delivery_slot).ok_or(Error::<T>::NoRetailer)?;
+                let (retailer_type, price) = match order.order_type {
+                    OrderType::Bid => (OrderType::Ask, tariff.retail_price),
+                    OrderType::Ask => (OrderType::Bid, tariff.feed_in_price),
+                };
+
+                let retailer_order_id = Self::open_retailer_order(retailer, retailer_type, &order_id, &order, price)?;
+                let (ask_id, bid_id) = match order.order_type {
+                    OrderType::Bid => (retailer_order_id, order_id),
+                    OrderType::Ask => (order_id, retailer_order_id),
+                };
+                Self::fill(ask_id, bid_id, order.energy_amount, price, false)?;
+
+                Self::deposit_event(Event::LastResortSettled {
+                    order_id,
+                    retailer_order_id,
+                    amount: order.energy_amount,
+                    price,
+                });
+                Ok(())
+            });
+
+            if settled.is_err() {
+                Self::cancel_remainder(order_id);
+            }
+        }
+
+        // The retailer's side of a last-resort trade, escrowed like any order but never booked
+        fn open_retailer_order(
+            retailer: T::AccountId,
+            order_type: OrderType,
+            order_id: &T::Hash,
+            order: &TradeOrder<T>,
+            price_per_unit: TokenBalanceOf<T>,
+        ) -> Result<T::Hash, DispatchError> {
+            let total_price = price_per_unit
+                .checked_mul(&order.energy_amount)
+                .ok_or(Error::<T>::InvalidPrice)?;
+            let market = order.market;
+            let fee = match order_type {
+                OrderType::Ask => {
+                    pallet_energy_token::Pallet::<T>::reserve(&retailer, Self::to_energy(order.energy_amount))?;
+                    Zero::zero()
+                },
+                OrderType::Bid => {
+                    let fee = Self::max_fee(market, &order.grid_location, total_price);
+                    T::Currency::reserve(&retailer, Self::to_currency(total_price.saturating_add(fee)))
+                        .map_err(|_| Error::<T>::InsufficientBalance)?;
+                    fee
+                },
+            };
+
+            let retailer_order = TradeOrder {
+                order_type,
+                order_kind: OrderKind::Limit,
+                creator: retailer.clone(),
+                counterparty: None,
+                energy_amount: order.energy_amount,
+                price_per_unit,
+                total_price,
+                status: OrderStatus::Open,
+                market,
+                grid_location: order.grid_location.clone(),
+                delivery_slot: order.delivery_slot,
+                created_at: <frame_system::Pallet<T>>::block_number(),
+                matched_at: None,
+                completed_at: None,
+                transfer_verification: None,
+                matched_order: None,
+                delivered_amount: None,
+                fee,
+            };
+            let retailer_order_id = T::Hashing::hash_of(&(order_id, &retailer_order));
+
+            OrdersByStatus::<T>::insert(OrderStatus::Open, retailer_order_id, ());
+            Self::push_account_order(&retailer, retailer_order_id);
+            <TradeOrders<T>>::insert(retailer_order_id, retailer_order);
+
+            Ok(retailer_order_id)
+        }
+
+        // Carve `amount` out of an open order into its own record; the original id keeps the
+        // remainder. Returns the id holding exactly `amount`.
+        pub(crate) fn split_order(
+            order_id: T::Hash,
+            amount: TokenBalanceOf<T>,
+        ) -> Result<T::Hash, DispatchError> {
+            let mut order = TradeOrders::<T>::get(order_id).ok_or(Error::<T>::OrderNotFound)?;
+            ensure!(order.status == OrderStatus::Open, Error::<T>::InvalidOrderStatus);
+            ensure!(!amount.is_zero() && amount <= order.energy_amount, Error::<T>::InvalidAmount);
+            if amount == order.energy_amount {
+                return Ok(order_id);
+            }
+
+            let mut child = order.clone();
+            child.energy_amount = amount;
+            child.total_price = order.price_per_unit
+                .checked_mul(&amount)
+                .ok_or(Error::<T>::InvalidPrice)?;
+
+            // An open bid's fee hold is shared out with its amount
+            child.fee = Self::pro_rata(order.fee, amount, order.energy_amount);
+
+            order.energy_amount = order.energy_amount.saturating_sub(amount);
+            order.total_price = order.total_price.saturating_sub(child.total_price);
+            order.fee = order.fee.saturating_sub(child.fee);
+
+            // The parent's remaining amount strictly decreases, keeping child ids unique
+            let child_id = T::Hashing::hash_of(&(order_id, order.energy_amount, &child));
+
+            // The parent keeps its place in the queue; the child is matched by the caller
+            // straight away, so it never joins the book
+            OrdersByStatus::<T>::insert(OrderStatus::Open, child_id, ());
+            Self::push_account_order(&order.creator, child_id);
+            if let Some(constraints) = Self::bid_constraints(order_id) {
+                BidConstraintsOf::<T>::insert(child_id, constraints);
+            }
+            <TradeOrders<T>>::insert(order_id, order);
+            <TradeOrders<T>>::insert(child_id, child);
+
+            Ok(child_id)
+        }
     }
 }
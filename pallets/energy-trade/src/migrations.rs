@@ -0,0 +1,254 @@
+use crate::pallet::{Config, OrderStatus, Pallet, TradeOrders};
+use frame_support::{
+    pallet_prelude::*,
+    storage_alias,
+    traits::{GetStorageVersion, OnRuntimeUpgrade, StorageVersion},
+};
+use sp_std::marker::PhantomData;
+
+pub mod v1 {
+    use super::*;
+
+    #[storage_alias]
+    type UserOrders<T: Config> = StorageMap<
+        Pallet<T>,
+        Blake2_128Concat,
+        <T as frame_system::Config>::AccountId,
+        BoundedVec<<T as frame_system::Config>::Hash, ConstU32<100>>,
+        ValueQuery,
+    >;
+
+    // Builds the order book, status and account indices from the existing orders and drops
+    // the capped `UserOrders` lists. Open orders that do not fit on their book are cancelled
+    // and their holds released.
+    pub struct MigrateToIndexedStorage<T>(PhantomData<T>);
+
+    impl<T: Config> OnRuntimeUpgrade for MigrateToIndexedStorage<T> {
+        fn on_runtime_upgrade() -> Weight {
+            if Pallet::<T>::on_chain_storage_version() >= 1 {
+                return T::DbWeight::get().reads(1);
+            }
+
+            let (mut reads, mut writes) = (1u64, 1u64);
+
+            for (who, orders) in UserOrders::<T>::drain() {
+                reads += 1;
+                writes += 1;
+                for order_id in orders {
+                    Pallet::<T>::push_account_order(&who, order_id);
+                    reads += 1;
+                    writes += 2;
+                }
+            }
+
+            for (order_id, mut order) in TradeOrders::<T>::iter() {
+                reads += 3;
+                writes += 3;
+                if Pallet::<T>::index_order(&order_id, &order).is_err() {
+                    Pallet::<T>::release_hold(&order);
+                    order.status = OrderStatus::Cancelled;
+                    let _ = Pallet::<T>::index_order(&order_id, &order);
+                    TradeOrders::<T>::insert(order_id, order);
+                    writes += 3;
+                }
+            }
+
+            // The indices were built in the current layout, so later migrations are not needed
+            Pallet::<T>::in_code_storage_version().put::<Pallet<T>>();
+            T::DbWeight::get().reads_writes(reads, writes)
+        }
+    }
+}
+
+pub mod v2 {
+    use super::*;
+    use crate::pallet::{DeliverySlot, OrderType, DEFAULT_MARKET};
+    use sp_std::prelude::*;
+
+    type Location = BoundedVec<u8, ConstU32<32>>;
+
+    #[storage_alias]
+    type OrderBook<T: Config> = StorageNMap<
+        Pallet<T>,
+        (
+            NMapKey<Blake2_128Concat, Location>,
+            NMapKey<Twox64Concat, DeliverySlot>,
+            NMapKey<Twox64Concat, OrderType>,
+            NMapKey<Twox64Concat, <T as Config>::TokenBalance>,
+        ),
+        BoundedVec<<T as frame_system::Config>::Hash, <T as Config>::MaxOrdersPerLevel>,
+        ValueQuery,
+    >;
+
+    #[storage_alias]
+    type PriceLevels<T: Config> = StorageNMap<
+        Pallet<T>,
+        (
+            NMapKey<Blake2_128Concat, Location>,
+            NMapKey<Twox64Concat, DeliverySlot>,
+            NMapKey<Twox64Concat, OrderType>,
+        ),
+        BoundedVec<<T as Config>::TokenBalance, <T as Config>::MaxPriceLevels>,
+        ValueQuery,
+    >;
+
+    #[storage_alias]
+    type BookLocations<T: Config> = StorageDoubleMap<
+        Pallet<T>,
+        Twox64Concat,
+        DeliverySlot,
+        Blake2_128Concat,
+        Location,
+        (),
+        OptionQuery,
+    >;
+
+    // Moves the order books and their slot index under `DEFAULT_MARKET`, the market of every
+    // existing order
+    pub struct MigrateToMarkets<T>(PhantomData<T>);
+
+    impl<T: Config> OnRuntimeUpgrade for MigrateToMarkets<T> {
+        fn on_runtime_upgrade() -> Weight {
+            if Pallet::<T>::on_chain_storage_version() != 1 {
+                return T::DbWeight::get().reads(1);
+            }
+
+            // Collected before writing, as the new keys share the storage prefixes
+            let books: Vec<_> = OrderBook::<T>::drain().collect();
+            let levels: Vec<_> = PriceLevels::<T>::drain().collect();
+            let locations: Vec<_> = BookLocations::<T>::drain().collect();
+            let entries = (books.len() + levels.len() + locations.len()) as u64;
+
+            for ((location, slot, order_type, price), queue) in books {
+                crate::pallet::OrderBook::<T>::insert((DEFAULT_MARKET, location, slot, order_type, price), queue);
+            }
+            for ((location, slot, order_type), prices) in levels {
+                crate::pallet::PriceLevels::<T>::insert((DEFAULT_MARKET, location, slot, order_type), prices);
+            }
+            for (slot, location, ()) in locations {
+                crate::pallet::BookLocations::<T>::insert(slot, (DEFAULT_MARKET, location), ());
+            }
+
+            StorageVersion::new(2).put::<Pallet<T>>();
+            T::DbWeight::get().reads_writes(entries.saturating_add(1), entries.saturating_mul(2).saturating_add(1))
+        }
+    }
+}
+
+pub mod v3 {
+    use super::*;
+    use crate::pallet::{
+        AuctionResult, DeliverySlot, IntervalIndex, MarketId, OrderKind, OrderType, TradeOrder, DEFAULT_MARKET,
+    };
+    use frame_system::pallet_prelude::BlockNumberFor;
+    use sp_std::prelude::*;
+
+    #[derive(Decode)]
+    struct OldTradeOrder<T: Config> {
+        order_type: OrderType,
+        order_kind: OrderKind<<T as Config>::TokenBalance>,
+        creator: T::AccountId,
+        counterparty: Option<T::AccountId>,
+        energy_amount: <T as Config>::TokenBalance,
+        price_per_unit: <T as Config>::TokenBalance,
+        total_price: <T as Config>::TokenBalance,
+        status: OrderStatus,
+        grid_location: BoundedVec<u8, ConstU32<32>>,
+        delivery_slot: DeliverySlot,
+        created_at: BlockNumberFor<T>,
+        matched_at: Option<BlockNumberFor<T>>,
+        completed_at: Option<BlockNumberFor<T>>,
+        transfer_verification: Option<T::Hash>,
+        matched_order: Option<T::Hash>,
+        delivered_amount: Option<<T as Config>::TokenBalance>,
+        fee: <T as Config>::TokenBalance,
+    }
+
+    #[storage_alias]
+    type OrderMarkets<T: Config> = StorageMap<
+        Pallet<T>,
+        Blake2_128Concat,
+        <T as frame_system::Config>::Hash,
+        MarketId,
+        ValueQuery,
+    >;
+
+    #[storage_alias]
+    type AuctionResults<T: Config> = StorageDoubleMap<
+        Pallet<T>,
+        Twox64Concat,
+        IntervalIndex,
+        Twox64Concat,
+        DeliverySlot,
+        AuctionResult<T>,
+        OptionQuery,
+    >;
+
+    #[storage_alias]
+    type MarketAuctionResults<T: Config> = StorageNMap<
+        Pallet<T>,
+        (
+            NMapKey<Twox64Concat, MarketId>,
+            NMapKey<Twox64Concat, IntervalIndex>,
+            NMapKey<Twox64Concat, DeliverySlot>,
+        ),
+        AuctionResult<T>,
+        OptionQuery,
+    >;
+
+    // Stores each order's market on the order itself, taken from `OrderMarkets` where an
+    // order has an entry and `DEFAULT_MARKET` otherwise, and keys all auction results by
+    // market
+    pub struct MigrateToOrderMarkets<T>(PhantomData<T>);
+
+    impl<T: Config> OnRuntimeUpgrade for MigrateToOrderMarkets<T> {
+        fn on_runtime_upgrade() -> Weight {
+            if Pallet::<T>::on_chain_storage_version() != 2 {
+                return T::DbWeight::get().reads(1);
+            }
+
+            let mut orders = 0u64;
+            TradeOrders::<T>::translate::<OldTradeOrder<T>, _>(|order_id, old| {
+                orders += 1;
+                Some(TradeOrder {
+                    order_type: old.order_type,
+                    order_kind: old.order_kind,
+                    creator: old.creator,
+                    counterparty: old.counterparty,
+                    energy_amount: old.energy_amount,
+                    price_per_unit: old.price_per_unit,
+                    total_price: old.total_price,
+                    status: old.status,
+                    market: OrderMarkets::<T>::take(order_id),
+                    grid_location: old.grid_location,
+                    delivery_slot: old.delivery_slot,
+                    created_at: old.created_at,
+                    matched_at: old.matched_at,
+                    completed_at: old.completed_at,
+                    transfer_verification: old.transfer_verification,
+                    matched_order: old.matched_order,
+                    delivered_amount: old.delivered_amount,
+                    fee: old.fee,
+                })
+            });
+            // Entries of orders that no longer exist
+            let stale = OrderMarkets::<T>::drain().count() as u64;
+
+            // Collected before writing, as the new keys share the storage prefix
+            let results: Vec<_> = AuctionResults::<T>::drain().collect();
+            let market_results: Vec<_> = MarketAuctionResults::<T>::drain().collect();
+            let entries = (results.len() + market_results.len()) as u64;
+            for (interval, slot, result) in results {
+                crate::pallet::AuctionResults::<T>::insert((DEFAULT_MARKET, interval, slot), result);
+            }
+            for ((market, interval, slot), result) in market_results {
+                crate::pallet::AuctionResults::<T>::insert((market, interval, slot), result);
+            }
+
+            StorageVersion::new(3).put::<Pallet<T>>();
+            let reads = orders.saturating_mul(2).saturating_add(stale).saturating_add(entries).saturating_add(1);
+            let writes = orders.saturating_mul(2).saturating_add(stale).saturating_add(entries.saturating_mul(2)).saturating_add(1);
+            T::DbWeight::get().reads_writes(reads, writes)
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use crate as pallet_energy_trade;
+use frame_support::{
+    derive_impl, parameter_types,
+    traits::{ConstU128, ConstU32, ConstU64, Hooks},
+};
+use frame_system::EnsureRoot;
+use sp_runtime::BuildStorage;
+
+type Block = frame_system::mocking::MockBlock<Test>;
+
+frame_support::construct_runtime!(
+    pub enum Test {
+        System: frame_system,
+        Timestamp: pallet_timestamp,
+        Balances: pallet_balances,
+        EnergyToken: pallet_energy_token,
+        EnergyTrade: pallet_energy_trade,
+    }
+);
+
+#[derive_impl(frame_system::config_preludes::TestDefaultConfig)]
+impl frame_system::Config for Test {
+    type Block = Block;
+    type AccountData = pallet_balances::AccountData<u128>;
+}
+
+#[derive_impl(pallet_balances::config_preludes::TestDefaultConfig)]
+impl pallet_balances::Config for Test {
+    type Balance = u128;
+    type ExistentialDeposit = ConstU128<1>;
+    type AccountStore = System;
+}
+
+impl pallet_timestamp::Config for Test {
+    type Moment = u64;
+    type OnTimestampSet = ();
+    type MinimumPeriod = ConstU64<1>;
+    type WeightInfo = ();
+}
+
+impl pallet_energy_token::Config for Test {
+    type RuntimeEvent = RuntimeEvent;
+    type TokenBalance = u128;
+}
+
+parameter_types! {
+    pub const AuctionInterval: u64 = 10;
+    pub const RevealPeriod: u64 = 3;
+    pub const CommitDeposit: u128 = 5;
+    pub const SlotDuration: u64 = 15 * 60 * 1000;
+    pub const TreasuryAccount: u64 = 100;
+    pub const CommunityFundAccount: u64 = 101;
+    pub const ImbalanceAccount: u64 = 102;
+}
+
+impl pallet_energy_trade::Config for Test {
+    type RuntimeEvent = RuntimeEvent;
+    type TokenBalance = u128;
+    type Currency = Balances;
+    type OperatorOrigin = EnsureRoot<u64>;
+    type MarketAdminOrigin = EnsureRoot<u64>;
+    type Participants = ();
+    type Distances = ();
+    type Statistics = ();
+    type AuctionInterval = AuctionInterval;
+    type MaxAuctionOrders = ConstU32<16>;
+    type MaxAuctionOrdersPerAccount = ConstU32<4>;
+    type MaxStopOrders = ConstU32<8>;
+    type TimeProvider = Timestamp;
+    type SlotDuration = SlotDuration;
+    type RevealPeriod = RevealPeriod;
+    type CommitDeposit = CommitDeposit;
+    type TreasuryAccount = TreasuryAccount;
+    type CommunityFundAccount = CommunityFundAccount;
+    type ImbalancePrices = ();
+    type ImbalanceAccount = ImbalanceAccount;
+    type MaxOrdersPerLevel = ConstU32<8>;
+    type MaxPriceLevels = ConstU32<16>;
+    type MaxFillsPerOrder = ConstU32<8>;
+    type MaxLastResortPerBlock = ConstU32<8>;
+    type RateLimitWindow = ConstU64<10>;
+    type MaxOrdersPerWindow = ConstU32<5>;
+    type MaxOrderValue = ConstU128<1_000_000>;
+    type OrderHooks = ();
+    type WeightInfo = ();
+    #[cfg(feature = "runtime-benchmarks")]
+    type BenchmarkHelper = ();
+}
+
+// Accounts 1 to 5 start with funds; the fee and penalty accounts are endowed so small shares
+// stay above the existential deposit
+pub fn new_test_ext() -> sp_io::TestExternalities {
+    let mut t = frame_system::GenesisConfig::<Test>::default().build_storage().unwrap();
+    pallet_balances::GenesisConfig::<Test> {
+        balances: (1..=5)
+            .chain([TreasuryAccount::get(), CommunityFundAccount::get(), ImbalanceAccount::get()])
+            .map(|who| (who, 10_000))
+            .collect(),
+        ..Default::default()
+    }
+    .assimilate_storage(&mut t)
+    .unwrap();
+
+    let mut ext = sp_io::TestExternalities::new(t);
+    ext.execute_with(|| System::set_block_number(1));
+    ext
+}
+
+pub fn run_to_block(n: u64) {
+    while System::block_number() < n {
+        EnergyTrade::on_finalize(System::block_number());
+        let next = System::block_number() + 1;
+        System::set_block_number(next);
+        EnergyTrade::on_initialize(next);
+    }
+}
@@ -0,0 +1,127 @@
+use crate::pallet::{Call, Config, OrderRates, Pallet};
+use codec::{Decode, DecodeWithMemTracking, Encode};
+use frame_support::{pallet_prelude::*, traits::IsSubType};
+use frame_system::pallet_prelude::BlockNumberFor;
+use scale_info::TypeInfo;
+use sp_runtime::{
+    traits::{
+        AsSystemOriginSigner, DispatchInfoOf, DispatchOriginOf, Dispatchable, Implication, One, Saturating,
+        TransactionExtension, UniqueSaturatedInto, ValidateResult,
+    },
+    transaction_validity::{TransactionSource, TransactionValidityError, ValidTransaction},
+};
+use sp_std::{marker::PhantomData, vec};
+
+// `InvalidTransaction::Custom` code of an order submitted over the account's allowance
+pub const ORDER_RATE_EXCEEDED: u8 = 1;
+
+// Counts the orders each account submits per `Config::RateLimitWindow` and rejects those over
+// its allowance, so the transaction pool drops them before they take up block space
+#[derive(Encode, Decode, DecodeWithMemTracking, Clone, Eq, PartialEq, TypeInfo)]
+#[scale_info(skip_type_params(T))]
+pub struct CheckOrderRate<T: Config + Send + Sync>(PhantomData<T>);
+
+impl<T: Config + Send + Sync> CheckOrderRate<T> {
+    pub fn new() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<T: Config + Send + Sync> Default for CheckOrderRate<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Config + Send + Sync> core::fmt::Debug for CheckOrderRate<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "CheckOrderRate")
+    }
+}
+
+// Calls that create an order, a sealed commitment or reveal one
+fn places_order<T: Config>(call: &T::RuntimeCall) -> bool
+where
+    T::RuntimeCall: IsSubType<Call<T>>,
+{
+    matches!(
+        call.is_sub_type(),
+        Some(
+            Call::create_ask_order { .. }
+                | Call::create_bid_order { .. }
+                | Call::create_order { .. }
+                | Call::create_market_order { .. }
+                | Call::commit_order { .. }
+                | Call::reveal_order { .. }
+                | Call::create_constrained_bid { .. }
+        )
+    )
+}
+
+impl<T: Config + Send + Sync> TransactionExtension<T::RuntimeCall> for CheckOrderRate<T>
+where
+    T::RuntimeCall: Dispatchable + IsSubType<Call<T>>,
+    DispatchOriginOf<T::RuntimeCall>: AsSystemOriginSigner<T::AccountId> + Clone,
+{
+    const IDENTIFIER: &'static str = "CheckOrderRate";
+    type Implicit = ();
+    // The submitter, their window and order count to record when the call places an order
+    type Val = Option<(T::AccountId, BlockNumberFor<T>, u32)>;
+    type Pre = ();
+
+    fn weight(&self, call: &T::RuntimeCall) -> Weight {
+        if places_order::<T>(call) {
+            T::DbWeight::get().reads_writes(1, 1)
+        } else {
+            Weight::zero()
+        }
+    }
+
+    fn validate(
+        &self,
+        origin: DispatchOriginOf<T::RuntimeCall>,
+        call: &T::RuntimeCall,
+        _info: &DispatchInfoOf<T::RuntimeCall>,
+        _len: usize,
+        _self_implicit: Self::Implicit,
+        _inherited_implication: &impl Implication,
+        _source: TransactionSource,
+    ) -> ValidateResult<Self::Val, T::RuntimeCall> {
+        let Some(who) = origin.as_system_origin_signer() else {
+            return Ok((ValidTransaction::default(), None, origin));
+        };
+        if !places_order::<T>(call) {
+            return Ok((ValidTransaction::default(), None, origin));
+        }
+
+        let (window_start, submitted) = Pallet::<T>::check_order_rate(who)?;
+
+        // Orders of one account taking the same place in the same window conflict in the pool,
+        // so it never holds more of them than the allowance lets through; they expire with it
+        let window = T::RateLimitWindow::get().max(One::one());
+        let now = <frame_system::Pallet<T>>::block_number();
+        let validity = ValidTransaction {
+            provides: vec![(Self::IDENTIFIER, who, window_start, submitted).encode()],
+            longevity: window_start.saturating_add(window).saturating_sub(now).unique_saturated_into(),
+            ..Default::default()
+        };
+        let val = Some((who.clone(), window_start, submitted));
+        Ok((validity, val, origin))
+    }
+
+    // Validation runs again when the extrinsic is applied, so orders that reached the pool
+    // together are still cut off at the allowance
+    fn prepare(
+        self,
+        val: Self::Val,
+        _origin: &DispatchOriginOf<T::RuntimeCall>,
+        _call: &T::RuntimeCall,
+        _info: &DispatchInfoOf<T::RuntimeCall>,
+        _len: usize,
+    ) -> Result<Self::Pre, TransactionValidityError> {
+        if let Some((who, window_start, submitted)) = val {
+            OrderRates::<T>::insert(who, (window_start, submitted));
+        }
+        Ok(())
+    }
+}
@@ -1,110 +1,785 @@
-use crate::{mock::*, Error, Event, OrderStatus};
-use frame_support::{assert_noop, assert_ok};
+use crate::{
+    mock::*, BidConstraints, BlockingConstraint, Error, Event, FeeDistribution, FeeSchedule, MarketConfig, MatchingMode,
+    OrderKind, OrderLimits, OrderRates, OrderStatus, OrderType, OrdersByStatus, SelfTradePrevention, TariffPeriod, DEFAULT_MARKET,
+    rate_limit::ORDER_RATE_EXCEEDED, CheckOrderRate,
+};
+use codec::Encode;
+use frame_support::{assert_noop, assert_ok, dispatch::GetDispatchInfo, traits::{ConstU32, Get, Hooks}, BoundedVec};
+use sp_runtime::{
+    traits::DispatchTransaction,
+    transaction_validity::{InvalidTransaction, TransactionSource},
+    Percent,
+};
 
 #[test]
-fn create_sell_order_works() {
+fn create_ask_order_works() {
     new_test_ext().execute_with(|| {
         let seller = 1;
-        let amount = 100;
-        let price = 10;
-
-        assert_ok!(EnergyTrade::create_sell_order(
-            RuntimeOrigin::signed(seller),
-            amount,
-            price
-        ));
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(seller), 100));
+        assert_ok!(EnergyTrade::create_ask_order(RuntimeOrigin::signed(seller), 100, 10, b"zone-a".to_vec(), 0));
 
         let order_id = System::events()
             .iter()
-            .find_map(|r| {
-                if let Event::TradeOrderCreated { order_id, .. } = r.event {
-                    Some(order_id)
-                } else {
-                    None
-                }
+            .find_map(|r| match r.event {
+                RuntimeEvent::EnergyTrade(Event::AskOrderCreated { order_id, .. }) => Some(order_id),
+                _ => None,
             })
             .unwrap();
 
         let order = EnergyTrade::trade_orders(order_id).unwrap();
-        assert_eq!(order.seller, seller);
-        assert_eq!(order.energy_amount, amount);
-        assert_eq!(order.price_per_unit, price);
+        assert_eq!(order.creator, seller);
+        assert_eq!(order.order_type, OrderType::Ask);
+        assert_eq!(order.energy_amount, 100);
+        assert_eq!(order.price_per_unit, 10);
+        assert_eq!(order.total_price, 1000);
         assert_eq!(order.status, OrderStatus::Open);
     });
 }
 
 #[test]
-fn match_order_works() {
+fn match_orders_works() {
     new_test_ext().execute_with(|| {
         let seller = 1;
         let buyer = 2;
-        let amount = 100;
-        let price = 10;
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(seller), 100));
+        assert_ok!(EnergyTrade::create_ask_order(RuntimeOrigin::signed(seller), 100, 10, b"zone-a".to_vec(), 0));
+        assert_ok!(EnergyTrade::create_bid_order(RuntimeOrigin::signed(buyer), 100, 10, b"zone-a".to_vec(), 0));
 
-        assert_ok!(EnergyTrade::create_sell_order(
-            RuntimeOrigin::signed(seller),
-            amount,
-            price
+        let ask_id = EnergyTrade::account_orders(&seller, 0, 1)[0];
+        let bid_id = EnergyTrade::account_orders(&buyer, 0, 1)[0];
+        assert_ok!(EnergyTrade::match_orders(RuntimeOrigin::signed(3), ask_id, bid_id));
+
+        let ask = EnergyTrade::trade_orders(ask_id).unwrap();
+        let bid = EnergyTrade::trade_orders(bid_id).unwrap();
+        assert_eq!((ask.status, bid.status), (OrderStatus::Matched, OrderStatus::Matched));
+        assert_eq!((ask.counterparty, bid.counterparty), (Some(buyer), Some(seller)));
+    });
+}
+
+#[test]
+fn complete_trade_works() {
+    new_test_ext().execute_with(|| {
+        let seller = 1;
+        let buyer = 2;
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(seller), 100));
+        assert_ok!(EnergyTrade::create_ask_order(RuntimeOrigin::signed(seller), 100, 10, b"zone-a".to_vec(), 0));
+        assert_ok!(EnergyTrade::create_bid_order(RuntimeOrigin::signed(buyer), 100, 10, b"zone-a".to_vec(), 0));
+
+        let ask_id = EnergyTrade::account_orders(&seller, 0, 1)[0];
+        let bid_id = EnergyTrade::account_orders(&buyer, 0, 1)[0];
+        assert_ok!(EnergyTrade::match_orders(RuntimeOrigin::signed(3), ask_id, bid_id));
+        assert_noop!(
+            EnergyTrade::verify_transfer(RuntimeOrigin::signed(seller), ask_id, vec![1], 100),
+            sp_runtime::DispatchError::BadOrigin
+        );
+        assert_ok!(EnergyTrade::verify_transfer(RuntimeOrigin::root(), ask_id, vec![1], 100));
+
+        let seller_funds = Balances::free_balance(seller);
+        assert_ok!(EnergyTrade::complete_trade(RuntimeOrigin::signed(3), ask_id));
+
+        assert_eq!(EnergyTrade::trade_orders(ask_id).unwrap().status, OrderStatus::Completed);
+        assert_eq!(EnergyToken::token_balance(buyer), 100);
+        assert_eq!(Balances::free_balance(seller), seller_funds + 1000);
+    });
+}
+
+#[test]
+fn periodic_auction_clears_at_uniform_price() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(EnergyTrade::set_matching_mode(
+            RuntimeOrigin::root(),
+            MatchingMode::PeriodicAuction
         ));
 
-        let order_id = System::events()
-            .iter()
-            .find_map(|r| {
-                if let Event::TradeOrderCreated { order_id, .. } = r.event {
-                    Some(order_id)
-                } else {
-                    None
-                }
-            })
-            .unwrap();
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(1), 60));
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(2), 60));
+        assert_ok!(EnergyTrade::create_ask_order(RuntimeOrigin::signed(1), 60, 8, b"zone-a".to_vec(), 0));
+        assert_ok!(EnergyTrade::create_ask_order(RuntimeOrigin::signed(2), 60, 10, b"zone-a".to_vec(), 0));
+        assert_ok!(EnergyTrade::create_bid_order(RuntimeOrigin::signed(3), 50, 12, b"zone-a".to_vec(), 0));
+        assert_ok!(EnergyTrade::create_bid_order(RuntimeOrigin::signed(4), 50, 10, b"zone-a".to_vec(), 0));
 
-        assert_ok!(EnergyTrade::match_order(
-            RuntimeOrigin::signed(buyer),
-            order_id
+        let interval = EnergyTrade::interval_index(System::block_number());
+        run_to_block(AuctionInterval::get() * (interval + 1) as u64);
+
+        let result = EnergyTrade::auction_results((DEFAULT_MARKET, interval, 0)).unwrap();
+        assert_eq!(result.clearing_price, 10);
+        assert_eq!(result.cleared_volume, 100);
+        assert!(EnergyTrade::auction_book(interval).is_empty());
+    });
+}
+
+#[test]
+fn auction_orders_are_capped_per_account_and_interval() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(EnergyTrade::set_matching_mode(
+            RuntimeOrigin::root(),
+            MatchingMode::PeriodicAuction
         ));
 
-        let order = EnergyTrade::trade_orders(order_id).unwrap();
-        assert_eq!(order.status, OrderStatus::Matched);
-        assert_eq!(order.buyer, Some(buyer));
+        for _ in 0..4 {
+            assert_ok!(EnergyTrade::create_bid_order(RuntimeOrigin::signed(3), 1, 1, b"zone-a".to_vec(), 0));
+        }
+        assert_noop!(
+            EnergyTrade::create_bid_order(RuntimeOrigin::signed(3), 1, 1, b"zone-a".to_vec(), 0),
+            Error::<Test>::TooManyAuctionOrders
+        );
+        // Other accounts keep their own allowance
+        assert_ok!(EnergyTrade::create_bid_order(RuntimeOrigin::signed(4), 1, 1, b"zone-a".to_vec(), 0));
+
+        let interval = EnergyTrade::interval_index(System::block_number());
+        assert_eq!(EnergyTrade::auction_entries(interval, 3), 4);
+        run_to_block(AuctionInterval::get() * (interval + 1) as u64);
+        assert_eq!(EnergyTrade::auction_entries(interval, 3), 0);
+        assert_ok!(EnergyTrade::create_bid_order(RuntimeOrigin::signed(3), 1, 1, b"zone-a".to_vec(), 0));
+    });
+}
+
+#[test]
+fn match_orders_rejected_in_auction_mode() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(1), 10));
+        assert_ok!(EnergyTrade::create_ask_order(RuntimeOrigin::signed(1), 10, 5, b"zone-a".to_vec(), 0));
+        assert_ok!(EnergyTrade::create_bid_order(RuntimeOrigin::signed(2), 10, 5, b"zone-a".to_vec(), 0));
+        let ask_id = EnergyTrade::account_orders(&1, 0, 1)[0];
+        let bid_id = EnergyTrade::account_orders(&2, 0, 1)[0];
+        assert_ok!(EnergyTrade::set_matching_mode(
+            RuntimeOrigin::root(),
+            MatchingMode::PeriodicAuction
+        ));
+
+        assert_noop!(
+            EnergyTrade::match_orders(RuntimeOrigin::signed(1), ask_id, bid_id),
+            Error::<Test>::AuctionModeActive
+        );
     });
 }
 
 #[test]
-fn complete_order_works() {
+fn bid_reserves_funds_and_cancel_releases_them() {
+    new_test_ext().execute_with(|| {
+        let buyer = 2;
+        assert_ok!(EnergyTrade::create_bid_order(RuntimeOrigin::signed(buyer), 10, 5, b"zone-a".to_vec(), 0));
+        assert_eq!(Balances::reserved_balance(buyer), 50);
+
+        let order_id = EnergyTrade::account_orders(&buyer, 0, 1)[0];
+        assert_noop!(
+            EnergyTrade::cancel_order(RuntimeOrigin::signed(1), order_id),
+            Error::<Test>::UnauthorizedAccess
+        );
+        assert_ok!(EnergyTrade::cancel_order(RuntimeOrigin::signed(buyer), order_id));
+
+        assert_eq!(Balances::reserved_balance(buyer), 0);
+        assert_eq!(EnergyTrade::trade_orders(order_id).unwrap().status, OrderStatus::Cancelled);
+    });
+}
+
+#[test]
+fn ask_requires_energy_tokens() {
+    new_test_ext().execute_with(|| {
+        let seller = 1;
+        assert_noop!(
+            EnergyTrade::create_ask_order(RuntimeOrigin::signed(seller), 10, 5, b"zone-a".to_vec(), 0),
+            pallet_energy_token::Error::<Test>::InsufficientBalance
+        );
+
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(seller), 10));
+        assert_ok!(EnergyTrade::create_ask_order(RuntimeOrigin::signed(seller), 10, 5, b"zone-a".to_vec(), 0));
+        assert_eq!(EnergyToken::token_balance(seller), 0);
+        assert_eq!(EnergyToken::reserved_balance(seller), 10);
+    });
+}
+
+#[test]
+fn complete_trade_delivers_tokens_and_pays_for_delivered_energy() {
     new_test_ext().execute_with(|| {
         let seller = 1;
         let buyer = 2;
-        let amount = 100;
-        let price = 10;
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(seller), 100));
+        assert_ok!(EnergyTrade::create_ask_order(RuntimeOrigin::signed(seller), 100, 10, b"zone-a".to_vec(), 0));
+        assert_ok!(EnergyTrade::create_bid_order(RuntimeOrigin::signed(buyer), 100, 10, b"zone-a".to_vec(), 0));
 
-        assert_ok!(EnergyTrade::create_sell_order(
-            RuntimeOrigin::signed(seller),
-            amount,
-            price
+        let ask_id = EnergyTrade::account_orders(&seller, 0, 1)[0];
+        let bid_id = EnergyTrade::account_orders(&buyer, 0, 1)[0];
+        assert_ok!(EnergyTrade::match_orders(RuntimeOrigin::signed(3), ask_id, bid_id));
+        assert_ok!(EnergyTrade::verify_transfer(RuntimeOrigin::root(), ask_id, vec![1], 80));
+
+        let seller_funds = Balances::free_balance(seller);
+        assert_ok!(EnergyTrade::complete_trade(RuntimeOrigin::signed(3), ask_id));
+
+        assert_eq!(EnergyToken::token_balance(buyer), 80);
+        assert_eq!(EnergyToken::token_balance(seller), 20);
+        assert_eq!(EnergyToken::reserved_balance(seller), 0);
+        // Without a reference price the 20 undelivered units are charged at the contract price
+        assert_eq!(Balances::free_balance(seller), seller_funds + 800 - 200);
+        assert_eq!(EnergyTrade::imbalances(ask_id).unwrap().penalty_paid, 200);
+        assert_eq!(Balances::reserved_balance(buyer), 0);
+        assert_eq!(EnergyTrade::trade_orders(bid_id).unwrap().status, OrderStatus::Completed);
+    });
+}
+
+#[test]
+fn immediate_or_cancel_fills_what_crosses_and_cancels_the_rest() {
+    new_test_ext().execute_with(|| {
+        let seller = 1;
+        let buyer = 2;
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(seller), 60));
+        assert_ok!(EnergyTrade::create_ask_order(RuntimeOrigin::signed(seller), 60, 10, b"zone-a".to_vec(), 0));
+
+        assert_ok!(EnergyTrade::create_order(
+            RuntimeOrigin::signed(buyer),
+            OrderType::Bid,
+            OrderKind::ImmediateOrCancel,
+            100,
+            12,
+            b"zone-a".to_vec(),
+            0,
         ));
 
-        let order_id = System::events()
-            .iter()
-            .find_map(|r| {
-                if let Event::TradeOrderCreated { order_id, .. } = r.event {
-                    Some(order_id)
-                } else {
-                    None
-                }
-            })
-            .unwrap();
+        let bid_id = EnergyTrade::account_orders(&buyer, 0, 1)[0];
+        assert_eq!(EnergyTrade::trade_orders(bid_id).unwrap().status, OrderStatus::Cancelled);
+        // Only the filled 60 units at the resting price stay reserved
+        assert_eq!(Balances::reserved_balance(buyer), 600);
+    });
+}
 
-        assert_ok!(EnergyTrade::match_order(
+#[test]
+fn post_only_and_fill_or_kill_are_rejected_when_they_cannot_rest_or_fill() {
+    new_test_ext().execute_with(|| {
+        let seller = 1;
+        let buyer = 2;
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(seller), 50));
+        assert_ok!(EnergyTrade::create_ask_order(RuntimeOrigin::signed(seller), 50, 10, b"zone-a".to_vec(), 0));
+
+        assert_noop!(
+            EnergyTrade::create_order(RuntimeOrigin::signed(buyer), OrderType::Bid, OrderKind::PostOnly, 10, 10, b"zone-a".to_vec(), 0),
+            Error::<Test>::WouldCrossBook
+        );
+        assert_noop!(
+            EnergyTrade::create_order(RuntimeOrigin::signed(buyer), OrderType::Bid, OrderKind::FillOrKill, 80, 10, b"zone-a".to_vec(), 0),
+            Error::<Test>::FillOrKillNotFilled
+        );
+        assert_ok!(EnergyTrade::create_order(
             RuntimeOrigin::signed(buyer),
-            order_id
+            OrderType::Bid,
+            OrderKind::PostOnly,
+            10,
+            9,
+            b"zone-a".to_vec(),
+            0,
+        ));
+    });
+}
+
+#[test]
+fn orders_only_match_within_their_delivery_slot() {
+    new_test_ext().execute_with(|| {
+        let seller = 1;
+        let buyer = 2;
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(seller), 10));
+        assert_ok!(EnergyTrade::create_ask_order(RuntimeOrigin::signed(seller), 10, 5, b"zone-a".to_vec(), 4));
+        assert_ok!(EnergyTrade::create_bid_order(RuntimeOrigin::signed(buyer), 10, 5, b"zone-a".to_vec(), 5));
+
+        let ask_id = EnergyTrade::account_orders(&seller, 0, 1)[0];
+        let bid_id = EnergyTrade::account_orders(&buyer, 0, 1)[0];
+        assert_noop!(
+            EnergyTrade::match_orders(RuntimeOrigin::signed(3), ask_id, bid_id),
+            Error::<Test>::SlotMismatch
+        );
+    });
+}
+
+#[test]
+fn bids_hold_their_fee_at_placement() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(EnergyTrade::set_fee_schedule(
+            RuntimeOrigin::root(),
+            None,
+            Some(FeeSchedule { maker_fee_bps: 100, taker_fee_bps: 200, min_fee: 0 }),
         ));
 
-        assert_ok!(EnergyTrade::complete_order(
-            RuntimeOrigin::signed(seller),
-            order_id
+        // 9_990 covers the trade value but not its fee
+        assert_ok!(Balances::transfer_allow_death(RuntimeOrigin::signed(2), 3, 10));
+        assert_noop!(
+            EnergyTrade::create_bid_order(RuntimeOrigin::signed(2), 1000, 10, b"zone-a".to_vec(), 0),
+            Error::<Test>::InsufficientBalance
+        );
+
+        // A maker bid gives back the difference to the taker fee when it is matched in part
+        assert_ok!(EnergyTrade::create_bid_order(RuntimeOrigin::signed(4), 100, 10, b"zone-a".to_vec(), 0));
+        assert_eq!(Balances::reserved_balance(4), 1020);
+        let bid_id = EnergyTrade::account_orders(&4, 0, 1)[0];
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(1), 50));
+        System::set_block_number(2);
+        assert_ok!(EnergyTrade::create_order(
+            RuntimeOrigin::signed(1),
+            OrderType::Ask,
+            OrderKind::ImmediateOrCancel,
+            50,
+            10,
+            b"zone-a".to_vec(),
+            0,
         ));
 
-        let order = EnergyTrade::trade_orders(order_id).unwrap();
-        assert_eq!(order.status, OrderStatus::Completed);
+        // The matched half keeps its maker fee, the open half its share of the fee hold
+        assert_eq!(Balances::reserved_balance(4), 500 + 5 + 500 + 10);
+        assert_ok!(EnergyTrade::cancel_order(RuntimeOrigin::signed(4), bid_id));
+        assert_eq!(Balances::reserved_balance(4), 505);
+    });
+}
+
+#[test]
+fn settlement_charges_maker_and_taker_fees_and_splits_them() {
+    new_test_ext().execute_with(|| {
+        let seller = 1;
+        let buyer = 2;
+        let grid_operator = 5;
+        assert_ok!(EnergyTrade::set_fee_schedule(
+            RuntimeOrigin::root(),
+            None,
+            Some(FeeSchedule { maker_fee_bps: 100, taker_fee_bps: 200, min_fee: 0 }),
+        ));
+        assert_ok!(EnergyTrade::set_fee_distribution(
+            RuntimeOrigin::root(),
+            FeeDistribution {
+                treasury: Percent::from_percent(50),
+                grid_operator: Percent::from_percent(30),
+                community: Percent::from_percent(20),
+            },
+        ));
+        assert_ok!(EnergyTrade::set_grid_operator_account(
+            RuntimeOrigin::root(),
+            b"zone-a".to_vec(),
+            Some(grid_operator),
+        ));
+
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(seller), 100));
+        assert_ok!(EnergyTrade::create_ask_order(RuntimeOrigin::signed(seller), 100, 10, b"zone-a".to_vec(), 0));
+        System::set_block_number(2);
+        assert_ok!(EnergyTrade::create_bid_order(RuntimeOrigin::signed(buyer), 100, 10, b"zone-a".to_vec(), 0));
+        // The bid holds the higher of the two fees from the start
+        assert_eq!(Balances::reserved_balance(buyer), 1020);
+
+        let ask_id = EnergyTrade::account_orders(&seller, 0, 1)[0];
+        let bid_id = EnergyTrade::account_orders(&buyer, 0, 1)[0];
+        assert_ok!(EnergyTrade::match_orders(RuntimeOrigin::signed(3), ask_id, bid_id));
+        // Trade value plus the taker fee stays on hold
+        assert_eq!(Balances::reserved_balance(buyer), 1020);
+
+        assert_ok!(EnergyTrade::verify_transfer(RuntimeOrigin::root(), ask_id, vec![1], 100));
+        let seller_funds = Balances::free_balance(seller);
+        let operator_funds = Balances::free_balance(grid_operator);
+        assert_ok!(EnergyTrade::complete_trade(RuntimeOrigin::signed(3), ask_id));
+
+        assert_eq!(Balances::free_balance(seller), seller_funds + 990);
+        assert_eq!(Balances::free_balance(grid_operator), operator_funds + 9);
+        assert_eq!(Balances::reserved_balance(buyer), 0);
+        System::assert_last_event(RuntimeEvent::EnergyTrade(Event::OrderCompleted {
+            order_id: ask_id,
+            seller,
+            buyer,
+            amount: 100,
+            price: 1000,
+            seller_fee: 10,
+            buyer_fee: 20,
+        }));
+    });
+}
+
+#[test]
+fn sealed_orders_are_revealed_into_the_book_and_unrevealed_deposits_forfeit() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(EnergyTrade::set_matching_mode(RuntimeOrigin::root(), MatchingMode::SealedBid));
+        assert_noop!(
+            EnergyTrade::create_bid_order(RuntimeOrigin::signed(2), 10, 5, b"zone-a".to_vec(), 0),
+            Error::<Test>::SealedBidModeActive
+        );
+
+        let salt = Default::default();
+        let commitment = EnergyTrade::sealed_commitment(&2, &OrderType::Bid, 10, 5, b"zone-a", 0, salt);
+        assert_ok!(EnergyTrade::commit_order(RuntimeOrigin::signed(2), commitment));
+        let unrevealed = EnergyTrade::sealed_commitment(&3, &OrderType::Bid, 10, 6, b"zone-a", 0, salt);
+        assert_ok!(EnergyTrade::commit_order(RuntimeOrigin::signed(3), unrevealed));
+        assert_eq!(Balances::reserved_balance(3), CommitDeposit::get());
+
+        let interval = EnergyTrade::interval_index(System::block_number());
+        let interval_end = AuctionInterval::get() * (interval + 1) as u64;
+        System::set_block_number(interval_end - RevealPeriod::get());
+        assert_ok!(EnergyTrade::reveal_order(
+            RuntimeOrigin::signed(2),
+            OrderType::Bid,
+            10,
+            5,
+            b"zone-a".to_vec(),
+            0,
+            salt,
+        ));
+        assert_eq!(EnergyTrade::auction_book(interval).len(), 1);
+
+        let treasury_funds = Balances::free_balance(TreasuryAccount::get());
+        run_to_block(interval_end);
+        assert_eq!(Balances::reserved_balance(3), 0);
+        assert_eq!(Balances::free_balance(TreasuryAccount::get()), treasury_funds + CommitDeposit::get());
+    });
+}
+
+#[test]
+fn reveals_breaking_the_order_limits_release_their_deposit() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(EnergyTrade::set_matching_mode(RuntimeOrigin::root(), MatchingMode::SealedBid));
+        let salt = Default::default();
+        let commitment = EnergyTrade::sealed_commitment(&2, &OrderType::Bid, 10, 7, b"zone-a", 0, salt);
+        assert_ok!(EnergyTrade::commit_order(RuntimeOrigin::signed(2), commitment));
+        // The operator tightens the tick size before the reveal
+        assert_ok!(EnergyTrade::set_order_limits(RuntimeOrigin::root(), Some(b"zone-a".to_vec()), Some(OrderLimits {
+            tick_size: 5,
+            ..Default::default()
+        })));
+
+        let interval = EnergyTrade::interval_index(System::block_number());
+        let interval_end = AuctionInterval::get() * (interval + 1) as u64;
+        System::set_block_number(interval_end - RevealPeriod::get());
+        assert_ok!(EnergyTrade::reveal_order(
+            RuntimeOrigin::signed(2),
+            OrderType::Bid,
+            10,
+            7,
+            b"zone-a".to_vec(),
+            0,
+            salt,
+        ));
+        System::assert_has_event(Event::CommitmentReleased { interval, who: 2, commitment }.into());
+        assert!(EnergyTrade::auction_book(interval).is_empty());
+        assert_eq!(Balances::reserved_balance(2), 0);
+
+        let treasury_funds = Balances::free_balance(TreasuryAccount::get());
+        run_to_block(interval_end);
+        assert_eq!(Balances::free_balance(TreasuryAccount::get()), treasury_funds);
+    });
+}
+
+#[test]
+fn order_book_index_tracks_open_orders_by_price_level() {
+    new_test_ext().execute_with(|| {
+        let location: BoundedVec<u8, ConstU32<32>> = b"zone-a".to_vec().try_into().unwrap();
+        assert_ok!(EnergyTrade::create_bid_order(RuntimeOrigin::signed(2), 10, 7, b"zone-a".to_vec(), 0));
+        assert_ok!(EnergyTrade::create_bid_order(RuntimeOrigin::signed(3), 10, 5, b"zone-a".to_vec(), 0));
+        assert_ok!(EnergyTrade::create_bid_order(RuntimeOrigin::signed(4), 10, 7, b"zone-a".to_vec(), 0));
+        let first = EnergyTrade::account_orders(&2, 0, 1)[0];
+        let second = EnergyTrade::account_orders(&4, 0, 1)[0];
+
+        assert_eq!(EnergyTrade::price_levels((DEFAULT_MARKET, &location, 0, OrderType::Bid)).into_inner(), vec![5, 7]);
+        assert_eq!(EnergyTrade::order_book((DEFAULT_MARKET, &location, 0, OrderType::Bid, 7)).into_inner(), vec![first, second]);
+
+        // An ask sees the highest bids first, in time priority
+        let crossing: Vec<_> = EnergyTrade::crossing_orders(DEFAULT_MARKET, &OrderType::Ask, &location, 0, Some(6))
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(crossing, vec![first, second]);
+
+        assert_ok!(EnergyTrade::cancel_order(RuntimeOrigin::signed(2), first));
+        assert_ok!(EnergyTrade::cancel_order(RuntimeOrigin::signed(4), second));
+        assert_eq!(EnergyTrade::price_levels((DEFAULT_MARKET, &location, 0, OrderType::Bid)).into_inner(), vec![5]);
+        assert!(OrdersByStatus::<Test>::contains_key(OrderStatus::Cancelled, first));
+        assert!(!OrdersByStatus::<Test>::contains_key(OrderStatus::Open, first));
+        assert_eq!(EnergyTrade::orders_with_status(OrderStatus::Open, None, 10).len(), 1);
+    });
+}
+
+#[test]
+fn over_delivery_is_recorded_as_excess_and_paid_at_the_contracted_amount() {
+    new_test_ext().execute_with(|| {
+        let seller = 1;
+        let buyer = 2;
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(seller), 100));
+        assert_ok!(EnergyTrade::create_ask_order(RuntimeOrigin::signed(seller), 100, 10, b"zone-a".to_vec(), 0));
+        assert_ok!(EnergyTrade::create_bid_order(RuntimeOrigin::signed(buyer), 100, 10, b"zone-a".to_vec(), 0));
+
+        let ask_id = EnergyTrade::account_orders(&seller, 0, 1)[0];
+        let bid_id = EnergyTrade::account_orders(&buyer, 0, 1)[0];
+        assert_ok!(EnergyTrade::match_orders(RuntimeOrigin::signed(3), ask_id, bid_id));
+        assert_ok!(EnergyTrade::verify_transfer(RuntimeOrigin::root(), ask_id, vec![1], 120));
+
+        let seller_funds = Balances::free_balance(seller);
+        assert_ok!(EnergyTrade::complete_trade(RuntimeOrigin::signed(3), ask_id));
+
+        assert_eq!(Balances::free_balance(seller), seller_funds + 1000);
+        let record = EnergyTrade::imbalances(ask_id).unwrap();
+        assert_eq!((record.shortfall, record.excess, record.spill_credit), (0, 20, 0));
+    });
+}
+
+#[test]
+fn constrained_bids_skip_denied_sellers_and_report_the_blocking_constraint() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(1), 10));
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(3), 10));
+        assert_ok!(EnergyTrade::create_ask_order(RuntimeOrigin::signed(1), 10, 5, b"zone-a".to_vec(), 0));
+        assert_ok!(EnergyTrade::create_ask_order(RuntimeOrigin::signed(3), 10, 6, b"zone-a".to_vec(), 0));
+        let denied_ask = EnergyTrade::account_orders(&1, 0, 1)[0];
+        let allowed_ask = EnergyTrade::account_orders(&3, 0, 1)[0];
+
+        let constraints = BidConstraints::<Test> {
+            device_types: Default::default(),
+            locations: Default::default(),
+            max_distance: None,
+            allowed_sellers: Default::default(),
+            denied_sellers: vec![1].try_into().unwrap(),
+        };
+        assert_ok!(EnergyTrade::create_constrained_bid(
+            RuntimeOrigin::signed(2),
+            OrderKind::ImmediateOrCancel,
+            10,
+            6,
+            b"zone-a".to_vec(),
+            0,
+            constraints,
+        ));
+        let bid_id = EnergyTrade::account_orders(&2, 0, 1)[0];
+
+        // The cheaper ask is skipped and stays on the book
+        assert_eq!(EnergyTrade::trade_orders(denied_ask).unwrap().status, OrderStatus::Open);
+        assert_eq!(EnergyTrade::trade_orders(allowed_ask).unwrap().status, OrderStatus::Matched);
+        assert_eq!(EnergyTrade::blocking_constraint(denied_ask, bid_id), Some(BlockingConstraint::SellerDenied));
+        System::assert_has_event(
+            Event::MatchBlocked { ask_id: denied_ask, bid_id, constraint: BlockingConstraint::SellerDenied }.into()
+        );
+    });
+}
+
+#[test]
+fn halted_location_rejects_orders_and_matches_but_allows_cancellation() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(1), 10));
+        assert_ok!(EnergyTrade::create_ask_order(RuntimeOrigin::signed(1), 10, 5, b"zone-a".to_vec(), 0));
+        assert_ok!(EnergyTrade::create_bid_order(RuntimeOrigin::signed(2), 10, 5, b"zone-a".to_vec(), 0));
+        let ask_id = EnergyTrade::account_orders(&1, 0, 1)[0];
+        let bid_id = EnergyTrade::account_orders(&2, 0, 1)[0];
+
+        assert_ok!(EnergyTrade::halt_location(RuntimeOrigin::root(), b"zone-a".to_vec()));
+        assert_noop!(
+            EnergyTrade::create_bid_order(RuntimeOrigin::signed(3), 10, 5, b"zone-a".to_vec(), 0),
+            Error::<Test>::MarketHalted
+        );
+        assert_noop!(
+            EnergyTrade::match_orders(RuntimeOrigin::signed(3), ask_id, bid_id),
+            Error::<Test>::MarketHalted
+        );
+        assert_ok!(EnergyTrade::create_bid_order(RuntimeOrigin::signed(3), 10, 5, b"zone-b".to_vec(), 0));
+        assert_ok!(EnergyTrade::cancel_order(RuntimeOrigin::signed(2), bid_id));
+
+        assert_ok!(EnergyTrade::resume_location(RuntimeOrigin::root(), b"zone-a".to_vec()));
+        assert_ok!(EnergyTrade::create_bid_order(RuntimeOrigin::signed(3), 10, 5, b"zone-a".to_vec(), 0));
+    });
+}
+
+#[test]
+fn unmatched_bids_settle_against_the_retailer_once_their_slot_ends() {
+    new_test_ext().execute_with(|| {
+        let retailer = 5;
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(retailer), 100));
+        assert_ok!(EnergyTrade::set_retailer(RuntimeOrigin::root(), Some(retailer)));
+        assert_ok!(EnergyTrade::set_tariffs(
+            RuntimeOrigin::root(),
+            vec![TariffPeriod { start_slot: 0, retail_price: 8, feed_in_price: 3 }],
+        ));
+        run_to_block(2);
+
+        let slot = EnergyTrade::current_slot();
+        assert_ok!(EnergyTrade::create_bid_order(RuntimeOrigin::signed(2), 10, 6, b"zone-a".to_vec(), slot));
+        let bid_id = EnergyTrade::account_orders(&2, 0, 1)[0];
+
+        Timestamp::set_timestamp(pallet_timestamp::Now::<Test>::get() + SlotDuration::get());
+        run_to_block(3);
+
+        // Matched with the retailer at the retail tariff, above the bid's own limit
+        let bid = EnergyTrade::trade_orders(bid_id).unwrap();
+        assert_eq!(bid.status, OrderStatus::Matched);
+        assert_eq!(bid.counterparty, Some(retailer));
+        assert_eq!(bid.price_per_unit, 8);
+        assert_eq!(EnergyTrade::next_slot_to_close(), Some(slot + 1));
+    });
+}
+
+#[test]
+fn slot_closing_starts_at_the_first_timestamped_slot() {
+    new_test_ext().execute_with(|| {
+        // The chain starts long after the epoch; the first block seeds the slot of its timestamp
+        Timestamp::set_timestamp(1000 * SlotDuration::get());
+        EnergyTrade::on_initialize(1);
+        assert_eq!(EnergyTrade::next_slot_to_close(), None);
+        run_to_block(2);
+        assert_eq!(EnergyTrade::next_slot_to_close(), Some(1000));
+    });
+}
+
+#[test]
+fn self_trades_are_prevented_according_to_the_mode() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(1), 20));
+        assert_ok!(EnergyTrade::create_ask_order(RuntimeOrigin::signed(1), 10, 5, b"zone-a".to_vec(), 0));
+        assert_ok!(EnergyTrade::create_bid_order(RuntimeOrigin::signed(1), 10, 5, b"zone-a".to_vec(), 0));
+        let ask_id = EnergyTrade::account_orders(&1, 0, 1)[0];
+        let bid_id = EnergyTrade::account_orders(&1, 1, 1)[0];
+        assert_noop!(
+            EnergyTrade::match_orders(RuntimeOrigin::signed(3), ask_id, bid_id),
+            Error::<Test>::SelfTrade
+        );
+        assert_ok!(EnergyTrade::cancel_order(RuntimeOrigin::signed(1), bid_id));
+
+        // Decrement both: the overlapping 4 units are cancelled on each side
+        assert_ok!(EnergyTrade::set_self_trade_prevention(RuntimeOrigin::root(), SelfTradePrevention::DecrementBoth));
+        assert_ok!(EnergyTrade::create_order(
+            RuntimeOrigin::signed(1),
+            OrderType::Bid,
+            OrderKind::ImmediateOrCancel,
+            4,
+            5,
+            b"zone-a".to_vec(),
+            0,
+        ));
+        let ask = EnergyTrade::trade_orders(ask_id).unwrap();
+        assert_eq!((ask.status, ask.energy_amount), (OrderStatus::Open, 6));
+        assert_eq!(EnergyToken::reserved_balance(1), 6);
+    });
+}
+
+#[test]
+fn order_submissions_are_limited_per_window() {
+    new_test_ext().execute_with(|| {
+        System::set_block_number(1);
+        let allowance = EnergyTrade::order_allowance(&1);
+        let max_orders: u32 = <Test as crate::Config>::MaxOrdersPerWindow::get();
+        assert_eq!(allowance, max_orders);
+
+        for submitted in 1..=allowance {
+            let (window_start, count) = EnergyTrade::check_order_rate(&1).unwrap();
+            assert_eq!(count, submitted);
+            OrderRates::<Test>::insert(1, (window_start, count));
+        }
+        assert_eq!(
+            EnergyTrade::check_order_rate(&1),
+            Err(InvalidTransaction::Custom(ORDER_RATE_EXCEEDED).into())
+        );
+        // Other accounts have their own allowance
+        assert_ok!(EnergyTrade::check_order_rate(&2));
+
+        // The count starts over in the next window
+        let window: u64 = <Test as crate::Config>::RateLimitWindow::get();
+        System::set_block_number(window);
+        assert_eq!(EnergyTrade::check_order_rate(&1).map(|(_, count)| count), Ok(1));
+    });
+}
+
+#[test]
+fn the_order_rate_extension_counts_reveals_and_tags_the_window() {
+    new_test_ext().execute_with(|| {
+        let reveal = RuntimeCall::EnergyTrade(crate::Call::reveal_order {
+            order_type: OrderType::Bid,
+            energy_amount: 10,
+            price_per_unit: 5,
+            grid_location: b"zone-a".to_vec(),
+            delivery_slot: 0,
+            salt: Default::default(),
+        });
+        let info = reveal.get_dispatch_info();
+        let validate = || {
+            CheckOrderRate::<Test>::new()
+                .validate_only(RuntimeOrigin::signed(1), &reveal, &info, 0, TransactionSource::External, 0)
+                .map(|(validity, val, _)| (validity, val))
+        };
+        let (window_start, _) = EnergyTrade::check_order_rate(&1).unwrap();
+
+        let (validity, val) = validate().unwrap();
+        assert_eq!(val, Some((1, window_start, 1)));
+        assert_eq!(validity.provides, vec![("CheckOrderRate", 1u64, window_start, 1u32).encode()]);
+
+        // The pool turns away reveals over the allowance like any other order
+        OrderRates::<Test>::insert(1, (window_start, EnergyTrade::order_allowance(&1)));
+        assert_eq!(validate().map(|_| ()), Err(InvalidTransaction::Custom(ORDER_RATE_EXCEEDED).into()));
+    });
+}
+
+#[test]
+fn markets_scope_orders_rules_and_statistics() {
+    new_test_ext().execute_with(|| {
+        let location = b"zone-a".to_vec();
+        assert_ok!(EnergyTrade::create_market(RuntimeOrigin::root(), MarketConfig {
+            matching_mode: MatchingMode::Continuous,
+            allowed_roles: BoundedVec::default(),
+            members_only: true,
+            fee_schedule: FeeSchedule::default(),
+            limits: OrderLimits { tick_size: 5, min_lot: 10, ..Default::default() },
+        }));
+        let market = EnergyTrade::market_count();
+        let place = |who, order_type, amount, price| EnergyTrade::create_market_order(
+            RuntimeOrigin::signed(who),
+            market,
+            order_type,
+            OrderKind::Limit,
+            amount,
+            price,
+            location.clone(),
+            0,
+        );
+
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(1), 20));
+        assert_noop!(place(1, OrderType::Ask, 10, 5), Error::<Test>::NotMarketMember);
+        assert_ok!(EnergyTrade::add_market_member(RuntimeOrigin::root(), market, 1));
+        assert_ok!(EnergyTrade::add_market_member(RuntimeOrigin::root(), market, 2));
+        assert_noop!(place(1, OrderType::Ask, 10, 7), Error::<Test>::InvalidTickSize);
+        assert_noop!(place(1, OrderType::Ask, 5, 5), Error::<Test>::BelowMinimumLot);
+        assert_ok!(place(1, OrderType::Ask, 10, 5));
+        let ask_id = EnergyTrade::account_orders(&1, 0, 1)[0];
+        assert_eq!(EnergyTrade::trade_orders(ask_id).unwrap().market, market);
+
+        // The default market's book is separate
+        assert_ok!(EnergyTrade::create_bid_order(RuntimeOrigin::signed(3), 10, 5, location.clone(), 0));
+        let default_bid = EnergyTrade::account_orders(&3, 0, 1)[0];
+        assert!(EnergyTrade::crossing_orders(DEFAULT_MARKET, &OrderType::Bid, &location.clone().try_into().unwrap(), 0, None).is_empty());
+        assert_noop!(
+            EnergyTrade::match_orders(RuntimeOrigin::signed(3), ask_id, default_bid),
+            Error::<Test>::MarketMismatch
+        );
+
+        assert_ok!(place(2, OrderType::Bid, 10, 5));
+        let bid_id = EnergyTrade::account_orders(&2, 0, 1)[0];
+        assert_ok!(EnergyTrade::match_orders(RuntimeOrigin::signed(3), ask_id, bid_id));
+        let stats = EnergyTrade::market_statistics(market, BoundedVec::<u8, ConstU32<32>>::try_from(location).unwrap());
+        assert_eq!((stats.last_price, stats.volume, stats.trades), (5, 10, 1));
+    });
+}
+
+#[test]
+fn order_limits_are_checked_at_creation() {
+    new_test_ext().execute_with(|| {
+        let location = b"zone-a".to_vec();
+        assert_ok!(EnergyTrade::set_order_limits(RuntimeOrigin::root(), Some(location.clone()), Some(OrderLimits {
+            tick_size: 5,
+            min_lot: 10,
+            max_lot: 100,
+            min_notional: 100,
+            max_notional: 1_000,
+        })));
+        assert_noop!(
+            EnergyTrade::set_order_limits(RuntimeOrigin::root(), None, Some(OrderLimits {
+                min_lot: 10,
+                max_lot: 5,
+                ..Default::default()
+            })),
+            Error::<Test>::InvalidOrderLimits
+        );
+
+        let bid = |amount, price| EnergyTrade::create_bid_order(RuntimeOrigin::signed(2), amount, price, location.clone(), 0);
+        assert_noop!(bid(20, 7), Error::<Test>::InvalidTickSize);
+        assert_noop!(bid(5, 20), Error::<Test>::BelowMinimumLot);
+        assert_noop!(bid(200, 5), Error::<Test>::AboveMaximumLot);
+        assert_noop!(bid(10, 5), Error::<Test>::BelowMinimumNotional);
+        assert_noop!(bid(100, 15), Error::<Test>::AboveMaximumNotional);
+        assert_noop!(bid(100, u128::MAX - u128::MAX % 5), Error::<Test>::AboveMaximumNotional);
+        assert_ok!(bid(20, 10));
+
+        // Other locations keep the default limits
+        assert_ok!(EnergyTrade::create_bid_order(RuntimeOrigin::signed(2), 5, 7, b"zone-b".to_vec(), 0));
     });
 }
@@ -0,0 +1,106 @@
+// Weights for pallet_energy_trade.
+//
+// Storage accesses are counted from the worst case of each benchmark in `benchmarking.rs`:
+// a full price level queue and a full set of price levels on the affected book. The
+// execution times are estimates until the benchmarks are run on reference hardware with
+// `solar-grid-node benchmark pallet --pallet pallet_energy_trade --extrinsic "*"`.
+
+#![allow(unused_parens)]
+#![allow(unused_imports)]
+
+use frame_support::{traits::Get, weights::{constants::RocksDbWeight, Weight}};
+use sp_std::marker::PhantomData;
+
+pub trait WeightInfo {
+    fn create_ask_order() -> Weight;
+    fn create_bid_order() -> Weight;
+    fn match_orders() -> Weight;
+    fn cancel_order() -> Weight;
+    fn create_order(n: u32) -> Weight;
+}
+
+pub struct SubstrateWeight<T>(PhantomData<T>);
+
+impl<T: frame_system::Config> WeightInfo for SubstrateWeight<T> {
+    // Reads: matching mode, timestamp, participant profile, slot ask volume, energy balances (2),
+    // price levels, level queue, account order count
+    // Writes: slot ask volume, energy balances (2), price levels, level queue, status index,
+    // order, account order index and count
+    fn create_ask_order() -> Weight {
+        Weight::from_parts(48_000_000, 12_000)
+            .saturating_add(T::DbWeight::get().reads(9))
+            .saturating_add(T::DbWeight::get().writes(9))
+    }
+
+    // Reads: matching mode, timestamp, participant profile, currency account, price levels,
+    // level queue, account order count
+    // Writes: currency account, price levels, level queue, status index, order, account order
+    // index and count
+    fn create_bid_order() -> Weight {
+        Weight::from_parts(45_000_000, 12_000)
+            .saturating_add(T::DbWeight::get().reads(7))
+            .saturating_add(T::DbWeight::get().writes(7))
+    }
+
+    // Reads: matching mode, both orders, fee schedules (2), currency account, both level queues,
+    // both price levels
+    // Writes: currency account, both orders, both level queues, both price levels, status index (4)
+    fn match_orders() -> Weight {
+        Weight::from_parts(62_000_000, 16_000)
+            .saturating_add(T::DbWeight::get().reads(10))
+            .saturating_add(T::DbWeight::get().writes(11))
+    }
+
+    // Reads: order, level queue, price levels, energy or currency balance
+    // Writes: order, level queue, price levels, slot ask volume, balances (2), status index (2)
+    fn cancel_order() -> Weight {
+        Weight::from_parts(38_000_000, 12_000)
+            .saturating_add(T::DbWeight::get().reads(5))
+            .saturating_add(T::DbWeight::get().writes(8))
+    }
+
+    // Order placement plus one split and fill per crossed resting order
+    fn create_order(n: u32) -> Weight {
+        Weight::from_parts(52_000_000, 12_000)
+            .saturating_add(Weight::from_parts(41_000_000, 6_000).saturating_mul(n.into()))
+            .saturating_add(T::DbWeight::get().reads(10))
+            .saturating_add(T::DbWeight::get().reads((6_u64).saturating_mul(n.into())))
+            .saturating_add(T::DbWeight::get().writes(9))
+            .saturating_add(T::DbWeight::get().writes((10_u64).saturating_mul(n.into())))
+    }
+}
+
+impl WeightInfo for () {
+    fn create_ask_order() -> Weight {
+        Weight::from_parts(48_000_000, 12_000)
+            .saturating_add(RocksDbWeight::get().reads(9))
+            .saturating_add(RocksDbWeight::get().writes(9))
+    }
+
+    fn create_bid_order() -> Weight {
+        Weight::from_parts(45_000_000, 12_000)
+            .saturating_add(RocksDbWeight::get().reads(7))
+            .saturating_add(RocksDbWeight::get().writes(7))
+    }
+
+    fn match_orders() -> Weight {
+        Weight::from_parts(62_000_000, 16_000)
+            .saturating_add(RocksDbWeight::get().reads(10))
+            .saturating_add(RocksDbWeight::get().writes(11))
+    }
+
+    fn cancel_order() -> Weight {
+        Weight::from_parts(38_000_000, 12_000)
+            .saturating_add(RocksDbWeight::get().reads(5))
+            .saturating_add(RocksDbWeight::get().writes(8))
+    }
+
+    fn create_order(n: u32) -> Weight {
+        Weight::from_parts(52_000_000, 12_000)
+            .saturating_add(Weight::from_parts(41_000_000, 6_000).saturating_mul(n.into()))
+            .saturating_add(RocksDbWeight::get().reads(10))
+            .saturating_add(RocksDbWeight::get().reads((6_u64).saturating_mul(n.into())))
+            .saturating_add(RocksDbWeight::get().writes(9))
+            .saturating_add(RocksDbWeight::get().writes((10_u64).saturating_mul(n.into())))
+    }
+}
@@ -2,20 +2,28 @@
 
 pub use pallet::*;
 
+#[cfg(test)]
+mod mock;
+#[cfg(test)]
+mod tests;
+
 #[frame_support::pallet]
 pub mod pallet {
+    use codec::DecodeAll;
     use frame_support::pallet_prelude::*;
     use frame_system::pallet_prelude::*;
-    use pallet_energy_trade::{self as energy_trade, TradeOrder};
+    use pallet_energy_trade::{self as energy_trade, DeliverySlot, TokenBalanceOf, TradeOrder};
+    use pallet_user_registry as user_registry;
     use scale_info::TypeInfo;
     use sp_std::prelude::*;
 
     #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo)]
+    #[scale_info(skip_type_params(T))]
     pub struct TransferData<T: Config> {
         pub order_id: T::Hash,
         pub start_time: T::Moment,
         pub end_time: Option<T::Moment>,
-        pub energy_delivered: T::TokenBalance,
+        pub energy_delivered: TokenBalanceOf<T>,
         pub grid_metrics: Vec<u8>,
         pub status: TransferStatus,
     }
@@ -28,22 +36,25 @@ pub mod pallet {
         Failed,
     }
 
-    #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo)]
+    #[derive(Clone, Encode, Decode, DecodeWithMemTracking, PartialEq, RuntimeDebug, TypeInfo)]
     pub struct IoTMeasurement {
         pub device_id: Vec<u8>,
-        pub timestamp: u64,
+        pub timestamp: u64,     // Unix time in milliseconds
         pub energy_amount: u64,
         pub grid_frequency: u32,
         pub voltage: u32,
     }
 
     #[pallet::config]
-    pub trait Config: frame_system::Config + energy_trade::Config {
+    pub trait Config: frame_system::Config + energy_trade::Config + user_registry::Config {
         type RuntimeEvent: From<Event<Self>> + IsType<<Self as frame_system::Config>::RuntimeEvent>;
         type Moment: Parameter + Default + Copy;
+        // Metering back end trusted to report readings and failed deliveries
+        type MeterOracleOrigin: EnsureOrigin<Self::RuntimeOrigin>;
     }
 
     #[pallet::pallet]
+    #[pallet::without_storage_info]
     pub struct Pallet<T>(_);
 
     #[pallet::storage]
@@ -60,6 +71,44 @@ pub mod pallet {
         ValueQuery,
     >;
 
+    // Metered energy per device and delivery slot, kept for baselines such as demand response
+    #[pallet::storage]
+    #[pallet::getter(fn device_readings)]
+    pub type DeviceReadings<T: Config> = StorageDoubleMap<
+        _,
+        Blake2_128Concat,
+        BoundedVec<u8, ConstU32<64>>,  // device_id
+        Twox64Concat,
+        DeliverySlot,
+        u64,
+        ValueQuery,
+    >;
+
+    // Metered energy per device and delivery slot as reported by the meter oracle alone, for
+    // settlements that cannot rest on the owner's own readings. Missing slots have no entry
+    #[pallet::storage]
+    #[pallet::getter(fn oracle_readings)]
+    pub type OracleReadings<T: Config> = StorageDoubleMap<
+        _,
+        Blake2_128Concat,
+        BoundedVec<u8, ConstU32<64>>,  // device_id
+        Twox64Concat,
+        DeliverySlot,
+        u64,
+        OptionQuery,
+    >;
+
+    // Timestamp and amount of each device's latest reading, e.g. a battery's state of charge
+    #[pallet::storage]
+    #[pallet::getter(fn latest_reading)]
+    pub type LatestReadings<T: Config> = StorageMap<
+        _,
+        Blake2_128Concat,
+        BoundedVec<u8, ConstU32<64>>,  // device_id
+        (u64, u64),
+        OptionQuery,
+    >;
+
     #[pallet::event]
     #[pallet::generate_deposit(pub(super) fn deposit_event)]
     pub enum Event<T: Config> {
@@ -74,12 +123,16 @@ pub mod pallet {
         },
         TransferCompleted {
             order_id: T::Hash,
-            total_energy: T::TokenBalance,
+            total_energy: TokenBalanceOf<T>,
         },
         TransferFailed {
             order_id: T::Hash,
             reason: Vec<u8>,
         },
+        MeterReadingSubmitted {
+            device_id: Vec<u8>,
+            energy_amount: u64,
+        },
     }
 
     #[pallet::error]
@@ -89,6 +142,64 @@ pub mod pallet {
         InvalidMeasurement,
         TransferAlreadyStarted,
         DeviceNotAuthorized,
+        MeasurementOutsideSlot,
+        NotTradeParty,
+        UnknownDevice,
+        NotDeviceOwner,
+    }
+
+    impl<T: Config> Pallet<T> {
+        // Meter readings only count towards an order if taken inside its delivery slot
+        fn ensure_in_slot(order_id: T::Hash, measurement: &IoTMeasurement) -> DispatchResult {
+            let order = energy_trade::Pallet::<T>::trade_orders(order_id)
+                .ok_or(energy_trade::Error::<T>::OrderNotFound)?;
+            ensure!(
+                energy_trade::Pallet::<T>::slot_of(measurement.timestamp) == order.delivery_slot,
+                Error::<T>::MeasurementOutsideSlot
+            );
+            Ok(())
+        }
+
+        // Meters report under the SCALE encoding of the registry's device id
+        pub fn meter_id(device_id: &T::Hash) -> BoundedVec<u8, ConstU32<64>> {
+            BoundedVec::truncate_from(device_id.encode())
+        }
+
+        // Readings come from the meter oracle or from the owner of the active registry device
+        // the meter id encodes. Returns whether the reading comes from the oracle
+        fn ensure_can_report(origin: OriginFor<T>, device_id: &[u8]) -> Result<bool, DispatchError> {
+            if T::MeterOracleOrigin::ensure_origin(origin.clone()).is_ok() {
+                return Ok(true);
+            }
+            let who = ensure_signed(origin)?;
+            let device = T::Hash::decode_all(&mut &device_id[..])
+                .ok()
+                .and_then(|id| user_registry::Pallet::<T>::devices(id))
+                .ok_or(Error::<T>::UnknownDevice)?;
+            ensure!(device.owner == who && device.active, Error::<T>::NotDeviceOwner);
+            Ok(false)
+        }
+
+        // Add a reading to its device's total for the slot it was taken in
+        fn record_device_reading(measurement: &IoTMeasurement, from_oracle: bool) -> DispatchResult {
+            let device_id: BoundedVec<u8, ConstU32<64>> = measurement.device_id.clone().try_into()
+                .map_err(|_| Error::<T>::InvalidMeasurement)?;
+            let slot = energy_trade::Pallet::<T>::slot_of(measurement.timestamp);
+            DeviceReadings::<T>::mutate(&device_id, slot, |energy| {
+                *energy = energy.saturating_add(measurement.energy_amount);
+            });
+            if from_oracle {
+                OracleReadings::<T>::mutate(&device_id, slot, |energy| {
+                    *energy = Some(energy.unwrap_or_default().saturating_add(measurement.energy_amount));
+                });
+            }
+            LatestReadings::<T>::mutate(&device_id, |latest| {
+                if latest.map_or(true, |(timestamp, _)| measurement.timestamp >= timestamp) {
+                    *latest = Some((measurement.timestamp, measurement.energy_amount));
+                }
+            });
+            Ok(())
+        }
     }
 
     #[pallet::call]
@@ -107,7 +218,7 @@ pub mod pallet {
                 order_id,
                 start_time,
                 end_time: None,
-                energy_delivered: T::TokenBalance::default(),
+                energy_delivered: TokenBalanceOf::<T>::default(),
                 grid_metrics: Vec::new(),
                 status: TransferStatus::InProgress,
             };
@@ -128,9 +239,11 @@ pub mod pallet {
             order_id: T::Hash,
             measurement: IoTMeasurement,
         ) -> DispatchResult {
-            let _ = ensure_signed(origin)?;
+            let from_oracle = Self::ensure_can_report(origin, &measurement.device_id)?;
 
             ensure!(Transfers::<T>::contains_key(&order_id), Error::<T>::TransferNotFound);
+            Self::ensure_in_slot(order_id, &measurement)?;
+            Self::record_device_reading(&measurement, from_oracle)?;
 
             let mut measurements = IoTMeasurements::<T>::get(&order_id);
             measurements.push(measurement.clone());
@@ -145,6 +258,7 @@ pub mod pallet {
             Ok(())
         }
 
+        // Settles on the final reading, so it has to come from the meter oracle
         #[pallet::weight(10_000)]
         pub fn complete_transfer(
             origin: OriginFor<T>,
@@ -152,11 +266,13 @@ pub mod pallet {
             end_time: T::Moment,
             final_measurement: IoTMeasurement,
         ) -> DispatchResult {
-            let _ = ensure_signed(origin)?;
+            T::MeterOracleOrigin::ensure_origin(origin)?;
 
             Transfers::<T>::try_mutate(order_id, |transfer_opt| -> DispatchResult {
                 let transfer = transfer_opt.as_mut().ok_or(Error::<T>::TransferNotFound)?;
                 ensure!(transfer.status == TransferStatus::InProgress, Error::<T>::InvalidTransferStatus);
+                Self::ensure_in_slot(order_id, &final_measurement)?;
+                Self::record_device_reading(&final_measurement, true)?;
 
                 // Record final measurement
                 let mut measurements = IoTMeasurements::<T>::get(&order_id);
@@ -165,14 +281,15 @@ pub mod pallet {
 
                 // Update transfer data
                 transfer.end_time = Some(end_time);
-                transfer.energy_delivered = T::TokenBalance::from(final_measurement.energy_amount as u32);
+                transfer.energy_delivered = final_measurement.energy_amount.try_into()
+                    .map_err(|_| Error::<T>::InvalidMeasurement)?;
                 transfer.status = TransferStatus::Completed;
 
-                // Verify transfer on the trade pallet
-                energy_trade::Pallet::<T>::verify_transfer(
-                    origin,
+                // Verify transfer on the trade pallet; settlement follows the metered amount
+                energy_trade::Pallet::<T>::do_verify_transfer(
                     order_id,
                     final_measurement.encode(),
+                    transfer.energy_delivered,
                 )?;
 
                 Self::deposit_event(Event::TransferCompleted {
@@ -184,18 +301,52 @@ pub mod pallet {
             })
         }
 
+        // Reading of a device that is not part of a trade, e.g. a flexible load
+        #[pallet::weight(10_000)]
+        pub fn submit_meter_reading(
+            origin: OriginFor<T>,
+            measurement: IoTMeasurement,
+        ) -> DispatchResult {
+            let from_oracle = Self::ensure_can_report(origin, &measurement.device_id)?;
+
+            Self::record_device_reading(&measurement, from_oracle)?;
+
+            Self::deposit_event(Event::MeterReadingSubmitted {
+                device_id: measurement.device_id,
+                energy_amount: measurement.energy_amount,
+            });
+
+            Ok(())
+        }
+
+        // Reported by either party of the trade, the meter oracle or the operator
         #[pallet::weight(10_000)]
         pub fn report_transfer_failure(
             origin: OriginFor<T>,
             order_id: T::Hash,
             reason: Vec<u8>,
         ) -> DispatchResult {
-            let _ = ensure_signed(origin)?;
+            let trusted = T::MeterOracleOrigin::ensure_origin(origin.clone()).is_ok()
+                || <T as energy_trade::Config>::OperatorOrigin::ensure_origin(origin.clone()).is_ok();
+            if !trusted {
+                let who = ensure_signed(origin)?;
+                let order = energy_trade::Pallet::<T>::trade_orders(order_id)
+                    .ok_or(energy_trade::Error::<T>::OrderNotFound)?;
+                ensure!(
+                    order.creator == who || order.counterparty.as_ref() == Some(&who),
+                    Error::<T>::NotTradeParty
+                );
+            }
 
             Transfers::<T>::try_mutate(order_id, |transfer_opt| -> DispatchResult {
                 let transfer = transfer_opt.as_mut().ok_or(Error::<T>::TransferNotFound)?;
+                // Once the delivery is verified the parties go through a dispute instead
+                ensure!(transfer.status == TransferStatus::InProgress, Error::<T>::InvalidTransferStatus);
                 transfer.status = TransferStatus::Failed;
 
+                // Release the escrowed funds and energy of both parties
+                energy_trade::Pallet::<T>::fail_trade(order_id, reason.clone())?;
+
                 Self::deposit_event(Event::TransferFailed {
                     order_id,
                     reason,
@@ -0,0 +1,131 @@
+use crate::{mock::*, Error, IoTMeasurement, TransferStatus};
+use frame_support::{assert_noop, assert_ok};
+use pallet_energy_trade::OrderStatus;
+
+// Account 1 sells 10 units at 5 to account 2 and the transfer is started
+fn matched_trade() -> sp_core::H256 {
+    assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(1), 10));
+    assert_ok!(EnergyTrade::create_ask_order(RuntimeOrigin::signed(1), 10, 5, b"zone-a".to_vec(), 0));
+    assert_ok!(EnergyTrade::create_bid_order(RuntimeOrigin::signed(2), 10, 5, b"zone-a".to_vec(), 0));
+
+    let ask_id = EnergyTrade::account_orders(&1, 0, 1)[0];
+    let bid_id = EnergyTrade::account_orders(&2, 0, 1)[0];
+    assert_ok!(EnergyTrade::match_orders(RuntimeOrigin::signed(3), ask_id, bid_id));
+    assert_ok!(EnergyTransfer::start_transfer(RuntimeOrigin::signed(1), ask_id, 0));
+    ask_id
+}
+
+fn reading(energy_amount: u64) -> IoTMeasurement {
+    IoTMeasurement { device_id: b"meter-1".to_vec(), timestamp: 0, energy_amount, grid_frequency: 50, voltage: 230 }
+}
+
+#[test]
+fn only_the_meter_oracle_completes_a_transfer() {
+    new_test_ext().execute_with(|| {
+        let ask_id = matched_trade();
+
+        assert_noop!(
+            EnergyTransfer::complete_transfer(RuntimeOrigin::signed(1), ask_id, 1, reading(10)),
+            sp_runtime::DispatchError::BadOrigin
+        );
+        assert_ok!(EnergyTransfer::complete_transfer(RuntimeOrigin::signed(MeterOracle::get()), ask_id, 1, reading(8)));
+
+        assert_eq!(EnergyTransfer::transfers(ask_id).unwrap().status, TransferStatus::Completed);
+        let trade = EnergyTrade::trade_orders(ask_id).unwrap();
+        assert_eq!((trade.status, trade.delivered_amount), (OrderStatus::InTransfer, Some(8)));
+    });
+}
+
+#[test]
+fn readings_above_u32_are_delivered_in_full() {
+    new_test_ext().execute_with(|| {
+        let ask_id = matched_trade();
+        let energy_amount = u64::from(u32::MAX) + 1;
+        assert_ok!(EnergyTransfer::complete_transfer(
+            RuntimeOrigin::signed(MeterOracle::get()),
+            ask_id,
+            1,
+            reading(energy_amount),
+        ));
+
+        assert_eq!(EnergyTransfer::transfers(ask_id).unwrap().energy_delivered, u128::from(energy_amount));
+    });
+}
+
+#[test]
+fn outsiders_cannot_fail_a_transfer() {
+    new_test_ext().execute_with(|| {
+        let ask_id = matched_trade();
+
+        assert_noop!(
+            EnergyTransfer::report_transfer_failure(RuntimeOrigin::signed(3), ask_id, b"outage".to_vec()),
+            Error::<Test>::NotTradeParty
+        );
+        assert_eq!(EnergyTrade::trade_orders(ask_id).unwrap().status, OrderStatus::Matched);
+    });
+}
+
+#[test]
+fn trade_parties_and_trusted_origins_fail_a_transfer() {
+    new_test_ext().execute_with(|| {
+        let ask_id = matched_trade();
+        assert_ok!(EnergyTransfer::report_transfer_failure(RuntimeOrigin::signed(2), ask_id, b"outage".to_vec()));
+        assert_eq!(EnergyTransfer::transfers(ask_id).unwrap().status, TransferStatus::Failed);
+        assert_eq!(EnergyTrade::trade_orders(ask_id).unwrap().status, OrderStatus::Failed);
+        assert_eq!(Balances::reserved_balance(2), 0);
+    });
+
+    for origin in [RuntimeOrigin::signed(MeterOracle::get()), RuntimeOrigin::root()] {
+        new_test_ext().execute_with(|| {
+            let ask_id = matched_trade();
+            assert_ok!(EnergyTransfer::report_transfer_failure(origin.clone(), ask_id, b"outage".to_vec()));
+            assert_eq!(EnergyTrade::trade_orders(ask_id).unwrap().status, OrderStatus::Failed);
+        });
+    }
+}
+
+#[test]
+fn only_transfers_in_progress_can_fail() {
+    new_test_ext().execute_with(|| {
+        let ask_id = matched_trade();
+        assert_ok!(EnergyTransfer::complete_transfer(RuntimeOrigin::signed(MeterOracle::get()), ask_id, 1, reading(10)));
+
+        assert_noop!(
+            EnergyTransfer::report_transfer_failure(RuntimeOrigin::signed(2), ask_id, b"outage".to_vec()),
+            Error::<Test>::InvalidTransferStatus
+        );
+        assert_eq!(EnergyTransfer::transfers(ask_id).unwrap().status, TransferStatus::Completed);
+    });
+
+    new_test_ext().execute_with(|| {
+        let ask_id = matched_trade();
+        assert_ok!(EnergyTransfer::report_transfer_failure(RuntimeOrigin::signed(2), ask_id, b"outage".to_vec()));
+
+        assert_noop!(
+            EnergyTransfer::report_transfer_failure(RuntimeOrigin::signed(1), ask_id, b"outage".to_vec()),
+            Error::<Test>::InvalidTransferStatus
+        );
+    });
+}
+
+#[test]
+fn meter_readings_come_from_the_device_owner_or_the_meter_oracle() {
+    new_test_ext().execute_with(|| {
+        let device_id = register_device(1, pallet_user_registry::DeviceType::SmartMeter, 50);
+        let measurement = IoTMeasurement { device_id: EnergyTransfer::meter_id(&device_id).into_inner(), ..reading(5) };
+
+        assert_noop!(
+            EnergyTransfer::submit_meter_reading(RuntimeOrigin::signed(2), measurement.clone()),
+            Error::<Test>::NotDeviceOwner
+        );
+        assert_noop!(
+            EnergyTransfer::submit_meter_reading(RuntimeOrigin::signed(1), reading(5)),
+            Error::<Test>::UnknownDevice
+        );
+        assert_ok!(EnergyTransfer::submit_meter_reading(RuntimeOrigin::signed(1), measurement.clone()));
+        assert_ok!(EnergyTransfer::submit_meter_reading(RuntimeOrigin::signed(MeterOracle::get()), measurement.clone()));
+
+        let meter_id = EnergyTransfer::meter_id(&device_id);
+        assert_eq!(EnergyTransfer::device_readings(meter_id, 0), 10);
+    });
+}
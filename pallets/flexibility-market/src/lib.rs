@@ -0,0 +1,409 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+pub use pallet::*;
+
+#[cfg(test)]
+mod mock;
+#[cfg(test)]
+mod tests;
+
+#[frame_support::pallet]
+pub mod pallet {
+    use frame_support::{
+        pallet_prelude::*,
+        traits::{BalanceStatus, Currency, ReservableCurrency},
+        BoundedVec,
+    };
+    use frame_system::pallet_prelude::*;
+    use pallet_energy_trade::{self as energy_trade, DeliverySlot, TokenBalanceOf};
+    use pallet_energy_transfer as energy_transfer;
+    use pallet_user_registry as user_registry;
+    use scale_info::TypeInfo;
+    use sp_runtime::{
+        traits::{Hash, Saturating, Zero},
+        SaturatedConversion,
+    };
+    use sp_std::prelude::*;
+
+    #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    pub enum RequestStatus {
+        Open,
+        Settled,
+        Cancelled,
+    }
+
+    #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    #[scale_info(skip_type_params(T))]
+    pub struct FlexibilityRequest<T: Config> {
+        pub operator: T::AccountId,
+        pub grid_location: BoundedVec<u8, ConstU32<32>>,
+        pub window_start: DeliverySlot,
+        pub window_end: DeliverySlot,   // Inclusive
+        pub reduction: u64,             // Load reduction per slot, in the meters' energy unit
+        pub price: TokenBalanceOf<T>,     // Per unit of reduction achieved
+        pub offered: u64,
+        pub status: RequestStatus,
+        pub created_at: BlockNumberFor<T>,
+    }
+
+    #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    #[scale_info(skip_type_params(T))]
+    pub struct FlexibilityOffer<T: Config> {
+        pub provider: T::AccountId,
+        pub device_id: T::Hash,
+        pub capacity: u64,  // Reduction per slot the device commits to
+    }
+
+    #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    #[scale_info(skip_type_params(T))]
+    pub struct DeliveryRecord<T: Config> {
+        pub baseline: u64,  // Expected consumption per slot without the reduction
+        pub metered: u64,   // Consumption over the whole window
+        pub achieved: u64,  // Reduction over the whole window, capped at the offered capacity
+        pub payment: TokenBalanceOf<T>,
+    }
+
+    #[pallet::config]
+    pub trait Config: frame_system::Config + energy_transfer::Config + user_registry::Config {
+        type RuntimeEvent: From<Event<Self>> + IsType<<Self as frame_system::Config>::RuntimeEvent>;
+        #[pallet::constant]
+        type MaxOffers: Get<u32>;
+        #[pallet::constant]
+        type MaxWindowSlots: Get<u32>;
+        // Slots before the window averaged into a device's baseline
+        #[pallet::constant]
+        type BaselineSlots: Get<u32>;
+    }
+
+    #[pallet::pallet]
+    pub struct Pallet<T>(_);
+
+    #[pallet::storage]
+    #[pallet::getter(fn requests)]
+    pub type Requests<T: Config> = StorageMap<_, Blake2_128Concat, T::Hash, FlexibilityRequest<T>, OptionQuery>;
+
+    #[pallet::storage]
+    #[pallet::getter(fn offers)]
+    pub type Offers<T: Config> = StorageMap<
+        _,
+        Blake2_128Concat,
+        T::Hash,  // request_id
+        BoundedVec<FlexibilityOffer<T>, T::MaxOffers>,
+        ValueQuery,
+    >;
+
+    #[pallet::storage]
+    #[pallet::getter(fn deliveries)]
+    pub type Deliveries<T: Config> = StorageDoubleMap<
+        _,
+        Blake2_128Concat,
+        T::Hash,  // request_id
+        Blake2_128Concat,
+        T::Hash,  // device_id
+        DeliveryRecord<T>,
+        OptionQuery,
+    >;
+
+    #[pallet::event]
+    #[pallet::generate_deposit(pub(super) fn deposit_event)]
+    pub enum Event<T: Config> {
+        FlexibilityRequested {
+            request_id: T::Hash,
+            operator: T::AccountId,
+            location: Vec<u8>,
+            window_start: DeliverySlot,
+            window_end: DeliverySlot,
+            reduction: u64,
+            price: TokenBalanceOf<T>,
+        },
+        FlexibilityOffered {
+            request_id: T::Hash,
+            provider: T::AccountId,
+            device_id: T::Hash,
+            capacity: u64,
+        },
+        FlexibilityDelivered {
+            request_id: T::Hash,
+            provider: T::AccountId,
+            device_id: T::Hash,
+            baseline: u64,
+            achieved: u64,
+            payment: TokenBalanceOf<T>,
+        },
+        FlexibilityRequestSettled {
+            request_id: T::Hash,
+            paid: TokenBalanceOf<T>,
+            refunded: TokenBalanceOf<T>,
+        },
+        FlexibilityRequestCancelled {
+            request_id: T::Hash,
+        },
+    }
+
+    #[pallet::error]
+    pub enum Error<T> {
+        NotGridOperator,
+        RequestNotFound,
+        InvalidRequestStatus,
+        InvalidWindow,
+        InvalidAmount,
+        InvalidPrice,
+        InvalidLocation,
+        InsufficientBalance,
+        DuplicateRequest,
+        DeviceNotFound,
+        NotDeviceOwner,
+        DeviceInactive,
+        AlreadyOffered,
+        TooManyOffers,
+        ExceedsRequestedReduction,
+        WindowStarted,
+        WindowNotOver,
+        Unauthorized,
+    }
+
+    #[pallet::call]
+    impl<T: Config> Pallet<T> {
+        // The operator's budget for the full reduction over the whole window is reserved
+        #[pallet::call_index(0)]
+        #[pallet::weight(10_000)]
+        pub fn request_flexibility(
+            origin: OriginFor<T>,
+            grid_location: Vec<u8>,
+            window_start: DeliverySlot,
+            window_end: DeliverySlot,
+            reduction: u64,
+            price: TokenBalanceOf<T>,
+        ) -> DispatchResult {
+            let operator = ensure_signed(origin)?;
+            ensure!(user_registry::Pallet::<T>::is_grid_operator(&operator), Error::<T>::NotGridOperator);
+            ensure!(
+                window_start > energy_trade::Pallet::<T>::current_slot() &&
+                    window_end >= window_start &&
+                    window_end - window_start < T::MaxWindowSlots::get(),
+                Error::<T>::InvalidWindow
+            );
+            ensure!(reduction > 0, Error::<T>::InvalidAmount);
+            ensure!(!price.is_zero(), Error::<T>::InvalidPrice);
+
+            let bounded_location: BoundedVec<u8, ConstU32<32>> = grid_location.clone().try_into()
+                .map_err(|_| Error::<T>::InvalidLocation)?;
+
+            let request = FlexibilityRequest {
+                operator: operator.clone(),
+                grid_location: bounded_location,
+                window_start,
+                window_end,
+                reduction,
+                price,
+                offered: 0,
+                status: RequestStatus::Open,
+                created_at: <frame_system::Pallet<T>>::block_number(),
+            };
+            let request_id = T::Hashing::hash_of(&request);
+            ensure!(!Requests::<T>::contains_key(request_id), Error::<T>::DuplicateRequest);
+
+            <T as energy_trade::Config>::Currency::reserve(
+                &operator,
+                energy_trade::Pallet::<T>::to_currency(Self::budget(&request)),
+            ).map_err(|_| Error::<T>::InsufficientBalance)?;
+            Requests::<T>::insert(request_id, request);
+
+            Self::deposit_event(Event::FlexibilityRequested {
+                request_id,
+                operator,
+                location: grid_location,
+                window_start,
+                window_end,
+                reduction,
+                price,
+            });
+
+            Ok(())
+        }
+
+        // Commit a registered device to reduce its load by `capacity` per slot of the window
+        #[pallet::call_index(1)]
+        #[pallet::weight(10_000)]
+        pub fn offer_flexibility(
+            origin: OriginFor<T>,
+            request_id: T::Hash,
+            device_id: T::Hash,
+            capacity: u64,
+        ) -> DispatchResult {
+            let provider = ensure_signed(origin)?;
+            ensure!(capacity > 0, Error::<T>::InvalidAmount);
+
+            let device = user_registry::Pallet::<T>::devices(device_id).ok_or(Error::<T>::DeviceNotFound)?;
+            ensure!(device.owner == provider, Error::<T>::NotDeviceOwner);
+            ensure!(device.active, Error::<T>::DeviceInactive);
+
+            Requests::<T>::try_mutate(request_id, |request| -> DispatchResult {
+                let request = request.as_mut().ok_or(Error::<T>::RequestNotFound)?;
+                ensure!(request.status == RequestStatus::Open, Error::<T>::InvalidRequestStatus);
+                ensure!(
+                    energy_trade::Pallet::<T>::current_slot() < request.window_start,
+                    Error::<T>::WindowStarted
+                );
+
+                let offered = request.offered.saturating_add(capacity);
+                ensure!(offered <= request.reduction, Error::<T>::ExceedsRequestedReduction);
+                request.offered = offered;
+
+                Offers::<T>::try_mutate(request_id, |offers| -> DispatchResult {
+                    ensure!(!offers.iter().any(|o| o.device_id == device_id), Error::<T>::AlreadyOffered);
+                    offers.try_push(FlexibilityOffer { provider: provider.clone(), device_id, capacity })
+                        .map_err(|_| Error::<T>::TooManyOffers)?;
+                    Ok(())
+                })
+            })?;
+
+            Self::deposit_event(Event::FlexibilityOffered {
+                request_id,
+                provider,
+                device_id,
+                capacity,
+            });
+
+            Ok(())
+        }
+
+        #[pallet::call_index(2)]
+        #[pallet::weight(10_000)]
+        pub fn cancel_request(
+            origin: OriginFor<T>,
+            request_id: T::Hash,
+        ) -> DispatchResult {
+            let who = ensure_signed(origin)?;
+
+            Requests::<T>::try_mutate(request_id, |request| -> DispatchResult {
+                let request = request.as_mut().ok_or(Error::<T>::RequestNotFound)?;
+                ensure!(request.operator == who, Error::<T>::Unauthorized);
+                ensure!(request.status == RequestStatus::Open, Error::<T>::InvalidRequestStatus);
+                ensure!(
+                    energy_trade::Pallet::<T>::current_slot() < request.window_start,
+                    Error::<T>::WindowStarted
+                );
+
+                <T as energy_trade::Config>::Currency::unreserve(
+                    &who,
+                    energy_trade::Pallet::<T>::to_currency(Self::budget(request)),
+                );
+                Offers::<T>::remove(request_id);
+                request.status = RequestStatus::Cancelled;
+
+                Self::deposit_event(Event::FlexibilityRequestCancelled { request_id });
+
+                Ok(())
+            })
+        }
+
+        // Once the window is over, pay every offer for the reduction its meter shows against
+        // its baseline and return the rest of the budget to the operator
+        #[pallet::call_index(3)]
+        #[pallet::weight(10_000)]
+        pub fn settle_request(
+            origin: OriginFor<T>,
+            request_id: T::Hash,
+        ) -> DispatchResult {
+            let _ = ensure_signed(origin)?;
+
+            Requests::<T>::try_mutate(request_id, |request| -> DispatchResult {
+                let request = request.as_mut().ok_or(Error::<T>::RequestNotFound)?;
+                ensure!(request.status == RequestStatus::Open, Error::<T>::InvalidRequestStatus);
+                ensure!(
+                    energy_trade::Pallet::<T>::current_slot() > request.window_end,
+                    Error::<T>::WindowNotOver
+                );
+
+                let window_slots = (request.window_end - request.window_start).saturating_add(1) as u64;
+                let mut paid = TokenBalanceOf::<T>::zero();
+
+                for offer in Offers::<T>::take(request_id) {
+                    let meter_id = Self::meter_id(&offer.device_id);
+                    let baseline = Self::baseline(&meter_id, request.window_start);
+                    let metered = Self::metered(&meter_id, request.window_start, request.window_end);
+
+                    // Nothing is paid unless the oracle metered every slot of the baseline and window
+                    let achieved = match (baseline, metered) {
+                        (Some(baseline), Some(metered)) => baseline
+                            .saturating_mul(window_slots)
+                            .saturating_sub(metered)
+                            .min(offer.capacity.saturating_mul(window_slots)),
+                        _ => 0,
+                    };
+                    let payment = request.price.saturating_mul(achieved.saturated_into());
+
+                    if !payment.is_zero() {
+                        let unpaid = <T as energy_trade::Config>::Currency::repatriate_reserved(
+                            &request.operator,
+                            &offer.provider,
+                            energy_trade::Pallet::<T>::to_currency(payment),
+                            BalanceStatus::Free,
+                        )?;
+                        ensure!(unpaid.is_zero(), Error::<T>::InsufficientBalance);
+                        paid = paid.saturating_add(payment);
+                    }
+
+                    let baseline = baseline.unwrap_or_default();
+                    Deliveries::<T>::insert(request_id, offer.device_id, DeliveryRecord {
+                        baseline,
+                        metered: metered.unwrap_or_default(),
+                        achieved,
+                        payment,
+                    });
+
+                    Self::deposit_event(Event::FlexibilityDelivered {
+                        request_id,
+                        provider: offer.provider,
+                        device_id: offer.device_id,
+                        baseline,
+                        achieved,
+                        payment,
+                    });
+                }
+
+                let refunded = Self::budget(request).saturating_sub(paid);
+                <T as energy_trade::Config>::Currency::unreserve(
+                    &request.operator,
+                    energy_trade::Pallet::<T>::to_currency(refunded),
+                );
+                request.status = RequestStatus::Settled;
+
+                Self::deposit_event(Event::FlexibilityRequestSettled { request_id, paid, refunded });
+
+                Ok(())
+            })
+        }
+    }
+
+    impl<T: Config> Pallet<T> {
+        // Most the operator can owe: the full reduction in every slot of the window
+        pub fn budget(request: &FlexibilityRequest<T>) -> TokenBalanceOf<T> {
+            let window_slots = (request.window_end - request.window_start).saturating_add(1) as u64;
+            request.price.saturating_mul(request.reduction.saturating_mul(window_slots).saturated_into())
+        }
+
+        pub fn meter_id(device_id: &T::Hash) -> BoundedVec<u8, ConstU32<64>> {
+            energy_transfer::Pallet::<T>::meter_id(device_id)
+        }
+
+        // Average consumption over the `BaselineSlots` slots before the window, as metered by
+        // the oracle. None unless every one of those slots has a reading
+        pub fn baseline(meter_id: &BoundedVec<u8, ConstU32<64>>, window_start: DeliverySlot) -> Option<u64> {
+            let slots = T::BaselineSlots::get().max(1);
+            let first = window_start.checked_sub(slots)?;
+            let total = Self::metered(meter_id, first, window_start - 1)?;
+            Some(total / slots as u64)
+        }
+
+        // Oracle-metered consumption from `first` to `last`, both inclusive. None if a slot has
+        // no reading
+        pub fn metered(meter_id: &BoundedVec<u8, ConstU32<64>>, first: DeliverySlot, last: DeliverySlot) -> Option<u64> {
+            (first..=last).try_fold(0u64, |total, slot| {
+                energy_transfer::Pallet::<T>::oracle_readings(meter_id, slot)
+                    .map(|energy| total.saturating_add(energy))
+            })
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use crate::{mock::*, Error, Event, RequestStatus};
+use frame_support::{assert_noop, assert_ok, traits::Get};
+use pallet_energy_transfer::IoTMeasurement;
+use pallet_user_registry::{DeviceType, UserRole};
+
+const OPERATOR: u64 = 5;
+
+// Reduction of 10 per slot at a price of 5 over slots 4 and 5
+fn request() -> sp_core::H256 {
+    if UserRegistry::user_profiles(OPERATOR).is_none() {
+        assert_ok!(UserRegistry::register_user(RuntimeOrigin::signed(OPERATOR), UserRole::GridOperator));
+    }
+    assert_ok!(FlexibilityMarket::request_flexibility(RuntimeOrigin::signed(OPERATOR), b"zone-a".to_vec(), 4, 5, 10, 5));
+    System::events()
+        .iter()
+        .find_map(|r| match r.event {
+            RuntimeEvent::FlexibilityMarket(Event::FlexibilityRequested { request_id, .. }) => Some(request_id),
+            _ => None,
+        })
+        .unwrap()
+}
+
+fn slot_start(slot: u64) -> u64 {
+    slot * <<Test as pallet_energy_trade::Config>::SlotDuration as Get<u64>>::get()
+}
+
+// Reading submitted by the meter oracle
+fn meter(device_id: sp_core::H256, slot: u64, energy_amount: u64) {
+    meter_as(MeterOracle::get(), device_id, slot, energy_amount);
+}
+
+fn meter_as(who: u64, device_id: sp_core::H256, slot: u64, energy_amount: u64) {
+    assert_ok!(EnergyTransfer::submit_meter_reading(RuntimeOrigin::signed(who), IoTMeasurement {
+        device_id: FlexibilityMarket::meter_id(&device_id).into_inner(),
+        timestamp: slot_start(slot),
+        energy_amount,
+        grid_frequency: 50,
+        voltage: 230,
+    }));
+}
+
+#[test]
+fn requests_come_from_grid_operators_and_reserve_the_budget() {
+    new_test_ext().execute_with(|| {
+        assert_noop!(
+            FlexibilityMarket::request_flexibility(RuntimeOrigin::signed(1), b"zone-a".to_vec(), 4, 5, 10, 5),
+            Error::<Test>::NotGridOperator
+        );
+        assert_ok!(UserRegistry::register_user(RuntimeOrigin::signed(OPERATOR), UserRole::GridOperator));
+        assert_noop!(
+            FlexibilityMarket::request_flexibility(RuntimeOrigin::signed(OPERATOR), b"zone-a".to_vec(), 0, 1, 10, 5),
+            Error::<Test>::InvalidWindow
+        );
+        assert_noop!(
+            FlexibilityMarket::request_flexibility(RuntimeOrigin::signed(OPERATOR), b"zone-a".to_vec(), 4, 20, 10, 5),
+            Error::<Test>::InvalidWindow
+        );
+
+        let request_id = request();
+        assert_eq!(Balances::reserved_balance(OPERATOR), 100);
+        assert_ok!(FlexibilityMarket::cancel_request(RuntimeOrigin::signed(OPERATOR), request_id));
+        assert_eq!(Balances::reserved_balance(OPERATOR), 0);
+        assert_eq!(FlexibilityMarket::requests(request_id).unwrap().status, RequestStatus::Cancelled);
+    });
+}
+
+#[test]
+fn offers_need_an_owned_device_within_the_requested_reduction() {
+    new_test_ext().execute_with(|| {
+        let request_id = request();
+        let device_id = register_device(1, DeviceType::SmartMeter, 50);
+
+        assert_noop!(
+            FlexibilityMarket::offer_flexibility(RuntimeOrigin::signed(2), request_id, device_id, 5),
+            Error::<Test>::NotDeviceOwner
+        );
+        assert_noop!(
+            FlexibilityMarket::offer_flexibility(RuntimeOrigin::signed(1), request_id, device_id, 11),
+            Error::<Test>::ExceedsRequestedReduction
+        );
+        assert_ok!(FlexibilityMarket::offer_flexibility(RuntimeOrigin::signed(1), request_id, device_id, 5));
+        assert_noop!(
+            FlexibilityMarket::offer_flexibility(RuntimeOrigin::signed(1), request_id, device_id, 5),
+            Error::<Test>::AlreadyOffered
+        );
+        assert_eq!(FlexibilityMarket::requests(request_id).unwrap().offered, 5);
+    });
+}
+
+#[test]
+fn settlement_pays_the_metered_reduction_against_the_baseline() {
+    new_test_ext().execute_with(|| {
+        let request_id = request();
+        let device_id = register_device(1, DeviceType::SmartMeter, 50);
+        assert_ok!(FlexibilityMarket::offer_flexibility(RuntimeOrigin::signed(1), request_id, device_id, 10));
+
+        // A baseline of 20 per slot and 15 per slot metered during the window
+        for slot in 0..4 {
+            meter(device_id, slot, 20);
+        }
+        meter(device_id, 4, 15);
+        meter(device_id, 5, 15);
+
+        assert_noop!(
+            FlexibilityMarket::settle_request(RuntimeOrigin::signed(3), request_id),
+            Error::<Test>::WindowNotOver
+        );
+        Timestamp::set_timestamp(slot_start(6));
+        assert_ok!(FlexibilityMarket::settle_request(RuntimeOrigin::signed(3), request_id));
+
+        let delivery = FlexibilityMarket::deliveries(request_id, device_id).unwrap();
+        assert_eq!((delivery.baseline, delivery.metered, delivery.achieved, delivery.payment), (20, 30, 10, 50));
+        assert_eq!(Balances::free_balance(1), 10_000 + 50);
+        assert_eq!(Balances::free_balance(OPERATOR), 10_000 - 50);
+        assert_eq!(Balances::reserved_balance(OPERATOR), 0);
+    });
+}
+
+#[test]
+fn settlement_only_counts_oracle_readings() {
+    new_test_ext().execute_with(|| {
+        let request_id = request();
+        let device_id = register_device(1, DeviceType::SmartMeter, 50);
+        assert_ok!(FlexibilityMarket::offer_flexibility(RuntimeOrigin::signed(1), request_id, device_id, 10));
+
+        // The owner reports a low window themselves while the oracle only saw the baseline
+        for slot in 0..4 {
+            meter(device_id, slot, 20);
+        }
+        meter_as(1, device_id, 4, 0);
+        meter_as(1, device_id, 5, 0);
+
+        Timestamp::set_timestamp(slot_start(6));
+        assert_ok!(FlexibilityMarket::settle_request(RuntimeOrigin::signed(3), request_id));
+
+        let delivery = FlexibilityMarket::deliveries(request_id, device_id).unwrap();
+        assert_eq!((delivery.achieved, delivery.payment), (0, 0));
+        assert_eq!(Balances::free_balance(1), 10_000);
+        assert_eq!(Balances::reserved_balance(OPERATOR), 0);
+    });
+}
+
+#[test]
+fn settlement_needs_a_reading_for_every_slot() {
+    new_test_ext().execute_with(|| {
+        let request_id = request();
+        let missing_window = register_device(1, DeviceType::SmartMeter, 50);
+        let missing_baseline = register_device(2, DeviceType::SmartMeter, 50);
+        assert_ok!(FlexibilityMarket::offer_flexibility(RuntimeOrigin::signed(1), request_id, missing_window, 5));
+        assert_ok!(FlexibilityMarket::offer_flexibility(RuntimeOrigin::signed(2), request_id, missing_baseline, 5));
+
+        // Slot 5 of the window is never metered for the first device
+        for slot in 0..5 {
+            meter(missing_window, slot, 20);
+        }
+        // The second device only has readings for two of the four baseline slots
+        for slot in 2..6 {
+            meter(missing_baseline, slot, if slot < 4 { 20 } else { 15 });
+        }
+
+        Timestamp::set_timestamp(slot_start(6));
+        assert_ok!(FlexibilityMarket::settle_request(RuntimeOrigin::signed(3), request_id));
+
+        assert_eq!(FlexibilityMarket::deliveries(request_id, missing_window).unwrap().payment, 0);
+        assert_eq!(FlexibilityMarket::deliveries(request_id, missing_baseline).unwrap().payment, 0);
+        assert_eq!(Balances::free_balance(OPERATOR), 10_000);
+        assert_eq!(Balances::reserved_balance(OPERATOR), 0);
+    });
+}
@@ -1,17 +1,18 @@
 #![cfg_attr(not(feature = "std"), no_std)]
 
-pub use frame_system::pallet::*;
+pub use pallet::*;
 
 #[frame_support::pallet]
 pub mod pallet {
     use frame_support::{pallet_prelude::*, traits::Currency};
     use frame_system::pallet_prelude::*;
-    use pallet_energy_trade::{self as energy_trade};
+    use pallet_energy_trade::{self as energy_trade, TokenBalanceOf};
     use scale_info::TypeInfo;
     use sp_std::prelude::*;
     use sp_io;
+    use sp_runtime::{traits::{Hash, Zero}, SaturatedConversion};
 
-    #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo)]
+    #[derive(Clone, Encode, Decode, DecodeWithMemTracking, PartialEq, RuntimeDebug, TypeInfo)]
     pub enum PaymentMethod {
         Native,             // Platform's native token
         Fiat,              // Traditional currency
@@ -42,11 +43,11 @@ pub mod pallet {
         pub order_id: T::Hash,
         pub payer: T::AccountId,
         pub payee: T::AccountId,
-        pub amount: T::TokenBalance,
+        pub amount: TokenBalanceOf<T>,
         pub payment_method: PaymentMethod,
         pub status: PaymentStatus,
         pub external_reference: Option<Vec<u8>>,
-        pub timestamp: T::BlockNumber,
+        pub timestamp: BlockNumberFor<T>,
     }
 
     #[pallet::config]
@@ -56,6 +57,7 @@ pub mod pallet {
     }
 
     #[pallet::pallet]
+    #[pallet::without_storage_info]
     pub struct Pallet<T>(_);
 
     #[pallet::storage]
@@ -84,7 +86,7 @@ pub mod pallet {
         PaymentCreated {
             payment_id: T::Hash,
             order_id: T::Hash,
-            amount: T::TokenBalance,
+            amount: TokenBalanceOf<T>,
             method: PaymentMethod,
         },
         PaymentCompleted {
@@ -100,6 +102,11 @@ pub mod pallet {
             to_token: Vec<u8>,
             rate: u128,
         },
+        PaymentRefunded {
+            payment_id: T::Hash,
+            order_id: T::Hash,
+            amount: TokenBalanceOf<T>,
+        },
     }
 
     #[pallet::error]
@@ -164,10 +171,10 @@ pub mod pallet {
                 ensure!(payment.payment_method == PaymentMethod::Native, Error::<T>::PaymentMethodNotSupported);
 
                 // Transfer native tokens
-                T::Currency::transfer(
+                <T as Config>::Currency::transfer(
                     &payment.payer,
                     &payment.payee,
-                    payment.amount,
+                    payment.amount.saturated_into::<u128>().saturated_into(),
                     frame_support::traits::ExistenceRequirement::KeepAlive,
                 )?;
 
@@ -261,12 +268,52 @@ pub mod pallet {
             !proof.is_empty()
         }
 
+        // Move a settlement correction between the parties of a completed trade, e.g. after
+        // a delivery dispute, and keep it on record as a refunded payment
+        pub fn refund(
+            order_id: T::Hash,
+            payer: T::AccountId,
+            payee: T::AccountId,
+            amount: TokenBalanceOf<T>,
+        ) -> DispatchResult {
+            ensure!(!amount.is_zero(), Error::<T>::InvalidAmount);
+
+            <T as Config>::Currency::transfer(
+                &payer,
+                &payee,
+                amount.saturated_into::<u128>().saturated_into(),
+                frame_support::traits::ExistenceRequirement::AllowDeath,
+            ).map_err(|_| Error::<T>::InsufficientBalance)?;
+
+            let payment = Payment {
+                order_id,
+                payer,
+                payee,
+                amount,
+                payment_method: PaymentMethod::Native,
+                status: PaymentStatus::Refunded,
+                external_reference: None,
+                timestamp: <frame_system::Pallet<T>>::block_number(),
+            };
+
+            let payment_id = T::Hashing::hash_of(&payment);
+            <Payments<T>>::insert(payment_id, payment);
+
+            Self::deposit_event(Event::PaymentRefunded {
+                payment_id,
+                order_id,
+                amount,
+            });
+
+            Ok(())
+        }
+
         // Convert amount between different currencies
         pub fn convert_amount(
-            amount: T::TokenBalance,
+            amount: TokenBalanceOf<T>,
             from_token: &[u8],
             to_token: &[u8],
-        ) -> Result<T::TokenBalance, Error<T>> {
+        ) -> Result<TokenBalanceOf<T>, Error<T>> {
             if let Some(rate) = ExchangeRates::<T>::get((from_token.to_vec(), to_token.to_vec())) {
                 let amount_u128: u128 = amount.saturated_into();
                 let converted = amount_u128.saturating_mul(rate.rate);
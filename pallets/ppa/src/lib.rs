@@ -0,0 +1,467 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+pub use pallet::*;
+
+#[cfg(test)]
+mod mock;
+#[cfg(test)]
+mod tests;
+
+#[frame_support::pallet]
+pub mod pallet {
+    use frame_support::{
+        pallet_prelude::*,
+        traits::{Currency, ExistenceRequirement},
+        BoundedVec,
+    };
+    use frame_system::pallet_prelude::*;
+    use pallet_energy_trade::{self as energy_trade, DeliverySlot, TokenBalanceOf};
+    use pallet_energy_transfer::{self as energy_transfer};
+    use pallet_price_discovery::{self as price_discovery};
+    use pallet_user_registry::{self as user_registry};
+    use scale_info::TypeInfo;
+    use sp_runtime::{
+        traits::{Hash, Saturating, Zero},
+        Percent, SaturatedConversion,
+    };
+    use sp_std::prelude::*;
+
+    #[derive(Clone, Encode, Decode, DecodeWithMemTracking, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    pub enum PpaPrice<Balance> {
+        Fixed(Balance),
+        // Reference price of the contract location less a discount, never below the floor
+        Indexed { discount: Percent, floor: Balance },
+    }
+
+    #[derive(Clone, Encode, Decode, DecodeWithMemTracking, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    pub enum VolumeShape<Balance, DeviceId> {
+        Baseload(Balance),  // Fixed amount every settlement period
+        // Share of the generation metered on one of the seller's registered devices
+        AsProduced { share: Percent, device_id: DeviceId },
+    }
+
+    #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    pub enum PpaStatus {
+        Proposed,
+        Active,
+        Terminated,
+        Expired,
+    }
+
+    #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    #[scale_info(skip_type_params(T))]
+    pub struct PowerPurchaseAgreement<T: Config> {
+        pub seller: T::AccountId,
+        pub buyer: T::AccountId,
+        pub price: PpaPrice<TokenBalanceOf<T>>,
+        pub volume: VolumeShape<TokenBalanceOf<T>, T::Hash>,
+        pub grid_location: BoundedVec<u8, ConstU32<32>>,
+        pub start: BlockNumberFor<T>,
+        pub end: BlockNumberFor<T>,
+        pub settlement_period: BlockNumberFor<T>,
+        pub next_settlement: BlockNumberFor<T>,
+        // Owed to the counterparty for every settlement period cut short by termination
+        pub termination_penalty: TokenBalanceOf<T>,
+        // First delivery slot whose metered production has not been settled yet
+        pub production_from: DeliverySlot,
+        pub status: PpaStatus,
+        pub created_at: BlockNumberFor<T>,
+    }
+
+    #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    #[scale_info(skip_type_params(T))]
+    pub struct PpaSettlement<T: Config> {
+        pub volume: TokenBalanceOf<T>,
+        pub price_per_unit: TokenBalanceOf<T>,
+        pub trade_id: Option<T::Hash>,
+        pub settled_at: BlockNumberFor<T>,
+    }
+
+    #[pallet::config]
+    pub trait Config:
+        frame_system::Config + price_discovery::Config + energy_transfer::Config + user_registry::Config
+    {
+        type RuntimeEvent: From<Event<Self>> + IsType<<Self as frame_system::Config>::RuntimeEvent>;
+        #[pallet::constant]
+        type MinSettlementPeriod: Get<BlockNumberFor<Self>>;
+        #[pallet::constant]
+        type MaxActivePpas: Get<u32>;
+        // Delivery slots of metered production summed in one settlement; older slots carry over
+        #[pallet::constant]
+        type MaxProductionSlots: Get<u32>;
+    }
+
+    #[pallet::pallet]
+    pub struct Pallet<T>(_);
+
+    #[pallet::storage]
+    #[pallet::getter(fn ppas)]
+    pub type Ppas<T: Config> = StorageMap<_, Blake2_128Concat, T::Hash, PowerPurchaseAgreement<T>, OptionQuery>;
+
+    #[pallet::storage]
+    #[pallet::getter(fn active_ppas)]
+    pub type ActivePpas<T: Config> = StorageValue<_, BoundedVec<T::Hash, T::MaxActivePpas>, ValueQuery>;
+
+    #[pallet::storage]
+    #[pallet::getter(fn settlements)]
+    pub type Settlements<T: Config> = StorageDoubleMap<
+        _,
+        Blake2_128Concat,
+        T::Hash,  // contract_id
+        Twox64Concat,
+        u32,      // settlement period index
+        PpaSettlement<T>,
+        OptionQuery,
+    >;
+
+    // Why a period could not be settled; as-produced volume stays pending for the next period
+    #[pallet::storage]
+    #[pallet::getter(fn settlement_failures)]
+    pub type SettlementFailures<T: Config> = StorageDoubleMap<
+        _,
+        Blake2_128Concat,
+        T::Hash,  // contract_id
+        Twox64Concat,
+        u32,      // settlement period index
+        DispatchError,
+        OptionQuery,
+    >;
+
+    #[pallet::event]
+    #[pallet::generate_deposit(pub(super) fn deposit_event)]
+    pub enum Event<T: Config> {
+        PpaProposed {
+            contract_id: T::Hash,
+            seller: T::AccountId,
+            buyer: T::AccountId,
+        },
+        PpaSigned {
+            contract_id: T::Hash,
+        },
+        PpaSettled {
+            contract_id: T::Hash,
+            period: u32,
+            volume: TokenBalanceOf<T>,
+            price: TokenBalanceOf<T>,
+            trade_id: Option<T::Hash>,
+        },
+        PpaSettlementFailed {
+            contract_id: T::Hash,
+            period: u32,
+            error: DispatchError,
+        },
+        PpaTerminated {
+            contract_id: T::Hash,
+            terminated_by: T::AccountId,
+            penalty: TokenBalanceOf<T>,
+        },
+        PpaExpired {
+            contract_id: T::Hash,
+        },
+    }
+
+    #[pallet::error]
+    pub enum Error<T> {
+        ContractNotFound,
+        InvalidContractStatus,
+        InvalidSchedule,
+        InvalidPrice,
+        InvalidVolume,
+        InvalidLocation,
+        Unauthorized,
+        DuplicateContract,
+        TooManyContracts,
+        NotDeviceOwner,
+        PenaltyPaymentFailed,
+    }
+
+    #[pallet::hooks]
+    impl<T: Config> Hooks<BlockNumberFor<T>> for Pallet<T> {
+        fn on_initialize(n: BlockNumberFor<T>) -> Weight {
+            let active = Self::active_ppas();
+            let mut still_active: Vec<T::Hash> = Vec::new();
+
+            for contract_id in active.iter() {
+                let Some(mut ppa) = Self::ppas(contract_id) else { continue };
+
+                // One period per block keeps the hook bounded if settlement fell behind
+                if ppa.status == PpaStatus::Active && n >= ppa.next_settlement {
+                    Self::settle_period(*contract_id, &mut ppa);
+                    ppa.next_settlement = ppa.next_settlement.saturating_add(ppa.settlement_period);
+
+                    if ppa.next_settlement > ppa.end {
+                        ppa.status = PpaStatus::Expired;
+                        Self::deposit_event(Event::PpaExpired { contract_id: *contract_id });
+                    }
+                    Ppas::<T>::insert(contract_id, &ppa);
+                }
+
+                if ppa.status == PpaStatus::Active {
+                    still_active.push(*contract_id);
+                }
+            }
+
+            let len = active.len() as u64;
+            if still_active.len() as u64 != len {
+                ActivePpas::<T>::put(BoundedVec::truncate_from(still_active));
+            }
+
+            T::DbWeight::get().reads_writes(
+                len.saturating_mul(6).saturating_add(1),
+                len.saturating_mul(6).saturating_add(1),
+            )
+        }
+    }
+
+    #[pallet::call]
+    impl<T: Config> Pallet<T> {
+        #[pallet::call_index(0)]
+        #[pallet::weight(10_000)]
+        pub fn propose_ppa(
+            origin: OriginFor<T>,
+            buyer: T::AccountId,
+            price: PpaPrice<TokenBalanceOf<T>>,
+            volume: VolumeShape<TokenBalanceOf<T>, T::Hash>,
+            grid_location: Vec<u8>,
+            start: BlockNumberFor<T>,
+            end: BlockNumberFor<T>,
+            settlement_period: BlockNumberFor<T>,
+            termination_penalty: TokenBalanceOf<T>,
+        ) -> DispatchResult {
+            let seller = ensure_signed(origin)?;
+            ensure!(seller != buyer, Error::<T>::Unauthorized);
+
+            let current_block = <frame_system::Pallet<T>>::block_number();
+            ensure!(
+                settlement_period >= T::MinSettlementPeriod::get() && !settlement_period.is_zero(),
+                Error::<T>::InvalidSchedule
+            );
+            ensure!(
+                start >= current_block && end >= start.saturating_add(settlement_period),
+                Error::<T>::InvalidSchedule
+            );
+
+            match &price {
+                PpaPrice::Fixed(p) => ensure!(!p.is_zero(), Error::<T>::InvalidPrice),
+                PpaPrice::Indexed { .. } => {},
+            }
+            match &volume {
+                VolumeShape::Baseload(amount) => ensure!(!amount.is_zero(), Error::<T>::InvalidVolume),
+                VolumeShape::AsProduced { share, device_id } => {
+                    ensure!(!share.is_zero(), Error::<T>::InvalidVolume);
+                    let device = user_registry::Pallet::<T>::devices(device_id).ok_or(Error::<T>::NotDeviceOwner)?;
+                    ensure!(device.owner == seller, Error::<T>::NotDeviceOwner);
+                },
+            }
+
+            let bounded_location: BoundedVec<u8, ConstU32<32>> = grid_location.try_into()
+                .map_err(|_| Error::<T>::InvalidLocation)?;
+
+            let ppa = PowerPurchaseAgreement {
+                seller: seller.clone(),
+                buyer: buyer.clone(),
+                price,
+                volume,
+                grid_location: bounded_location,
+                start,
+                end,
+                settlement_period,
+                next_settlement: start.saturating_add(settlement_period),
+                termination_penalty,
+                production_from: Zero::zero(),
+                status: PpaStatus::Proposed,
+                created_at: current_block,
+            };
+
+            let contract_id = T::Hashing::hash_of(&ppa);
+            ensure!(!Ppas::<T>::contains_key(contract_id), Error::<T>::DuplicateContract);
+            Ppas::<T>::insert(contract_id, ppa);
+
+            Self::deposit_event(Event::PpaProposed {
+                contract_id,
+                seller,
+                buyer,
+            });
+
+            Ok(())
+        }
+
+        #[pallet::call_index(1)]
+        #[pallet::weight(10_000)]
+        pub fn sign_ppa(
+            origin: OriginFor<T>,
+            contract_id: T::Hash,
+        ) -> DispatchResult {
+            let who = ensure_signed(origin)?;
+
+            Ppas::<T>::try_mutate(contract_id, |ppa| -> DispatchResult {
+                let ppa = ppa.as_mut().ok_or(Error::<T>::ContractNotFound)?;
+                ensure!(ppa.buyer == who, Error::<T>::Unauthorized);
+                ensure!(ppa.status == PpaStatus::Proposed, Error::<T>::InvalidContractStatus);
+                ensure!(
+                    <frame_system::Pallet<T>>::block_number() < ppa.end,
+                    Error::<T>::InvalidSchedule
+                );
+
+                ActivePpas::<T>::try_mutate(|active| {
+                    active.try_push(contract_id).map_err(|_| Error::<T>::TooManyContracts)
+                })?;
+                ppa.status = PpaStatus::Active;
+                ppa.production_from = energy_trade::Pallet::<T>::current_slot();
+
+                Self::deposit_event(Event::PpaSigned { contract_id });
+
+                Ok(())
+            })
+        }
+
+        #[pallet::call_index(3)]
+        #[pallet::weight(10_000)]
+        pub fn terminate_ppa(
+            origin: OriginFor<T>,
+            contract_id: T::Hash,
+        ) -> DispatchResult {
+            let who = ensure_signed(origin)?;
+
+            Ppas::<T>::try_mutate(contract_id, |ppa| -> DispatchResult {
+                let ppa = ppa.as_mut().ok_or(Error::<T>::ContractNotFound)?;
+                let counterparty = if ppa.seller == who {
+                    ppa.buyer.clone()
+                } else if ppa.buyer == who {
+                    ppa.seller.clone()
+                } else {
+                    return Err(Error::<T>::Unauthorized.into());
+                };
+
+                let penalty = match ppa.status {
+                    // Withdrawing an unsigned proposal costs nothing
+                    PpaStatus::Proposed => Zero::zero(),
+                    PpaStatus::Active => {
+                        let penalty = ppa.termination_penalty
+                            .saturating_mul(Self::remaining_periods(ppa).into());
+                        if !penalty.is_zero() {
+                            <T as energy_trade::Config>::Currency::transfer(
+                                &who,
+                                &counterparty,
+                                energy_trade::Pallet::<T>::to_currency(penalty),
+                                ExistenceRequirement::KeepAlive,
+                            ).map_err(|_| Error::<T>::PenaltyPaymentFailed)?;
+                        }
+                        ActivePpas::<T>::mutate(|active| active.retain(|id| *id != contract_id));
+                        penalty
+                    },
+                    _ => return Err(Error::<T>::InvalidContractStatus.into()),
+                };
+
+                ppa.status = PpaStatus::Terminated;
+
+                Self::deposit_event(Event::PpaTerminated {
+                    contract_id,
+                    terminated_by: who,
+                    penalty,
+                });
+
+                Ok(())
+            })
+        }
+    }
+
+    impl<T: Config> Pallet<T> {
+        fn period_index(ppa: &PowerPurchaseAgreement<T>) -> u32 {
+            (ppa.next_settlement.saturating_sub(ppa.start) / ppa.settlement_period)
+                .saturated_into()
+        }
+
+        // Settlement periods not yet settled, counting the one in progress
+        fn remaining_periods(ppa: &PowerPurchaseAgreement<T>) -> u32 {
+            let remaining: u32 = (ppa.end.saturating_sub(ppa.next_settlement) / ppa.settlement_period)
+                .saturated_into();
+            remaining.saturating_add(1)
+        }
+
+        fn period_price(ppa: &PowerPurchaseAgreement<T>) -> Option<TokenBalanceOf<T>> {
+            let price = match &ppa.price {
+                PpaPrice::Fixed(price) => *price,
+                PpaPrice::Indexed { discount, floor } => {
+                    let reference = price_discovery::Pallet::<T>::market_data(ppa.grid_location.to_vec())
+                        .map(|data| data.current_price)
+                        .unwrap_or_default();
+                    reference.saturating_sub(*discount * reference).max(*floor)
+                },
+            };
+            Some(price).filter(|price| !price.is_zero())
+        }
+
+        // Volume of the period and the slot up to which metered production is included
+        fn period_volume(ppa: &PowerPurchaseAgreement<T>) -> (TokenBalanceOf<T>, DeliverySlot) {
+            match &ppa.volume {
+                VolumeShape::Baseload(amount) => (*amount, ppa.production_from),
+                VolumeShape::AsProduced { share, device_id } => {
+                    // Only slots that are over have their full readings, and only the meter
+                    // oracle's count since the seller owns the device
+                    let until = energy_trade::Pallet::<T>::current_slot()
+                        .min(ppa.production_from.saturating_add(T::MaxProductionSlots::get()));
+                    let meter_id = energy_transfer::Pallet::<T>::meter_id(device_id);
+                    let produced = (ppa.production_from..until)
+                        .filter_map(|slot| energy_transfer::Pallet::<T>::oracle_readings(&meter_id, slot))
+                        .fold(0u64, |total, energy| total.saturating_add(energy));
+                    (*share * produced.saturated_into::<TokenBalanceOf<T>>(), until.max(ppa.production_from))
+                },
+            }
+        }
+
+        fn settlement_failed(contract_id: T::Hash, period: u32, error: DispatchError) {
+            SettlementFailures::<T>::insert(contract_id, period, error);
+            Self::deposit_event(Event::PpaSettlementFailed { contract_id, period, error });
+        }
+
+        // Turn the due period into an escrowed trade between the parties. Metered production
+        // is only consumed once its trade exists
+        fn settle_period(contract_id: T::Hash, ppa: &mut PowerPurchaseAgreement<T>) {
+            let period = Self::period_index(ppa);
+            let Some(price) = Self::period_price(ppa) else {
+                Self::settlement_failed(contract_id, period, Error::<T>::InvalidPrice.into());
+                return;
+            };
+            let (volume, production_until) = Self::period_volume(ppa);
+
+            let trade_id = if volume.is_zero() {
+                None
+            } else {
+                let created = frame_support::storage::with_storage_layer(|| {
+                    energy_trade::Pallet::<T>::create_matched_trade(
+                        ppa.seller.clone(),
+                        ppa.buyer.clone(),
+                        volume,
+                        price,
+                        ppa.grid_location.to_vec(),
+                        energy_trade::Pallet::<T>::current_slot(),
+                    )
+                });
+                match created {
+                    Ok(trade_id) => Some(trade_id),
+                    Err(error) => {
+                        Self::settlement_failed(contract_id, period, error);
+                        return;
+                    },
+                }
+            };
+            ppa.production_from = production_until;
+
+            Settlements::<T>::insert(contract_id, period, PpaSettlement {
+                volume,
+                price_per_unit: price,
+                trade_id,
+                settled_at: <frame_system::Pallet<T>>::block_number(),
+            });
+
+            Self::deposit_event(Event::PpaSettled {
+                contract_id,
+                period,
+                volume,
+                price,
+                trade_id,
+            });
+        }
+    }
+}
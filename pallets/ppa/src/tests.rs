@@ -0,0 +1,195 @@
+use crate::{mock::*, Error, Event, PpaPrice, PpaStatus, VolumeShape};
+use frame_support::{assert_noop, assert_ok, traits::Get};
+use pallet_energy_trade::OrderStatus;
+use pallet_energy_transfer::IoTMeasurement;
+use pallet_user_registry::DeviceType;
+use sp_runtime::Percent;
+
+// Account 1 sells 10 units at 5 to account 2 every 10 blocks from block 1 to 21
+fn propose_baseload(termination_penalty: u128) -> sp_core::H256 {
+    assert_ok!(Ppa::propose_ppa(
+        RuntimeOrigin::signed(1),
+        2,
+        PpaPrice::Fixed(5),
+        VolumeShape::Baseload(10),
+        b"zone-a".to_vec(),
+        1,
+        21,
+        10,
+        termination_penalty,
+    ));
+    System::events()
+        .iter()
+        .find_map(|r| match r.event {
+            RuntimeEvent::Ppa(Event::PpaProposed { contract_id, .. }) => Some(contract_id),
+            _ => None,
+        })
+        .unwrap()
+}
+
+// Half of what `device_id` of account 1 produces, sold to account 2 every 10 blocks from block 1 to 21
+fn propose_as_produced(device_id: sp_core::H256) -> sp_core::H256 {
+    assert_ok!(Ppa::propose_ppa(
+        RuntimeOrigin::signed(1),
+        2,
+        PpaPrice::Fixed(5),
+        VolumeShape::AsProduced { share: Percent::from_percent(50), device_id },
+        b"zone-a".to_vec(),
+        1,
+        21,
+        10,
+        0,
+    ));
+    System::events()
+        .iter()
+        .find_map(|r| match r.event {
+            RuntimeEvent::Ppa(Event::PpaProposed { contract_id, .. }) => Some(contract_id),
+            _ => None,
+        })
+        .unwrap()
+}
+
+fn slot_start(slot: u64) -> u64 {
+    slot * <<Test as pallet_energy_trade::Config>::SlotDuration as Get<u64>>::get()
+}
+
+fn meter(device_id: sp_core::H256, slot: u64, energy_amount: u64) {
+    assert_ok!(EnergyTransfer::submit_meter_reading(RuntimeOrigin::signed(MeterOracle::get()), IoTMeasurement {
+        device_id: EnergyTransfer::meter_id(&device_id).into_inner(),
+        timestamp: slot_start(slot),
+        energy_amount,
+        grid_frequency: 50,
+        voltage: 230,
+    }));
+}
+
+#[test]
+fn only_the_buyer_signs_a_valid_proposal() {
+    new_test_ext().execute_with(|| {
+        assert_noop!(
+            Ppa::propose_ppa(
+                RuntimeOrigin::signed(1),
+                2,
+                PpaPrice::Fixed(5),
+                VolumeShape::Baseload(10),
+                b"zone-a".to_vec(),
+                1,
+                21,
+                2,
+                0,
+            ),
+            Error::<Test>::InvalidSchedule
+        );
+        let contract_id = propose_baseload(0);
+
+        assert_noop!(Ppa::sign_ppa(RuntimeOrigin::signed(1), contract_id), Error::<Test>::Unauthorized);
+        assert_ok!(Ppa::sign_ppa(RuntimeOrigin::signed(2), contract_id));
+        assert_eq!(Ppa::ppas(contract_id).unwrap().status, PpaStatus::Active);
+        assert_eq!(Ppa::active_ppas().into_inner(), vec![contract_id]);
+    });
+}
+
+#[test]
+fn baseload_periods_settle_into_matched_trades_until_expiry() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(1), 20));
+        let contract_id = propose_baseload(0);
+        assert_ok!(Ppa::sign_ppa(RuntimeOrigin::signed(2), contract_id));
+
+        run_to_block(11);
+        let settlement = Ppa::settlements(contract_id, 1).unwrap();
+        assert_eq!((settlement.volume, settlement.price_per_unit), (10, 5));
+        let trade = EnergyTrade::trade_orders(settlement.trade_id.unwrap()).unwrap();
+        assert_eq!((trade.status, trade.creator, trade.counterparty), (OrderStatus::Matched, 1, Some(2)));
+
+        run_to_block(21);
+        assert!(Ppa::settlements(contract_id, 2).is_some());
+        assert_eq!(Ppa::ppas(contract_id).unwrap().status, PpaStatus::Expired);
+        assert!(Ppa::active_ppas().is_empty());
+    });
+}
+
+#[test]
+fn settlement_fails_when_the_seller_has_no_energy() {
+    new_test_ext().execute_with(|| {
+        let contract_id = propose_baseload(0);
+        assert_ok!(Ppa::sign_ppa(RuntimeOrigin::signed(2), contract_id));
+
+        run_to_block(11);
+        let error = pallet_energy_token::Error::<Test>::InsufficientBalance.into();
+        System::assert_has_event(Event::PpaSettlementFailed { contract_id, period: 1, error }.into());
+        assert!(Ppa::settlements(contract_id, 1).is_none());
+        assert_eq!(Ppa::settlement_failures(contract_id, 1), Some(error));
+    });
+}
+
+#[test]
+fn as_produced_contracts_need_a_device_of_the_seller() {
+    new_test_ext().execute_with(|| {
+        let device_id = register_device(3, DeviceType::SolarPanel, 100);
+        assert_noop!(
+            Ppa::propose_ppa(
+                RuntimeOrigin::signed(1),
+                2,
+                PpaPrice::Fixed(5),
+                VolumeShape::AsProduced { share: Percent::from_percent(50), device_id },
+                b"zone-a".to_vec(),
+                1,
+                21,
+                10,
+                0,
+            ),
+            Error::<Test>::NotDeviceOwner
+        );
+    });
+}
+
+#[test]
+fn as_produced_volume_follows_metered_production_until_it_settles() {
+    new_test_ext().execute_with(|| {
+        let device_id = register_device(1, DeviceType::SolarPanel, 100);
+        let contract_id = propose_as_produced(device_id);
+        assert_ok!(Ppa::sign_ppa(RuntimeOrigin::signed(2), contract_id));
+
+        meter(device_id, 0, 20);
+        meter(device_id, 1, 10);
+        Timestamp::set_timestamp(slot_start(2));
+
+        // Without energy to deliver, the metered production stays pending
+        run_to_block(11);
+        assert!(Ppa::settlement_failures(contract_id, 1).is_some());
+        assert_eq!(Ppa::ppas(contract_id).unwrap().production_from, 0);
+
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(1), 20));
+        meter(device_id, 2, 6);
+        // The seller's own reading of their device does not add to the volume
+        assert_ok!(EnergyTransfer::submit_meter_reading(RuntimeOrigin::signed(1), IoTMeasurement {
+            device_id: EnergyTransfer::meter_id(&device_id).into_inner(),
+            timestamp: slot_start(2),
+            energy_amount: 100,
+            grid_frequency: 50,
+            voltage: 230,
+        }));
+        Timestamp::set_timestamp(slot_start(3));
+        run_to_block(21);
+
+        let settlement = Ppa::settlements(contract_id, 2).unwrap();
+        assert_eq!((settlement.volume, settlement.price_per_unit), (18, 5));
+        assert_eq!(Ppa::ppas(contract_id).unwrap().production_from, 3);
+    });
+}
+
+#[test]
+fn terminating_an_active_ppa_pays_for_the_remaining_periods() {
+    new_test_ext().execute_with(|| {
+        let contract_id = propose_baseload(50);
+        assert_ok!(Ppa::sign_ppa(RuntimeOrigin::signed(2), contract_id));
+
+        assert_noop!(Ppa::terminate_ppa(RuntimeOrigin::signed(3), contract_id), Error::<Test>::Unauthorized);
+        assert_ok!(Ppa::terminate_ppa(RuntimeOrigin::signed(1), contract_id));
+
+        assert_eq!(Balances::free_balance(2), 10_000 + 100);
+        assert_eq!(Ppa::ppas(contract_id).unwrap().status, PpaStatus::Terminated);
+        assert!(Ppa::active_ppas().is_empty());
+    });
+}
@@ -1,29 +1,42 @@
 #![cfg_attr(not(feature = "std"), no_std)]
 
-pub use frame_system::pallet::*;
+pub use pallet::*;
 
 #[frame_support::pallet]
 pub mod pallet {
     use frame_support::pallet_prelude::*;
-    use frame_system::pallet_prelude::*;
-    use pallet_energy_trade::{self as energy_trade, OrderType};
+    use frame_system::{
+        offchain::{CreateInherent, SubmitTransaction},
+        pallet_prelude::*,
+    };
+    use pallet_energy_trade::{self as energy_trade, MarketId, MatchingMode, OrderStatus, OrderType, TokenBalanceOf, TradeOrder, DEFAULT_MARKET};
     use scale_info::TypeInfo;
+    use sp_runtime::{
+        traits::{Saturating, Zero},
+        transaction_validity::{
+            InvalidTransaction, TransactionPriority, TransactionSource, TransactionValidity,
+            ValidTransaction,
+        },
+        Percent, SaturatedConversion,
+    };
     use sp_std::prelude::*;
 
     #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo)]
+    #[scale_info(skip_type_params(T))]
     pub struct PricePoint<T: Config> {
-        pub price: T::TokenBalance,
-        pub timestamp: T::BlockNumber,
-        pub volume: T::TokenBalance,
+        pub price: TokenBalanceOf<T>,
+        pub timestamp: BlockNumberFor<T>,
+        pub volume: TokenBalanceOf<T>,
         pub location: Vec<u8>,
     }
 
     #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo)]
+    #[scale_info(skip_type_params(T))]
     pub struct MarketData<T: Config> {
-        pub current_price: T::TokenBalance,
-        pub daily_high: T::TokenBalance,
-        pub daily_low: T::TokenBalance,
-        pub daily_volume: T::TokenBalance,
+        pub current_price: TokenBalanceOf<T>,
+        pub daily_high: TokenBalanceOf<T>,
+        pub daily_low: TokenBalanceOf<T>,
+        pub daily_volume: TokenBalanceOf<T>,
         pub price_history: Vec<PricePoint<T>>,
     }
 
@@ -34,7 +47,7 @@ pub mod pallet {
         pub stability_index: u8,     // 0-100
     }
 
-    #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo)]
+    #[derive(Clone, Encode, Decode, DecodeWithMemTracking, PartialEq, RuntimeDebug, TypeInfo)]
     pub struct LocationPriority {
         pub location: Vec<u8>,
         pub priority: u8,            // 0-100
@@ -42,13 +55,99 @@ pub mod pallet {
     }
 
     #[pallet::config]
-    pub trait Config: frame_system::Config + energy_trade::Config {
+    pub trait Config: frame_system::Config + energy_trade::Config + CreateInherent<Call<Self>> {
         type RuntimeEvent: From<Event<Self>> + IsType<<Self as frame_system::Config>::RuntimeEvent>;
+        // Priority of the unsigned matches submitted by the off-chain worker
+        #[pallet::constant]
+        type UnsignedPriority: Get<TransactionPriority>;
+        #[pallet::constant]
+        type MaxMatchesPerBlock: Get<u32>;
+        // Mark-up on the market price charged for undelivered energy
+        #[pallet::constant]
+        type ImbalancePremium: Get<Percent>;
+        // Discount on the market price credited for energy delivered beyond a contract
+        #[pallet::constant]
+        type SpillDiscount: Get<Percent>;
+        // Price move against any recorded price of the last `BreakerWindow` blocks that
+        // halts the location for `BreakerCooldown` blocks
+        #[pallet::constant]
+        type BreakerThreshold: Get<Percent>;
+        #[pallet::constant]
+        type BreakerWindow: Get<BlockNumberFor<Self>>;
+        #[pallet::constant]
+        type BreakerCooldown: Get<BlockNumberFor<Self>>;
+        // Reports market prices; only these and executed trades move reference prices,
+        // trip the circuit breaker and trigger stop orders
+        type PriceOracleOrigin: EnsureOrigin<Self::RuntimeOrigin>;
     }
 
     #[pallet::pallet]
+    #[pallet::without_storage_info]
     pub struct Pallet<T>(_);
 
+    #[pallet::hooks]
+    impl<T: Config> Hooks<BlockNumberFor<T>> for Pallet<T> {
+        // Look for the best counterparty of every open ask and submit the pairs as unsigned
+        // matches; the runtime re-checks each pair before it is included
+        fn offchain_worker(_n: BlockNumberFor<T>) {
+            if energy_trade::Pallet::<T>::matching_mode() != MatchingMode::Continuous {
+                return;
+            }
+
+            let mut used: Vec<T::Hash> = Vec::new();
+            let mut submitted = 0u32;
+            for ask_id in energy_trade::OrdersByStatus::<T>::iter_key_prefix(OrderStatus::Open) {
+                if submitted >= T::MaxMatchesPerBlock::get() {
+                    break;
+                }
+                let Some(ask) = energy_trade::Pallet::<T>::trade_orders(ask_id) else { continue };
+                if ask.order_type != OrderType::Ask || used.contains(&ask_id) {
+                    continue;
+                }
+                let Some((bid_id, _)) = Self::find_optimal_match(ask_id, OrderType::Ask) else { continue };
+                if used.contains(&bid_id) {
+                    continue;
+                }
+
+                let call = Call::submit_match { ask_id, bid_id };
+                let xt = T::create_inherent(call.into());
+                if SubmitTransaction::<T, Call<T>>::submit_transaction(xt).is_ok() {
+                    used.push(ask_id);
+                    used.push(bid_id);
+                    submitted = submitted.saturating_add(1);
+                }
+            }
+        }
+    }
+
+    #[pallet::validate_unsigned]
+    impl<T: Config> ValidateUnsigned for Pallet<T> {
+        type Call = Call<T>;
+
+        fn validate_unsigned(source: TransactionSource, call: &Self::Call) -> TransactionValidity {
+            let Call::submit_match { ask_id, bid_id } = call else {
+                return InvalidTransaction::Call.into();
+            };
+
+            // Only the local off-chain worker may submit matches
+            if !matches!(source, TransactionSource::Local | TransactionSource::InBlock) {
+                return InvalidTransaction::Call.into();
+            }
+            if energy_trade::Pallet::<T>::ensure_matchable(*ask_id, *bid_id).is_err() {
+                return InvalidTransaction::Stale.into();
+            }
+
+            // Each order is provided once, so conflicting pairs cannot both enter a block
+            ValidTransaction::with_tag_prefix("PriceDiscoveryMatch")
+                .priority(T::UnsignedPriority::get())
+                .and_provides((b"ask", ask_id))
+                .and_provides((b"bid", bid_id))
+                .longevity(5)
+                .propagate(false)
+                .build()
+        }
+    }
+
     #[pallet::storage]
     #[pallet::getter(fn market_data)]
     pub type MarketDataStore<T: Config> = StorageMap<
@@ -59,6 +158,17 @@ pub mod pallet {
         OptionQuery,
     >;
 
+    // Last price traded in the default market, the base of the imbalance prices
+    #[pallet::storage]
+    #[pallet::getter(fn trade_price)]
+    pub type TradePrices<T: Config> = StorageMap<
+        _,
+        Blake2_128Concat,
+        Vec<u8>,  // location
+        TokenBalanceOf<T>,
+        OptionQuery,
+    >;
+
     #[pallet::storage]
     #[pallet::getter(fn grid_metrics)]
     pub type GridMetricsStore<T: Config> = StorageMap<
@@ -84,7 +194,7 @@ pub mod pallet {
     pub enum Event<T: Config> {
         PriceUpdated {
             location: Vec<u8>,
-            new_price: T::TokenBalance,
+            new_price: TokenBalanceOf<T>,
         },
         GridMetricsUpdated {
             location: Vec<u8>,
@@ -94,7 +204,13 @@ pub mod pallet {
         OptimalMatchFound {
             ask_id: T::Hash,
             bid_id: T::Hash,
-            matched_price: T::TokenBalance,
+            matched_price: TokenBalanceOf<T>,
+        },
+        CircuitBreakerTripped {
+            location: Vec<u8>,
+            reference_price: TokenBalanceOf<T>,
+            price: TokenBalanceOf<T>,
+            until: BlockNumberFor<T>,
         },
     }
 
@@ -110,8 +226,8 @@ pub mod pallet {
         // Calculate optimal price based on market conditions and grid metrics
         pub fn calculate_optimal_price(
             location: &Vec<u8>,
-            base_price: T::TokenBalance,
-        ) -> Result<T::TokenBalance, Error<T>> {
+            base_price: TokenBalanceOf<T>,
+        ) -> Result<TokenBalanceOf<T>, Error<T>> {
             let market_data = Self::market_data(location).ok_or(Error::<T>::NoMarketData)?;
             let grid_metrics = Self::grid_metrics(location).ok_or(Error::<T>::NoMarketData)?;
 
@@ -125,12 +241,12 @@ pub mod pallet {
             
             if congestion_factor > 0 {
                 optimal_price = optimal_price.saturating_mul((100 + congestion_factor).into());
-                optimal_price = optimal_price.saturating_div(100.into());
+                optimal_price = optimal_price / 100u32.into();
             }
             
             if loss_factor > 0 {
                 optimal_price = optimal_price.saturating_mul((100 + loss_factor).into());
-                optimal_price = optimal_price.saturating_div(100.into());
+                optimal_price = optimal_price / 100u32.into();
             }
 
             // Ensure price is within daily range
@@ -146,18 +262,37 @@ pub mod pallet {
         pub fn find_optimal_match(
             order_id: T::Hash,
             order_type: OrderType,
-        ) -> Option<(T::Hash, T::TokenBalance)> {
+        ) -> Option<(T::Hash, TokenBalanceOf<T>)> {
             if let Some(order) = energy_trade::Pallet::<T>::trade_orders(order_id) {
-                let location = order.grid_location.clone();
+                let location = order.grid_location.to_vec();
                 let priorities = Self::location_priorities(&location);
                 
-                // Get all potential matching orders
-                let matching_orders: Vec<_> = energy_trade::Pallet::<T>::trade_orders()
+                // Candidates rest on the books of the order's own location and its priority
+                // locations, for the same slot and crossing on price
+                let mut locations = vec![order.grid_location.clone()];
+                for priority in priorities.iter() {
+                    if let Ok(target) = priority.location.clone().try_into() {
+                        if !locations.contains(&target) {
+                            locations.push(target);
+                        }
+                    }
+                }
+                let matching_orders: Vec<_> = locations
                     .iter()
-                    .filter(|(_, o)| {
-                        o.order_type != order_type && 
-                        o.energy_amount == order.energy_amount &&
-                        o.status == energy_trade::OrderStatus::Open
+                    .flat_map(|target| energy_trade::Pallet::<T>::crossing_orders(
+                        order.market,
+                        &order_type,
+                        target,
+                        order.delivery_slot,
+                        Some(order.price_per_unit),
+                    ))
+                    .filter(|(_, o)| o.energy_amount == order.energy_amount)
+                    .filter(|(id, _)| {
+                        let (ask_id, bid_id) = match order_type {
+                            OrderType::Ask => (order_id, *id),
+                            OrderType::Bid => (*id, order_id),
+                        };
+                        energy_trade::Pallet::<T>::blocking_constraint(ask_id, bid_id).is_none()
                     })
                     .collect();
 
@@ -167,7 +302,7 @@ pub mod pallet {
                     .filter_map(|(id, matching_order)| {
                         let location_score = Self::calculate_location_score(
                             &location,
-                            &matching_order.grid_location,
+                            &matching_order.grid_location.to_vec(),
                             &priorities,
                         );
                         
@@ -178,7 +313,7 @@ pub mod pallet {
 
                         let grid_score = Self::calculate_grid_score(
                             &location,
-                            &matching_order.grid_location,
+                            &matching_order.grid_location.to_vec(),
                         );
 
                         let total_score = location_score
@@ -193,12 +328,71 @@ pub mod pallet {
                 if !scored_matches.is_empty() {
                     scored_matches.sort_by(|a, b| b.1.cmp(&a.1));
                     let (matched_id, _, price) = scored_matches[0];
+                    // Bilateral matches settle at the ask price
+                    let price = match order_type {
+                        OrderType::Ask => order.price_per_unit,
+                        OrderType::Bid => price,
+                    };
                     return Some((matched_id, price));
                 }
             }
             None
         }
 
+        // Add a price to the location's statistics; returns the recent price it moved too far
+        // from, if any
+        fn record_price(
+            location: &Vec<u8>,
+            price: TokenBalanceOf<T>,
+            volume: TokenBalanceOf<T>,
+        ) -> Option<TokenBalanceOf<T>> {
+            let now = <frame_system::Pallet<T>>::block_number();
+            MarketDataStore::<T>::mutate(location, |market_data| {
+                let data = market_data.get_or_insert(MarketData {
+                    current_price: price,
+                    daily_high: price,
+                    daily_low: price,
+                    daily_volume: volume,
+                    price_history: Vec::new(),
+                });
+
+                // Any recent price the new one moved too far from trips the breaker
+                let reference_price = data.price_history.iter()
+                    .filter(|point| point.timestamp.saturating_add(T::BreakerWindow::get()) >= now)
+                    .map(|point| point.price)
+                    .find(|reference| {
+                        let moved = if price > *reference { price - *reference } else { *reference - price };
+                        moved > T::BreakerThreshold::get() * *reference
+                    });
+
+                // Update market data
+                data.current_price = price;
+                data.daily_high = data.daily_high.max(price);
+                data.daily_low = data.daily_low.min(price);
+                data.daily_volume = data.daily_volume.saturating_add(volume);
+
+                // Add to price history
+                data.price_history.push(PricePoint {
+                    price,
+                    timestamp: now,
+                    volume,
+                    location: location.clone(),
+                });
+
+                // Keep only last 24 hours of history
+                if data.price_history.len() > 24 {
+                    data.price_history.remove(0);
+                }
+
+                Self::deposit_event(Event::PriceUpdated {
+                    location: location.clone(),
+                    new_price: price,
+                });
+
+                reference_price
+            })
+        }
+
         // Calculate score based on location proximity and priorities
         fn calculate_location_score(
             source: &Vec<u8>,
@@ -214,10 +408,7 @@ pub mod pallet {
         }
 
         // Calculate score based on price matching
-        fn calculate_price_score<O>(order: &O, matching_order: &O) -> u32 
-        where
-            O: PartialEq + Clone,
-        {
+        fn calculate_price_score(order: &TradeOrder<T>, matching_order: &TradeOrder<T>) -> u32 {
             let price_diff = if matching_order.price_per_unit > order.price_per_unit {
                 matching_order.price_per_unit - order.price_per_unit
             } else {
@@ -229,8 +420,8 @@ pub mod pallet {
                 100
             } else {
                 let max_price = order.price_per_unit.max(matching_order.price_per_unit);
-                let score = (price_diff * 100.into()) / max_price;
-                100 - score.saturated_into::<u32>()
+                let score = (price_diff * 100u32.into()) / max_price;
+                100u32.saturating_sub(score.saturated_into::<u32>())
             }
         }
 
@@ -256,53 +447,89 @@ pub mod pallet {
         }
     }
 
+    // Imbalance prices follow the last traded price of the location, never a reported one
+    impl<T: Config> energy_trade::ImbalancePricing<TokenBalanceOf<T>> for Pallet<T> {
+        fn imbalance_price(location: &[u8]) -> Option<TokenBalanceOf<T>> {
+            let price = Self::trade_price(location.to_vec())?;
+            Some(price.saturating_add(T::ImbalancePremium::get() * price))
+        }
+
+        fn spill_price(location: &[u8]) -> Option<TokenBalanceOf<T>> {
+            let price = Self::trade_price(location.to_vec())?;
+            Some(price.saturating_sub(T::SpillDiscount::get() * price))
+        }
+    }
+
+    // Arm's-length trades feed the statistics; only reported prices drive the circuit
+    // breaker and stop orders
+    impl<T: Config> energy_trade::TradeStatistics<TokenBalanceOf<T>> for Pallet<T> {
+        // Reference prices follow the default market; other markets keep their own statistics
+        fn record_trade(market: MarketId, location: &[u8], price_per_unit: TokenBalanceOf<T>, amount: TokenBalanceOf<T>) {
+            if market != DEFAULT_MARKET {
+                return;
+            }
+            TradePrices::<T>::insert(location.to_vec(), price_per_unit);
+            let _ = Self::record_price(&location.to_vec(), price_per_unit, amount);
+        }
+    }
+
+    // Distances come from the source location's priorities
+    impl<T: Config> energy_trade::LocationDistance for Pallet<T> {
+        fn distance(from: &[u8], to: &[u8]) -> Option<u8> {
+            Self::location_priorities(from.to_vec())
+                .into_iter()
+                .find(|p| p.location == to)
+                .map(|p| p.distance_factor)
+        }
+    }
+
     #[pallet::call]
     impl<T: Config> Pallet<T> {
+        // Submitted unsigned by the off-chain worker
         #[pallet::weight(10_000)]
-        pub fn update_market_data(
+        pub fn submit_match(
             origin: OriginFor<T>,
-            location: Vec<u8>,
-            price: T::TokenBalance,
-            volume: T::TokenBalance,
+            ask_id: T::Hash,
+            bid_id: T::Hash,
         ) -> DispatchResult {
-            let _ = ensure_signed(origin)?;
-            ensure!(!price.is_zero(), Error::<T>::InvalidPrice);
+            ensure_none(origin)?;
 
-            MarketDataStore::<T>::try_mutate(&location, |market_data| -> DispatchResult {
-                let data = market_data.get_or_insert(MarketData {
-                    current_price: price,
-                    daily_high: price,
-                    daily_low: price,
-                    daily_volume: volume,
-                    price_history: Vec::new(),
-                });
+            let (ask, _) = energy_trade::Pallet::<T>::ensure_matchable(ask_id, bid_id)?;
+            energy_trade::Pallet::<T>::do_match_orders(ask_id, bid_id)?;
 
-                // Update market data
-                data.current_price = price;
-                data.daily_high = data.daily_high.max(price);
-                data.daily_low = data.daily_low.min(price);
-                data.daily_volume = data.daily_volume.saturating_add(volume);
+            Self::deposit_event(Event::OptimalMatchFound {
+                ask_id,
+                bid_id,
+                matched_price: ask.price_per_unit,
+            });
 
-                // Add to price history
-                data.price_history.push(PricePoint {
-                    price,
-                    timestamp: <frame_system::Pallet<T>>::block_number(),
-                    volume,
-                    location: location.clone(),
-                });
+            Ok(())
+        }
 
-                // Keep only last 24 hours of history
-                if data.price_history.len() > 24 {
-                    data.price_history.remove(0);
-                }
+        #[pallet::weight(10_000)]
+        pub fn update_market_data(
+            origin: OriginFor<T>,
+            location: Vec<u8>,
+            price: TokenBalanceOf<T>,
+            volume: TokenBalanceOf<T>,
+        ) -> DispatchResult {
+            T::PriceOracleOrigin::ensure_origin(origin)?;
+            ensure!(!price.is_zero(), Error::<T>::InvalidPrice);
 
-                Self::deposit_event(Event::PriceUpdated {
-                    location: location.clone(),
-                    new_price: price,
-                });
+            let now = <frame_system::Pallet<T>>::block_number();
+            let tripped = Self::record_price(&location, price, volume);
 
-                Ok(())
-            })
+            // A tripped breaker also keeps the move from triggering stop orders
+            match tripped {
+                Some(reference_price) => {
+                    let until = now.saturating_add(T::BreakerCooldown::get());
+                    energy_trade::Pallet::<T>::trip_circuit_breaker(&location, until);
+                    Self::deposit_event(Event::CircuitBreakerTripped { location, reference_price, price, until });
+                },
+                None => energy_trade::Pallet::<T>::trigger_stop_orders(&location, price),
+            }
+
+            Ok(())
         }
 
         #[pallet::weight(10_000)]
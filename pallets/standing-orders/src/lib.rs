@@ -0,0 +1,368 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+pub use pallet::*;
+
+#[cfg(test)]
+mod mock;
+#[cfg(test)]
+mod tests;
+
+#[frame_support::pallet]
+pub mod pallet {
+    use frame_support::{
+        dispatch::GetDispatchInfo,
+        pallet_prelude::*,
+        traits::{
+            schedule::{v3::Named as ScheduleNamed, DispatchTime, LOWEST_PRIORITY},
+            OriginTrait, QueryPreimage, StorePreimage,
+        },
+        BoundedVec,
+    };
+    use frame_system::pallet_prelude::*;
+    use pallet_energy_trade::{self as energy_trade, DeliverySlot, OrderKind, OrderType, TokenBalanceOf, WeightInfo as _};
+    use pallet_price_discovery::{self as price_discovery};
+    use scale_info::TypeInfo;
+    use sp_runtime::{
+        traits::{BlakeTwo256, Dispatchable, Hash, Saturating, Zero},
+        Percent,
+    };
+    use sp_std::prelude::*;
+
+    pub type CallOf<T> = <T as Config>::RuntimeCall;
+    pub type PalletsOriginOf<T> = <<T as frame_system::Config>::RuntimeOrigin as OriginTrait>::PalletsOrigin;
+
+    #[derive(Clone, Encode, Decode, DecodeWithMemTracking, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    pub enum StandingPrice<Balance> {
+        Fixed(Balance),
+        // Reference price of the template location plus or less a share of it
+        AboveReference(Percent),
+        BelowReference(Percent),
+    }
+
+    #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    pub enum TemplateStatus {
+        Active,
+        Paused,
+    }
+
+    #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    #[scale_info(skip_type_params(T))]
+    pub struct StandingOrder<T: Config> {
+        pub owner: T::AccountId,
+        pub order_type: OrderType,
+        pub energy_amount: TokenBalanceOf<T>,
+        pub price: StandingPrice<TokenBalanceOf<T>>,
+        pub limit_price: TokenBalanceOf<T>,   // Lowest ask or highest bid price ever posted
+        pub grid_location: BoundedVec<u8, ConstU32<32>>,
+        pub slot_of_day: u32,               // Delivery slot of each day the order is for
+        pub remaining: u32,                 // Orders still to be posted
+        pub status: TemplateStatus,
+        pub last_posted: Option<DeliverySlot>,
+    }
+
+    #[pallet::config]
+    pub trait Config: frame_system::Config + price_discovery::Config {
+        type RuntimeEvent: From<Event<Self>> + IsType<<Self as frame_system::Config>::RuntimeEvent>;
+        type RuntimeCall: Parameter
+            + Dispatchable<RuntimeOrigin = <Self as frame_system::Config>::RuntimeOrigin>
+            + GetDispatchInfo
+            + From<Call<Self>>
+            + IsType<<Self as frame_system::Config>::RuntimeCall>;
+        type Scheduler: ScheduleNamed<BlockNumberFor<Self>, CallOf<Self>, PalletsOriginOf<Self>, Hasher = Self::Hashing>;
+        type Preimages: QueryPreimage<H = Self::Hashing> + StorePreimage;
+        // Blocks between two posts of a template
+        #[pallet::constant]
+        type BlocksPerDay: Get<BlockNumberFor<Self>>;
+        #[pallet::constant]
+        type MaxTemplatesPerAccount: Get<u32>;
+        // Orders a template may post in total
+        #[pallet::constant]
+        type MaxPosts: Get<u32>;
+    }
+
+    #[pallet::pallet]
+    pub struct Pallet<T>(_);
+
+    #[pallet::storage]
+    #[pallet::getter(fn templates)]
+    pub type Templates<T: Config> = StorageMap<_, Twox64Concat, u32, StandingOrder<T>, OptionQuery>;
+
+    #[pallet::storage]
+    #[pallet::getter(fn templates_of)]
+    pub type TemplatesOf<T: Config> = StorageMap<
+        _,
+        Blake2_128Concat,
+        T::AccountId,
+        BoundedVec<u32, T::MaxTemplatesPerAccount>,
+        ValueQuery,
+    >;
+
+    #[pallet::storage]
+    #[pallet::getter(fn next_template_id)]
+    pub type NextTemplateId<T: Config> = StorageValue<_, u32, ValueQuery>;
+
+    #[pallet::event]
+    #[pallet::generate_deposit(pub(super) fn deposit_event)]
+    pub enum Event<T: Config> {
+        StandingOrderCreated {
+            template_id: u32,
+            owner: T::AccountId,
+            slot_of_day: u32,
+            remaining: u32,
+        },
+        StandingOrderPosted {
+            template_id: u32,
+            order_id: T::Hash,
+            delivery_slot: DeliverySlot,
+            price_per_unit: TokenBalanceOf<T>,
+        },
+        StandingOrderFailed {
+            template_id: u32,
+            delivery_slot: DeliverySlot,
+            error: DispatchError,
+        },
+        StandingOrderPaused {
+            template_id: u32,
+        },
+        StandingOrderResumed {
+            template_id: u32,
+        },
+        StandingOrderCancelled {
+            template_id: u32,
+        },
+        StandingOrderExhausted {
+            template_id: u32,
+        },
+    }
+
+    #[pallet::error]
+    pub enum Error<T> {
+        TemplateNotFound,
+        NotTemplateOwner,
+        InvalidAmount,
+        InvalidPrice,
+        InvalidSlot,
+        InvalidLocation,
+        InvalidPosts,
+        TooManyTemplates,
+        AlreadyPaused,
+        NotPaused,
+        ScheduleFailed,
+        TemplateIdOverflow,
+    }
+
+    #[pallet::call]
+    impl<T: Config> Pallet<T> {
+        // The first order is posted in the next block, then one a day until `posts` are made
+        #[pallet::call_index(0)]
+        #[pallet::weight(10_000)]
+        pub fn create_standing_order(
+            origin: OriginFor<T>,
+            order_type: OrderType,
+            energy_amount: TokenBalanceOf<T>,
+            price: StandingPrice<TokenBalanceOf<T>>,
+            limit_price: TokenBalanceOf<T>,
+            grid_location: Vec<u8>,
+            slot_of_day: u32,
+            posts: u32,
+        ) -> DispatchResult {
+            let owner = ensure_signed(origin)?;
+            ensure!(!energy_amount.is_zero(), Error::<T>::InvalidAmount);
+            ensure!(!limit_price.is_zero(), Error::<T>::InvalidPrice);
+            if let StandingPrice::Fixed(fixed) = price {
+                ensure!(!fixed.is_zero(), Error::<T>::InvalidPrice);
+            }
+            ensure!(slot_of_day < energy_trade::Pallet::<T>::slots_per_day(), Error::<T>::InvalidSlot);
+            ensure!(posts > 0 && posts <= T::MaxPosts::get(), Error::<T>::InvalidPosts);
+            let grid_location: BoundedVec<u8, ConstU32<32>> =
+                grid_location.try_into().map_err(|_| Error::<T>::InvalidLocation)?;
+
+            let template_id = Self::next_template_id();
+            let next_template_id = template_id.checked_add(1).ok_or(Error::<T>::TemplateIdOverflow)?;
+            TemplatesOf::<T>::try_mutate(&owner, |templates| templates.try_push(template_id))
+                .map_err(|_| Error::<T>::TooManyTemplates)?;
+            Self::schedule(template_id, posts)?;
+
+            Templates::<T>::insert(template_id, StandingOrder {
+                owner: owner.clone(),
+                order_type,
+                energy_amount,
+                price,
+                limit_price,
+                grid_location,
+                slot_of_day,
+                remaining: posts,
+                status: TemplateStatus::Active,
+                last_posted: None,
+            });
+            NextTemplateId::<T>::put(next_template_id);
+
+            Self::deposit_event(Event::StandingOrderCreated { template_id, owner, slot_of_day, remaining: posts });
+
+            Ok(())
+        }
+
+        // Posted orders stay on the book
+        #[pallet::call_index(1)]
+        #[pallet::weight(10_000)]
+        pub fn pause_standing_order(origin: OriginFor<T>, template_id: u32) -> DispatchResult {
+            let who = ensure_signed(origin)?;
+            Templates::<T>::try_mutate(template_id, |template| -> DispatchResult {
+                let template = template.as_mut().ok_or(Error::<T>::TemplateNotFound)?;
+                ensure!(template.owner == who, Error::<T>::NotTemplateOwner);
+                ensure!(template.status == TemplateStatus::Active, Error::<T>::AlreadyPaused);
+                T::Scheduler::cancel_named(Self::task_name(template_id))?;
+                template.status = TemplateStatus::Paused;
+                Ok(())
+            })?;
+
+            Self::deposit_event(Event::StandingOrderPaused { template_id });
+
+            Ok(())
+        }
+
+        #[pallet::call_index(2)]
+        #[pallet::weight(10_000)]
+        pub fn resume_standing_order(origin: OriginFor<T>, template_id: u32) -> DispatchResult {
+            let who = ensure_signed(origin)?;
+            Templates::<T>::try_mutate(template_id, |template| -> DispatchResult {
+                let template = template.as_mut().ok_or(Error::<T>::TemplateNotFound)?;
+                ensure!(template.owner == who, Error::<T>::NotTemplateOwner);
+                ensure!(template.status == TemplateStatus::Paused, Error::<T>::NotPaused);
+                Self::schedule(template_id, template.remaining)?;
+                template.status = TemplateStatus::Active;
+                Ok(())
+            })?;
+
+            Self::deposit_event(Event::StandingOrderResumed { template_id });
+
+            Ok(())
+        }
+
+        // Posted orders stay on the book and are cancelled through the energy trade pallet
+        #[pallet::call_index(3)]
+        #[pallet::weight(10_000)]
+        pub fn cancel_standing_order(origin: OriginFor<T>, template_id: u32) -> DispatchResult {
+            let who = ensure_signed(origin)?;
+            let template = Self::templates(template_id).ok_or(Error::<T>::TemplateNotFound)?;
+            ensure!(template.owner == who, Error::<T>::NotTemplateOwner);
+
+            if template.status == TemplateStatus::Active {
+                T::Scheduler::cancel_named(Self::task_name(template_id))?;
+            }
+            Self::remove_template(template_id, &template.owner);
+
+            Self::deposit_event(Event::StandingOrderCancelled { template_id });
+
+            Ok(())
+        }
+
+        // Dispatched by the scheduler once a day for each active template
+        #[pallet::call_index(4)]
+        #[pallet::weight(<T as energy_trade::Config>::WeightInfo::create_order(T::MaxFillsPerOrder::get()))]
+        pub fn post_standing_order(origin: OriginFor<T>, template_id: u32) -> DispatchResult {
+            ensure_root(origin)?;
+            let mut template = Self::templates(template_id).ok_or(Error::<T>::TemplateNotFound)?;
+            if template.status != TemplateStatus::Active || template.remaining == 0 {
+                return Ok(());
+            }
+
+            let delivery_slot = Self::next_delivery_slot(template.slot_of_day);
+            if template.last_posted != Some(delivery_slot) {
+                let posted = Self::order_price(&template)
+                    .ok_or(DispatchError::from(price_discovery::Error::<T>::NoMarketData))
+                    .and_then(|price| {
+                        energy_trade::Pallet::<T>::do_place_order(
+                            template.owner.clone(),
+                            template.order_type.clone(),
+                            OrderKind::Limit,
+                            template.energy_amount,
+                            price,
+                            template.grid_location.to_vec(),
+                            delivery_slot,
+                        )
+                        .map(|order_id| (order_id, price))
+                    });
+                match posted {
+                    Ok((order_id, price_per_unit)) => Self::deposit_event(Event::StandingOrderPosted {
+                        template_id,
+                        order_id,
+                        delivery_slot,
+                        price_per_unit,
+                    }),
+                    Err(error) => Self::deposit_event(Event::StandingOrderFailed { template_id, delivery_slot, error }),
+                }
+                template.last_posted = Some(delivery_slot);
+            }
+
+            // A failed post uses up its day like the scheduler does
+            template.remaining -= 1;
+            if template.remaining == 0 {
+                Self::remove_template(template_id, &template.owner);
+                Self::deposit_event(Event::StandingOrderExhausted { template_id });
+            } else {
+                Templates::<T>::insert(template_id, template);
+            }
+
+            Ok(())
+        }
+    }
+
+    impl<T: Config> Pallet<T> {
+        pub fn task_name(template_id: u32) -> [u8; 32] {
+            BlakeTwo256::hash_of(&(b"gx/stand", template_id)).0
+        }
+
+        // Posts the template `posts` times, starting in the next block and then once a day
+        fn schedule(template_id: u32, posts: u32) -> DispatchResult {
+            let call: CallOf<T> = Call::<T>::post_standing_order { template_id }.into();
+            let bounded = T::Preimages::bound(call)?;
+            let periodic = (posts > 1).then(|| (T::BlocksPerDay::get(), posts));
+            T::Scheduler::schedule_named(
+                Self::task_name(template_id),
+                DispatchTime::After(Zero::zero()),
+                periodic,
+                LOWEST_PRIORITY,
+                frame_system::RawOrigin::Root.into(),
+                bounded,
+            )
+            .map_err(|_| Error::<T>::ScheduleFailed)?;
+            Ok(())
+        }
+
+        fn remove_template(template_id: u32, owner: &T::AccountId) {
+            Templates::<T>::remove(template_id);
+            TemplatesOf::<T>::mutate(owner, |templates| templates.retain(|id| *id != template_id));
+        }
+
+        // Next slot at `slot_of_day` that has not started yet
+        pub fn next_delivery_slot(slot_of_day: u32) -> DeliverySlot {
+            let current = energy_trade::Pallet::<T>::current_slot();
+            let slots_per_day = energy_trade::Pallet::<T>::slots_per_day();
+            let slot = current - current % slots_per_day + slot_of_day;
+            if slot > current { slot } else { slot.saturating_add(slots_per_day) }
+        }
+
+        // Asks are posted no lower and bids no higher than the limit price; `None` without a
+        // reference price for an indexed template
+        pub fn order_price(template: &StandingOrder<T>) -> Option<TokenBalanceOf<T>> {
+            let price = match template.price {
+                StandingPrice::Fixed(price) => price,
+                StandingPrice::AboveReference(share) | StandingPrice::BelowReference(share) => {
+                    let reference = price_discovery::Pallet::<T>::market_data(template.grid_location.to_vec())?
+                        .current_price;
+                    if matches!(template.price, StandingPrice::AboveReference(_)) {
+                        reference.saturating_add(share * reference)
+                    } else {
+                        reference.saturating_sub(share * reference)
+                    }
+                },
+            };
+
+            Some(match template.order_type {
+                OrderType::Ask => price.max(template.limit_price),
+                OrderType::Bid => price.min(template.limit_price),
+            })
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use crate::{mock::*, Error, Event, StandingPrice, TemplateStatus};
+use frame_support::{assert_noop, assert_ok, traits::Get};
+use pallet_energy_trade::{OrderStatus, OrderType};
+
+fn slot_start(slot: u64) -> u64 {
+    slot * <<Test as pallet_energy_trade::Config>::SlotDuration as Get<u64>>::get()
+}
+
+// Ask for 10 units at 5 in slot 40 of each day, posted `posts` times from account 1
+fn create_ask(posts: u32) {
+    assert_ok!(StandingOrders::create_standing_order(
+        RuntimeOrigin::signed(1),
+        OrderType::Ask,
+        10,
+        StandingPrice::Fixed(5),
+        5,
+        b"zone-a".to_vec(),
+        40,
+        posts,
+    ));
+}
+
+fn posted_orders() -> Vec<(sp_core::H256, u32)> {
+    System::events()
+        .iter()
+        .filter_map(|r| match r.event {
+            RuntimeEvent::StandingOrders(Event::StandingOrderPosted { order_id, delivery_slot, .. }) => {
+                Some((order_id, delivery_slot))
+            },
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn templates_are_checked_at_creation() {
+    new_test_ext().execute_with(|| {
+        let create = |slot_of_day, posts| StandingOrders::create_standing_order(
+            RuntimeOrigin::signed(1),
+            OrderType::Ask,
+            10,
+            StandingPrice::Fixed(5),
+            5,
+            b"zone-a".to_vec(),
+            slot_of_day,
+            posts,
+        );
+        assert_noop!(create(96, 2), Error::<Test>::InvalidSlot);
+        assert_noop!(create(40, 0), Error::<Test>::InvalidPosts);
+        assert_noop!(create(40, 31), Error::<Test>::InvalidPosts);
+
+        assert_ok!(create(40, 2));
+        assert_eq!(StandingOrders::templates_of(1).into_inner(), vec![0]);
+        assert_eq!(StandingOrders::templates(0).unwrap().remaining, 2);
+    });
+}
+
+#[test]
+fn template_ids_do_not_wrap_around() {
+    new_test_ext().execute_with(|| {
+        crate::NextTemplateId::<Test>::put(u32::MAX);
+        assert_noop!(
+            StandingOrders::create_standing_order(
+                RuntimeOrigin::signed(1),
+                OrderType::Ask,
+                10,
+                StandingPrice::Fixed(5),
+                5,
+                b"zone-a".to_vec(),
+                40,
+                2,
+            ),
+            Error::<Test>::TemplateIdOverflow
+        );
+    });
+}
+
+#[test]
+fn orders_are_posted_in_the_next_block_and_then_daily() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(1), 20));
+        create_ask(2);
+
+        run_to_block(2);
+        let posted = posted_orders();
+        assert_eq!(posted.len(), 1);
+        let (order_id, delivery_slot) = posted[0];
+        assert_eq!(delivery_slot, 40);
+        let order = EnergyTrade::trade_orders(order_id).unwrap();
+        assert_eq!((order.creator, order.energy_amount, order.status), (1, 10, OrderStatus::Open));
+
+        // A day later the next slot 40 is a day further on
+        Timestamp::set_timestamp(slot_start(96));
+        run_to_block(12);
+        assert_eq!(posted_orders().last().unwrap().1, 136);
+        System::assert_has_event(Event::StandingOrderExhausted { template_id: 0 }.into());
+        assert!(StandingOrders::templates(0).is_none());
+        assert!(StandingOrders::templates_of(1).is_empty());
+    });
+}
+
+#[test]
+fn failed_posts_are_reported_and_use_up_their_day() {
+    new_test_ext().execute_with(|| {
+        create_ask(2);
+
+        run_to_block(2);
+        assert!(posted_orders().is_empty());
+        assert!(System::events().iter().any(|r| matches!(
+            r.event,
+            RuntimeEvent::StandingOrders(Event::StandingOrderFailed { template_id: 0, delivery_slot: 40, .. })
+        )));
+        assert_eq!(StandingOrders::templates(0).unwrap().remaining, 1);
+    });
+}
+
+#[test]
+fn paused_templates_post_nothing_until_resumed() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(EnergyToken::mint_tokens(RuntimeOrigin::signed(1), 20));
+        create_ask(2);
+        assert_noop!(
+            StandingOrders::pause_standing_order(RuntimeOrigin::signed(2), 0),
+            Error::<Test>::NotTemplateOwner
+        );
+        assert_ok!(StandingOrders::pause_standing_order(RuntimeOrigin::signed(1), 0));
+        assert_eq!(StandingOrders::templates(0).unwrap().status, TemplateStatus::Paused);
+
+        run_to_block(5);
+        assert!(posted_orders().is_empty());
+
+        assert_ok!(StandingOrders::resume_standing_order(RuntimeOrigin::signed(1), 0));
+        run_to_block(6);
+        assert_eq!(posted_orders().len(), 1);
+
+        assert_ok!(StandingOrders::cancel_standing_order(RuntimeOrigin::signed(1), 0));
+        assert!(StandingOrders::templates(0).is_none());
+    });
+}
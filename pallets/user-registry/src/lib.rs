@@ -1,6 +1,11 @@
 #![cfg_attr(not(feature = "std"), no_std)]
 
-pub use frame_system::pallet::*;
+pub use pallet::*;
+
+#[cfg(test)]
+mod mock;
+#[cfg(test)]
+mod tests;
 
 #[frame_support::pallet]
 pub mod pallet {
@@ -11,7 +16,7 @@ pub mod pallet {
     use frame_support::traits::StorageVersion;
     use sp_runtime::traits::Hash;
 
-    #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    #[derive(Clone, Encode, Decode, DecodeWithMemTracking, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
     pub enum UserRole {
         Consumer,
         Prosumer,
@@ -39,7 +44,7 @@ pub mod pallet {
         pub registration_date: BlockNumberFor<T>,
     }
 
-    #[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
+    #[derive(Clone, Encode, Decode, DecodeWithMemTracking, PartialEq, RuntimeDebug, TypeInfo, MaxEncodedLen)]
     pub enum DeviceType {
         SolarPanel,
         Battery,
@@ -58,6 +63,14 @@ pub mod pallet {
 
     const STORAGE_VERSION: StorageVersion = StorageVersion::new(1);
 
+    // `are_related` follows relations at most this many hops and visits at most this many
+    // accounts, which keeps its cost bounded
+    pub const MAX_RELATION_HOPS: u32 = 3;
+    pub const MAX_RELATED_ACCOUNTS: usize = 32;
+
+    // Delegates an account can have, and accounts it can act for
+    pub const MAX_DELEGATIONS: u32 = 10;
+
     #[pallet::storage]
     #[pallet::getter(fn user_profiles)]
     pub type UserProfiles<T: Config> = StorageMap<
@@ -78,6 +91,78 @@ pub mod pallet {
         OptionQuery,
     >;
 
+    // Devices other accounts have shared with the account, e.g. household members
+    #[pallet::storage]
+    #[pallet::getter(fn shared_devices)]
+    pub type SharedDevices<T: Config> = StorageMap<
+        _,
+        Blake2_128Concat,
+        T::AccountId,
+        BoundedVec<T::Hash, ConstU32<10>>,
+        ValueQuery,
+    >;
+
+    // Accounts each device is shared with, the reverse of `SharedDevices`
+    #[pallet::storage]
+    #[pallet::getter(fn device_users)]
+    pub type DeviceUsers<T: Config> = StorageDoubleMap<
+        _,
+        Blake2_128Concat,
+        T::Hash,       // device_id
+        Blake2_128Concat,
+        T::AccountId,  // with
+        (),
+        OptionQuery,
+    >;
+
+    // Shares the other account has not accepted yet
+    #[pallet::storage]
+    #[pallet::getter(fn share_offers)]
+    pub type ShareOffers<T: Config> = StorageDoubleMap<
+        _,
+        Blake2_128Concat,
+        T::AccountId,  // with
+        Blake2_128Concat,
+        T::Hash,       // device_id
+        (),
+        OptionQuery,
+    >;
+
+    // Accounts allowed to act for the delegator
+    #[pallet::storage]
+    #[pallet::getter(fn delegations)]
+    pub type Delegations<T: Config> = StorageMap<
+        _,
+        Blake2_128Concat,
+        T::AccountId,  // delegator
+        BoundedVec<T::AccountId, ConstU32<MAX_DELEGATIONS>>,
+        ValueQuery,
+    >;
+
+    // The reverse of `Delegations`
+    #[pallet::storage]
+    #[pallet::getter(fn delegators)]
+    pub type Delegators<T: Config> = StorageMap<
+        _,
+        Blake2_128Concat,
+        T::AccountId,  // delegate
+        BoundedVec<T::AccountId, ConstU32<MAX_DELEGATIONS>>,
+        ValueQuery,
+    >;
+
+    // Delegations the delegate has not accepted yet
+    #[pallet::storage]
+    #[pallet::getter(fn delegate_offers)]
+    pub type DelegateOffers<T: Config> = StorageDoubleMap<
+        _,
+        Blake2_128Concat,
+        T::AccountId,  // delegate
+        Blake2_128Concat,
+        T::AccountId,  // delegator
+        (),
+        OptionQuery,
+    >;
+
     #[pallet::event]
     #[pallet::generate_deposit(pub(super) fn deposit_event)]
     pub enum Event<T: Config> {
@@ -96,6 +181,34 @@ pub mod pallet {
         DeviceUpdated {
             device_id: T::Hash,
         },
+        ReputationChanged {
+            account: T::AccountId,
+            reputation_score: u32,
+        },
+        DeviceShareOffered {
+            device_id: T::Hash,
+            with: T::AccountId,
+        },
+        DeviceShared {
+            device_id: T::Hash,
+            with: T::AccountId,
+        },
+        DeviceUnshared {
+            device_id: T::Hash,
+            with: T::AccountId,
+        },
+        DelegateOffered {
+            delegator: T::AccountId,
+            delegate: T::AccountId,
+        },
+        DelegateAdded {
+            delegator: T::AccountId,
+            delegate: T::AccountId,
+        },
+        DelegateRemoved {
+            delegator: T::AccountId,
+            delegate: T::AccountId,
+        },
     }
 
     #[pallet::error]
@@ -107,6 +220,13 @@ pub mod pallet {
         Unauthorized,
         InvalidRole,
         TooManyDevices,
+        AlreadyShared,
+        NotShared,
+        AlreadyDelegated,
+        NotDelegated,
+        NoShareOffer,
+        NoDelegateOffer,
+        TooManyDelegations,
     }
 
     #[pallet::call]
@@ -121,7 +241,7 @@ pub mod pallet {
             ensure!(!UserProfiles::<T>::contains_key(&account), Error::<T>::UserAlreadyRegistered);
 
             let profile = UserProfile {
-                role,
+                role: role.clone(),
                 devices: BoundedVec::new(),
                 active: true,
                 reputation_score: 100,
@@ -205,5 +325,254 @@ pub mod pallet {
 
             Ok(())
         }
+
+        #[pallet::weight(10_000)]
+        pub fn share_device(
+            origin: OriginFor<T>,
+            device_id: T::Hash,
+            with: T::AccountId,
+        ) -> DispatchResult {
+            let owner = ensure_signed(origin)?;
+
+            let device = Devices::<T>::get(device_id).ok_or(Error::<T>::DeviceNotFound)?;
+            ensure!(device.owner == owner, Error::<T>::Unauthorized);
+            ensure!(UserProfiles::<T>::contains_key(&with), Error::<T>::UserNotFound);
+            ensure!(
+                !DeviceUsers::<T>::contains_key(device_id, &with) &&
+                    !ShareOffers::<T>::contains_key(&with, device_id),
+                Error::<T>::AlreadyShared
+            );
+
+            // The device is only shared once the other account accepts
+            ShareOffers::<T>::insert(&with, device_id, ());
+
+            Self::deposit_event(Event::DeviceShareOffered { device_id, with });
+
+            Ok(())
+        }
+
+        // Either side can end a share or withdraw an offer
+        #[pallet::weight(10_000)]
+        pub fn unshare_device(
+            origin: OriginFor<T>,
+            device_id: T::Hash,
+            with: T::AccountId,
+        ) -> DispatchResult {
+            let who = ensure_signed(origin)?;
+
+            let device = Devices::<T>::get(device_id).ok_or(Error::<T>::DeviceNotFound)?;
+            ensure!(device.owner == who || with == who, Error::<T>::Unauthorized);
+
+            if ShareOffers::<T>::take(&with, device_id).is_none() {
+                SharedDevices::<T>::try_mutate(&with, |shared| -> DispatchResult {
+                    let index = shared.iter().position(|id| *id == device_id).ok_or(Error::<T>::NotShared)?;
+                    shared.remove(index);
+                    Ok(())
+                })?;
+                DeviceUsers::<T>::remove(device_id, &with);
+            }
+
+            Self::deposit_event(Event::DeviceUnshared { device_id, with });
+
+            Ok(())
+        }
+
+        #[pallet::weight(10_000)]
+        pub fn add_delegate(
+            origin: OriginFor<T>,
+            delegate: T::AccountId,
+        ) -> DispatchResult {
+            let delegator = ensure_signed(origin)?;
+            ensure!(UserProfiles::<T>::contains_key(&delegator), Error::<T>::UserNotFound);
+            ensure!(
+                !Self::delegations(&delegator).contains(&delegate) &&
+                    !DelegateOffers::<T>::contains_key(&delegate, &delegator),
+                Error::<T>::AlreadyDelegated
+            );
+
+            // The delegation only takes effect once the delegate accepts
+            DelegateOffers::<T>::insert(&delegate, &delegator, ());
+
+            Self::deposit_event(Event::DelegateOffered { delegator, delegate });
+
+            Ok(())
+        }
+
+        // Ends a delegation or withdraws an offer
+        #[pallet::weight(10_000)]
+        pub fn remove_delegate(
+            origin: OriginFor<T>,
+            delegate: T::AccountId,
+        ) -> DispatchResult {
+            let delegator = ensure_signed(origin)?;
+
+            if DelegateOffers::<T>::take(&delegate, &delegator).is_none() {
+                Delegations::<T>::try_mutate(&delegator, |delegates| -> DispatchResult {
+                    let index = delegates.iter().position(|d| *d == delegate).ok_or(Error::<T>::NotDelegated)?;
+                    delegates.remove(index);
+                    Ok(())
+                })?;
+                Delegators::<T>::mutate(&delegate, |delegators| delegators.retain(|d| *d != delegator));
+            }
+
+            Self::deposit_event(Event::DelegateRemoved { delegator, delegate });
+
+            Ok(())
+        }
+
+        #[pallet::weight(10_000)]
+        pub fn accept_device_share(
+            origin: OriginFor<T>,
+            device_id: T::Hash,
+        ) -> DispatchResult {
+            let with = ensure_signed(origin)?;
+            ensure!(ShareOffers::<T>::contains_key(&with, device_id), Error::<T>::NoShareOffer);
+            ensure!(Devices::<T>::contains_key(device_id), Error::<T>::DeviceNotFound);
+
+            SharedDevices::<T>::try_mutate(&with, |shared| -> DispatchResult {
+                shared.try_push(device_id).map_err(|_| Error::<T>::TooManyDevices)?;
+                Ok(())
+            })?;
+            ShareOffers::<T>::remove(&with, device_id);
+            DeviceUsers::<T>::insert(device_id, &with, ());
+
+            Self::deposit_event(Event::DeviceShared { device_id, with });
+
+            Ok(())
+        }
+
+        #[pallet::weight(10_000)]
+        pub fn accept_delegation(
+            origin: OriginFor<T>,
+            delegator: T::AccountId,
+        ) -> DispatchResult {
+            let delegate = ensure_signed(origin)?;
+            ensure!(DelegateOffers::<T>::contains_key(&delegate, &delegator), Error::<T>::NoDelegateOffer);
+
+            Delegations::<T>::try_mutate(&delegator, |delegates| {
+                delegates.try_push(delegate.clone()).map_err(|_| Error::<T>::TooManyDelegations)
+            })?;
+            Delegators::<T>::try_mutate(&delegate, |delegators| {
+                delegators.try_push(delegator.clone()).map_err(|_| Error::<T>::TooManyDelegations)
+            })?;
+            DelegateOffers::<T>::remove(&delegate, &delegator);
+
+            Self::deposit_event(Event::DelegateAdded { delegator, delegate });
+
+            Ok(())
+        }
+    }
+
+    impl<T: Config> Pallet<T> {
+        pub fn is_active_user(account: &T::AccountId) -> bool {
+            Self::user_profiles(account).map(|profile| profile.active).unwrap_or(false)
+        }
+
+        pub fn can_sell(account: &T::AccountId) -> bool {
+            Self::user_profiles(account)
+                .map(|profile| {
+                    profile.active && matches!(profile.role, UserRole::Prosumer | UserRole::GridOperator)
+                })
+                .unwrap_or(false)
+        }
+
+        // Sum of the `max_capacity` of the account's active devices
+        pub fn generation_capacity(account: &T::AccountId) -> u128 {
+            Self::user_profiles(account)
+                .map(|profile| {
+                    profile.devices.iter()
+                        .filter_map(|device_id| Self::devices(device_id))
+                        .filter(|device| device.active)
+                        .fold(0u128, |acc, device| acc.saturating_add(device.max_capacity as u128))
+                })
+                .unwrap_or(0)
+        }
+
+        pub fn has_device_type(account: &T::AccountId, device_type: &DeviceType) -> bool {
+            Self::user_profiles(account)
+                .map(|profile| {
+                    profile.devices.iter()
+                        .filter_map(|device_id| Self::devices(device_id))
+                        .any(|device| device.active && device.device_type == *device_type)
+                })
+                .unwrap_or(false)
+        }
+
+        // Owned devices and devices shared with the account
+        pub fn devices_of(account: &T::AccountId) -> Vec<T::Hash> {
+            let mut devices: Vec<T::Hash> = Self::user_profiles(account)
+                .map(|profile| profile.devices.into_inner())
+                .unwrap_or_default();
+            devices.extend(Self::shared_devices(account));
+            devices
+        }
+
+        // Accounts that share a device with the account or delegate to or for it. A device can
+        // be shared with any number of accounts, so only the first `MAX_RELATED_ACCOUNTS` of
+        // them are read
+        fn related_accounts(account: &T::AccountId) -> Vec<T::AccountId> {
+            let mut related: Vec<T::AccountId> = Self::delegations(account).into_inner();
+            related.extend(Self::delegators(account));
+            for device_id in Self::devices_of(account) {
+                related.extend(Self::devices(device_id).map(|device| device.owner));
+                related.extend(DeviceUsers::<T>::iter_key_prefix(device_id).take(MAX_RELATED_ACCOUNTS));
+            }
+            related.retain(|other| other != account);
+            related
+        }
+
+        // Accounts linked through a chain of shared devices and delegations, so circular
+        // flows such as A to B to C and back to A count as related. The search stops once
+        // `MAX_RELATED_ACCOUNTS` accounts have been visited
+        pub fn are_related(a: &T::AccountId, b: &T::AccountId) -> bool {
+            if a == b {
+                return false;
+            }
+            let mut visited = sp_std::vec![a.clone()];
+            let mut frontier = sp_std::vec![a.clone()];
+            for _ in 0..MAX_RELATION_HOPS {
+                let mut next = Vec::new();
+                for account in frontier {
+                    for other in Self::related_accounts(&account) {
+                        if other == *b {
+                            return true;
+                        }
+                        if !visited.contains(&other) {
+                            if visited.len() >= MAX_RELATED_ACCOUNTS {
+                                return false;
+                            }
+                            visited.push(other.clone());
+                            next.push(other);
+                        }
+                    }
+                }
+                if next.is_empty() {
+                    break;
+                }
+                frontier = next;
+            }
+            false
+        }
+
+        pub fn is_grid_operator(account: &T::AccountId) -> bool {
+            Self::user_profiles(account)
+                .map(|profile| profile.active && profile.role == UserRole::GridOperator)
+                .unwrap_or(false)
+        }
+
+        // Raise or lower a user's reputation, e.g. after a dispute outcome
+        pub fn adjust_reputation(account: &T::AccountId, delta: i32) -> DispatchResult {
+            UserProfiles::<T>::try_mutate(account, |profile| -> DispatchResult {
+                let profile = profile.as_mut().ok_or(Error::<T>::UserNotFound)?;
+                profile.reputation_score = profile.reputation_score.saturating_add_signed(delta);
+
+                Self::deposit_event(Event::ReputationChanged {
+                    account: account.clone(),
+                    reputation_score: profile.reputation_score,
+                });
+
+                Ok(())
+            })
+        }
     }
 }
@@ -1,4 +1,4 @@
-use crate::{mock::*, Error, Event, UserRole, DeviceType};
+use crate::{mock::*, Error, Event, UserRole, DeviceType, MAX_DELEGATIONS};
 use frame_support::{assert_noop, assert_ok};
 
 #[test]
@@ -46,7 +46,7 @@ fn register_device_works() {
         let device_id = System::events()
             .iter()
             .find_map(|r| {
-                if let Event::DeviceRegistered { device_id, .. } = r.event {
+                if let RuntimeEvent::UserRegistry(Event::DeviceRegistered { device_id, .. }) = r.event {
                     Some(device_id)
                 } else {
                     None
@@ -117,3 +117,105 @@ fn update_user_role_works() {
         assert_eq!(profile.role, new_role);
     });
 }
+
+#[test]
+fn devices_are_shared_once_the_other_account_accepts() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(UserRegistry::register_user(RuntimeOrigin::signed(1), UserRole::Prosumer));
+        assert_ok!(UserRegistry::register_user(RuntimeOrigin::signed(2), UserRole::Consumer));
+        assert_ok!(UserRegistry::register_device(RuntimeOrigin::signed(1), DeviceType::SolarPanel, 10));
+        let device_id = UserRegistry::user_profiles(1).unwrap().devices[0];
+
+        assert_ok!(UserRegistry::share_device(RuntimeOrigin::signed(1), device_id, 2));
+        System::assert_last_event(Event::DeviceShareOffered { device_id, with: 2 }.into());
+        assert!(UserRegistry::shared_devices(2).is_empty());
+        assert!(!UserRegistry::are_related(&1, &2));
+
+        assert_noop!(
+            UserRegistry::accept_device_share(RuntimeOrigin::signed(3), device_id),
+            Error::<Test>::NoShareOffer
+        );
+        assert_ok!(UserRegistry::accept_device_share(RuntimeOrigin::signed(2), device_id));
+        assert_eq!(UserRegistry::shared_devices(2).into_inner(), vec![device_id]);
+        assert!(UserRegistry::are_related(&1, &2));
+
+        // The account it is shared with can leave on its own
+        assert_ok!(UserRegistry::unshare_device(RuntimeOrigin::signed(2), device_id, 2));
+        assert!(UserRegistry::shared_devices(2).is_empty());
+        assert!(!UserRegistry::are_related(&1, &2));
+    });
+}
+
+fn delegate(delegator: u64, delegate: u64) {
+    assert_ok!(UserRegistry::add_delegate(RuntimeOrigin::signed(delegator), delegate));
+    assert_ok!(UserRegistry::accept_delegation(RuntimeOrigin::signed(delegate), delegator));
+}
+
+#[test]
+fn relations_are_followed_across_accounts() {
+    new_test_ext().execute_with(|| {
+        for account in 1..=5 {
+            assert_ok!(UserRegistry::register_user(RuntimeOrigin::signed(account), UserRole::Prosumer));
+        }
+        // 1 delegates to 2 and 2 to 3, closing the loop 1 -> 2 -> 3 -> 1
+        delegate(1, 2);
+        delegate(2, 3);
+        assert!(UserRegistry::are_related(&1, &3));
+        assert!(UserRegistry::are_related(&3, &1));
+
+        // Beyond `MAX_RELATION_HOPS` accounts are no longer related
+        delegate(3, 4);
+        delegate(4, 5);
+        assert!(UserRegistry::are_related(&1, &4));
+        assert!(!UserRegistry::are_related(&1, &5));
+    });
+}
+
+#[test]
+fn delegations_take_effect_once_the_delegate_accepts() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(UserRegistry::register_user(RuntimeOrigin::signed(1), UserRole::Prosumer));
+
+        assert_ok!(UserRegistry::add_delegate(RuntimeOrigin::signed(1), 2));
+        System::assert_last_event(Event::DelegateOffered { delegator: 1, delegate: 2 }.into());
+        assert_noop!(
+            UserRegistry::add_delegate(RuntimeOrigin::signed(1), 2),
+            Error::<Test>::AlreadyDelegated
+        );
+        assert!(UserRegistry::delegations(1).is_empty());
+        assert!(!UserRegistry::are_related(&1, &2));
+
+        assert_noop!(
+            UserRegistry::accept_delegation(RuntimeOrigin::signed(3), 1),
+            Error::<Test>::NoDelegateOffer
+        );
+        assert_ok!(UserRegistry::accept_delegation(RuntimeOrigin::signed(2), 1));
+        assert_eq!(UserRegistry::delegations(1).into_inner(), vec![2]);
+        assert_eq!(UserRegistry::delegators(2).into_inner(), vec![1]);
+        assert!(UserRegistry::are_related(&1, &2));
+
+        assert_ok!(UserRegistry::remove_delegate(RuntimeOrigin::signed(1), 2));
+        assert!(UserRegistry::delegations(1).is_empty() && UserRegistry::delegators(2).is_empty());
+        assert_noop!(
+            UserRegistry::remove_delegate(RuntimeOrigin::signed(1), 2),
+            Error::<Test>::NotDelegated
+        );
+    });
+}
+
+#[test]
+fn delegations_are_capped_per_account() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(UserRegistry::register_user(RuntimeOrigin::signed(1), UserRole::Prosumer));
+        for delegate in 2..2 + MAX_DELEGATIONS as u64 {
+            self::delegate(1, delegate);
+        }
+
+        let delegate = 2 + MAX_DELEGATIONS as u64;
+        assert_ok!(UserRegistry::add_delegate(RuntimeOrigin::signed(1), delegate));
+        assert_noop!(
+            UserRegistry::accept_delegation(RuntimeOrigin::signed(delegate), 1),
+            Error::<Test>::TooManyDelegations
+        );
+    });
+}
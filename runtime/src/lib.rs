@@ -5,12 +5,13 @@ use frame_support::{
     construct_runtime, parameter_types,
     traits::{ConstU128, ConstU32, ConstU64, ConstU8},
     weights::Weight,
+    PalletId,
 };
 use frame_system::limits::{BlockLength, BlockWeights};
 use sp_runtime::{
     create_runtime_str,
     generic,
-    traits::{AccountIdLookup, BlakeTwo256, Block as BlockT, IdentifyAccount, Verify},
+    traits::{AccountIdConversion, AccountIdLookup, BlakeTwo256, Block as BlockT, IdentifyAccount, Verify},
     transaction_validity::{TransactionSource, TransactionValidity},
     MultiSignature,
 };
@@ -38,6 +39,24 @@ parameter_types! {
     pub const BlockHashCount: BlockNumber = 2400;
 }
 
+pub type Address = sp_runtime::MultiAddress<AccountId, ()>;
+pub type Header = generic::Header<BlockNumber, BlakeTwo256>;
+pub type Block = generic::Block<Header, UncheckedExtrinsic>;
+
+// Checked for every signed extrinsic in the transaction pool and again when it is applied
+pub type TxExtension = (
+    frame_system::CheckNonZeroSender<Runtime>,
+    frame_system::CheckSpecVersion<Runtime>,
+    frame_system::CheckTxVersion<Runtime>,
+    frame_system::CheckGenesis<Runtime>,
+    frame_system::CheckEra<Runtime>,
+    frame_system::CheckNonce<Runtime>,
+    frame_system::CheckWeight<Runtime>,
+    pallet_energy_trade::CheckOrderRate<Runtime>,
+);
+
+pub type UncheckedExtrinsic = generic::UncheckedExtrinsic<Address, RuntimeCall, Signature, TxExtension>;
+
 construct_runtime!(
     pub enum Runtime where
         Block = Block,
@@ -95,9 +114,93 @@ impl pallet_energy_token::Config for Runtime {
     type TokenBalance = Balance;
 }
 
+parameter_types! {
+    // 15 minute delivery intervals at 6 second blocks
+    pub const AuctionInterval: BlockNumber = 150;
+    pub TreasuryAccount: AccountId = PalletId(*b"gx/trsry").into_account_truncating();
+    pub CommunityFundAccount: AccountId = PalletId(*b"gx/cmnty").into_account_truncating();
+    pub ImbalanceAccount: AccountId = PalletId(*b"gx/imbal").into_account_truncating();
+}
+
+// Trading permissions and generation capacity come from the user registry
+pub struct RegistryParticipants;
+
+impl pallet_energy_trade::ParticipantRegistry<AccountId> for RegistryParticipants {
+    fn is_active(who: &AccountId) -> bool {
+        UserRegistry::is_active_user(who)
+    }
+
+    fn can_sell(who: &AccountId) -> bool {
+        UserRegistry::can_sell(who)
+    }
+
+    fn generation_capacity(who: &AccountId) -> u128 {
+        UserRegistry::generation_capacity(who)
+    }
+
+    fn has_device_type(who: &AccountId, device_type: &pallet_user_registry::DeviceType) -> bool {
+        UserRegistry::has_device_type(who, device_type)
+    }
+
+    fn are_related(a: &AccountId, b: &AccountId) -> bool {
+        UserRegistry::are_related(a, b)
+    }
+
+    fn reputation(who: &AccountId) -> u32 {
+        UserRegistry::user_profiles(who).map(|profile| profile.reputation_score).unwrap_or(0)
+    }
+
+    fn has_role(who: &AccountId, role: &pallet_user_registry::UserRole) -> bool {
+        UserRegistry::user_profiles(who).map_or(false, |profile| profile.active && profile.role == *role)
+    }
+}
+
+#[cfg(feature = "runtime-benchmarks")]
+impl pallet_energy_trade::BenchmarkHelper<AccountId> for RegistryParticipants {
+    fn register_participant(who: &AccountId) {
+        let origin = RuntimeOrigin::signed(who.clone());
+        let _ = UserRegistry::register_user(origin.clone(), pallet_user_registry::UserRole::Prosumer);
+        let _ = UserRegistry::register_device(origin, pallet_user_registry::DeviceType::SolarPanel, u32::MAX);
+    }
+}
+
 impl pallet_energy_trade::Config for Runtime {
     type RuntimeEvent = RuntimeEvent;
     type TokenBalance = Balance;
+    type Currency = Balances;
+    type OperatorOrigin = frame_system::EnsureRoot<AccountId>;
+    type MarketAdminOrigin = frame_system::EnsureRoot<AccountId>;
+    type Participants = RegistryParticipants;
+    // Without price discovery only bids' own location is within any distance
+    type Distances = ();
+    type Statistics = ();
+    type AuctionInterval = AuctionInterval;
+    type MaxAuctionOrders = ConstU32<256>;
+    type MaxAuctionOrdersPerAccount = ConstU32<16>;
+    type MaxStopOrders = ConstU32<64>;
+    type TimeProvider = Timestamp;
+    type SlotDuration = ConstU64<{ 15 * 60 * 1000 }>;
+    type RevealPeriod = ConstU32<30>;
+    type CommitDeposit = ConstU128<1_000_000_000_000>;
+    type TreasuryAccount = TreasuryAccount;
+    type CommunityFundAccount = CommunityFundAccount;
+    // Price discovery is not part of this runtime yet; shortfalls settle at the contract price
+    type ImbalancePrices = ();
+    type ImbalanceAccount = ImbalanceAccount;
+    type MaxOrdersPerLevel = ConstU32<64>;
+    type MaxPriceLevels = ConstU32<128>;
+    type MaxFillsPerOrder = ConstU32<32>;
+    type MaxLastResortPerBlock = ConstU32<64>;
+    // 20 orders a minute at the starting reputation
+    type RateLimitWindow = ConstU32<10>;
+    type MaxOrdersPerWindow = ConstU32<20>;
+    // A million tokens per order, far below where fees and penalties could overflow
+    type MaxOrderValue = ConstU128<1_000_000_000_000_000_000>;
+    // Battery storage is not part of this runtime yet
+    type OrderHooks = ();
+    type WeightInfo = pallet_energy_trade::weights::SubstrateWeight<Runtime>;
+    #[cfg(feature = "runtime-benchmarks")]
+    type BenchmarkHelper = RegistryParticipants;
 }
 
 impl pallet_user_registry::Config for Runtime {